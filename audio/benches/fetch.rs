@@ -0,0 +1,165 @@
+//! Latency of the audio file fetch against a `MockRequester`, to evaluate changes to the
+//! download heuristics: the time until the first audio data can be read, the time until data
+//! is read after a seek, and the time to download a whole file.
+//!
+//! Run with `cargo bench -p librespot-audio --features test-support`. The mock delays
+//! responses in real time, so the results are dominated by the round trips the fetch makes.
+
+use std::io::{Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use librespot_audio::test_support::{test_data, MockConfig, MockRequester};
+use librespot_audio::{AudioFileStreaming, FetchProfile};
+use librespot_core::spotify_id::FileId;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+
+// About 15 seconds of a 160 kbit/s file.
+const FILE_SIZE: usize = 300 * 1024 + 13;
+const DATA_RATE: usize = 20 * 1024;
+const READ_SIZE: usize = 4096;
+
+fn configs() -> Vec<(&'static str, MockConfig)> {
+    vec![
+        ("fast", MockConfig::default()),
+        (
+            "mobile",
+            MockConfig {
+                latency: Duration::from_millis(80),
+                jitter: Duration::from_millis(20),
+                bytes_per_second: Some(256 * 1024),
+                ..MockConfig::default()
+            },
+        ),
+        ("lossy", MockConfig::lossy()),
+    ]
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap()
+}
+
+fn open(
+    requester: &Arc<MockRequester>,
+    play_from_beginning: bool,
+) -> (
+    AudioFileStreaming,
+    oneshot::Receiver<tempfile::NamedTempFile>,
+) {
+    let (complete_tx, complete_rx) = oneshot::channel();
+    let file = AudioFileStreaming::open_with_requester(
+        requester.clone(),
+        FileId([1; 20]),
+        FILE_SIZE,
+        complete_tx,
+        DATA_RATE,
+        play_from_beginning,
+        FetchProfile::default(),
+    )
+    .unwrap();
+    (file, complete_rx)
+}
+
+async fn read_some(file: &mut AudioFileStreaming) {
+    let mut buffer = [0u8; READ_SIZE];
+    let read = file.read_async(&mut buffer).await.unwrap();
+    assert!(read > 0);
+}
+
+fn time_to_first_audio(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("time_to_first_audio");
+    group.sample_size(20);
+    for (name, config) in configs() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iterations| {
+                let mut total = Duration::default();
+                for _ in 0..iterations {
+                    total += runtime.block_on(async {
+                        let requester =
+                            Arc::new(MockRequester::new(test_data(FILE_SIZE), config.clone()));
+                        let start = Instant::now();
+                        let (mut file, _complete_rx) = open(&requester, true);
+                        read_some(&mut file).await;
+                        let elapsed = start.elapsed();
+                        requester.shutdown();
+                        elapsed
+                    });
+                }
+                total
+            })
+        });
+    }
+    group.finish();
+}
+
+fn seek_latency(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("seek_latency");
+    group.sample_size(20);
+    for (name, config) in configs() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iterations| {
+                let mut total = Duration::default();
+                for _ in 0..iterations {
+                    total += runtime.block_on(async {
+                        let requester =
+                            Arc::new(MockRequester::new(test_data(FILE_SIZE), config.clone()));
+                        let (mut file, _complete_rx) = open(&requester, false);
+                        read_some(&mut file).await;
+
+                        // Past the data requested when the file was opened.
+                        let start = Instant::now();
+                        file.seek(SeekFrom::Start(FILE_SIZE as u64 * 2 / 3))
+                            .unwrap();
+                        read_some(&mut file).await;
+                        let elapsed = start.elapsed();
+                        requester.shutdown();
+                        elapsed
+                    });
+                }
+                total
+            })
+        });
+    }
+    group.finish();
+}
+
+// How well the scheduler keeps requests in flight: the time until the whole file is
+// downloaded while it is read from the start.
+fn full_download(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("full_download");
+    group.sample_size(10);
+    for (name, config) in configs() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iterations| {
+                let mut total = Duration::default();
+                for _ in 0..iterations {
+                    total += runtime.block_on(async {
+                        let requester =
+                            Arc::new(MockRequester::new(test_data(FILE_SIZE), config.clone()));
+                        let start = Instant::now();
+                        let (mut file, complete_rx) = open(&requester, true);
+                        let mut buffer = [0u8; READ_SIZE];
+                        while file.read_async(&mut buffer).await.unwrap() > 0 {}
+                        complete_rx.await.unwrap();
+                        let elapsed = start.elapsed();
+                        requester.shutdown();
+                        elapsed
+                    });
+                }
+                total
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, time_to_first_audio, seek_latency, full_download);
+criterion_main!(benches);
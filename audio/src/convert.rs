@@ -1,5 +1,7 @@
 use zerocopy::AsBytes;
 
+use crate::simd;
+
 #[derive(AsBytes, Copy, Clone, Debug)]
 #[allow(non_camel_case_types)]
 #[repr(transparent)]
@@ -13,35 +15,40 @@ impl i24 {
 }
 
 // Losslessly represent [-1.0, 1.0] to [$type::MIN, $type::MAX] while maintaining DC linearity.
-macro_rules! convert_samples_to {
-    ($type: ident, $samples: expr) => {
-        convert_samples_to!($type, $samples, 0)
-    };
-    ($type: ident, $samples: expr, $drop_bits: expr) => {
-        $samples
-            .iter()
-            .map(|sample| {
-                // Losslessly represent [-1.0, 1.0] to [$type::MIN, $type::MAX]
-                // while maintaining DC linearity. There is nothing to be gained
-                // by doing this in f64, as the significand of a f32 is 24 bits,
-                // just like the maximum bit depth we are converting to.
-                let int_value = *sample * (std::$type::MAX as f32 + 0.5) - 0.5;
-
-                // Casting floats to ints truncates by default, which results
-                // in larger quantization error than rounding arithmetically.
-                // Flooring is faster, but again with larger error.
-                int_value.round() as $type >> $drop_bits
-            })
-            .collect()
+// The vectorized conversions in `simd` must compute the same.
+macro_rules! convert_sample_to {
+    ($type: ident, $sample: expr) => {
+        convert_sample_to!($type, $sample, 0)
     };
+    ($type: ident, $sample: expr, $drop_bits: expr) => {{
+        // Losslessly represent [-1.0, 1.0] to [$type::MIN, $type::MAX]
+        // while maintaining DC linearity. There is nothing to be gained
+        // by doing this in f64, as the significand of a f32 is 24 bits,
+        // just like the maximum bit depth we are converting to.
+        let int_value = $sample * ($type::MAX as f32 + 0.5) - 0.5;
+
+        // Casting floats to ints truncates by default, which results
+        // in larger quantization error than rounding arithmetically.
+        // Flooring is faster, but again with larger error.
+        int_value.round() as $type >> $drop_bits
+    }};
+}
+
+fn to_i32(samples: &[f32], drop_bits: u32) -> Vec<i32> {
+    let mut output = vec![0; samples.len()];
+    let converted = simd::to_i32(samples, drop_bits, &mut output);
+    for (sample, output) in samples[converted..].iter().zip(&mut output[converted..]) {
+        *output = convert_sample_to!(i32, *sample, drop_bits);
+    }
+    output
 }
 
 pub fn to_s32(samples: &[f32]) -> Vec<i32> {
-    convert_samples_to!(i32, samples)
+    to_i32(samples, 0)
 }
 
 pub fn to_s24(samples: &[f32]) -> Vec<i32> {
-    convert_samples_to!(i32, samples, 8)
+    to_i32(samples, 8)
 }
 
 pub fn to_s24_3(samples: &[f32]) -> Vec<i24> {
@@ -52,5 +59,62 @@ pub fn to_s24_3(samples: &[f32]) -> Vec<i24> {
 }
 
 pub fn to_s16(samples: &[f32]) -> Vec<i16> {
-    convert_samples_to!(i16, samples)
+    let mut output = vec![0; samples.len()];
+    let converted = simd::to_i16(samples, &mut output);
+    for (sample, output) in samples[converted..].iter().zip(&mut output[converted..]) {
+        *output = convert_sample_to!(i16, *sample);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rounding ties, the ends of the range, values beyond them and NaN.
+    fn samples() -> Vec<f32> {
+        let mut samples = vec![
+            0.0,
+            -0.0,
+            1.0,
+            -1.0,
+            1.5,
+            -1.5,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::NAN,
+            0.5 / 32767.5,
+            -0.5 / 32767.5,
+            1.5 / 32767.5,
+            -1.5 / 32767.5,
+            0.999_99,
+            -0.999_99,
+        ];
+        samples.extend((0..1000).map(|i| (i as f32 * 0.37).sin()));
+        samples
+    }
+
+    #[test]
+    fn converts_like_scalar_code() {
+        let samples = samples();
+        let s16: Vec<i16> = samples
+            .iter()
+            .map(|s| convert_sample_to!(i16, *s))
+            .collect();
+        let s24: Vec<i32> = samples
+            .iter()
+            .map(|s| convert_sample_to!(i32, *s, 8))
+            .collect();
+        let s32: Vec<i32> = samples
+            .iter()
+            .map(|s| convert_sample_to!(i32, *s))
+            .collect();
+
+        // Every offset puts other samples in the remainder that isn't vectorized.
+        for offset in 0..8 {
+            assert_eq!(to_s16(&samples[offset..]), s16[offset..]);
+            assert_eq!(to_s24(&samples[offset..]), s24[offset..]);
+            assert_eq!(to_s32(&samples[offset..]), s32[offset..]);
+        }
+    }
 }
@@ -0,0 +1,344 @@
+//! Fetch of streams whose size is unknown or that don't end, e.g. live or radio-like content.
+//!
+//! The data is requested in consecutive chunks at the pace it is read, like it is produced.
+//! Only a window around the read position is kept in memory: the data behind it is discarded,
+//! so seeking back is limited to the retained data.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use librespot_core::session::Session;
+use librespot_core::spotify_id::FileId;
+use tokio::sync::Notify;
+
+use super::requester::DataRequester;
+use super::READ_AHEAD_DURING_PLAYBACK_SECONDS;
+use crate::range_set::{Range, RangeSet};
+
+/// The parameters of fetching a live stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LiveFetchConfig {
+    /// How much data ahead of the read position is requested.
+    pub read_ahead_seconds: f64,
+    /// How much data behind the read position is kept, e.g. for seeking back.
+    pub retain_seconds: f64,
+    /// The duration of the data of a request.
+    pub request_seconds: f64,
+    /// The wait before requesting data again when a request returned none, e.g. because it
+    /// was ahead of the live edge of the stream.
+    pub retry_interval: Duration,
+    /// The stream has ended when this many requests in a row returned no data.
+    pub max_empty_responses: usize,
+}
+
+impl Default for LiveFetchConfig {
+    fn default() -> Self {
+        Self {
+            read_ahead_seconds: READ_AHEAD_DURING_PLAYBACK_SECONDS,
+            retain_seconds: 10.0,
+            request_seconds: 1.0,
+            retry_interval: Duration::from_secs(1),
+            max_empty_responses: 5,
+        }
+    }
+}
+
+/// Reads a stream of unknown size, see the module documentation. Reads return 0 once the
+/// stream has ended, and reading or seeking to data that was discarded fails.
+pub struct AudioFileLive {
+    position: usize,
+    shared: Arc<LiveShared>,
+}
+
+struct LiveShared {
+    file_id: FileId,
+    data_rate: usize,
+    config: LiveFetchConfig,
+    // Notified when data arrived, the stream ended or the read position changed.
+    changed: Notify,
+    window: Mutex<LiveWindow>,
+    // Set when the `AudioFileLive` is dropped.
+    closed: AtomicBool,
+}
+
+struct LiveWindow {
+    // The downloaded data from `start`.
+    data: VecDeque<u8>,
+    start: usize,
+    downloaded: RangeSet,
+    read_position: usize,
+    ended: bool,
+}
+
+impl LiveWindow {
+    fn end(&self) -> usize {
+        self.start + self.data.len()
+    }
+
+    // Adds the data received at `offset`, unless it would leave a gap.
+    fn append(&mut self, offset: usize, data: &[u8]) -> bool {
+        let end = self.end();
+        if offset > end {
+            return false;
+        }
+        if offset + data.len() > end {
+            self.data.extend(&data[end - offset..]);
+            self.downloaded
+                .add_range(&Range::new(end, offset + data.len() - end));
+        }
+        true
+    }
+
+    fn discard_before(&mut self, offset: usize) {
+        let length = min(offset.saturating_sub(self.start), self.data.len());
+        self.data.drain(..length);
+        self.start += length;
+        self.downloaded.subtract_range(&Range::new(0, self.start));
+    }
+
+    // Restarts the window at `offset`, e.g. after seeking past the downloaded data.
+    // Requests have to be aligned to 4 bytes, so the window starts at or before it.
+    fn restart(&mut self, offset: usize) {
+        self.data.clear();
+        self.start = offset - offset % 4;
+        self.downloaded = RangeSet::new();
+    }
+
+    fn copy_to(&self, offset: usize, output: &mut [u8]) {
+        let (front, back) = self.data.as_slices();
+        let offset = offset - self.start;
+        let mut written = 0;
+        if offset < front.len() {
+            let length = min(output.len(), front.len() - offset);
+            output[..length].copy_from_slice(&front[offset..offset + length]);
+            written = length;
+        }
+        if written < output.len() {
+            let offset = offset + written - front.len();
+            let length = output.len() - written;
+            output[written..].copy_from_slice(&back[offset..offset + length]);
+        }
+    }
+}
+
+impl AudioFileLive {
+    /// Streams `file_id` with a data rate of `data_rate` bytes per second.
+    pub fn open(
+        session: &Session,
+        file_id: FileId,
+        data_rate: usize,
+        config: LiveFetchConfig,
+    ) -> AudioFileLive {
+        Self::open_with_requester(Arc::new(session.clone()), file_id, data_rate, config)
+    }
+
+    /// Like `open`, but the data is requested from `requester` instead of a session.
+    pub fn open_with_requester(
+        requester: Arc<dyn DataRequester>,
+        file_id: FileId,
+        data_rate: usize,
+        config: LiveFetchConfig,
+    ) -> AudioFileLive {
+        let shared = Arc::new(LiveShared {
+            file_id,
+            data_rate,
+            config,
+            changed: Notify::new(),
+            window: Mutex::new(LiveWindow {
+                data: VecDeque::new(),
+                start: 0,
+                downloaded: RangeSet::new(),
+                read_position: 0,
+                ended: false,
+            }),
+            closed: AtomicBool::new(false),
+        });
+
+        let fetch_shared = shared.clone();
+        let shutdown = requester.shutdown_requested();
+        requester.clone().spawn(Box::pin(async move {
+            tokio::select! {
+                _ = fetch_live(requester, fetch_shared.clone()) => (),
+                _ = shutdown => (),
+            }
+            fetch_shared.window.lock().unwrap().ended = true;
+            fetch_shared.changed.notify_waiters();
+        }));
+
+        AudioFileLive {
+            position: 0,
+            shared,
+        }
+    }
+
+    /// The ranges of the stream that are kept in memory, as offsets and lengths.
+    pub fn downloaded_ranges(&self) -> Vec<(usize, usize)> {
+        let window = self.shared.window.lock().unwrap();
+        window
+            .downloaded
+            .iter()
+            .map(|range| (range.start, range.length))
+            .collect()
+    }
+
+    /// Reads like `Read::read`, but waits for data that is still being downloaded without
+    /// blocking the thread.
+    pub async fn read_async(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if output.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            // Created before checking, so a notification in between isn't missed.
+            let changed = self.shared.changed.notified();
+            {
+                let mut window = self.shared.window.lock().unwrap();
+                if self.position < window.start {
+                    return Err(self.discarded_error());
+                }
+
+                let available = window.downloaded.contained_length_from_value(self.position);
+                if available > 0 {
+                    let length = min(output.len(), available);
+                    window.copy_to(self.position, &mut output[..length]);
+                    self.position += length;
+                    window.read_position = self.position;
+
+                    let retained =
+                        (self.shared.config.retain_seconds * self.shared.data_rate as f64) as usize;
+                    window.discard_before(self.position.saturating_sub(retained));
+                    self.shared.changed.notify_waiters();
+                    return Ok(length);
+                }
+
+                if window.ended {
+                    return Ok(0);
+                }
+            }
+            changed.await;
+        }
+    }
+
+    fn discarded_error(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "position {} of live stream {} was discarded",
+                self.position, self.shared.file_id
+            ),
+        )
+    }
+}
+
+impl Read for AudioFileLive {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        futures_executor::block_on(self.read_async(output))
+    }
+}
+
+impl Seek for AudioFileLive {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "live streams have no end to seek from",
+                ))
+            }
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        }
+
+        let mut window = self.shared.window.lock().unwrap();
+        if (position as usize) < window.start {
+            self.position = position as usize;
+            return Err(self.discarded_error());
+        }
+        self.position = position as usize;
+        window.read_position = self.position;
+        self.shared.changed.notify_waiters();
+        Ok(self.position as u64)
+    }
+}
+
+impl Drop for AudioFileLive {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, atomic::Ordering::Relaxed);
+        self.shared.changed.notify_waiters();
+    }
+}
+
+// Requests the data after the window while it doesn't reach far enough ahead of the read
+// position, until the stream ends or the `AudioFileLive` is dropped.
+async fn fetch_live(requester: Arc<dyn DataRequester>, shared: Arc<LiveShared>) {
+    let config = shared.config;
+    let read_ahead = (config.read_ahead_seconds * shared.data_rate as f64) as usize;
+    let request_length = (config.request_seconds * shared.data_rate as f64) as usize;
+    let request_length = request_length.max(4) + (4 - request_length % 4) % 4;
+    let mut empty_responses = 0;
+
+    while !shared.closed.load(atomic::Ordering::Relaxed) {
+        let changed = shared.changed.notified();
+        let offset = {
+            let mut window = shared.window.lock().unwrap();
+            let read_position = window.read_position;
+            if read_position > window.end() {
+                window.restart(read_position);
+            }
+            let end = window.end();
+            if end >= read_position + read_ahead {
+                None
+            } else {
+                Some(end - end % 4)
+            }
+        };
+        let offset = match offset {
+            Some(offset) => offset,
+            None => {
+                changed.await;
+                continue;
+            }
+        };
+
+        let mut data = requester.request_range(shared.file_id, offset, request_length);
+        let mut position = offset;
+        let mut received = false;
+        while let Some(Ok(bytes)) = data.next().await {
+            let appended = {
+                let mut window = shared.window.lock().unwrap();
+                let end = window.end();
+                let appended = window.append(position, &bytes);
+                received |= window.end() > end;
+                appended
+            };
+            shared.changed.notify_waiters();
+            if !appended || shared.closed.load(atomic::Ordering::Relaxed) {
+                // Seeked away from the data of the request.
+                break;
+            }
+            position += bytes.len();
+        }
+
+        if received {
+            empty_responses = 0;
+        } else {
+            empty_responses += 1;
+            if empty_responses >= config.max_empty_responses {
+                debug!("Live stream {} ended at {}", shared.file_id, offset);
+                return;
+            }
+            tokio::time::sleep(config.retry_interval).await;
+        }
+    }
+}
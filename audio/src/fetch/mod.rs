@@ -1,21 +1,35 @@
+mod live;
+mod profile;
 mod receive;
+mod requester;
 
 use std::cmp::{max, min};
 use std::fs;
+use std::future::Future;
 use std::io::{self, Read, Seek, SeekFrom};
-use std::sync::atomic::{self, AtomicUsize};
-use std::sync::{Arc, Condvar, Mutex};
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use byteorder::{BigEndian, ByteOrder};
 use futures_util::{future, StreamExt, TryFutureExt, TryStreamExt};
-use librespot_core::channel::{ChannelData, ChannelError, ChannelHeaders};
+use librespot_core::channel::{
+    ChannelData, ChannelHeaders, DownloadRate, DEFAULT_DOWNLOAD_RATE_WINDOW,
+};
 use librespot_core::session::Session;
 use librespot_core::spotify_id::FileId;
+use librespot_core::Error;
+use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
 
-use self::receive::{audio_file_fetch, request_range};
+pub use self::live::{AudioFileLive, LiveFetchConfig};
+pub use self::profile::FetchProfile;
+use self::receive::audio_file_fetch;
+use self::requester::request_range;
+pub use self::requester::{DataRequester, DataStream};
 use crate::range_set::{Range, RangeSet};
 
 const MINIMUM_DOWNLOAD_SIZE: usize = 1024 * 16;
@@ -61,6 +75,18 @@ pub const READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS: f64 = 10.0;
 // Note: the calculations are done using the nominal bitrate of the file. The actual amount
 // of audio data may be larger or smaller.
 
+pub const SEEK_PREFETCH_WINDOW_SECONDS: f64 = 2.0;
+// In random access mode, data around each read position is requested in addition to the data
+// that is read, half of it before and half of it after the position. This makes it likely that
+// the pages the decoder visits while seeking, and the targets of small jumps when scrubbing, are
+// present already. The window can be changed with StreamLoaderController::set_seek_prefetch_window.
+// Note: the calculations are done using the nominal bitrate of the file. The actual amount
+// of audio data may be larger or smaller.
+
+const SEEK_PREFETCH_FOOTER_SIZE: usize = 1024 * 16;
+// When switching to random access mode, the end of the file is requested, as the decoder reads the
+// last page to find the length of the stream when seeking.
+
 const PREFETCH_THRESHOLD_FACTOR: f64 = 4.0;
 // If the amount of data that is pending (requested but not received) is less than a certain amount,
 // data is pre-fetched in addition to the read ahead settings above. The threshold for requesting more
@@ -77,6 +103,10 @@ const FAST_PREFETCH_THRESHOLD_FACTOR: f64 = 1.5;
 // performed while downloading. Values smaller than 1 cause the download rate to collapse and effectively
 // only PREFETCH_THRESHOLD_FACTOR is in effect. Thus, set to zero if bandwidth saturation is not wanted.
 
+const TEMP_FILE_PREFIX: &str = "librespot-download-";
+// The prefix of the temporary files of downloads. Files left behind by a crash are recognised
+// by it and removed with remove_temp_files.
+
 const MAX_PREFETCH_REQUESTS: usize = 4;
 // Limit the number of requests that are pending simultaneously before pre-fetching data. Pending
 // requests share bandwidth. Thus, havint too many requests can lead to the one that is needed next
@@ -88,6 +118,43 @@ pub enum AudioFile {
     Streaming(AudioFileStreaming),
 }
 
+/// A range of an audio file returned by `AudioFile::read_range`.
+pub struct RangeRead {
+    /// The bytes at the start of the range that were downloaded already.
+    pub available: Vec<u8>,
+    /// Resolves to the remaining bytes of the range once they are downloaded. Resolves to no
+    /// bytes at once if the whole range was available.
+    pub rest: Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>,
+}
+
+/// How audio files are downloaded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FetchConfig {
+    pub profile: FetchProfile,
+    /// The directory of the temporary files of downloads. Defaults to the audio cache directory
+    /// of the session, or to the system temporary directory, which may be a small tmpfs, if
+    /// there is no cache.
+    pub tmp_dir: Option<PathBuf>,
+}
+
+/// The parts of a file that are downloaded, e.g. for a cache manager that stores partially
+/// downloaded files itself and continues their download with `AudioFile::open_with_snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadSnapshot {
+    pub file_size: usize,
+    /// The downloaded ranges as offsets and lengths in bytes, ordered by offset.
+    pub downloaded: Vec<(usize, usize)>,
+}
+
+impl DownloadSnapshot {
+    pub fn is_complete(&self) -> bool {
+        match self.downloaded.first() {
+            Some(&(start, length)) => start == 0 && length >= self.file_size,
+            None => self.file_size == 0,
+        }
+    }
+}
+
 #[derive(Debug)]
 enum StreamLoaderCommand {
     Fetch(Range),       // signal the stream loader to fetch a range of the file
@@ -137,6 +204,50 @@ impl StreamLoaderController {
         })
     }
 
+    /// The downloaded parts of the file as offsets and lengths in bytes, ordered by offset.
+    pub fn downloaded_ranges(&self) -> Vec<(usize, usize)> {
+        match self.stream_shared {
+            Some(ref shared) => shared.downloaded_ranges_rx.borrow().clone(),
+            None => vec![(0, self.file_size)],
+        }
+    }
+
+    /// A receiver of `downloaded_ranges`, notified whenever data was downloaded, e.g. to draw
+    /// a bar of the buffered parts. `None` if the file is cached.
+    pub fn downloaded_ranges_changes(&self) -> Option<watch::Receiver<Vec<(usize, usize)>>> {
+        self.stream_shared
+            .as_ref()
+            .map(|shared| shared.downloaded_ranges_rx.clone())
+    }
+
+    /// The download status of the file. A cached file is complete.
+    pub fn download_snapshot(&self) -> DownloadSnapshot {
+        DownloadSnapshot {
+            file_size: self.file_size,
+            downloaded: self.downloaded_ranges(),
+        }
+    }
+
+    /// The rate in bytes per second at which data of this file was received within the
+    /// download rate window of the session.
+    pub fn download_rate_estimate(&self) -> usize {
+        self.stream_shared
+            .as_ref()
+            .map_or(0, |shared| shared.download_rate.lock().unwrap().estimate())
+    }
+
+    /// Whether data was requested that hasn't arrived yet, i.e. whether the download rate is
+    /// limited by the network rather than by how much was asked for.
+    pub fn is_fetching(&self) -> bool {
+        self.stream_shared.as_ref().map_or(false, |shared| {
+            let download_status = shared.download_status.lock().unwrap();
+            !download_status
+                .requested
+                .minus(&download_status.downloaded)
+                .is_empty()
+        })
+    }
+
     fn send_stream_loader_command(&self, command: StreamLoaderCommand) {
         if let Some(ref channel) = self.channel_tx {
             // ignore the error in case the channel has been closed already.
@@ -161,18 +272,48 @@ impl StreamLoaderController {
 
         self.fetch(range);
 
-        if let Some(ref shared) = self.stream_shared {
-            let mut download_status = shared.download_status.lock().unwrap();
-            while range.length
-                > download_status
-                    .downloaded
-                    .contained_length_from_value(range.start)
+        futures_executor::block_on(self.wait_for_range(range));
+    }
+
+    /// Fetches a range of the file and resolves when it is loaded.
+    pub async fn fetch_async(&self, mut range: Range) {
+        if range.start >= self.len() {
+            range.length = 0;
+        } else if range.end() > self.len() {
+            range.length = self.len() - range.start;
+        }
+
+        self.fetch(range);
+        self.wait_for_range(range).await;
+    }
+
+    /// Fetches the whole file, e.g. to cache it without playing it. Returns whether it was
+    /// downloaded completely.
+    pub async fn fetch_all(&self) -> bool {
+        let range = Range::new(0, self.len());
+        self.fetch_async(range).await;
+        self.range_available(range)
+    }
+
+    async fn wait_for_range(&self, range: Range) {
+        let shared = match self.stream_shared {
+            Some(ref shared) => shared,
+            None => return,
+        };
+
+        loop {
+            // Created before checking, so a notification in between isn't missed.
+            let changed = shared.download_status_changed.notified();
             {
-                download_status = shared
-                    .cond
-                    .wait_timeout(download_status, Duration::from_millis(1000))
-                    .unwrap()
-                    .0;
+                let download_status = shared.download_status.lock().unwrap();
+                if range.length
+                    <= download_status
+                        .downloaded
+                        .contained_length_from_value(range.start)
+                    || shared.fetch_stopped.load(atomic::Ordering::Relaxed)
+                {
+                    return;
+                }
                 if range.length
                     > (download_status
                         .downloaded
@@ -184,6 +325,7 @@ impl StreamLoaderController {
                     self.fetch(range);
                 }
             }
+            changed.await;
         }
     }
 
@@ -217,6 +359,15 @@ impl StreamLoaderController {
         self.send_stream_loader_command(StreamLoaderCommand::StreamMode());
     }
 
+    pub fn set_seek_prefetch_window(&self, bytes: usize) {
+        // the amount of data around the read position to request in random access mode.
+        if let Some(ref shared) = self.stream_shared {
+            shared
+                .seek_prefetch_window
+                .store(bytes, atomic::Ordering::Relaxed);
+        }
+    }
+
     pub fn close(&self) {
         // terminate stream loading and don't load any more data for this file.
         self.send_stream_loader_command(StreamLoaderCommand::Close());
@@ -245,11 +396,21 @@ struct AudioFileShared {
     file_id: FileId,
     file_size: usize,
     stream_data_rate: usize,
-    cond: Condvar,
+    profile: FetchProfile,
+    // Notified when data was downloaded, a request failed or the fetch stopped.
+    download_status_changed: Notify,
     download_status: Mutex<AudioFileDownloadStatus>,
     download_strategy: Mutex<DownloadStrategy>,
     ping_time_ms: AtomicUsize,
+    download_rate: Mutex<DownloadRate>,
     read_position: AtomicUsize,
+    seek_prefetch_window: AtomicUsize,
+    // Set when the fetch task has stopped, e.g. because the file could not be written.
+    // Data that is missing at that point will never arrive.
+    fetch_stopped: AtomicBool,
+    // Snapshots of the downloaded ranges. The receiver is kept so that sending never fails.
+    downloaded_ranges_tx: watch::Sender<Vec<(usize, usize)>>,
+    downloaded_ranges_rx: watch::Receiver<Vec<(usize, usize)>>,
 }
 
 impl AudioFile {
@@ -258,7 +419,45 @@ impl AudioFile {
         file_id: FileId,
         bytes_per_second: usize,
         play_from_beginning: bool,
-    ) -> Result<AudioFile, ChannelError> {
+    ) -> Result<AudioFile, Error> {
+        Self::open_with_profile(
+            session,
+            file_id,
+            bytes_per_second,
+            play_from_beginning,
+            FetchProfile::default(),
+        )
+        .await
+    }
+
+    pub async fn open_with_profile(
+        session: &Session,
+        file_id: FileId,
+        bytes_per_second: usize,
+        play_from_beginning: bool,
+        profile: FetchProfile,
+    ) -> Result<AudioFile, Error> {
+        let config = FetchConfig {
+            profile,
+            ..FetchConfig::default()
+        };
+        Self::open_with_config(
+            session,
+            file_id,
+            bytes_per_second,
+            play_from_beginning,
+            &config,
+        )
+        .await
+    }
+
+    pub async fn open_with_config(
+        session: &Session,
+        file_id: FileId,
+        bytes_per_second: usize,
+        play_from_beginning: bool,
+        config: &FetchConfig,
+    ) -> Result<AudioFile, Error> {
         if let Some(file) = session.cache().and_then(|cache| cache.file(file_id)) {
             debug!("File {} already in cache", file_id);
             return Ok(AudioFile::Cached(file));
@@ -267,34 +466,26 @@ impl AudioFile {
         debug!("Downloading file {}", file_id);
 
         let (complete_tx, complete_rx) = oneshot::channel();
-        let mut initial_data_length = if play_from_beginning {
-            INITIAL_DOWNLOAD_SIZE
-                + max(
-                    (READ_AHEAD_DURING_PLAYBACK_SECONDS * bytes_per_second as f64) as usize,
-                    (INITIAL_PING_TIME_ESTIMATE_SECONDS
-                        * READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS
-                        * bytes_per_second as f64) as usize,
-                )
-        } else {
-            INITIAL_DOWNLOAD_SIZE
-        };
-        if initial_data_length % 4 != 0 {
-            initial_data_length += 4 - (initial_data_length % 4);
-        }
+        let initial_data_length =
+            initial_data_length(bytes_per_second, play_from_beginning, &config.profile);
         let (headers, data) = request_range(session, file_id, 0, initial_data_length).split();
 
+        let tmp_dir = tmp_dir(session, config);
         let streaming = AudioFileStreaming::open(
             session.clone(),
             data,
             initial_data_length,
-            Instant::now(),
+            tokio::time::Instant::now(),
             headers,
             file_id,
             complete_tx,
             bytes_per_second,
+            config.profile,
+            tmp_dir.as_deref(),
         );
 
         let session_ = session.clone();
+        let download = session.cache().map(|cache| cache.start_download(file_id));
         session.spawn(complete_rx.map_ok(move |mut file| {
             if let Some(cache) = session_.cache() {
                 debug!("File {} complete, saving to cache", file_id);
@@ -302,11 +493,76 @@ impl AudioFile {
             } else {
                 debug!("File {} complete", file_id);
             }
+            // Only now Cache::ensure_cached finds the file.
+            drop(download);
         }));
 
         Ok(AudioFile::Streaming(streaming.await?))
     }
 
+    /// Continues the download of a file whose downloaded parts were stored elsewhere, e.g. by
+    /// an external cache manager. The ranges of `snapshot` are read from `data` at the same
+    /// offsets on a blocking thread, the rest is downloaded as it is read.
+    pub async fn open_with_snapshot<R: Read + Seek + Send + 'static>(
+        session: &Session,
+        file_id: FileId,
+        bytes_per_second: usize,
+        config: &FetchConfig,
+        snapshot: &DownloadSnapshot,
+        mut data: R,
+    ) -> Result<AudioFile, Error> {
+        if let Some(file) = session.cache().and_then(|cache| cache.file(file_id)) {
+            debug!("File {} already in cache", file_id);
+            return Ok(AudioFile::Cached(file));
+        }
+
+        debug!(
+            "Continuing download of file {} from {} downloaded bytes",
+            file_id,
+            snapshot
+                .downloaded
+                .iter()
+                .map(|&(_, length)| length)
+                .sum::<usize>()
+        );
+
+        let seed_snapshot = snapshot.clone();
+        let seed_dir = tmp_dir(session, config);
+        let (write_file, downloaded) = session
+            .spawn_blocking(move || {
+                seed_from_snapshot(&seed_snapshot, &mut data, seed_dir.as_deref())
+            })
+            .await?;
+
+        let (complete_tx, complete_rx) = oneshot::channel();
+        let streaming = AudioFileStreaming::start_with_snapshot(
+            Arc::new(session.clone()),
+            file_id,
+            complete_tx,
+            bytes_per_second,
+            config.profile,
+            session.channel().download_rate_window(),
+            snapshot.file_size,
+            write_file,
+            downloaded,
+        )?;
+
+        let session_ = session.clone();
+        let download = session.cache().map(|cache| cache.start_download(file_id));
+        session.spawn(complete_rx.map_ok(move |mut file| {
+            if let Some(cache) = session_.cache() {
+                debug!("File {} complete, saving to cache", file_id);
+                cache.save_file(file_id, &mut file);
+            } else {
+                debug!("File {} complete", file_id);
+            }
+            // Only now Cache::ensure_cached finds the file.
+            drop(download);
+        }));
+
+        Ok(AudioFile::Streaming(streaming))
+    }
+
     pub fn get_stream_loader_controller(&self) -> StreamLoaderController {
         match self {
             AudioFile::Streaming(ref stream) => StreamLoaderController {
@@ -317,7 +573,9 @@ impl AudioFile {
             AudioFile::Cached(ref file) => StreamLoaderController {
                 channel_tx: None,
                 stream_shared: None,
-                file_size: file.metadata().unwrap().len() as usize,
+                file_size: file
+                    .metadata()
+                    .map_or(0, |metadata| metadata.len() as usize),
             },
         }
     }
@@ -325,6 +583,136 @@ impl AudioFile {
     pub fn is_cached(&self) -> bool {
         matches!(self, AudioFile::Cached { .. })
     }
+
+    /// Reads `length` bytes from `offset`, without moving the read position. The part of the
+    /// range that is downloaded is returned at once, the rest is fetched and returned by a
+    /// future, e.g. to serve HTTP range requests while the file is being streamed. The range
+    /// is cut at the end of the file.
+    pub fn read_range(&self, offset: usize, length: usize) -> io::Result<RangeRead> {
+        let controller = self.get_stream_loader_controller();
+        let end = min(offset.saturating_add(length), controller.len());
+        let length = end.saturating_sub(offset);
+
+        let (file, available_length) = match self {
+            AudioFile::Cached(file) => (file, length),
+            AudioFile::Streaming(stream) => {
+                let download_status = stream.shared.download_status.lock().unwrap();
+                let downloaded = download_status
+                    .downloaded
+                    .contained_length_from_value(offset);
+                (&stream.read_file, min(downloaded, length))
+            }
+        };
+
+        let mut available = vec![0u8; available_length];
+        read_exact_at(file, &mut available, offset)?;
+
+        if available_length == length {
+            return Ok(RangeRead {
+                available,
+                rest: Box::pin(future::ready(Ok(Vec::new()))),
+            });
+        }
+
+        let file = file.try_clone()?;
+        let range = Range::new(offset + available_length, length - available_length);
+        let rest = async move {
+            controller.fetch_async(range).await;
+            if !controller.range_available(range) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "download of the file stopped",
+                ));
+            }
+            let mut data = vec![0u8; range.length];
+            read_exact_at(&file, &mut data, range.start)?;
+            Ok(data)
+        };
+
+        Ok(RangeRead {
+            available,
+            rest: Box::pin(rest),
+        })
+    }
+}
+
+// Reads at `offset` without moving the cursor the file shares with its clones, so reading
+// ranges doesn't interfere with the decoder reading the file.
+#[cfg(unix)]
+fn read_exact_at(file: &fs::File, buf: &mut [u8], offset: usize) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset as u64)
+}
+
+// On Windows, positional reads move the cursor. The decoder seeks before every read, so
+// this only matters if a range is read while the decoder reads.
+#[cfg(windows)]
+fn read_exact_at(file: &fs::File, mut buf: &mut [u8], mut offset: usize) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset as u64)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The directory of the temporary files of downloads, see FetchConfig::tmp_dir.
+fn tmp_dir(session: &Session, config: &FetchConfig) -> Option<PathBuf> {
+    config.tmp_dir.clone().or_else(|| {
+        session
+            .cache()
+            .and_then(|cache| cache.audio_location())
+            .map(Path::to_owned)
+    })
+}
+
+/// Removes the temporary files of downloads from `dir` that were left behind, e.g. because
+/// librespot crashed. Call it on startup, before any files are downloaded to `dir`. Returns the
+/// number of removed files.
+pub fn remove_temp_files(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_temp_file = entry
+            .file_name()
+            .to_str()
+            .map_or(false, |name| name.starts_with(TEMP_FILE_PREFIX));
+        if is_temp_file && entry.file_type()?.is_file() {
+            match fs::remove_file(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Cannot remove {}: {}", entry.path().display(), e),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+// The amount of data requested when a file is opened.
+fn initial_data_length(
+    bytes_per_second: usize,
+    play_from_beginning: bool,
+    profile: &FetchProfile,
+) -> usize {
+    let mut initial_data_length = if play_from_beginning {
+        INITIAL_DOWNLOAD_SIZE
+            + max(
+                (profile.read_ahead_during_playback_seconds * bytes_per_second as f64) as usize,
+                (INITIAL_PING_TIME_ESTIMATE_SECONDS
+                    * profile.read_ahead_during_playback_roundtrips
+                    * bytes_per_second as f64) as usize,
+            )
+    } else {
+        INITIAL_DOWNLOAD_SIZE
+    };
+    if initial_data_length % 4 != 0 {
+        initial_data_length += 4 - (initial_data_length % 4);
+    }
+    initial_data_length
 }
 
 impl AudioFileStreaming {
@@ -332,46 +720,180 @@ impl AudioFileStreaming {
         session: Session,
         initial_data_rx: ChannelData,
         initial_data_length: usize,
-        initial_request_sent_time: Instant,
+        initial_request_sent_time: tokio::time::Instant,
         headers: ChannelHeaders,
         file_id: FileId,
         complete_tx: oneshot::Sender<NamedTempFile>,
         streaming_data_rate: usize,
-    ) -> Result<AudioFileStreaming, ChannelError> {
+        profile: FetchProfile,
+        tmp_dir: Option<&Path>,
+    ) -> Result<AudioFileStreaming, Error> {
         let (_, data) = headers
             .try_filter(|(id, _)| future::ready(*id == 0x3))
             .next()
             .await
-            .unwrap()?;
+            .ok_or_else(|| Error::Protocol("no file size header".to_owned()))??;
 
+        if data.len() < 4 {
+            return Err(Error::Protocol("invalid file size header".to_owned()));
+        }
         let size = BigEndian::read_u32(&data) as usize * 4;
+        let download_rate_window = session.channel().download_rate_window();
+
+        Self::start(
+            Arc::new(session),
+            Box::pin(initial_data_rx),
+            initial_data_length,
+            initial_request_sent_time,
+            file_id,
+            size,
+            temp_file(size, tmp_dir)?,
+            RangeSet::new(),
+            complete_tx,
+            streaming_data_rate,
+            profile,
+            download_rate_window,
+        )
+    }
+
+    /// Streams a file of `file_size` bytes whose data is requested from `requester` instead of
+    /// a session, e.g. to test the fetch with a `test_support::MockRequester`. The complete
+    /// file is sent to `complete_tx`.
+    pub fn open_with_requester(
+        requester: Arc<dyn DataRequester>,
+        file_id: FileId,
+        file_size: usize,
+        complete_tx: oneshot::Sender<NamedTempFile>,
+        streaming_data_rate: usize,
+        play_from_beginning: bool,
+        profile: FetchProfile,
+    ) -> Result<AudioFileStreaming, Error> {
+        let initial_data_length = min(
+            initial_data_length(streaming_data_rate, play_from_beginning, &profile),
+            file_size + (4 - file_size % 4) % 4,
+        );
+        let initial_data_rx = requester.request_range(file_id, 0, initial_data_length);
+
+        Self::start(
+            requester,
+            initial_data_rx,
+            initial_data_length,
+            tokio::time::Instant::now(),
+            file_id,
+            file_size,
+            temp_file(file_size, None)?,
+            RangeSet::new(),
+            complete_tx,
+            streaming_data_rate,
+            profile,
+            DEFAULT_DOWNLOAD_RATE_WINDOW,
+        )
+    }
+
+    /// Like `open_with_requester`, but continues the download of the parts of the file that
+    /// are missing from `snapshot`, see `AudioFile::open_with_snapshot`.
+    pub fn open_with_snapshot<R: Read + Seek>(
+        requester: Arc<dyn DataRequester>,
+        file_id: FileId,
+        complete_tx: oneshot::Sender<NamedTempFile>,
+        streaming_data_rate: usize,
+        profile: FetchProfile,
+        snapshot: &DownloadSnapshot,
+        data: &mut R,
+    ) -> Result<AudioFileStreaming, Error> {
+        let (write_file, downloaded) = seed_from_snapshot(snapshot, data, None)?;
+        Self::start_with_snapshot(
+            requester,
+            file_id,
+            complete_tx,
+            streaming_data_rate,
+            profile,
+            DEFAULT_DOWNLOAD_RATE_WINDOW,
+            snapshot.file_size,
+            write_file,
+            downloaded,
+        )
+    }
+
+    fn start_with_snapshot(
+        requester: Arc<dyn DataRequester>,
+        file_id: FileId,
+        complete_tx: oneshot::Sender<NamedTempFile>,
+        streaming_data_rate: usize,
+        profile: FetchProfile,
+        download_rate_window: Duration,
+        size: usize,
+        write_file: NamedTempFile,
+        downloaded: RangeSet,
+    ) -> Result<AudioFileStreaming, Error> {
+        // The file size is known, so nothing is requested before the missing data is read.
+        Self::start(
+            requester,
+            Box::pin(futures_util::stream::empty()),
+            0,
+            tokio::time::Instant::now(),
+            file_id,
+            size,
+            write_file,
+            downloaded,
+            complete_tx,
+            streaming_data_rate,
+            profile,
+            download_rate_window,
+        )
+    }
 
+    fn start(
+        requester: Arc<dyn DataRequester>,
+        initial_data_rx: DataStream,
+        initial_data_length: usize,
+        initial_request_sent_time: tokio::time::Instant,
+        file_id: FileId,
+        size: usize,
+        mut write_file: NamedTempFile,
+        downloaded: RangeSet,
+        complete_tx: oneshot::Sender<NamedTempFile>,
+        streaming_data_rate: usize,
+        profile: FetchProfile,
+        download_rate_window: Duration,
+    ) -> Result<AudioFileStreaming, Error> {
+        let (downloaded_ranges_tx, downloaded_ranges_rx) = watch::channel(
+            downloaded
+                .iter()
+                .map(|range| (range.start, range.length))
+                .collect(),
+        );
         let shared = Arc::new(AudioFileShared {
             file_id,
             file_size: size,
             stream_data_rate: streaming_data_rate,
-            cond: Condvar::new(),
+            profile,
+            download_status_changed: Notify::new(),
             download_status: Mutex::new(AudioFileDownloadStatus {
                 requested: RangeSet::new(),
-                downloaded: RangeSet::new(),
+                downloaded,
             }),
             download_strategy: Mutex::new(DownloadStrategy::RandomAccess()), // start with random access mode until someone tells us otherwise
             ping_time_ms: AtomicUsize::new(0),
+            download_rate: Mutex::new(DownloadRate::new(download_rate_window)),
             read_position: AtomicUsize::new(0),
+            seek_prefetch_window: AtomicUsize::new(
+                (profile.seek_prefetch_window_seconds * streaming_data_rate as f64) as usize,
+            ),
+            fetch_stopped: AtomicBool::new(false),
+            downloaded_ranges_tx,
+            downloaded_ranges_rx,
         });
 
-        let mut write_file = NamedTempFile::new().unwrap();
-        write_file.as_file().set_len(size as u64).unwrap();
-        write_file.seek(SeekFrom::Start(0)).unwrap();
-
-        let read_file = write_file.reopen().unwrap();
+        write_file.seek(SeekFrom::Start(0))?;
+        let read_file = write_file.reopen()?;
 
         //let (seek_tx, seek_rx) = mpsc::unbounded();
         let (stream_loader_command_tx, stream_loader_command_rx) =
             mpsc::unbounded_channel::<StreamLoaderCommand>();
 
-        session.spawn(audio_file_fetch(
-            session.clone(),
+        requester.spawn(Box::pin(audio_file_fetch(
+            requester.clone(),
             shared.clone(),
             initial_data_rx,
             initial_request_sent_time,
@@ -379,7 +901,7 @@ impl AudioFileStreaming {
             write_file,
             stream_loader_command_rx,
             complete_tx,
-        ));
+        )));
 
         Ok(AudioFileStreaming {
             read_file,
@@ -390,8 +912,64 @@ impl AudioFileStreaming {
     }
 }
 
-impl Read for AudioFileStreaming {
-    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+// A temporary file of `size` bytes to write the downloaded data to, in `dir` or the system
+// temporary directory.
+// Copies the downloaded ranges of `snapshot` from `data` into a new temporary file. Blocking.
+fn seed_from_snapshot<R: Read + Seek>(
+    snapshot: &DownloadSnapshot,
+    data: &mut R,
+    tmp_dir: Option<&Path>,
+) -> Result<(NamedTempFile, RangeSet), Error> {
+    let size = snapshot.file_size;
+    let mut write_file = temp_file(size, tmp_dir)?;
+    let mut downloaded = RangeSet::new();
+
+    for &(start, length) in &snapshot.downloaded {
+        if start.checked_add(length).map_or(true, |end| end > size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "downloaded range {} (+{}) is out of the file",
+                    start, length
+                ),
+            )
+            .into());
+        }
+
+        data.seek(SeekFrom::Start(start as u64))?;
+        write_file.seek(SeekFrom::Start(start as u64))?;
+        let copied = io::copy(&mut data.by_ref().take(length as u64), &mut write_file)?;
+        if copied < length as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "data of downloaded range {} (+{}) is missing",
+                    start, length
+                ),
+            )
+            .into());
+        }
+        downloaded.add_range(&Range::new(start, length));
+    }
+
+    Ok((write_file, downloaded))
+}
+
+fn temp_file(size: usize, dir: Option<&Path>) -> io::Result<NamedTempFile> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(TEMP_FILE_PREFIX);
+    let write_file = match dir {
+        Some(dir) => builder.tempfile_in(dir)?,
+        None => builder.tempfile()?,
+    };
+    write_file.as_file().set_len(size as u64)?;
+    Ok(write_file)
+}
+
+impl AudioFileStreaming {
+    /// Reads like `Read::read`, but waits for data that is still being downloaded without
+    /// blocking the thread.
+    pub async fn read_async(&mut self, output: &mut [u8]) -> io::Result<usize> {
         let offset = self.position as usize;
 
         if offset >= self.shared.file_size {
@@ -400,7 +978,47 @@ impl Read for AudioFileStreaming {
 
         let length = min(output.len(), self.shared.file_size - offset);
 
-        let length_to_request = match *(self.shared.download_strategy.lock().unwrap()) {
+        self.request_data(offset, length);
+
+        if length == 0 {
+            return Ok(0);
+        }
+
+        let waited = self.wait_for_data(offset, length).await?;
+
+        let available_length = self
+            .shared
+            .download_status
+            .lock()
+            .unwrap()
+            .downloaded
+            .contained_length_from_value(offset);
+        assert!(available_length > 0);
+
+        self.position = self.read_file.seek(SeekFrom::Start(offset as u64))?;
+        let read_len = min(length, available_length);
+        let read_len = self.read_file.read(&mut output[..read_len])?;
+
+        if waited {
+            debug!(
+                "Read at postion {} completed. {} bytes returned, {} bytes were requested.",
+                offset,
+                read_len,
+                output.len()
+            );
+        }
+
+        self.position += read_len as u64;
+        self.shared
+            .read_position
+            .store(self.position as usize, atomic::Ordering::Relaxed);
+
+        Ok(read_len)
+    }
+
+    fn request_data(&self, offset: usize, length: usize) {
+        let download_strategy = *self.shared.download_strategy.lock().unwrap();
+        let length_to_request = match download_strategy {
             DownloadStrategy::RandomAccess() => length,
             DownloadStrategy::Streaming() => {
                 // Due to the read-ahead stuff, we potentially request more than the actual reqeust demanded.
@@ -409,9 +1027,9 @@ impl Read for AudioFileStreaming {
 
                 let length_to_request = length
                     + max(
-                        (READ_AHEAD_DURING_PLAYBACK_SECONDS * self.shared.stream_data_rate as f64)
-                            as usize,
-                        (READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS
+                        (self.shared.profile.read_ahead_during_playback_seconds
+                            * self.shared.stream_data_rate as f64) as usize,
+                        (self.shared.profile.read_ahead_during_playback_roundtrips
                             * ping_time_seconds
                             * self.shared.stream_data_rate as f64) as usize,
                     );
@@ -422,60 +1040,80 @@ impl Read for AudioFileStreaming {
         let mut ranges_to_request = RangeSet::new();
         ranges_to_request.add_range(&Range::new(offset, length_to_request));
 
-        let mut download_status = self.shared.download_status.lock().unwrap();
+        let download_status = self.shared.download_status.lock().unwrap();
         ranges_to_request.subtract_range_set(&download_status.downloaded);
         ranges_to_request.subtract_range_set(&download_status.requested);
 
         for &range in ranges_to_request.iter() {
-            self.stream_loader_command_tx
-                .send(StreamLoaderCommand::Fetch(range))
-                .unwrap();
+            // If the fetch task has stopped, waiting for the data fails.
+            let _ = self
+                .stream_loader_command_tx
+                .send(StreamLoaderCommand::Fetch(range));
         }
 
-        if length == 0 {
-            return Ok(0);
+        if download_strategy == DownloadStrategy::RandomAccess() {
+            // Requested after the data that is read, so it doesn't delay it.
+            let window = self
+                .shared
+                .seek_prefetch_window
+                .load(atomic::Ordering::Relaxed);
+            let start = offset.saturating_sub(window / 2);
+            let end = min(offset + length + window / 2, self.shared.file_size);
+
+            let mut window_ranges = RangeSet::new();
+            window_ranges.add_range(&Range::new(start, end - start));
+            window_ranges.subtract_range_set(&ranges_to_request);
+            window_ranges.subtract_range_set(&download_status.downloaded);
+            window_ranges.subtract_range_set(&download_status.requested);
+
+            for &range in window_ranges.iter() {
+                let _ = self
+                    .stream_loader_command_tx
+                    .send(StreamLoaderCommand::Fetch(range));
+            }
         }
+    }
 
-        let mut download_message_printed = false;
-        while !download_status.downloaded.contains(offset) {
-            if let DownloadStrategy::Streaming() = *self.shared.download_strategy.lock().unwrap() {
-                if !download_message_printed {
+    // Waits until the data at `offset` is downloaded. Returns whether it had to wait.
+    async fn wait_for_data(&self, offset: usize, length: usize) -> io::Result<bool> {
+        let mut waited = false;
+        loop {
+            // Created before checking, so a notification in between isn't missed.
+            let changed = self.shared.download_status_changed.notified();
+            {
+                let download_status = self.shared.download_status.lock().unwrap();
+                if download_status.downloaded.contains(offset) {
+                    return Ok(waited);
+                }
+                if self.shared.fetch_stopped.load(atomic::Ordering::Relaxed) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("download of file {} stopped", self.shared.file_id),
+                    ));
+                }
+                if !download_status.requested.contains(offset) {
+                    // The request failed or was cut short. Request the data again.
+                    let _ = self
+                        .stream_loader_command_tx
+                        .send(StreamLoaderCommand::Fetch(Range::new(offset, length)));
+                }
+                if !waited
+                    && *self.shared.download_strategy.lock().unwrap()
+                        == DownloadStrategy::Streaming()
+                {
                     debug!("Stream waiting for download of file position {}. Downloaded ranges: {}. Pending ranges: {}", offset, download_status.downloaded, download_status.requested.minus(&download_status.downloaded));
-                    download_message_printed = true;
                 }
             }
-            download_status = self
-                .shared
-                .cond
-                .wait_timeout(download_status, Duration::from_millis(1000))
-                .unwrap()
-                .0;
-        }
-        let available_length = download_status
-            .downloaded
-            .contained_length_from_value(offset);
-        assert!(available_length > 0);
-        drop(download_status);
-
-        self.position = self.read_file.seek(SeekFrom::Start(offset as u64)).unwrap();
-        let read_len = min(length, available_length);
-        let read_len = self.read_file.read(&mut output[..read_len])?;
-
-        if download_message_printed {
-            debug!(
-                "Read at postion {} completed. {} bytes returned, {} bytes were requested.",
-                offset,
-                read_len,
-                output.len()
-            );
+            waited = true;
+            changed.await;
         }
+    }
+}
 
-        self.position += read_len as u64;
-        self.shared
-            .read_position
-            .store(self.position as usize, atomic::Ordering::Relaxed);
-
-        Ok(read_len)
+impl Read for AudioFileStreaming {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        // The decoder reads synchronously on the player thread.
+        futures_executor::block_on(self.read_async(output))
     }
 }
 
@@ -490,6 +1128,16 @@ impl Seek for AudioFileStreaming {
     }
 }
 
+impl AudioFile {
+    /// Reads without blocking the thread while waiting for data that is being downloaded.
+    pub async fn read_async(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            AudioFile::Cached(ref mut file) => file.read(output),
+            AudioFile::Streaming(ref mut file) => file.read_async(output).await,
+        }
+    }
+}
+
 impl Read for AudioFile {
     fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
         match *self {
@@ -507,3 +1155,23 @@ impl Seek for AudioFile {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_temp_files_only() {
+        let dir = tempfile::tempdir().unwrap();
+        // A file kept like after a crash.
+        temp_file(100, Some(dir.path())).unwrap().keep().unwrap();
+        fs::write(dir.path().join("other"), b"data").unwrap();
+
+        assert_eq!(remove_temp_files(dir.path()).unwrap(), 1);
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["other"]);
+    }
+}
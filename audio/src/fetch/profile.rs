@@ -0,0 +1,94 @@
+use std::str::FromStr;
+
+use super::{
+    FAST_PREFETCH_THRESHOLD_FACTOR, MAX_PREFETCH_REQUESTS, MINIMUM_DOWNLOAD_SIZE,
+    PREFETCH_THRESHOLD_FACTOR, READ_AHEAD_BEFORE_PLAYBACK_ROUNDTRIPS,
+    READ_AHEAD_BEFORE_PLAYBACK_SECONDS, READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS,
+    READ_AHEAD_DURING_PLAYBACK_SECONDS, SEEK_PREFETCH_WINDOW_SECONDS,
+};
+
+/// The parameters of downloading audio files. The default profile uses the constants of the
+/// same names, which describe the meaning of each parameter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FetchProfile {
+    pub minimum_download_size: usize,
+    pub read_ahead_before_playback_seconds: f64,
+    pub read_ahead_before_playback_roundtrips: f64,
+    pub read_ahead_during_playback_seconds: f64,
+    pub read_ahead_during_playback_roundtrips: f64,
+    pub prefetch_threshold_factor: f64,
+    pub fast_prefetch_threshold_factor: f64,
+    pub max_prefetch_requests: usize,
+    pub seek_prefetch_window_seconds: f64,
+}
+
+impl Default for FetchProfile {
+    fn default() -> Self {
+        Self {
+            minimum_download_size: MINIMUM_DOWNLOAD_SIZE,
+            read_ahead_before_playback_seconds: READ_AHEAD_BEFORE_PLAYBACK_SECONDS,
+            read_ahead_before_playback_roundtrips: READ_AHEAD_BEFORE_PLAYBACK_ROUNDTRIPS,
+            read_ahead_during_playback_seconds: READ_AHEAD_DURING_PLAYBACK_SECONDS,
+            read_ahead_during_playback_roundtrips: READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS,
+            prefetch_threshold_factor: PREFETCH_THRESHOLD_FACTOR,
+            fast_prefetch_threshold_factor: FAST_PREFETCH_THRESHOLD_FACTOR,
+            max_prefetch_requests: MAX_PREFETCH_REQUESTS,
+            seek_prefetch_window_seconds: SEEK_PREFETCH_WINDOW_SECONDS,
+        }
+    }
+}
+
+impl FetchProfile {
+    /// Keeps few small requests in flight, for devices like the Raspberry Pi Zero.
+    pub fn low_memory() -> Self {
+        Self {
+            minimum_download_size: 1024 * 8,
+            read_ahead_during_playback_seconds: 3.0,
+            read_ahead_during_playback_roundtrips: 5.0,
+            prefetch_threshold_factor: 2.0,
+            fast_prefetch_threshold_factor: 0.0,
+            max_prefetch_requests: 2,
+            seek_prefetch_window_seconds: 1.0,
+            ..Self::default()
+        }
+    }
+
+    /// Downloads files as fast as possible with large requests, for fast connections.
+    pub fn aggressive() -> Self {
+        Self {
+            minimum_download_size: 1024 * 64,
+            read_ahead_during_playback_seconds: 10.0,
+            read_ahead_during_playback_roundtrips: 20.0,
+            prefetch_threshold_factor: 8.0,
+            fast_prefetch_threshold_factor: 3.0,
+            max_prefetch_requests: 8,
+            seek_prefetch_window_seconds: 4.0,
+            ..Self::default()
+        }
+    }
+
+    /// Downloads at the pace of playback and nothing that isn't played, for metered
+    /// connections.
+    pub fn metered() -> Self {
+        Self {
+            prefetch_threshold_factor: 2.0,
+            fast_prefetch_threshold_factor: 0.0,
+            max_prefetch_requests: 2,
+            seek_prefetch_window_seconds: 0.0,
+            ..Self::default()
+        }
+    }
+}
+
+impl FromStr for FetchProfile {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::default()),
+            "low-memory" => Ok(Self::low_memory()),
+            "aggressive" => Ok(Self::aggressive()),
+            "metered" => Ok(Self::metered()),
+            _ => Err(()),
+        }
+    }
+}
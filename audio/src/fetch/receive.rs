@@ -1,55 +1,19 @@
 use std::cmp::{max, min};
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{self, Seek, SeekFrom, Write};
 use std::sync::{atomic, Arc};
-use std::time::Instant;
 
-use byteorder::{BigEndian, WriteBytesExt};
 use bytes::Bytes;
 use futures_util::StreamExt;
-use librespot_core::channel::{Channel, ChannelData};
-use librespot_core::session::Session;
-use librespot_core::spotify_id::FileId;
 use tempfile::NamedTempFile;
 use tokio::sync::{mpsc, oneshot};
+// Follows the paused clock of simulations.
+use tokio::time::Instant;
 
 use crate::range_set::{Range, RangeSet};
 
+use super::requester::{DataRequester, DataStream};
 use super::{AudioFileShared, DownloadStrategy, StreamLoaderCommand};
-use super::{
-    FAST_PREFETCH_THRESHOLD_FACTOR, MAXIMUM_ASSUMED_PING_TIME_SECONDS, MAX_PREFETCH_REQUESTS,
-    MINIMUM_DOWNLOAD_SIZE, PREFETCH_THRESHOLD_FACTOR,
-};
-
-pub fn request_range(session: &Session, file: FileId, offset: usize, length: usize) -> Channel {
-    assert!(
-        offset % 4 == 0,
-        "Range request start positions must be aligned by 4 bytes."
-    );
-    assert!(
-        length % 4 == 0,
-        "Range request range lengths must be aligned by 4 bytes."
-    );
-    let start = offset / 4;
-    let end = (offset + length) / 4;
-
-    let (id, channel) = session.channel().allocate();
-
-    let mut data: Vec<u8> = Vec::new();
-    data.write_u16::<BigEndian>(id).unwrap();
-    data.write_u8(0).unwrap();
-    data.write_u8(1).unwrap();
-    data.write_u16::<BigEndian>(0x0000).unwrap();
-    data.write_u32::<BigEndian>(0x00000000).unwrap();
-    data.write_u32::<BigEndian>(0x00009C40).unwrap();
-    data.write_u32::<BigEndian>(0x00020000).unwrap();
-    data.write(&file.0).unwrap();
-    data.write_u32::<BigEndian>(start as u32).unwrap();
-    data.write_u32::<BigEndian>(end as u32).unwrap();
-
-    session.send_packet(0x8, data);
-
-    channel
-}
+use super::{MAXIMUM_ASSUMED_PING_TIME_SECONDS, SEEK_PREFETCH_FOOTER_SIZE};
 
 struct PartialFileData {
     offset: usize,
@@ -61,10 +25,18 @@ enum ReceivedData {
     Data(PartialFileData),
 }
 
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(offset = initial_data_offset, length = initial_request_length)
+    )
+)]
 async fn receive_data(
     shared: Arc<AudioFileShared>,
     file_data_tx: mpsc::UnboundedSender<ReceivedData>,
-    mut data_rx: ChannelData,
+    mut data_rx: DataStream,
     initial_data_offset: usize,
     initial_request_length: usize,
     request_sent_time: Instant,
@@ -118,7 +90,7 @@ async fn receive_data(
 
         let mut download_status = shared.download_status.lock().unwrap();
         download_status.requested.subtract_range(&missing_range);
-        shared.cond.notify_all();
+        shared.download_status_changed.notify_waiters();
     }
 
     let _ = finish_tx.send(());
@@ -137,7 +109,7 @@ async fn receive_data(
 }
 
 struct AudioFileFetch {
-    session: Session,
+    requester: Arc<dyn DataRequester>,
     shared: Arc<AudioFileShared>,
     output: Option<NamedTempFile>,
 
@@ -161,35 +133,21 @@ impl AudioFileFetch {
         *(self.shared.download_strategy.lock().unwrap())
     }
 
-    fn download_range(&mut self, mut offset: usize, mut length: usize) {
-        if length < MINIMUM_DOWNLOAD_SIZE {
-            length = MINIMUM_DOWNLOAD_SIZE;
-        }
-
-        // ensure the values are within the bounds and align them by 4 for the spotify protocol.
-        if offset >= self.shared.file_size {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
+    fn download_range(&mut self, offset: usize, length: usize) {
+        let file_size = self.shared.file_size;
+        if offset >= file_size {
             return;
         }
 
+        let length = max(length, self.shared.profile.minimum_download_size);
         if length == 0 {
             return;
         }
-
-        if offset + length > self.shared.file_size {
-            length = self.shared.file_size - offset;
-        }
-
-        if offset % 4 != 0 {
-            length += offset % 4;
-            offset -= offset % 4;
-        }
-
-        if length % 4 != 0 {
-            length += 4 - (length % 4);
-        }
+        let end = min(offset.saturating_add(length), file_size);
 
         let mut ranges_to_request = RangeSet::new();
-        ranges_to_request.add_range(&Range::new(offset, length));
+        ranges_to_request.add_range(&Range::new(offset, end - offset));
 
         let mut download_status = self.shared.download_status.lock().unwrap();
 
@@ -197,17 +155,19 @@ impl AudioFileFetch {
         ranges_to_request.subtract_range_set(&download_status.requested);
 
         for range in ranges_to_request.iter() {
-            let (_headers, data) = request_range(
-                &self.session,
-                self.shared.file_id,
-                range.start,
-                range.length,
-            )
-            .split();
-
-            download_status.requested.add_range(range);
-
-            self.session.spawn(receive_data(
+            // The Spotify protocol requires requests aligned by 4 bytes. Ranges left over by
+            // interrupted requests may not be aligned, and the aligned end of a file whose size
+            // isn't a multiple of 4 lies past its end.
+            let start = range.start - range.start % 4;
+            let end = range.end() + (4 - range.end() % 4) % 4;
+            let data = self
+                .requester
+                .request_range(self.shared.file_id, start, end - start);
+
+            let range = Range::new(start, min(end, file_size) - start);
+            download_status.requested.add_range(&range);
+
+            self.requester.spawn(Box::pin(receive_data(
                 self.shared.clone(),
                 self.file_data_tx.clone(),
                 data,
@@ -216,7 +176,7 @@ impl AudioFileFetch {
                 Instant::now(),
                 self.number_of_open_requests == 0,
                 self.download_finish_tx.clone(),
-            ));
+            )));
 
             self.number_of_open_requests += 1;
         }
@@ -299,23 +259,43 @@ impl AudioFileFetch {
                     .ping_time_ms
                     .store(ping_time_ms, atomic::Ordering::Relaxed);
             }
-            ReceivedData::Data(data) => {
-                self.output
-                    .as_mut()
-                    .unwrap()
-                    .seek(SeekFrom::Start(data.offset as u64))
-                    .unwrap();
-                self.output
-                    .as_mut()
+            ReceivedData::Data(mut data) => {
+                // Data past the end of the file, e.g. padding to the 4 byte alignment.
+                let length = min(
+                    data.data.len(),
+                    self.shared.file_size.saturating_sub(data.offset),
+                );
+                data.data.truncate(length);
+                if data.data.is_empty() {
+                    return ControlFlow::Continue;
+                }
+
+                if let Err(e) = self.write_data(&data) {
+                    error!(
+                        "Cannot write data of file {} to disk: {}",
+                        self.shared.file_id, e
+                    );
+                    return ControlFlow::Break;
+                }
+
+                self.shared
+                    .download_rate
+                    .lock()
                     .unwrap()
-                    .write_all(data.data.as_ref())
-                    .unwrap();
+                    .record(data.data.len());
 
                 let mut download_status = self.shared.download_status.lock().unwrap();
 
                 let received_range = Range::new(data.offset, data.data.len());
                 download_status.downloaded.add_range(&received_range);
-                self.shared.cond.notify_all();
+                self.shared.download_status_changed.notify_waiters();
+                let _ = self.shared.downloaded_ranges_tx.send(
+                    download_status
+                        .downloaded
+                        .iter()
+                        .map(|range| (range.start, range.length))
+                        .collect(),
+                );
 
                 let full = download_status.downloaded.contained_length_from_value(0)
                     >= self.shared.file_size;
@@ -331,6 +311,15 @@ impl AudioFileFetch {
         ControlFlow::Continue
     }
 
+    fn write_data(&mut self, data: &PartialFileData) -> io::Result<()> {
+        let output = match self.output.as_mut() {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        output.seek(SeekFrom::Start(data.offset as u64))?;
+        output.write_all(data.data.as_ref())
+    }
+
     fn handle_stream_loader_command(&mut self, cmd: StreamLoaderCommand) -> ControlFlow {
         match cmd {
             StreamLoaderCommand::Fetch(request) => {
@@ -338,6 +327,9 @@ impl AudioFileFetch {
             }
             StreamLoaderCommand::RandomAccessMode() => {
                 *(self.shared.download_strategy.lock().unwrap()) = DownloadStrategy::RandomAccess();
+
+                let footer_size = min(SEEK_PREFETCH_FOOTER_SIZE, self.shared.file_size);
+                self.download_range(self.shared.file_size - footer_size, footer_size);
             }
             StreamLoaderCommand::StreamMode() => {
                 *(self.shared.download_strategy.lock().unwrap()) = DownloadStrategy::Streaming();
@@ -349,19 +341,24 @@ impl AudioFileFetch {
     }
 
     fn finish(&mut self) {
-        let mut output = self.output.take().unwrap();
-        let complete_tx = self.complete_tx.take().unwrap();
-
-        output.seek(SeekFrom::Start(0)).unwrap();
-        let _ = complete_tx.send(output);
+        if let (Some(mut output), Some(complete_tx)) = (self.output.take(), self.complete_tx.take())
+        {
+            match output.seek(SeekFrom::Start(0)) {
+                Ok(_) => {
+                    let _ = complete_tx.send(output);
+                }
+                Err(e) => error!("Cannot rewind file {}: {}", self.shared.file_id, e),
+            }
+        }
     }
 
     fn trigger_preload(&mut self) {
-        if self.number_of_open_requests >= MAX_PREFETCH_REQUESTS {
+        let profile = self.shared.profile;
+        if self.number_of_open_requests >= profile.max_prefetch_requests {
             return;
         }
 
-        let max_requests_to_send = MAX_PREFETCH_REQUESTS - self.number_of_open_requests;
+        let max_requests_to_send = profile.max_prefetch_requests - self.number_of_open_requests;
 
         let bytes_pending: usize = {
             let download_status = self.shared.download_status.lock().unwrap();
@@ -373,12 +370,14 @@ impl AudioFileFetch {
 
         let ping_time_seconds =
             0.001 * self.shared.ping_time_ms.load(atomic::Ordering::Relaxed) as f64;
-        let download_rate = self.session.channel().get_download_rate_estimate();
+        let download_rate = self.requester.download_rate_estimate();
 
         let desired_pending_bytes = max(
-            (PREFETCH_THRESHOLD_FACTOR * ping_time_seconds * self.shared.stream_data_rate as f64)
+            (profile.prefetch_threshold_factor
+                * ping_time_seconds
+                * self.shared.stream_data_rate as f64) as usize,
+            (profile.fast_prefetch_threshold_factor * ping_time_seconds * download_rate as f64)
                 as usize,
-            (FAST_PREFETCH_THRESHOLD_FACTOR * ping_time_seconds * download_rate as f64) as usize,
         );
 
         if bytes_pending < desired_pending_bytes {
@@ -387,10 +386,18 @@ impl AudioFileFetch {
     }
 }
 
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(file_id = %shared.file_id.to_base16())
+    )
+)]
 pub(super) async fn audio_file_fetch(
-    session: Session,
+    requester: Arc<dyn DataRequester>,
     shared: Arc<AudioFileShared>,
-    initial_data_rx: ChannelData,
+    initial_data_rx: DataStream,
     initial_request_sent_time: Instant,
     initial_data_length: usize,
 
@@ -401,38 +408,65 @@ pub(super) async fn audio_file_fetch(
     let (file_data_tx, mut file_data_rx) = mpsc::unbounded_channel();
     let (download_finish_tx, mut download_finish_rx) = mpsc::unbounded_channel();
 
-    {
+    // The initial request is sent before the file size is known, it may exceed the file.
+    let initial_data_length = min(initial_data_length, shared.file_size);
+    if initial_data_length > 0 {
         let requested_range = Range::new(0, initial_data_length);
-        let mut download_status = shared.download_status.lock().unwrap();
-        download_status.requested.add_range(&requested_range);
+        shared
+            .download_status
+            .lock()
+            .unwrap()
+            .requested
+            .add_range(&requested_range);
+
+        requester.spawn(Box::pin(receive_data(
+            shared.clone(),
+            file_data_tx.clone(),
+            initial_data_rx,
+            0,
+            initial_data_length,
+            initial_request_sent_time,
+            true,
+            download_finish_tx.clone(),
+        )));
     }
 
-    session.spawn(receive_data(
-        shared.clone(),
-        file_data_tx.clone(),
-        initial_data_rx,
-        0,
-        initial_data_length,
-        initial_request_sent_time,
-        true,
-        download_finish_tx.clone(),
-    ));
+    let shutdown = requester.shutdown_requested();
+    tokio::pin!(shutdown);
 
     let mut fetch = AudioFileFetch {
-        session,
+        requester,
         shared,
         output: Some(output),
 
         file_data_tx,
         complete_tx: Some(complete_tx),
         network_response_times_ms: Vec::new(),
-        number_of_open_requests: 1,
+        number_of_open_requests: if initial_data_length > 0 { 1 } else { 0 },
 
         download_finish_tx,
     };
 
+    // An empty file, or one whose data was all seeded, is complete without any download.
+    if fetch
+        .shared
+        .download_status
+        .lock()
+        .unwrap()
+        .downloaded
+        .contained_length_from_value(0)
+        >= fetch.shared.file_size
+    {
+        fetch.finish();
+        return;
+    }
+
     loop {
+        // Polled in order rather than at random, so that simulations are reproducible.
         tokio::select! {
+            biased;
+
+            _ = &mut shutdown => break,
             cmd = stream_loader_command_rx.recv() => {
                 if cmd.map_or(true, |cmd| fetch.handle_stream_loader_command(cmd) == ControlFlow::Break) {
                     break;
@@ -452,4 +486,11 @@ pub(super) async fn audio_file_fetch(
             }
         }
     }
+
+    // Wake up readers waiting for data that will not arrive anymore.
+    fetch
+        .shared
+        .fetch_stopped
+        .store(true, atomic::Ordering::Relaxed);
+    fetch.shared.download_status_changed.notify_waiters();
 }
@@ -0,0 +1,91 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use bytes::Bytes;
+use futures_util::{future, Stream};
+use librespot_core::channel::{Channel, ChannelError};
+use librespot_core::packet::PacketBuilder;
+use librespot_core::session::Session;
+use librespot_core::spawner::BoxedTask;
+use librespot_core::spotify_id::FileId;
+
+/// The data of a range request, in order, as it arrives. The stream ends early if the server
+/// sent less data than requested.
+pub type DataStream = Pin<Box<dyn Stream<Item = Result<Bytes, ChannelError>> + Send>>;
+
+/// Requests ranges of audio files for the fetch task of an `AudioFileStreaming`.
+///
+/// A `Session` requests them from the Spotify servers. Tests drive the fetch with a mock,
+/// see `test_support::MockRequester`.
+pub trait DataRequester: Send + Sync + 'static {
+    /// Requests `length` bytes of `file` from `offset`. Both are aligned to 4 bytes.
+    fn request_range(&self, file: FileId, offset: usize, length: usize) -> DataStream;
+
+    /// Spawns the tasks receiving the data of the requests.
+    fn spawn(&self, task: BoxedTask);
+
+    /// The rate in bytes per second at which data is currently received over all requests.
+    fn download_rate_estimate(&self) -> usize;
+
+    /// Resolves when the fetch should stop, e.g. because the session is shut down.
+    fn shutdown_requested(&self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(future::pending())
+    }
+}
+
+impl DataRequester for Session {
+    fn request_range(&self, file: FileId, offset: usize, length: usize) -> DataStream {
+        let (_headers, data) = request_range(self, file, offset, length).split();
+        Box::pin(data)
+    }
+
+    fn spawn(&self, task: BoxedTask) {
+        Session::spawn(self, task);
+    }
+
+    fn download_rate_estimate(&self) -> usize {
+        self.channel().get_download_rate_estimate()
+    }
+
+    fn shutdown_requested(&self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let session = self.clone();
+        Box::pin(async move { session.shutdown_requested().await })
+    }
+}
+
+pub(super) fn request_range(
+    session: &Session,
+    file: FileId,
+    offset: usize,
+    length: usize,
+) -> Channel {
+    assert!(
+        offset % 4 == 0,
+        "Range request start positions must be aligned by 4 bytes."
+    );
+    assert!(
+        length % 4 == 0,
+        "Range request range lengths must be aligned by 4 bytes."
+    );
+    let start = offset / 4;
+    let end = (offset + length) / 4;
+
+    let (id, channel) = session.channel().allocate();
+
+    let data = PacketBuilder::new()
+        .u16(id)
+        .u8(0)
+        .u8(1)
+        .u16(0x0000)
+        .u32(0x00000000)
+        .u32(0x00009C40)
+        .u32(0x00020000)
+        .bytes(&file.0)
+        .u32(start as u32)
+        .u32(end as u32)
+        .finish();
+
+    session.send_packet_bytes(0x8, data);
+
+    channel
+}
@@ -23,12 +23,24 @@ mod passthrough_decoder;
 pub use passthrough_decoder::{PassthroughDecoder, PassthroughError};
 
 mod range_set;
+pub mod simd;
+
+#[cfg(feature = "simulation")]
+pub mod simulation;
+#[cfg(feature = "test-support")]
+pub mod test_support;
 
 pub use decrypt::AudioDecrypt;
-pub use fetch::{AudioFile, StreamLoaderController};
+pub use fetch::{
+    remove_temp_files, AudioFile, AudioFileStreaming, DownloadSnapshot, FetchConfig,
+    FetchProfile, StreamLoaderController,
+};
+pub use fetch::{AudioFileLive, LiveFetchConfig};
+pub use fetch::{DataRequester, DataStream, RangeRead};
 pub use fetch::{
     READ_AHEAD_BEFORE_PLAYBACK_ROUNDTRIPS, READ_AHEAD_BEFORE_PLAYBACK_SECONDS,
     READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS, READ_AHEAD_DURING_PLAYBACK_SECONDS,
+    SEEK_PREFETCH_WINDOW_SECONDS,
 };
 use std::fmt;
 
@@ -0,0 +1,390 @@
+//! Vectorized kernels for the per-sample work of playback: applying the software volume and
+//! converting samples to integers for the sinks.
+//!
+//! On x86 and x86-64, the SSE and AVX code paths are picked at runtime from the features of
+//! the CPU, and so are the NEON code paths on aarch64 and on 32-bit ARM Linux with hardware
+//! floating point, e.g. a Raspberry Pi 2 or later. Elsewhere the plain loops are used. The
+//! results are the same on every path, except that NEON on 32-bit ARM flushes denormal
+//! samples to zero when scaling, which is inaudible.
+
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64 as arch;
+#[cfg(all(target_arch = "arm", target_os = "linux", target_abi = "eabihf"))]
+use std::arch::asm;
+#[cfg(target_arch = "x86")]
+use std::arch::x86 as arch;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64 as arch;
+
+#[cfg(target_arch = "aarch64")]
+fn has_neon() -> bool {
+    std::arch::is_aarch64_feature_detected!("neon")
+}
+
+// The feature detection of the standard library isn't stable on 32-bit ARM.
+#[cfg(all(target_arch = "arm", target_os = "linux", target_abi = "eabihf"))]
+fn has_neon() -> bool {
+    const HWCAP_NEON: libc::c_ulong = 1 << 12;
+    unsafe { libc::getauxval(libc::AT_HWCAP) & HWCAP_NEON != 0 }
+}
+
+/// Multiplies every sample by `factor`.
+pub fn scale(samples: &mut [f32], factor: f32) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx") {
+            return unsafe { scale_avx(samples, factor) };
+        }
+        if is_x86_feature_detected!("sse") {
+            return unsafe { scale_sse(samples, factor) };
+        }
+    }
+    #[cfg(any(
+        target_arch = "aarch64",
+        all(target_arch = "arm", target_os = "linux", target_abi = "eabihf")
+    ))]
+    {
+        if has_neon() {
+            return unsafe { scale_neon(samples, factor) };
+        }
+    }
+    scale_scalar(samples, factor)
+}
+
+fn scale_scalar(samples: &mut [f32], factor: f32) {
+    for sample in samples.iter_mut() {
+        *sample *= factor;
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+unsafe fn scale_avx(samples: &mut [f32], factor: f32) {
+    use arch::*;
+
+    let factor_v = _mm256_set1_ps(factor);
+    let mut chunks = samples.chunks_exact_mut(8);
+    for chunk in &mut chunks {
+        let v = _mm256_loadu_ps(chunk.as_ptr());
+        _mm256_storeu_ps(chunk.as_mut_ptr(), _mm256_mul_ps(v, factor_v));
+    }
+    scale_scalar(chunks.into_remainder(), factor);
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse")]
+unsafe fn scale_sse(samples: &mut [f32], factor: f32) {
+    use arch::*;
+
+    let factor_v = _mm_set1_ps(factor);
+    let mut chunks = samples.chunks_exact_mut(4);
+    for chunk in &mut chunks {
+        let v = _mm_loadu_ps(chunk.as_ptr());
+        _mm_storeu_ps(chunk.as_mut_ptr(), _mm_mul_ps(v, factor_v));
+    }
+    scale_scalar(chunks.into_remainder(), factor);
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn scale_neon(samples: &mut [f32], factor: f32) {
+    use arch::*;
+
+    let mut chunks = samples.chunks_exact_mut(4);
+    for chunk in &mut chunks {
+        let v = vld1q_f32(chunk.as_ptr());
+        vst1q_f32(chunk.as_mut_ptr(), vmulq_n_f32(v, factor));
+    }
+    scale_scalar(chunks.into_remainder(), factor);
+}
+
+// The NEON intrinsics and target feature of 32-bit ARM aren't stable, so its code paths are
+// written in assembly. They only use q0 to q7, which exist with any NEON unit.
+#[cfg(all(target_arch = "arm", target_os = "linux", target_abi = "eabihf"))]
+unsafe fn scale_neon(samples: &mut [f32], factor: f32) {
+    let length = samples.len() / 4 * 4;
+    if length > 0 {
+        asm!(
+            ".fpu neon",
+            "vdup.32 q0, {factor}",
+            "2:",
+            "vld1.32 {{d2, d3}}, [{samples}]",
+            "vmul.f32 q1, q1, q0",
+            "vst1.32 {{d2, d3}}, [{samples}]!",
+            "subs {length}, {length}, #4",
+            "bne 2b",
+            factor = in(reg) factor.to_bits(),
+            samples = inout(reg) samples.as_mut_ptr() => _,
+            length = inout(reg) length => _,
+            out("d0") _, out("d1") _, out("d2") _, out("d3") _,
+            options(nostack),
+        );
+    }
+    scale_scalar(&mut samples[length..], factor);
+}
+
+// The conversions below compute what `convert` does per sample: scale to the range of the
+// integer type, round half away from zero and cast, which saturates and turns NaN into 0.
+// They convert a prefix of `samples` that fits in vectors and return its length, the caller
+// converts the rest.
+
+/// Converts to `i32` shifted right by `drop_bits`.
+pub(crate) fn to_i32(samples: &[f32], drop_bits: u32, output: &mut [i32]) -> usize {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { to_i32_sse2(samples, drop_bits, output) };
+        }
+    }
+    #[cfg(any(
+        target_arch = "aarch64",
+        all(target_arch = "arm", target_os = "linux", target_abi = "eabihf")
+    ))]
+    {
+        if has_neon() {
+            return unsafe { to_i32_neon(samples, drop_bits, output) };
+        }
+    }
+    let _ = (samples, drop_bits, output);
+    0
+}
+
+/// Converts to `i16`.
+pub(crate) fn to_i16(samples: &[f32], output: &mut [i16]) -> usize {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { to_i16_sse2(samples, output) };
+        }
+    }
+    #[cfg(any(
+        target_arch = "aarch64",
+        all(target_arch = "arm", target_os = "linux", target_abi = "eabihf")
+    ))]
+    {
+        if has_neon() {
+            return unsafe { to_i16_neon(samples, output) };
+        }
+    }
+    let _ = (samples, output);
+    0
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn to_i32_sse2(samples: &[f32], drop_bits: u32, output: &mut [i32]) -> usize {
+    use arch::*;
+
+    let gain = _mm_set1_ps(i32::MAX as f32 + 0.5);
+    let half = _mm_set1_ps(0.5);
+    // i32::MAX isn't representable as f32, larger values are where the cast saturates.
+    let overflow_at = _mm_set1_ps(2_147_483_648.0);
+    let max = _mm_set1_epi32(i32::MAX);
+    let shift = _mm_cvtsi32_si128(drop_bits as i32);
+
+    let length = samples.len().min(output.len()) / 4 * 4;
+    for (input, output) in samples[..length]
+        .chunks_exact(4)
+        .zip(output[..length].chunks_exact_mut(4))
+    {
+        let v = _mm_sub_ps(_mm_mul_ps(_mm_loadu_ps(input.as_ptr()), gain), half);
+        // The largest f32 below 2^31.
+        let rounded = round_clamped(v, -2_147_483_648.0, 2_147_483_520.0);
+        let overflow = _mm_castps_si128(_mm_cmpge_ps(v, overflow_at));
+        let saturated = _mm_or_si128(
+            _mm_andnot_si128(overflow, rounded),
+            _mm_and_si128(overflow, max),
+        );
+        _mm_storeu_si128(
+            output.as_mut_ptr() as *mut __m128i,
+            _mm_sra_epi32(saturated, shift),
+        );
+    }
+    length
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn to_i16_sse2(samples: &[f32], output: &mut [i16]) -> usize {
+    use arch::*;
+
+    let gain = _mm_set1_ps(i16::MAX as f32 + 0.5);
+    let half = _mm_set1_ps(0.5);
+    let min = i16::MIN as f32;
+    let max = i16::MAX as f32;
+
+    let length = samples.len().min(output.len()) / 8 * 8;
+    for (input, output) in samples[..length]
+        .chunks_exact(8)
+        .zip(output[..length].chunks_exact_mut(8))
+    {
+        let low = _mm_sub_ps(_mm_mul_ps(_mm_loadu_ps(input.as_ptr()), gain), half);
+        let high = _mm_sub_ps(_mm_mul_ps(_mm_loadu_ps(input[4..].as_ptr()), gain), half);
+        let packed = _mm_packs_epi32(round_clamped(low, min, max), round_clamped(high, min, max));
+        _mm_storeu_si128(output.as_mut_ptr() as *mut __m128i, packed);
+    }
+    length
+}
+
+// Rounds half away from zero like `f32::round` after clamping to `min..=max`, which must be
+// integers in the range of i32. NaN becomes 0.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn round_clamped(v: arch::__m128, min: f32, max: f32) -> arch::__m128i {
+    use arch::*;
+
+    let v = _mm_and_ps(v, _mm_cmpord_ps(v, v));
+    let v = _mm_min_ps(_mm_max_ps(v, _mm_set1_ps(min)), _mm_set1_ps(max));
+    let truncated = _mm_cvttps_epi32(v);
+    // Exact: it is `v` itself below 1, and the truncated value is within a factor of two
+    // of `v` above.
+    let fraction = _mm_sub_ps(v, _mm_cvtepi32_ps(truncated));
+    // The comparisons are -1 where they hold.
+    let up = _mm_castps_si128(_mm_cmpge_ps(fraction, _mm_set1_ps(0.5)));
+    let down = _mm_castps_si128(_mm_cmple_ps(fraction, _mm_set1_ps(-0.5)));
+    _mm_add_epi32(_mm_sub_epi32(truncated, up), down)
+}
+
+// FCVTAS rounds half away from zero, saturates and turns NaN into 0 just like the cast.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn to_i32_neon(samples: &[f32], drop_bits: u32, output: &mut [i32]) -> usize {
+    use arch::*;
+
+    let gain = vdupq_n_f32(i32::MAX as f32 + 0.5);
+    let half = vdupq_n_f32(0.5);
+    // Shifting left by a negative amount shifts right, keeping the sign.
+    let shift = vdupq_n_s32(-(drop_bits as i32));
+
+    let length = samples.len().min(output.len()) / 4 * 4;
+    for (input, output) in samples[..length]
+        .chunks_exact(4)
+        .zip(output[..length].chunks_exact_mut(4))
+    {
+        let v = vsubq_f32(vmulq_f32(vld1q_f32(input.as_ptr()), gain), half);
+        vst1q_s32(output.as_mut_ptr(), vshlq_s32(vcvtaq_s32_f32(v), shift));
+    }
+    length
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn to_i16_neon(samples: &[f32], output: &mut [i16]) -> usize {
+    use arch::*;
+
+    let gain = vdupq_n_f32(i16::MAX as f32 + 0.5);
+    let half = vdupq_n_f32(0.5);
+
+    let length = samples.len().min(output.len()) / 8 * 8;
+    for (input, output) in samples[..length]
+        .chunks_exact(8)
+        .zip(output[..length].chunks_exact_mut(8))
+    {
+        let low = vsubq_f32(vmulq_f32(vld1q_f32(input.as_ptr()), gain), half);
+        let high = vsubq_f32(vmulq_f32(vld1q_f32(input[4..].as_ptr()), gain), half);
+        // Narrowing saturates what is beyond the range of i16.
+        let packed = vcombine_s16(
+            vqmovn_s32(vcvtaq_s32_f32(low)),
+            vqmovn_s32(vcvtaq_s32_f32(high)),
+        );
+        vst1q_s16(output.as_mut_ptr(), packed);
+    }
+    length
+}
+
+// 32-bit ARM only converts to integers by truncating, which saturates and turns NaN into 0.
+// The fraction left by truncating decides whether to round up or down, like in
+// `round_clamped`. Rounding saturates instead of clamping first, which keeps the ends of the
+// range, and the comparisons fail for NaN.
+#[cfg(all(target_arch = "arm", target_os = "linux", target_abi = "eabihf"))]
+unsafe fn to_i32_neon(samples: &[f32], drop_bits: u32, output: &mut [i32]) -> usize {
+    let length = samples.len().min(output.len()) / 4 * 4;
+    if length > 0 {
+        asm!(
+            ".fpu neon",
+            "vdup.32 q0, {gain}",
+            "vmov.f32 q1, #0.5",
+            "vmov.f32 q2, #-0.5",
+            "vdup.32 q3, {shift}",
+            "2:",
+            "vld1.32 {{d8, d9}}, [{input}]!",
+            "vmul.f32 q4, q4, q0",
+            "vsub.f32 q4, q4, q1",
+            "vcvt.s32.f32 q5, q4",
+            "vcvt.f32.s32 q6, q5",
+            "vsub.f32 q6, q4, q6",
+            // The comparisons are -1 where they hold.
+            "vcge.f32 q4, q6, q1",
+            "vqsub.s32 q5, q5, q4",
+            "vcge.f32 q4, q2, q6",
+            "vqadd.s32 q5, q5, q4",
+            // Shifting left by a negative amount shifts right, keeping the sign.
+            "vshl.s32 q5, q5, q3",
+            "vst1.32 {{d10, d11}}, [{output}]!",
+            "subs {length}, {length}, #4",
+            "bne 2b",
+            gain = in(reg) (i32::MAX as f32 + 0.5).to_bits(),
+            shift = in(reg) -(drop_bits as i32),
+            input = inout(reg) samples.as_ptr() => _,
+            output = inout(reg) output.as_mut_ptr() => _,
+            length = inout(reg) length => _,
+            out("d0") _, out("d1") _, out("d2") _, out("d3") _,
+            out("d4") _, out("d5") _, out("d6") _, out("d7") _,
+            out("d8") _, out("d9") _, out("d10") _, out("d11") _,
+            out("d12") _, out("d13") _,
+            options(nostack),
+        );
+    }
+    length
+}
+
+#[cfg(all(target_arch = "arm", target_os = "linux", target_abi = "eabihf"))]
+unsafe fn to_i16_neon(samples: &[f32], output: &mut [i16]) -> usize {
+    let length = samples.len().min(output.len()) / 4 * 4;
+    if length > 0 {
+        asm!(
+            ".fpu neon",
+            "vdup.32 q0, {gain}",
+            "vmov.f32 q1, #0.5",
+            "vmov.f32 q2, #-0.5",
+            "2:",
+            "vld1.32 {{d8, d9}}, [{input}]!",
+            "vmul.f32 q4, q4, q0",
+            "vsub.f32 q4, q4, q1",
+            "vcvt.s32.f32 q5, q4",
+            "vcvt.f32.s32 q6, q5",
+            "vsub.f32 q6, q4, q6",
+            "vcge.f32 q4, q6, q1",
+            "vqsub.s32 q5, q5, q4",
+            "vcge.f32 q4, q2, q6",
+            "vqadd.s32 q5, q5, q4",
+            // Narrowing saturates what is beyond the range of i16.
+            "vqmovn.s32 d8, q5",
+            "vst1.16 {{d8}}, [{output}]!",
+            "subs {length}, {length}, #4",
+            "bne 2b",
+            gain = in(reg) (i16::MAX as f32 + 0.5).to_bits(),
+            input = inout(reg) samples.as_ptr() => _,
+            output = inout(reg) output.as_mut_ptr() => _,
+            length = inout(reg) length => _,
+            out("d0") _, out("d1") _, out("d2") _, out("d3") _,
+            out("d4") _, out("d5") _, out("d8") _, out("d9") _,
+            out("d10") _, out("d11") _, out("d12") _, out("d13") _,
+            options(nostack),
+        );
+    }
+    length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_every_sample() {
+        let mut samples: Vec<f32> = (0..19).map(|i| i as f32 - 9.0).collect();
+        let expected: Vec<f32> = samples.iter().map(|sample| sample * 0.3).collect();
+        scale(&mut samples, 0.3);
+        assert_eq!(samples, expected);
+    }
+}
@@ -0,0 +1,324 @@
+//! Replays network traces through the download heuristics of `AudioFileStreaming`.
+//!
+//! Enabled by the `simulation` feature. The simulation runs on tokio's paused clock, so a
+//! trace of minutes is replayed in milliseconds and the same trace always gives the same
+//! report. Use it for regression tests of fetch profiles and prefetch constants.
+
+use std::cmp::{max, min};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::stream;
+use librespot_core::spawner::BoxedTask;
+use librespot_core::spotify_id::FileId;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use crate::fetch::{AudioFile, AudioFileStreaming, DataRequester, DataStream, FetchProfile};
+use crate::range_set::Range;
+
+// The size of the data packets of the simulated responses, as sent by the Spotify servers.
+const PACKET_SIZE: usize = 4096;
+
+// The audio data consumed at once by the simulated playback.
+const PLAYBACK_TICK: Duration = Duration::from_millis(100);
+
+/// The state of the network for a while.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceSegment {
+    pub duration: Duration,
+    /// The time until the first data of a response arrives.
+    pub latency: Duration,
+    /// The bandwidth shared by all pending requests.
+    pub bytes_per_second: usize,
+}
+
+/// A recorded latency and throughput profile of a connection. The last segment lasts until
+/// the end of the simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkTrace {
+    segments: Vec<TraceSegment>,
+}
+
+impl NetworkTrace {
+    pub fn new(segments: Vec<TraceSegment>) -> Self {
+        assert!(!segments.is_empty(), "A network trace needs a segment.");
+        Self { segments }
+    }
+
+    pub fn constant(latency: Duration, bytes_per_second: usize) -> Self {
+        Self::new(vec![TraceSegment {
+            duration: Duration::from_secs(0),
+            latency,
+            bytes_per_second,
+        }])
+    }
+
+    pub fn segments(&self) -> &[TraceSegment] {
+        &self.segments
+    }
+
+    fn at(&self, elapsed: Duration) -> &TraceSegment {
+        let mut end = Duration::from_secs(0);
+        for segment in &self.segments {
+            end += segment.duration;
+            if elapsed < end {
+                return segment;
+            }
+        }
+        self.segments.last().unwrap()
+    }
+}
+
+/// A trace line that cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseTraceError {
+    pub line: usize,
+}
+
+impl fmt::Display for ParseTraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid network trace in line {}", self.line)
+    }
+}
+
+impl std::error::Error for ParseTraceError {}
+
+impl FromStr for NetworkTrace {
+    type Err = ParseTraceError;
+
+    /// Parses a trace with one segment per line: its duration and latency in milliseconds
+    /// and its throughput in kilobytes per second, e.g. `5000 80 512`. Empty lines and lines
+    /// starting with `#` are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = ParseTraceError { line: index + 1 };
+            let fields = line
+                .split_whitespace()
+                .map(|field| field.parse::<u64>().map_err(|_| error.clone()))
+                .collect::<Result<Vec<_>, _>>()?;
+            if fields.len() != 3 {
+                return Err(error);
+            }
+
+            segments.push(TraceSegment {
+                duration: Duration::from_millis(fields[0]),
+                latency: Duration::from_millis(fields[1]),
+                bytes_per_second: fields[2] as usize * 1024,
+            });
+        }
+
+        if segments.is_empty() {
+            return Err(ParseTraceError { line: 0 });
+        }
+        Ok(Self::new(segments))
+    }
+}
+
+/// The file and the fetch profile of a simulation.
+#[derive(Debug, Clone)]
+pub struct SimulationConfig {
+    pub file_size: usize,
+    /// The nominal bitrate of the file in bytes per second, playback consumes the file at this
+    /// rate.
+    pub stream_data_rate: usize,
+    pub profile: FetchProfile,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        // Three and a half minutes at 160 kbit/s.
+        Self {
+            file_size: 210 * 20 * 1024,
+            stream_data_rate: 20 * 1024,
+            profile: FetchProfile::default(),
+        }
+    }
+}
+
+/// The outcome of a simulation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimulationReport {
+    /// The time until playback could start.
+    pub startup_time: Duration,
+    /// How often playback stopped because the data wasn't downloaded yet.
+    pub stalls: usize,
+    pub stall_time: Duration,
+    /// The time from opening the file until playback finished.
+    pub total_time: Duration,
+    pub requests: usize,
+    pub bytes_requested: usize,
+}
+
+struct Link {
+    trace: NetworkTrace,
+    start: Instant,
+    data: Bytes,
+    pending_requests: AtomicUsize,
+    requests: Mutex<(usize, usize)>,
+}
+
+impl Link {
+    fn segment(&self) -> TraceSegment {
+        *self.trace.at(Instant::now() - self.start)
+    }
+}
+
+// Serves the requests of the simulated file over the link described by the trace.
+struct TraceRequester(Arc<Link>);
+
+impl DataRequester for TraceRequester {
+    fn request_range(&self, _file: FileId, offset: usize, length: usize) -> DataStream {
+        {
+            let mut requests = self.0.requests.lock().unwrap();
+            requests.0 += 1;
+            requests.1 += length;
+        }
+        let link = self.0.clone();
+        let end = min(offset + length, link.data.len());
+        link.pending_requests.fetch_add(1, Ordering::Relaxed);
+
+        Box::pin(stream::unfold((offset, true), move |(position, first)| {
+            let link = link.clone();
+            async move {
+                if first {
+                    tokio::time::sleep(link.segment().latency).await;
+                }
+                if position >= end {
+                    link.pending_requests.fetch_sub(1, Ordering::Relaxed);
+                    return None;
+                }
+
+                let chunk_end = min(position + PACKET_SIZE, end);
+                // Concurrent requests share the bandwidth.
+                let pending = max(link.pending_requests.load(Ordering::Relaxed), 1);
+                let rate = max(link.segment().bytes_per_second / pending, 1);
+                let transfer = (chunk_end - position) as f64 / rate as f64;
+                tokio::time::sleep(Duration::from_secs_f64(transfer)).await;
+
+                Some((Ok(link.data.slice(position..chunk_end)), (chunk_end, false)))
+            }
+        }))
+    }
+
+    fn spawn(&self, task: BoxedTask) {
+        tokio::spawn(task);
+    }
+
+    fn download_rate_estimate(&self) -> usize {
+        self.0.segment().bytes_per_second
+    }
+}
+
+/// Plays a file downloaded over a link following `trace` from the beginning to the end, the
+/// way the player does, and reports how often playback stalled.
+///
+/// The simulation runs on a runtime of its own, so it must not be called from within a
+/// runtime.
+pub fn simulate(trace: &NetworkTrace, config: &SimulationConfig) -> SimulationReport {
+    // Paused from the start, so that no wall-clock time passes before the simulation.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .expect("cannot create the simulation runtime");
+
+    runtime.block_on(play(trace, config))
+}
+
+async fn play(trace: &NetworkTrace, config: &SimulationConfig) -> SimulationReport {
+    let start = Instant::now();
+    let link = Arc::new(Link {
+        trace: trace.clone(),
+        start,
+        data: Bytes::from(vec![0u8; config.file_size]),
+        pending_requests: AtomicUsize::new(0),
+        requests: Mutex::new((0, 0)),
+    });
+
+    let (complete_tx, _complete_rx) = oneshot::channel();
+    let streaming = AudioFileStreaming::open_with_requester(
+        Arc::new(TraceRequester(link.clone())),
+        FileId([0; 20]),
+        config.file_size,
+        complete_tx,
+        config.stream_data_rate,
+        true,
+        config.profile,
+    )
+    .expect("cannot create the simulated file");
+    let mut file = AudioFile::Streaming(streaming);
+    let controller = file.get_stream_loader_controller();
+    controller.set_stream_mode();
+
+    // Wait for the data needed before playback, like the player does when starting a track.
+    let profile = &config.profile;
+    let before_playback = max(
+        (profile.read_ahead_before_playback_roundtrips
+            * 0.001
+            * controller.ping_time_ms() as f64
+            * config.stream_data_rate as f64) as usize,
+        (profile.read_ahead_before_playback_seconds * config.stream_data_rate as f64) as usize,
+    );
+    controller
+        .fetch_async(Range::new(0, min(before_playback, config.file_size)))
+        .await;
+
+    let mut report = SimulationReport {
+        startup_time: Instant::now() - start,
+        ..SimulationReport::default()
+    };
+
+    let tick_length = max(
+        (config.stream_data_rate as f64 * PLAYBACK_TICK.as_secs_f64()) as usize,
+        1,
+    );
+    let mut buffer = vec![0u8; tick_length];
+    let mut position = 0;
+    let mut next_tick = Instant::now();
+
+    while position < config.file_size {
+        tokio::time::sleep_until(next_tick).await;
+
+        let length = min(tick_length, config.file_size - position);
+        let stalled = !controller.range_available(Range::new(position, length));
+        let stall_start = Instant::now();
+
+        let mut read = 0;
+        while read < length {
+            match file.read_async(&mut buffer[read..length]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => read += n,
+            }
+        }
+        if read == 0 {
+            break;
+        }
+        position += read;
+
+        if stalled {
+            report.stalls += 1;
+            report.stall_time += Instant::now() - stall_start;
+            // Playback continues from the stall, it doesn't catch up.
+            next_tick = Instant::now();
+        }
+        next_tick += PLAYBACK_TICK;
+    }
+
+    controller.close();
+    report.total_time = Instant::now() - start;
+    let requests = *link.requests.lock().unwrap();
+    report.requests = requests.0;
+    report.bytes_requested = requests.1;
+    report
+}
@@ -0,0 +1,209 @@
+//! Drives the fetch of an `AudioFileStreaming` with simulated responses instead of a session.
+//!
+//! Enabled by the `test-support` feature. Pass a `MockRequester` to
+//! `AudioFileStreaming::open_with_requester` to simulate slow, lossy or out of order responses.
+//! The benchmarks in `benches/fetch.rs` use it to measure the startup and seek latency.
+
+use std::cmp::min;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::stream;
+use librespot_core::channel::ChannelError;
+use librespot_core::spawner::BoxedTask;
+use librespot_core::spotify_id::FileId;
+use tokio::sync::watch;
+
+use crate::fetch::{DataRequester, DataStream};
+
+/// How the responses of a `MockRequester` behave.
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    /// The time until the first data of a response arrives.
+    pub latency: Duration,
+    /// A random delay of up to this much is added to the latency of each response, so that
+    /// responses of concurrent requests arrive out of order.
+    pub jitter: Duration,
+    /// The size of the data packets of a response.
+    pub chunk_size: usize,
+    /// The rate at which the data of a response arrives, unlimited if `None`.
+    pub bytes_per_second: Option<usize>,
+    /// The probability that a response is cut short, either ending early or with an error.
+    pub loss_rate: f64,
+    /// The seed of the random delays and losses, so that a simulation can be repeated.
+    pub seed: u64,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(0),
+            chunk_size: 4096,
+            bytes_per_second: None,
+            loss_rate: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+impl MockConfig {
+    /// A slow connection: high latency and a data rate of 16 kB/s.
+    pub fn slow() -> Self {
+        Self {
+            latency: Duration::from_millis(300),
+            bytes_per_second: Some(16 * 1024),
+            ..Self::default()
+        }
+    }
+
+    /// Responses that arrive out of order and are often cut short.
+    pub fn lossy() -> Self {
+        Self {
+            jitter: Duration::from_millis(50),
+            loss_rate: 0.3,
+            ..Self::default()
+        }
+    }
+}
+
+struct MockState {
+    random: u64,
+    requests: Vec<(usize, usize)>,
+}
+
+impl MockState {
+    // A linear congruential generator, returns a value in [0, 1).
+    fn next_random(&mut self) -> f64 {
+        self.random = self
+            .random
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.random >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Serves range requests from the data of a file in memory. The tasks are spawned on the
+/// current tokio runtime, which needs a timer.
+pub struct MockRequester {
+    data: Bytes,
+    config: MockConfig,
+    state: Mutex<MockState>,
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl MockRequester {
+    pub fn new(data: impl Into<Bytes>, config: MockConfig) -> Self {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        Self {
+            data: data.into(),
+            state: Mutex::new(MockState {
+                random: config.seed,
+                requests: Vec::new(),
+            }),
+            config,
+            shutdown_tx,
+            shutdown_rx,
+        }
+    }
+
+    /// The offsets and lengths of the requests made so far, in order.
+    pub fn requests(&self) -> Vec<(usize, usize)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Stops the fetch tasks, like shutting down a session.
+    pub fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(true);
+    }
+}
+
+// What a response does after its data.
+#[derive(Clone, Copy)]
+enum ResponseEnd {
+    Complete,
+    Error,
+    Done,
+}
+
+impl DataRequester for MockRequester {
+    fn request_range(&self, _file: FileId, offset: usize, length: usize) -> DataStream {
+        let config = &self.config;
+        let full_end = min(offset + length, self.data.len());
+        let (delay, end, response_end) = {
+            let mut state = self.state.lock().unwrap();
+            state.requests.push((offset, length));
+
+            let delay = config.latency + config.jitter.mul_f64(state.next_random());
+            if state.next_random() < config.loss_rate {
+                let received =
+                    (full_end.saturating_sub(offset) as f64 * state.next_random()) as usize;
+                let response_end = if state.next_random() < 0.5 {
+                    ResponseEnd::Error
+                } else {
+                    ResponseEnd::Complete
+                };
+                (delay, offset + received, response_end)
+            } else {
+                (delay, full_end, ResponseEnd::Complete)
+            }
+        };
+
+        let data = self.data.clone();
+        let chunk_size = config.chunk_size.max(1);
+        let chunk_delay = config
+            .bytes_per_second
+            .map(|rate| Duration::from_secs_f64(chunk_size as f64 / rate.max(1) as f64));
+
+        Box::pin(stream::unfold(
+            (offset, Some(delay), response_end),
+            move |(position, delay, response_end)| {
+                let data = data.clone();
+                async move {
+                    if let Some(delay) = delay {
+                        tokio::time::sleep(delay).await;
+                    }
+                    if position < end {
+                        let chunk_end = min(position + chunk_size, end);
+                        let chunk = data.slice(position..chunk_end);
+                        return Some((Ok(chunk), (chunk_end, chunk_delay, response_end)));
+                    }
+                    match response_end {
+                        ResponseEnd::Error => {
+                            Some((Err(ChannelError), (position, None, ResponseEnd::Done)))
+                        }
+                        ResponseEnd::Complete | ResponseEnd::Done => None,
+                    }
+                }
+            },
+        ))
+    }
+
+    fn spawn(&self, task: BoxedTask) {
+        tokio::spawn(task);
+    }
+
+    fn download_rate_estimate(&self) -> usize {
+        self.config.bytes_per_second.unwrap_or(0)
+    }
+
+    fn shutdown_requested(&self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let mut shutdown = self.shutdown_rx.clone();
+        Box::pin(async move {
+            while !*shutdown.borrow() {
+                if shutdown.changed().await.is_err() {
+                    break;
+                }
+            }
+        })
+    }
+}
+
+/// Returns `length` bytes of deterministic test data, e.g. the contents of a mock file.
+pub fn test_data(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i * 7 + i / 256) as u8).collect()
+}
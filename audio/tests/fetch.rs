@@ -0,0 +1,320 @@
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::Duration;
+
+use librespot_audio::test_support::{test_data, MockConfig, MockRequester};
+use librespot_audio::{
+    AudioFile, AudioFileLive, AudioFileStreaming, DownloadSnapshot, FetchProfile, LiveFetchConfig,
+};
+use librespot_core::spotify_id::FileId;
+use tokio::sync::oneshot;
+
+const FILE_SIZE: usize = 300 * 1024 + 13;
+const DATA_RATE: usize = 20 * 1024;
+
+async fn read_to_end(config: MockConfig, play_from_beginning: bool) -> Vec<u8> {
+    read_file_to_end(FILE_SIZE, config, play_from_beginning).await
+}
+
+async fn read_file_to_end(
+    file_size: usize,
+    config: MockConfig,
+    play_from_beginning: bool,
+) -> Vec<u8> {
+    let requester = Arc::new(MockRequester::new(test_data(file_size), config));
+    let (complete_tx, complete_rx) = oneshot::channel();
+
+    let mut file = AudioFileStreaming::open_with_requester(
+        requester.clone(),
+        FileId([1; 20]),
+        file_size,
+        complete_tx,
+        DATA_RATE,
+        play_from_beginning,
+        FetchProfile::default(),
+    )
+    .unwrap();
+
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let read = tokio::time::timeout(Duration::from_secs(30), file.read_async(&mut buffer))
+            .await
+            .expect("read timed out")
+            .unwrap();
+        if read == 0 {
+            break;
+        }
+        output.extend_from_slice(&buffer[..read]);
+    }
+
+    let mut complete = Vec::new();
+    complete_rx
+        .await
+        .unwrap()
+        .read_to_end(&mut complete)
+        .unwrap();
+    assert_eq!(complete, output);
+
+    // Requests are aligned like the Spotify protocol requires and don't go past the
+    // aligned end of the file.
+    let aligned_size = file_size + (4 - file_size % 4) % 4;
+    for (offset, length) in requester.requests() {
+        assert_eq!(offset % 4, 0, "unaligned request at {}", offset);
+        assert_eq!(length % 4, 0, "unaligned request of {} bytes", length);
+        assert!(
+            offset + length <= aligned_size,
+            "request past the end of the file"
+        );
+    }
+
+    requester.shutdown();
+    output
+}
+
+#[tokio::test]
+async fn reads_file() {
+    let output = read_to_end(MockConfig::default(), true).await;
+    assert_eq!(output, test_data(FILE_SIZE));
+}
+
+#[tokio::test]
+async fn reads_small_files() {
+    // Empty, tiny, unaligned and aligned files, and files around the minimum download size.
+    for &file_size in &[0, 1, 3, 4, 5, 4096, 16 * 1024 - 1, 16 * 1024, 16 * 1024 + 2] {
+        let output = read_file_to_end(file_size, MockConfig::default(), false).await;
+        assert_eq!(output, test_data(file_size), "file of {} bytes", file_size);
+    }
+}
+
+#[tokio::test]
+async fn rerequests_lost_data_of_unaligned_files() {
+    for &file_size in &[7, 16 * 1024 + 2, 50_001] {
+        let output = read_file_to_end(file_size, MockConfig::lossy(), true).await;
+        assert_eq!(output, test_data(file_size), "file of {} bytes", file_size);
+    }
+}
+
+#[tokio::test]
+async fn rerequests_lost_data() {
+    let output = read_to_end(MockConfig::lossy(), false).await;
+    assert_eq!(output, test_data(FILE_SIZE));
+}
+
+#[tokio::test]
+async fn reads_out_of_order_responses() {
+    let config = MockConfig {
+        jitter: Duration::from_millis(200),
+        chunk_size: 1024,
+        ..MockConfig::default()
+    };
+    let output = read_to_end(config, true).await;
+    assert_eq!(output, test_data(FILE_SIZE));
+}
+
+#[tokio::test]
+async fn reports_downloaded_ranges() {
+    let requester = Arc::new(MockRequester::new(test_data(FILE_SIZE), MockConfig::default()));
+    let (complete_tx, _complete_rx) = oneshot::channel();
+    let mut file = AudioFile::Streaming(
+        AudioFileStreaming::open_with_requester(
+            requester.clone(),
+            FileId([1; 20]),
+            FILE_SIZE,
+            complete_tx,
+            DATA_RATE,
+            false,
+            FetchProfile::default(),
+        )
+        .unwrap(),
+    );
+    let controller = file.get_stream_loader_controller();
+    let mut changes = controller.downloaded_ranges_changes().unwrap();
+
+    changes.changed().await.unwrap();
+    let ranges = changes.borrow().clone();
+    assert_eq!(ranges.first().map(|range| range.0), Some(0));
+
+    let middle = FILE_SIZE / 2;
+    file.seek(SeekFrom::Start(middle as u64)).unwrap();
+    let mut buffer = [0u8; 1024];
+    let read = file.read_async(&mut buffer).await.unwrap();
+    assert!(controller
+        .downloaded_ranges()
+        .iter()
+        .any(|&(start, length)| start <= middle && middle + read <= start + length));
+
+    requester.shutdown();
+}
+
+#[tokio::test]
+async fn reads_ranges() {
+    let data = test_data(FILE_SIZE);
+    let requester = Arc::new(MockRequester::new(data.clone(), MockConfig::lossy()));
+    let (complete_tx, _complete_rx) = oneshot::channel();
+    let file = AudioFile::Streaming(
+        AudioFileStreaming::open_with_requester(
+            requester.clone(),
+            FileId([1; 20]),
+            FILE_SIZE,
+            complete_tx,
+            DATA_RATE,
+            true,
+            FetchProfile::default(),
+        )
+        .unwrap(),
+    );
+
+    for &(offset, length) in &[(0, 100), (FILE_SIZE / 3, 50_000), (FILE_SIZE - 10, 100)] {
+        let range = file.read_range(offset, length).unwrap();
+        let mut output = range.available;
+        output.extend(range.rest.await.unwrap());
+
+        let end = (offset + length).min(FILE_SIZE);
+        assert_eq!(output, &data[offset..end]);
+    }
+
+    requester.shutdown();
+}
+
+#[tokio::test]
+async fn continues_download_from_snapshot() {
+    let data = test_data(FILE_SIZE);
+    let snapshot = DownloadSnapshot {
+        file_size: FILE_SIZE,
+        downloaded: vec![(0, 40_000), (100_000, 20_000)],
+    };
+    // Only the downloaded ranges of the stored data are used.
+    let mut stored = vec![0u8; FILE_SIZE];
+    for &(start, length) in &snapshot.downloaded {
+        stored[start..start + length].copy_from_slice(&data[start..start + length]);
+    }
+
+    let requester = Arc::new(MockRequester::new(data.clone(), MockConfig::default()));
+    let (complete_tx, complete_rx) = oneshot::channel();
+    let file = AudioFile::Streaming(
+        AudioFileStreaming::open_with_snapshot(
+            requester.clone(),
+            FileId([1; 20]),
+            complete_tx,
+            DATA_RATE,
+            FetchProfile::default(),
+            &snapshot,
+            &mut Cursor::new(stored),
+        )
+        .unwrap(),
+    );
+    assert_eq!(
+        file.get_stream_loader_controller().download_snapshot(),
+        snapshot
+    );
+    assert!(requester.requests().is_empty());
+
+    let range = file.read_range(0, FILE_SIZE).unwrap();
+    let mut output = range.available;
+    assert_eq!(output.len(), 40_000);
+    output.extend(range.rest.await.unwrap());
+    assert_eq!(output, data);
+
+    let mut complete = Vec::new();
+    complete_rx
+        .await
+        .unwrap()
+        .read_to_end(&mut complete)
+        .unwrap();
+    assert_eq!(complete, data);
+    assert!(file
+        .get_stream_loader_controller()
+        .download_snapshot()
+        .is_complete());
+    assert!(requester
+        .requests()
+        .iter()
+        .all(|&(offset, length)| offset + length <= 100_000 || offset >= 120_000));
+
+    requester.shutdown();
+}
+
+fn live_config() -> LiveFetchConfig {
+    LiveFetchConfig {
+        retain_seconds: 1.0,
+        retry_interval: Duration::from_millis(10),
+        max_empty_responses: 2,
+        ..LiveFetchConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn reads_live_stream() {
+    for config in vec![MockConfig::default(), MockConfig::lossy()] {
+        let requester = Arc::new(MockRequester::new(test_data(FILE_SIZE), config));
+        let mut file = AudioFileLive::open_with_requester(
+            requester.clone(),
+            FileId([1; 20]),
+            DATA_RATE,
+            live_config(),
+        );
+
+        let mut output = Vec::new();
+        let mut buffer = [0u8; 8192];
+        loop {
+            let read = tokio::time::timeout(Duration::from_secs(30), file.read_async(&mut buffer))
+                .await
+                .expect("read timed out")
+                .unwrap();
+            if read == 0 {
+                break;
+            }
+            output.extend_from_slice(&buffer[..read]);
+
+            // Only the retained second before the read position is kept.
+            let (start, _) = file.downloaded_ranges()[0];
+            assert!(start + DATA_RATE >= output.len());
+        }
+        assert_eq!(output, test_data(FILE_SIZE));
+        for (offset, length) in requester.requests() {
+            assert_eq!(offset % 4, 0, "unaligned request at {}", offset);
+            assert_eq!(length % 4, 0, "unaligned request of {} bytes", length);
+        }
+
+        requester.shutdown();
+    }
+}
+
+#[tokio::test]
+async fn seeks_in_live_stream() {
+    let data = test_data(FILE_SIZE);
+    let requester = Arc::new(MockRequester::new(data.clone(), MockConfig::default()));
+    let mut file = AudioFileLive::open_with_requester(
+        requester.clone(),
+        FileId([1; 20]),
+        DATA_RATE,
+        live_config(),
+    );
+
+    let mut buffer = [0u8; 100];
+    let mut position = 0;
+    while position < 3 * DATA_RATE {
+        position += file.read_async(&mut buffer).await.unwrap();
+    }
+
+    // Back within the retained data, and ahead of the downloaded data.
+    let back = position - DATA_RATE / 2;
+    assert_eq!(
+        file.seek(SeekFrom::Start(back as u64)).unwrap(),
+        back as u64
+    );
+    let read = file.read_async(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..read], &data[back..back + read]);
+
+    let ahead = 200_001;
+    file.seek(SeekFrom::Start(ahead as u64)).unwrap();
+    let read = file.read_async(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..read], &data[ahead..ahead + read]);
+
+    // The data before the window was discarded.
+    assert!(file.seek(SeekFrom::Start(0)).is_err());
+    assert!(file.read_async(&mut buffer).await.is_err());
+
+    requester.shutdown();
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use librespot_audio::simulation::{simulate, NetworkTrace, SimulationConfig};
+use librespot_audio::FetchProfile;
+
+// A connection that drops below the bitrate for ten seconds.
+const FLAKY_TRACE: &str = "
+# duration_ms latency_ms kbytes_per_second
+20000 50 256
+10000 400 8
+60000 50 256
+";
+
+#[test]
+fn fast_connection_does_not_stall() {
+    let trace = NetworkTrace::constant(Duration::from_millis(50), 512 * 1024);
+    let report = simulate(&trace, &SimulationConfig::default());
+
+    assert_eq!(report.stalls, 0);
+    assert!(report.startup_time < Duration::from_secs(1));
+    assert!(report.bytes_requested >= SimulationConfig::default().file_size);
+}
+
+#[test]
+fn slow_connection_stalls() {
+    let trace = NetworkTrace::constant(Duration::from_millis(200), 10 * 1024);
+    let report = simulate(&trace, &SimulationConfig::default());
+
+    assert!(report.stalls > 0);
+    assert!(report.stall_time > Duration::from_secs(10));
+}
+
+#[test]
+fn aggressive_profile_buffers_more_than_metered() {
+    let trace: NetworkTrace = FLAKY_TRACE.parse().unwrap();
+    assert_eq!(trace.segments().len(), 3);
+
+    let run = |profile| {
+        simulate(
+            &trace,
+            &SimulationConfig {
+                profile,
+                ..SimulationConfig::default()
+            },
+        )
+    };
+    let aggressive = run(FetchProfile::aggressive());
+    let metered = run(FetchProfile::metered());
+
+    assert!(aggressive.stall_time <= metered.stall_time);
+}
+
+#[test]
+fn simulation_is_deterministic() {
+    let trace: NetworkTrace = FLAKY_TRACE.parse().unwrap();
+    let config = SimulationConfig::default();
+
+    assert_eq!(simulate(&trace, &config), simulate(&trace, &config));
+}
+
+#[test]
+fn rejects_invalid_traces() {
+    assert_eq!(
+        "1000 50".parse::<NetworkTrace>().unwrap_err().to_string(),
+        "invalid network trace in line 1"
+    );
+    assert!("# nothing\n".parse::<NetworkTrace>().is_err());
+}
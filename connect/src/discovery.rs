@@ -3,73 +3,405 @@ use aes_ctr::cipher::{NewStreamCipher, SyncStreamCipher};
 use aes_ctr::Aes128Ctr;
 use futures_core::Stream;
 use hmac::{Hmac, Mac, NewMac};
-use hyper::service::{make_service_fn, service_fn};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
 use hyper::{Body, Method, Request, Response, StatusCode};
+use rand::Rng;
 use serde_json::json;
 use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 use tokio::sync::{mpsc, oneshot};
 
 #[cfg(feature = "with-dns-sd")]
 use dns_sd::DNSService;
 
 use librespot_core::authentication::Credentials;
-use librespot_core::config::ConnectConfig;
+use librespot_core::config::{ConnectConfig, SessionConfig};
 use librespot_core::diffie_hellman::DhLocalKeys;
 
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 type HmacSha1 = Hmac<Sha1>;
+type ConfirmFuture = Pin<Box<dyn Future<Output = bool> + Send>>;
+
+// Instance names are sent as a single DNS label, which can't be longer than this.
+const MAX_SERVICE_NAME_LEN: usize = 63;
+// The period `max_auth_attempts` applies to.
+const AUTH_ATTEMPTS_PERIOD: Duration = Duration::from_secs(60);
+// Logins that aren't confirmed in this time are rejected.
+const CONFIRM_LOGIN_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The implementation used to announce the device on the local network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZeroconfBackend {
+    /// Use the system's DNS-SD daemon (e.g. avahi) if available, and the built-in
+    /// responder otherwise.
+    Auto,
+    /// The built-in mDNS responder. It conflicts with an mDNS daemon already
+    /// running on the host.
+    Libmdns,
+    /// The system's DNS-SD daemon, requires the `with-dns-sd` feature.
+    DnsSd,
+}
+
+impl FromStr for ZeroconfBackend {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::ZeroconfBackend::*;
+        match s.to_lowercase().as_ref() {
+            "auto" => Ok(Auto),
+            "libmdns" => Ok(Libmdns),
+            "dns-sd" | "avahi" => Ok(DnsSd),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for ZeroconfBackend {
+    fn default() -> ZeroconfBackend {
+        ZeroconfBackend::Auto
+    }
+}
+
+/// An IP network in CIDR notation, e.g. `192.168.1.0/24`, or a single address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            // An IPv4 client of a server listening on IPv6.
+            (IpAddr::V4(network), IpAddr::V6(ip)) => {
+                let octets = ip.octets();
+                octets[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]
+                    && prefix_matches(&network.octets(), &octets[12..], self.prefix_len)
+            }
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let bytes = prefix_len as usize / 8;
+    let bits = prefix_len % 8;
+    network[..bytes] == ip[..bytes]
+        && (bits == 0 || (network[bytes] ^ ip[bytes]) >> (8 - bits) == 0)
+}
+
+impl FromStr for IpNetwork {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let address = parts.next().unwrap().parse::<IpAddr>().map_err(|_| ())?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| ())?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(());
+        }
+        Ok(IpNetwork {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+/// What happens on the discovery server, returned by `DiscoveryStream`.
+#[derive(Clone, Debug)]
+pub enum DiscoveryEvent {
+    /// A user logged in over zeroconf, e.g. from the Spotify app.
+    CredentialsReceived {
+        username: String,
+        credentials: Credentials,
+    },
+    /// The user reported as active to zeroconf clients changed, after a login of another
+    /// user or `DiscoveryStream::set_active_user`.
+    ActiveUserChanged {
+        username: Option<String>,
+    },
+    Error(DiscoveryError),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiscoveryError {
+    /// A login request that is malformed or can't be decrypted.
+    InvalidLogin { client_address: IpAddr },
+    /// A login request over `DiscoveryConfig::max_auth_attempts`.
+    TooManyLogins { client_address: IpAddr },
+    /// A login that was rejected or not confirmed in time.
+    LoginNotConfirmed {
+        username: String,
+        client_address: IpAddr,
+    },
+    /// The server can't accept connections.
+    Server(String),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::InvalidLogin { client_address } => {
+                write!(f, "Invalid login request from {}", client_address)
+            }
+            DiscoveryError::TooManyLogins { client_address } => {
+                write!(f, "Too many login attempts from {}", client_address)
+            }
+            DiscoveryError::LoginNotConfirmed {
+                username,
+                client_address,
+            } => write!(
+                f,
+                "Login of {} from {} wasn't confirmed",
+                username, client_address
+            ),
+            DiscoveryError::Server(e) => {
+                write!(f, "Discovery server cannot accept a connection: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+/// A login received over zeroconf that waits for confirmation.
+#[derive(Clone, Debug)]
+pub struct LoginRequest {
+    pub username: String,
+    /// The address of the device the user logs in from.
+    pub client_address: IpAddr,
+}
+
+/// Decides whether a login received over zeroconf is accepted, e.g. by asking the user of
+/// the embedding application or by a PIN shown on the device.
+///
+/// The HTTP request of the login is answered once the login is confirmed or rejected, and
+/// the client is told if it was rejected. A login that isn't confirmed within two minutes is
+/// rejected.
+#[derive(Clone)]
+pub struct LoginConfirmation(Confirm);
+
+#[derive(Clone)]
+enum Confirm {
+    Callback(Arc<dyn Fn(LoginRequest) -> ConfirmFuture + Send + Sync>),
+    Pin(Arc<dyn Fn(&LoginRequest, &str) + Send + Sync>),
+}
+
+impl LoginConfirmation {
+    /// Confirms a login when the future returned by `confirm` resolves to `true`.
+    pub fn new<F, R>(confirm: F) -> Self
+    where
+        F: Fn(LoginRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = bool> + Send + 'static,
+    {
+        LoginConfirmation(Confirm::Callback(Arc::new(move |request| {
+            Box::pin(confirm(request))
+        })))
+    }
+
+    /// Confirms a login when the connecting user enters a PIN of four digits, which `show`
+    /// displays on the device. The PIN is entered on the discovery server, e.g. by opening
+    /// `http://<device>:<port>/?action=confirmLogin&pin=<PIN>` in a browser. A wrong PIN
+    /// rejects the logins waiting for one, so that it can't be guessed.
+    pub fn pin<F>(show: F) -> Self
+    where
+        F: Fn(&LoginRequest, &str) + Send + Sync + 'static,
+    {
+        LoginConfirmation(Confirm::Pin(Arc::new(show)))
+    }
+}
+
+impl fmt::Debug for LoginConfirmation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LoginConfirmation")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DiscoveryConfig {
+    /// The port of the HTTP server announced over zeroconf, `0` picks any free port.
+    pub port: u16,
+    /// Restrict the HTTP server and the mDNS responder to this address instead of
+    /// listening on all interfaces.
+    pub bind_address: Option<IpAddr>,
+    pub backend: ZeroconfBackend,
+    /// Serve HTTPS with a self-signed certificate generated at startup, requires the
+    /// `with-discovery-tls` feature. Only clients that try HTTPS can connect then, the
+    /// official Spotify clients only speak HTTP to `addUser`.
+    pub tls: bool,
+    /// Only accept connections from these networks, or from anywhere if empty.
+    pub allowed_networks: Vec<IpNetwork>,
+    /// The maximum number of authentication attempts per minute from an address.
+    pub max_auth_attempts: Option<u32>,
+    /// Only use the credentials of a login once it is confirmed, instead of right away.
+    pub confirm_login: Option<LoginConfirmation>,
+}
 
 #[derive(Clone)]
 struct Discovery(Arc<DiscoveryInner>);
 struct DiscoveryInner {
     config: ConnectConfig,
-    device_id: String,
+    session_config: SessionConfig,
     keys: DhLocalKeys,
-    tx: mpsc::UnboundedSender<Credentials>,
+    tx: mpsc::UnboundedSender<DiscoveryEvent>,
+    active_user: Mutex<Option<String>>,
+    auth_limiter: Option<AuthLimiter>,
+    confirm_login: Option<LoginConfirmation>,
+    pins: PendingPins,
+}
+
+// The PINs of the logins waiting for one to be entered.
+#[derive(Default)]
+struct PendingPins(Mutex<HashMap<String, oneshot::Sender<bool>>>);
+
+impl PendingPins {
+    // Picks the PIN of a login, the receiver is sent whether it was entered.
+    fn add(&self) -> (String, oneshot::Receiver<bool>) {
+        let mut pins = self.0.lock().unwrap();
+        // Drops the logins that timed out.
+        pins.retain(|_, confirmed| !confirmed.is_closed());
+        let pin = loop {
+            let pin = format!("{:04}", rand::thread_rng().gen_range(0..10_000));
+            if !pins.contains_key(&pin) {
+                break pin;
+            }
+        };
+        let (confirmed_tx, confirmed_rx) = oneshot::channel();
+        pins.insert(pin.clone(), confirmed_tx);
+        (pin, confirmed_rx)
+    }
+
+    // Confirms the login of `pin`. Any other PIN rejects all waiting logins.
+    fn enter(&self, pin: &str) -> bool {
+        let mut pins = self.0.lock().unwrap();
+        pins.retain(|_, confirmed| !confirmed.is_closed());
+        match pins.remove(pin) {
+            Some(confirmed) => confirmed.send(true).is_ok(),
+            None => {
+                for (_, confirmed) in pins.drain() {
+                    let _ = confirmed.send(false);
+                }
+                false
+            }
+        }
+    }
+}
+
+// Counts the authentication attempts of each address in the current period.
+struct AuthLimiter {
+    max_attempts: u32,
+    attempts: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl AuthLimiter {
+    fn new(max_attempts: u32) -> Self {
+        AuthLimiter {
+            max_attempts,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts an attempt, returns whether it is allowed.
+    fn attempt(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, (started, _)| now.duration_since(*started) < AUTH_ATTEMPTS_PERIOD);
+        let (_, count) = attempts.entry(ip).or_insert((now, 0));
+        if *count >= self.max_attempts {
+            return false;
+        }
+        *count += 1;
+        true
+    }
 }
 
 impl Discovery {
     fn new(
         config: ConnectConfig,
-        device_id: String,
-    ) -> (Discovery, mpsc::UnboundedReceiver<Credentials>) {
+        session_config: SessionConfig,
+        discovery_config: &DiscoveryConfig,
+    ) -> (Discovery, mpsc::UnboundedReceiver<DiscoveryEvent>) {
         let (tx, rx) = mpsc::unbounded_channel();
 
         let discovery = Discovery(Arc::new(DiscoveryInner {
             config,
-            device_id,
+            session_config,
             keys: DhLocalKeys::random(&mut rand::thread_rng()),
             tx,
+            active_user: Mutex::new(None),
+            auth_limiter: discovery_config.max_auth_attempts.map(AuthLimiter::new),
+            confirm_login: discovery_config.confirm_login.clone(),
+            pins: PendingPins::default(),
         }));
 
         (discovery, rx)
     }
 
+    // Nobody listens once the stream is dropped.
+    fn send(&self, event: DiscoveryEvent) {
+        let _ = self.0.tx.send(event);
+    }
+
+    fn fail(&self, error: DiscoveryError) {
+        warn!("{}", error);
+        self.send(DiscoveryEvent::Error(error));
+    }
+
+    fn set_active_user(&self, username: Option<String>) {
+        let mut active_user = self.0.active_user.lock().unwrap();
+        if *active_user != username {
+            *active_user = username.clone();
+            self.send(DiscoveryEvent::ActiveUserChanged { username });
+        }
+    }
+
+    fn login(&self, username: String, credentials: Credentials) {
+        self.send(DiscoveryEvent::CredentialsReceived {
+            username: username.clone(),
+            credentials,
+        });
+        self.set_active_user(Some(username));
+    }
+
     fn handle_get_info(&self, _: BTreeMap<Cow<'_, str>, Cow<'_, str>>) -> Response<hyper::Body> {
         let public_key = base64::encode(&self.0.keys.public_key());
+        let active_user = self.0.active_user.lock().unwrap().clone();
 
         let result = json!({
             "status": 101,
             "statusString": "ERROR-OK",
             "spotifyError": 0,
             "version": "2.7.1",
-            "deviceID": (self.0.device_id),
+            "deviceID": (self.0.session_config.device_id),
             "remoteName": (self.0.config.name),
-            "activeUser": "",
+            "activeUser": (active_user.unwrap_or_default()),
             "publicKey": (public_key),
             "deviceType": (self.0.config.device_type.to_string().to_uppercase()),
             "libraryVersion": "0.1.0",
             "accountReq": "PREMIUM",
-            "brandDisplayName": "librespot",
-            "modelDisplayName": "librespot",
+            "brandDisplayName": (self.0.session_config.client_brand),
+            "modelDisplayName": (self.0.session_config.client_model),
             "resolverVersion": "0",
             "groupStatus": "NONE",
             "voiceSupport": "NO",
@@ -79,20 +411,42 @@ impl Discovery {
         Response::new(Body::from(body))
     }
 
-    fn handle_add_user(
+    async fn handle_add_user(
         &self,
+        peer: IpAddr,
         params: BTreeMap<Cow<'_, str>, Cow<'_, str>>,
     ) -> Response<hyper::Body> {
-        let username = params.get("userName").unwrap().as_ref();
-        let encrypted_blob = params.get("blob").unwrap();
-        let client_key = params.get("clientKey").unwrap();
+        if let Some(limiter) = &self.0.auth_limiter {
+            if !limiter.attempt(peer, Instant::now()) {
+                self.fail(DiscoveryError::TooManyLogins {
+                    client_address: peer,
+                });
+                return self.error_response(StatusCode::TOO_MANY_REQUESTS);
+            }
+        }
 
-        let encrypted_blob = base64::decode(encrypted_blob.as_bytes()).unwrap();
+        let (username, encrypted_blob, client_key) = match (
+            params.get("userName"),
+            params
+                .get("blob")
+                .and_then(|blob| base64::decode(blob.as_bytes()).ok()),
+            params
+                .get("clientKey")
+                .and_then(|key| base64::decode(key.as_bytes()).ok()),
+        ) {
+            // The IV and the checksum.
+            (Some(username), Some(blob), Some(key)) if blob.len() >= 16 + 20 => {
+                (username.as_ref(), blob, key)
+            }
+            _ => {
+                self.fail(DiscoveryError::InvalidLogin {
+                    client_address: peer,
+                });
+                return self.error_response(StatusCode::BAD_REQUEST);
+            }
+        };
 
-        let shared_key = self
-            .0
-            .keys
-            .shared_secret(&base64::decode(client_key.as_bytes()).unwrap());
+        let shared_key = self.0.keys.shared_secret(&client_key);
 
         let iv = &encrypted_blob[0..16];
         let encrypted = &encrypted_blob[16..encrypted_blob.len() - 20];
@@ -116,7 +470,10 @@ impl Discovery {
         let mut h = HmacSha1::new_varkey(&checksum_key).expect("HMAC can take key of any size");
         h.update(encrypted);
         if h.verify(cksum).is_err() {
-            warn!("Login error for user {:?}: MAC mismatch", username);
+            debug!("Login error for user {:?}: MAC mismatch", username);
+            self.fail(DiscoveryError::InvalidLogin {
+                client_address: peer,
+            });
             let result = json!({
                 "status": 102,
                 "spotifyError": 1,
@@ -134,13 +491,83 @@ impl Discovery {
                 &GenericArray::from_slice(iv),
             );
             cipher.apply_keystream(&mut data);
-            String::from_utf8(data).unwrap()
+            match String::from_utf8(data) {
+                Ok(decrypted) => decrypted,
+                Err(_) => {
+                    self.fail(DiscoveryError::InvalidLogin {
+                        client_address: peer,
+                    });
+                    return self.error_response(StatusCode::BAD_REQUEST);
+                }
+            }
         };
 
-        let credentials =
-            Credentials::with_blob(username.to_string(), &decrypted, &self.0.device_id);
+        let credentials = Credentials::with_blob(
+            username.to_string(),
+            &decrypted,
+            &self.0.session_config.device_id,
+        );
+
+        let username = username.to_owned();
+        if let Some(confirm_login) = &self.0.confirm_login {
+            let request = LoginRequest {
+                username: username.clone(),
+                client_address: peer,
+            };
+            info!(
+                "Waiting for the login of {} from {} to be confirmed",
+                username, peer
+            );
+            let confirmed: ConfirmFuture = match &confirm_login.0 {
+                Confirm::Callback(confirm) => confirm(request),
+                Confirm::Pin(show) => {
+                    let (pin, confirmed) = self.0.pins.add();
+                    show(&request, &pin);
+                    Box::pin(async move { confirmed.await.unwrap_or(false) })
+                }
+            };
+            match tokio::time::timeout(CONFIRM_LOGIN_TIMEOUT, confirmed).await {
+                Ok(true) => (),
+                result => {
+                    if result.is_ok() {
+                        debug!("Login from {} was rejected", peer);
+                    } else {
+                        debug!("Login from {} wasn't confirmed in time", peer);
+                    }
+                    self.fail(DiscoveryError::LoginNotConfirmed {
+                        username,
+                        client_address: peer,
+                    });
+                    let result = json!({
+                        "status": 105,
+                        "spotifyError": 0,
+                        "statusString": "ERROR-LOGIN-FAILED"
+                    });
+                    return Response::new(Body::from(result.to_string()));
+                }
+            }
+        }
+        self.login(username, credentials);
+
+        let result = json!({
+            "status": 101,
+            "spotifyError": 0,
+            "statusString": "ERROR-OK"
+        });
+
+        let body = result.to_string();
+        Response::new(Body::from(body))
+    }
 
-        self.0.tx.send(credentials).unwrap();
+    fn handle_confirm_login(
+        &self,
+        params: BTreeMap<Cow<'_, str>, Cow<'_, str>>,
+    ) -> Response<hyper::Body> {
+        let pin = params.get("pin").map_or("", AsRef::as_ref);
+        if !self.0.pins.enter(pin) {
+            warn!("A wrong PIN was entered, rejecting the logins waiting for one");
+            return self.error_response(StatusCode::FORBIDDEN);
+        }
 
         let result = json!({
             "status": 101,
@@ -152,13 +579,13 @@ impl Discovery {
         Response::new(Body::from(body))
     }
 
-    fn not_found(&self) -> Response<hyper::Body> {
+    fn error_response(&self, status: StatusCode) -> Response<hyper::Body> {
         let mut res = Response::default();
-        *res.status_mut() = StatusCode::NOT_FOUND;
+        *res.status_mut() = status;
         res
     }
 
-    async fn call(self, request: Request<Body>) -> hyper::Result<Response<Body>> {
+    async fn call(self, peer: IpAddr, request: Request<Body>) -> hyper::Result<Response<Body>> {
         let mut params = BTreeMap::new();
 
         let (parts, body) = request.into_parts();
@@ -179,85 +606,325 @@ impl Discovery {
         Ok(
             match (parts.method, params.get("action").map(AsRef::as_ref)) {
                 (Method::GET, Some("getInfo")) => self.handle_get_info(params),
-                (Method::POST, Some("addUser")) => self.handle_add_user(params),
-                _ => self.not_found(),
+                (Method::POST, Some("addUser")) => self.handle_add_user(peer, params).await,
+                (Method::GET, Some("confirmLogin")) | (Method::POST, Some("confirmLogin")) => {
+                    self.handle_confirm_login(params)
+                }
+                _ => self.error_response(StatusCode::NOT_FOUND),
             },
         )
     }
 }
 
-#[cfg(feature = "with-dns-sd")]
-pub struct DiscoveryStream {
-    credentials: mpsc::UnboundedReceiver<Credentials>,
-    _svc: DNSService,
-    _close_tx: oneshot::Sender<Infallible>,
+// Only held to keep the device announced until discovery is dropped.
+#[allow(dead_code)]
+enum ZeroconfService {
+    Libmdns(libmdns::Service),
+    #[cfg(feature = "with-dns-sd")]
+    DnsSd(DNSService),
 }
 
-#[cfg(not(feature = "with-dns-sd"))]
+/// The events of the discovery server, which runs until this is dropped.
 pub struct DiscoveryStream {
-    credentials: mpsc::UnboundedReceiver<Credentials>,
-    _svc: libmdns::Service,
+    events: mpsc::UnboundedReceiver<DiscoveryEvent>,
+    discovery: Discovery,
+    _svc: ZeroconfService,
     _close_tx: oneshot::Sender<Infallible>,
 }
 
+/// Turns a device name into a valid DNS-SD instance name.
+///
+/// Any Unicode is allowed, but control characters are dropped and the name is
+/// truncated (at a character boundary) to fit into a single DNS label.
+fn service_name(name: &str) -> String {
+    let mut service_name = String::with_capacity(name.len());
+    for c in name.chars().filter(|c| !c.is_control()) {
+        if service_name.len() + c.len_utf8() > MAX_SERVICE_NAME_LEN {
+            break;
+        }
+        service_name.push(c);
+    }
+
+    if service_name.trim().is_empty() {
+        "Librespot".to_owned()
+    } else {
+        service_name
+    }
+}
+
 pub fn discovery(
     config: ConnectConfig,
-    device_id: String,
-    port: u16,
+    session_config: SessionConfig,
+    discovery_config: DiscoveryConfig,
 ) -> io::Result<DiscoveryStream> {
-    let (discovery, creds_rx) = Discovery::new(config.clone(), device_id);
+    let name = service_name(&config.name);
+    if name != config.name {
+        warn!("Device name {:?} advertised as {:?}", config.name, name);
+    }
+
+    let (discovery, events) = Discovery::new(config, session_config, &discovery_config);
     let (close_tx, close_rx) = oneshot::channel();
 
-    let address = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+    let tls = if discovery_config.tls {
+        Some(tls_acceptor()?)
+    } else {
+        None
+    };
+
+    let address = SocketAddr::new(
+        discovery_config
+            .bind_address
+            .unwrap_or_else(|| Ipv4Addr::UNSPECIFIED.into()),
+        discovery_config.port,
+    );
 
-    let make_service = make_service_fn(move |_| {
-        let discovery = discovery.clone();
-        async move { Ok::<_, hyper::Error>(service_fn(move |request| discovery.clone().call(request))) }
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let s_port = listener.local_addr()?.port();
+    debug!(
+        "Zeroconf server listening on {}{}",
+        listener.local_addr()?,
+        if tls.is_some() { " (HTTPS)" } else { "" }
+    );
+
+    let allowed_networks = discovery_config.allowed_networks.clone();
+    let server_discovery = discovery.clone();
+    tokio::spawn(async move {
+        let discovery = server_discovery;
+        let mut close_rx = close_rx;
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        discovery.fail(DiscoveryError::Server(e.to_string()));
+                        continue;
+                    }
+                },
+                _ = &mut close_rx => break,
+            };
+
+            let peer = peer.ip();
+            if !allowed_networks.is_empty()
+                && !allowed_networks
+                    .iter()
+                    .any(|network| network.contains(peer))
+            {
+                debug!("Refusing discovery connection from {}", peer);
+                continue;
+            }
+
+            let discovery = discovery.clone();
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                let result = match tls {
+                    Some(tls) => match tls.accept(stream).await {
+                        Ok(stream) => serve_connection(discovery, peer, stream).await,
+                        Err(e) => {
+                            debug!("TLS handshake with {} failed: {}", peer, e);
+                            return;
+                        }
+                    },
+                    None => serve_connection(discovery, peer, stream).await,
+                };
+                if let Err(e) = result {
+                    debug!("Discovery connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+        debug!("Shutting down discovery server");
     });
 
-    let server = hyper::Server::bind(&address).serve(make_service);
+    let svc = register(&name, s_port, &discovery_config)?;
 
-    let s_port = server.local_addr().port();
-    debug!("Zeroconf server listening on 0.0.0.0:{}", s_port);
+    Ok(DiscoveryStream {
+        events,
+        discovery,
+        _svc: svc,
+        _close_tx: close_tx,
+    })
+}
 
-    tokio::spawn(server.with_graceful_shutdown(async {
-        close_rx.await.unwrap_err();
-        debug!("Shutting down discovery server");
-    }));
+async fn serve_connection<S>(discovery: Discovery, peer: IpAddr, stream: S) -> hyper::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |request| discovery.clone().call(peer, request));
+    Http::new()
+        .http1_only(true)
+        .serve_connection(stream, service)
+        .await
+}
 
-    #[cfg(feature = "with-dns-sd")]
+#[cfg(feature = "with-discovery-tls")]
+type TlsAcceptor = tokio_rustls::TlsAcceptor;
+
+// Clients can't verify the certificate, it is generated for each run.
+#[cfg(feature = "with-discovery-tls")]
+fn tls_acceptor() -> io::Result<TlsAcceptor> {
+    use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+
+    fn to_io_error<E: std::fmt::Display>(e: E) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e.to_string())
+    }
+
+    let certificate =
+        rcgen::generate_simple_self_signed(vec!["librespot".to_owned()]).map_err(to_io_error)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(
+            vec![Certificate(
+                certificate.serialize_der().map_err(to_io_error)?,
+            )],
+            PrivateKey(certificate.serialize_private_key_der()),
+        )
+        .map_err(to_io_error)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(not(feature = "with-discovery-tls"))]
+#[derive(Clone)]
+enum TlsAcceptor {}
+
+#[cfg(not(feature = "with-discovery-tls"))]
+impl TlsAcceptor {
+    async fn accept(&self, _stream: tokio::net::TcpStream) -> io::Result<tokio::net::TcpStream> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "with-discovery-tls"))]
+fn tls_acceptor() -> io::Result<TlsAcceptor> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "librespot was built without TLS support",
+    ))
+}
+
+fn register(name: &str, port: u16, config: &DiscoveryConfig) -> io::Result<ZeroconfService> {
+    match config.backend {
+        ZeroconfBackend::Libmdns => register_libmdns(name, port, config),
+        ZeroconfBackend::DnsSd => register_dns_sd(name, port, config),
+        ZeroconfBackend::Auto => register_dns_sd(name, port, config).or_else(|e| {
+            debug!(
+                "DNS-SD not available ({}), using the built-in mDNS responder",
+                e
+            );
+            register_libmdns(name, port, config)
+        }),
+    }
+}
+
+fn register_libmdns(
+    name: &str,
+    port: u16,
+    config: &DiscoveryConfig,
+) -> io::Result<ZeroconfService> {
+    let handle = tokio::runtime::Handle::current();
+    let responder = match config.bind_address {
+        Some(ip) => libmdns::Responder::spawn_with_ip_list(&handle, vec![ip])?,
+        None => libmdns::Responder::spawn(&handle)?,
+    };
+
+    let svc = responder.register(
+        "_spotify-connect._tcp".to_owned(),
+        name.to_owned(),
+        port,
+        &["VERSION=1.0", "CPath=/"],
+    );
+
+    info!("Announcing device using the built-in mDNS responder");
+    Ok(ZeroconfService::Libmdns(svc))
+}
+
+#[cfg(feature = "with-dns-sd")]
+fn register_dns_sd(name: &str, port: u16, config: &DiscoveryConfig) -> io::Result<ZeroconfService> {
     let svc = DNSService::register(
-        Some(&*config.name),
+        Some(name),
         "_spotify-connect._tcp",
         None,
         None,
-        s_port,
+        port,
         &["VERSION=1.0", "CPath=/"],
     )
-    .unwrap();
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
 
-    #[cfg(not(feature = "with-dns-sd"))]
-    let responder = libmdns::Responder::spawn(&tokio::runtime::Handle::current())?;
+    if config.bind_address.is_some() {
+        warn!("DNS-SD announces on all interfaces, only the server is bound to the given address");
+    }
 
-    #[cfg(not(feature = "with-dns-sd"))]
-    let svc = responder.register(
-        "_spotify-connect._tcp".to_owned(),
-        config.name,
-        s_port,
-        &["VERSION=1.0", "CPath=/"],
-    );
+    info!("Announcing device using the system's DNS-SD daemon");
+    Ok(ZeroconfService::DnsSd(svc))
+}
 
-    Ok(DiscoveryStream {
-        credentials: creds_rx,
-        _svc: svc,
-        _close_tx: close_tx,
-    })
+#[cfg(not(feature = "with-dns-sd"))]
+fn register_dns_sd(_: &str, _: u16, _: &DiscoveryConfig) -> io::Result<ZeroconfService> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "librespot was built without DNS-SD support",
+    ))
+}
+
+impl DiscoveryStream {
+    /// Sets the user reported as active to zeroconf clients, e.g. `None` after logging out.
+    pub fn set_active_user(&self, username: Option<&str>) {
+        self.discovery
+            .set_active_user(username.map(ToOwned::to_owned));
+    }
 }
 
 impl Stream for DiscoveryStream {
-    type Item = Credentials;
+    type Item = DiscoveryEvent;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.credentials.poll_recv(cx)
+        self.events.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_networks() {
+        let network: IpNetwork = "192.168.1.0/23".parse().unwrap();
+        assert!(network.contains("192.168.0.7".parse().unwrap()));
+        assert!(!network.contains("192.168.2.7".parse().unwrap()));
+        assert!(network.contains("::ffff:192.168.1.7".parse().unwrap()));
+
+        let address: IpNetwork = "fe80::1".parse().unwrap();
+        assert!(address.contains("fe80::1".parse().unwrap()));
+        assert!(!address.contains("fe80::2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("0.0.0.0/0".parse::<IpNetwork>().is_ok());
+    }
+
+    #[test]
+    fn limits_auth_attempts_per_address() {
+        let limiter = AuthLimiter::new(2);
+        let start = Instant::now();
+        let client = IpAddr::from([192, 168, 1, 7]);
+
+        assert!(limiter.attempt(client, start));
+        assert!(limiter.attempt(client, start));
+        assert!(!limiter.attempt(client, start));
+        assert!(limiter.attempt(IpAddr::from([192, 168, 1, 8]), start));
+        assert!(limiter.attempt(client, start + AUTH_ATTEMPTS_PERIOD));
+    }
+
+    #[test]
+    fn confirms_logins_with_their_pin() {
+        let pins = PendingPins::default();
+        let (pin, mut confirmed) = pins.add();
+        let (other_pin, mut other_confirmed) = pins.add();
+        assert_eq!(pin.len(), 4);
+        assert_ne!(pin, other_pin);
+
+        assert!(pins.enter(&pin));
+        assert_eq!(confirmed.try_recv(), Ok(true));
+        assert!(!pins.enter(&pin));
+        assert_eq!(other_confirmed.try_recv(), Ok(false));
     }
 }
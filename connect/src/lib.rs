@@ -2,6 +2,7 @@
 extern crate log;
 
 use librespot_core as core;
+use librespot_metadata as metadata;
 use librespot_playback as playback;
 use librespot_protocol as protocol;
 
@@ -1,16 +1,19 @@
 use std::future::Future;
 use std::pin::Pin;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::context::StationContext;
-use crate::core::config::{ConnectConfig, VolumeCtrl};
+use crate::core::config::{ConnectCapabilities, ConnectConfig, PlaybackMovedPolicy, VolumeCtrl};
 use crate::core::mercury::{MercuryError, MercurySender};
 use crate::core::session::Session;
 use crate::core::spotify_id::{SpotifyAudioType, SpotifyId, SpotifyIdError};
 use crate::core::util::SeqGenerator;
-use crate::core::version;
+use crate::core::Error;
+use crate::metadata::{Album, Artist, Metadata, Playlist, Show};
+use crate::playback::config::Bitrate;
 use crate::playback::mixer::Mixer;
-use crate::playback::player::{Player, PlayerEvent, PlayerEventChannel};
+use crate::playback::player::{Player, PlayerEvent, PlayerEventChannel, TrackTransitionReason};
 use crate::protocol;
 use crate::protocol::spirc::{DeviceState, Frame, MessageType, PlayStatus, State, TrackRef};
 
@@ -20,7 +23,6 @@ use futures_util::{FutureExt, StreamExt};
 use protobuf::{self, Message};
 use rand::seq::SliceRandom;
 use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 
 enum SpircPlayStatus {
     Stopped,
@@ -54,6 +56,8 @@ struct SpircTask {
     device: DeviceState,
     state: State,
     play_request_id: Option<u64>,
+    // The track last loaded into the player, until playback stops.
+    playing_track: Option<SpotifyId>,
     mixer_started: bool,
     play_status: SpircPlayStatus,
 
@@ -66,7 +70,40 @@ struct SpircTask {
     session: Session,
     context_fut: BoxedFuture<Result<serde_json::Value, MercuryError>>,
     autoplay_fut: BoxedFuture<Result<String, MercuryError>>,
+    load_context_fut: BoxedFuture<Result<LoadedContext, Error>>,
     context: Option<StationContext>,
+
+    // The name of the device that loaded the current context.
+    remote_device_name: Option<String>,
+    // The ident and state of the active device while this one is inactive, to send it jumps.
+    remote_state: Option<(String, State)>,
+    shared_state: Arc<Mutex<SharedState>>,
+    // Loads from clients are refused, see ConnectConfig::passive.
+    passive: bool,
+    playback_moved_policy: PlaybackMovedPolicy,
+    // Resolves to the position to resume at when playback is taken back from another device,
+    // see PlaybackMovedPolicy::ReclaimAfterSeconds.
+    reclaim_fut: BoxedFuture<u32>,
+}
+
+/// A track of the Connect queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedTrack {
+    /// The position of the track in the queue.
+    pub index: usize,
+    pub track_id: SpotifyId,
+    /// Whether the track was queued by the user, rather than being part of the context.
+    pub queued: bool,
+}
+
+// What the Spirc handle can tell about the state of its task.
+#[derive(Debug, Default)]
+struct SharedState {
+    context_uri: String,
+    queue: Vec<QueuedTrack>,
+    playing_track_index: usize,
+    remote_device_name: Option<String>,
+    logged_out: bool,
 }
 
 pub enum SpircCommand {
@@ -77,19 +114,53 @@ pub enum SpircCommand {
     Next,
     VolumeUp,
     VolumeDown,
+    Seek(u32),
+    JumpToIndex(u32),
+    SetVolume(u16),
+    LoadContext {
+        uri: String,
+        start_index: u32,
+        position_ms: u32,
+    },
+    SetBitrate(Bitrate),
+    PrecacheNext(usize),
+    SetPassive(bool),
     Shutdown,
 }
 
+// A context resolved for Spirc::load_context().
+struct LoadedContext {
+    uri: String,
+    tracks: Vec<SpotifyId>,
+    start_index: u32,
+    position_ms: u32,
+}
+
 struct SpircTaskConfig {
     volume_ctrl: VolumeCtrl,
+    volume_steps: u16,
+    mixer_volume_range: (u16, u16),
     autoplay: bool,
+    capabilities: ConnectCapabilities,
 }
 
+// The type of the device metadata entry that reports the bitrate, in kbps. Spirc has no
+// capability or message for the audio quality.
+const AUDIO_QUALITY_METADATA: &str = "audio_quality";
+// The type of the device metadata entry that reports whether playback is gapless, 1 or 0.
+const GAPLESS_METADATA: &str = "gapless";
+
 const CONTEXT_TRACKS_HISTORY: usize = 10;
 const CONTEXT_FETCH_THRESHOLD: u32 = 5;
 
+// How often the position is reported while playing, even if it didn't drift. Clients
+// extrapolate it with their own clocks, which drift from the audible position.
+const POSITION_RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
 pub struct Spirc {
     commands: mpsc::UnboundedSender<SpircCommand>,
+    shared_state: Arc<Mutex<SharedState>>,
 }
 
 fn initial_state() -> State {
@@ -102,10 +173,10 @@ fn initial_state() -> State {
     frame
 }
 
-fn initial_device_state(config: ConnectConfig) -> DeviceState {
+fn initial_device_state(config: ConnectConfig, sw_version: &str) -> DeviceState {
     {
         let mut msg = DeviceState::new();
-        msg.set_sw_version(version::VERSION_STRING.to_string());
+        msg.set_sw_version(sw_version.to_string());
         msg.set_is_active(false);
         msg.set_can_play(true);
         msg.set_volume(0);
@@ -144,7 +215,7 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
                 msg.set_typ(protocol::spirc::CapabilityType::kSupportsLogout);
                 {
                     let repeated = msg.mut_intValue();
-                    repeated.push(0)
+                    repeated.push(config.capabilities.logout as i64)
                 };
                 msg
             };
@@ -162,10 +233,11 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
                 msg.set_typ(protocol::spirc::CapabilityType::kVolumeSteps);
                 {
                     let repeated = msg.mut_intValue();
-                    if let VolumeCtrl::Fixed = config.volume_ctrl {
+                    let fixed = matches!(config.volume_ctrl, VolumeCtrl::Fixed);
+                    if fixed || !config.capabilities.volume_control {
                         repeated.push(0)
                     } else {
-                        repeated.push(64)
+                        repeated.push(config.volume_steps as i64)
                     }
                 };
                 msg
@@ -209,6 +281,11 @@ fn initial_device_state(config: ConnectConfig) -> DeviceState {
                 msg
             };
         };
+        {
+            let entry = msg.mut_metadata().push_default();
+            entry.set_field_type(GAPLESS_METADATA.to_owned());
+            entry.set_metadata((config.capabilities.gapless as u8).to_string());
+        }
         msg
     }
 }
@@ -235,25 +312,49 @@ fn calc_logarithmic_volume(volume: u16) -> u16 {
     val
 }
 
-fn volume_to_mixer(volume: u16, volume_ctrl: &VolumeCtrl) -> u16 {
-    match volume_ctrl {
+fn volume_to_mixer(volume: u16, volume_ctrl: &VolumeCtrl, (min, max): (u16, u16)) -> u16 {
+    let volume = match volume_ctrl {
         VolumeCtrl::Linear => volume,
         VolumeCtrl::Log => calc_logarithmic_volume(volume),
         VolumeCtrl::Fixed => volume,
-    }
+    };
+
+    // Scale into the mixer volume range.
+    let (min, max) = (min as u32, max.max(min) as u32);
+    (min + volume as u32 * (max - min) / 0xFFFF) as u16
 }
 
 fn url_encode(bytes: impl AsRef<[u8]>) -> String {
     form_urlencoded::byte_serialize(bytes.as_ref()).collect()
 }
 
+fn queued_tracks(tracks: &[TrackRef]) -> Vec<QueuedTrack> {
+    tracks
+        .iter()
+        .enumerate()
+        .filter_map(|(index, track_ref)| {
+            let track_id = SpotifyId::from_raw(track_ref.get_gid())
+                .or_else(|_| SpotifyId::from_uri(track_ref.get_uri()))
+                .ok()?;
+            Some(QueuedTrack {
+                index,
+                track_id,
+                queued: track_ref.get_queued(),
+            })
+        })
+        .collect()
+}
+
 impl Spirc {
+    /// Creates the Connect device of `session` and the task that runs it. The task resolves
+    /// to the player and the mixer once it ends, so that the device of the next session can
+    /// use them without opening the sink again.
     pub fn new(
         config: ConnectConfig,
         session: Session,
         player: Player,
         mixer: Box<dyn Mixer>,
-    ) -> (Spirc, impl Future<Output = ()>) {
+    ) -> (Spirc, impl Future<Output = (Player, Box<dyn Mixer>)>) {
         debug!("new Spirc[{}]", session.session_id());
 
         let ident = session.device_id().to_owned();
@@ -265,14 +366,8 @@ impl Spirc {
         let subscription = Box::pin(
             session
                 .mercury()
-                .subscribe(uri.clone())
-                .map(Result::unwrap)
-                .map(UnboundedReceiverStream::new)
-                .flatten_stream()
-                .map(|response| -> Frame {
-                    let data = response.payload.first().unwrap();
-                    protobuf::parse_from_bytes(data).unwrap()
-                }),
+                .subscribe::<Frame, _>(uri.clone())
+                .map(|update| update.message),
         );
 
         let sender = session.mercury().sender(uri);
@@ -282,12 +377,18 @@ impl Spirc {
         let volume = config.volume;
         let task_config = SpircTaskConfig {
             volume_ctrl: config.volume_ctrl.to_owned(),
+            volume_steps: config.volume_steps,
+            mixer_volume_range: config.mixer_volume_range,
             autoplay: config.autoplay,
+            capabilities: config.capabilities.clone(),
         };
 
-        let device = initial_device_state(config);
+        let passive = config.passive;
+        let playback_moved_policy = config.playback_moved_policy;
+        let device = initial_device_state(config, session.version_string());
 
         let player_events = player.get_player_event_channel();
+        let shared_state = Arc::new(Mutex::new(SharedState::default()));
 
         let mut task = SpircTask {
             player,
@@ -301,6 +402,7 @@ impl Spirc {
             device,
             state: initial_state(),
             play_request_id: None,
+            playing_track: None,
             mixer_started: false,
             play_status: SpircPlayStatus::Stopped,
 
@@ -314,12 +416,23 @@ impl Spirc {
 
             context_fut: Box::pin(future::pending()),
             autoplay_fut: Box::pin(future::pending()),
+            load_context_fut: Box::pin(future::pending()),
             context: None,
+
+            remote_device_name: None,
+            remote_state: None,
+            shared_state: shared_state.clone(),
+            passive,
+            playback_moved_policy,
+            reclaim_fut: Box::pin(future::pending()),
         };
 
         task.set_volume(volume);
 
-        let spirc = Spirc { commands: cmd_tx };
+        let spirc = Spirc {
+            commands: cmd_tx,
+            shared_state,
+        };
 
         task.hello();
 
@@ -347,13 +460,75 @@ impl Spirc {
     pub fn volume_down(&self) {
         let _ = self.commands.send(SpircCommand::VolumeDown);
     }
+    pub fn seek(&self, position_ms: u32) {
+        let _ = self.commands.send(SpircCommand::Seek(position_ms));
+    }
+    pub fn set_volume(&self, volume: u16) {
+        let _ = self.commands.send(SpircCommand::SetVolume(volume));
+    }
+    /// Starts playing the track of the queue with the given index, see `queue()`, instead of
+    /// skipping to it track by track. While another device plays, it is asked to jump.
+    pub fn jump_to_index(&self, index: u32) {
+        let _ = self.commands.send(SpircCommand::JumpToIndex(index));
+    }
+    /// Starts playing a context, e.g. a playlist, album, artist or show, at the track with the
+    /// given index. This makes the device active without a Connect client.
+    pub fn load_context(&self, uri: &str, start_index: u32, position_ms: u32) {
+        let _ = self.commands.send(SpircCommand::LoadContext {
+            uri: uri.to_owned(),
+            start_index,
+            position_ms,
+        });
+    }
+    /// Plays at `bitrate` from now on, continuing the current track at the current position,
+    /// and reports it to Connect clients in the metadata of the device.
+    pub fn set_bitrate(&self, bitrate: Bitrate) {
+        let _ = self.commands.send(SpircCommand::SetBitrate(bitrate));
+    }
+    /// Downloads the next `count` tracks of the queue into the cache, see `Player::precache`.
+    pub fn precache_next(&self, count: usize) {
+        let _ = self.commands.send(SpircCommand::PrecacheNext(count));
+    }
+    /// Whether clients can transfer playback to the device, see `ConnectConfig::passive`.
+    /// Playback that was already transferred continues.
+    pub fn set_passive(&self, passive: bool) {
+        let _ = self.commands.send(SpircCommand::SetPassive(passive));
+    }
     pub fn shutdown(&self) {
         let _ = self.commands.send(SpircCommand::Shutdown);
     }
+
+    /// The URI of the context being played, e.g. of a playlist or an album. Empty if nothing
+    /// was loaded.
+    pub fn context_uri(&self) -> String {
+        self.shared_state.lock().unwrap().context_uri.clone()
+    }
+
+    /// The tracks of the queue, including the ones already played.
+    pub fn queue(&self) -> Vec<QueuedTrack> {
+        self.shared_state.lock().unwrap().queue.clone()
+    }
+
+    /// The position of the current track in the queue.
+    pub fn playing_track_index(&self) -> usize {
+        self.shared_state.lock().unwrap().playing_track_index
+    }
+
+    /// The name of the device that started the playback on this device. While the device is
+    /// passive and inactive, this and the context and queue are those of the active device.
+    pub fn remote_device_name(&self) -> Option<String> {
+        self.shared_state.lock().unwrap().remote_device_name.clone()
+    }
+
+    /// Whether a Connect client logged the device out, which shut down the Spirc task.
+    pub fn logged_out(&self) -> bool {
+        self.shared_state.lock().unwrap().logged_out
+    }
 }
 
 impl SpircTask {
-    async fn run(mut self) {
+    async fn run(mut self) -> (Player, Box<dyn Mixer>) {
+        let mut position_resync = tokio::time::interval(POSITION_RESYNC_INTERVAL);
         while !self.session.is_invalid() && !self.shutdown {
             let commands = self.commands.as_mut();
             let player_events = self.player_events.as_mut();
@@ -371,6 +546,10 @@ impl SpircTask {
                 event = async { player_events.unwrap().recv().await }, if player_events.is_some() => if let Some(event) = event {
                     self.handle_player_event(event)
                 },
+                _ = position_resync.tick() => self.resync_position(),
+                position_ms = &mut self.reclaim_fut, if !self.reclaim_fut.is_terminated() => {
+                    self.reclaim_playback(position_ms)
+                },
                 result = self.sender.flush(), if !self.sender.is_flushed() => if result.is_err() {
                     error!("Cannot flush spirc event sender.");
                     break;
@@ -415,6 +594,12 @@ impl SpircTask {
                         }
                     }
                 },
+                loaded = &mut self.load_context_fut, if !self.load_context_fut.is_terminated() => {
+                    match loaded {
+                        Ok(context) => self.handle_context_loaded(context),
+                        Err(err) => error!("Unable to load context: {}", err),
+                    }
+                },
                 else => break
             }
         }
@@ -422,6 +607,14 @@ impl SpircTask {
         if self.sender.flush().await.is_err() {
             warn!("Cannot flush spirc event sender.");
         }
+
+        // The player outlives the task, nothing of this session may keep playing.
+        self.player.stop();
+        self.ensure_mixer_stopped();
+
+        debug!("drop Spirc[{}]", self.session.session_id());
+        let SpircTask { player, mixer, .. } = self;
+        (player, mixer)
     }
 
     fn now_ms(&mut self) -> i64 {
@@ -453,6 +646,16 @@ impl SpircTask {
         self.state.set_position_ms(position_ms);
     }
 
+    fn resync_position(&mut self) {
+        if let SpircPlayStatus::Playing { .. } = self.play_status {
+            if self.device.get_is_active() {
+                let position_ms = self.position();
+                self.update_state_position(position_ms);
+                self.notify(None, true);
+            }
+        }
+    }
+
     fn handle_command(&mut self, cmd: SpircCommand) {
         let active = self.device.get_is_active();
         match cmd {
@@ -490,7 +693,7 @@ impl SpircTask {
             }
             SpircCommand::Next => {
                 if active {
-                    self.handle_next();
+                    self.handle_next(TrackTransitionReason::Skipped);
                     self.notify(None, true);
                 } else {
                     CommandSender::new(self, MessageType::kMessageTypeNext).send();
@@ -512,13 +715,102 @@ impl SpircTask {
                     CommandSender::new(self, MessageType::kMessageTypeVolumeDown).send();
                 }
             }
-            SpircCommand::Shutdown => {
-                CommandSender::new(self, MessageType::kMessageTypeGoodbye).send();
-                self.shutdown = true;
-                if let Some(rx) = self.commands.as_mut() {
-                    rx.close()
+            SpircCommand::Seek(position_ms) => {
+                if active {
+                    self.handle_seek(position_ms);
+                    self.notify(None, true);
+                } else {
+                    CommandSender::new(self, MessageType::kMessageTypeSeek)
+                        .position(position_ms)
+                        .send();
+                }
+            }
+            SpircCommand::JumpToIndex(index) => {
+                if active {
+                    self.handle_jump_to_index(index);
+                    self.notify(None, true);
+                } else {
+                    self.send_jump_to_index(index);
+                }
+            }
+            SpircCommand::SetVolume(volume) => {
+                if active {
+                    self.set_volume(volume);
+                    self.notify(None, true);
+                } else {
+                    CommandSender::new(self, MessageType::kMessageTypeVolume)
+                        .volume(volume)
+                        .send();
                 }
             }
+            SpircCommand::LoadContext {
+                uri,
+                start_index,
+                position_ms,
+            } => {
+                self.load_context_fut = self.resolve_context(uri, start_index, position_ms);
+            }
+            SpircCommand::SetBitrate(bitrate) => {
+                self.player.reload_current(bitrate);
+                self.set_audio_quality(bitrate);
+                if active {
+                    self.notify(None, true);
+                }
+            }
+            SpircCommand::PrecacheNext(count) => {
+                for track_id in self.upcoming_tracks(count) {
+                    self.player.precache(track_id);
+                }
+            }
+            SpircCommand::SetPassive(passive) => self.passive = passive,
+            SpircCommand::Shutdown => self.handle_shutdown(),
+        }
+    }
+
+    // The next playable tracks of the queue, from the start again with repeat.
+    fn upcoming_tracks(&self, count: usize) -> Vec<SpotifyId> {
+        let tracks_len = self.state.get_track().len() as u32;
+        let playing_index = self.state.get_playing_track_index();
+
+        let mut tracks = Vec::new();
+        if tracks_len == 0 {
+            return tracks;
+        }
+        let mut index = playing_index + 1;
+        while tracks.len() < count && (index < tracks_len || self.state.get_repeat()) {
+            let (track_id, found_index) = match self.get_track_id_to_play_from_playlist(index) {
+                Some(track) => track,
+                None => break,
+            };
+            // The search wraps around at the end of the queue.
+            let wrapped = found_index < index % tracks_len;
+            if found_index == playing_index || (wrapped && !self.state.get_repeat()) {
+                break;
+            }
+            tracks.push(track_id);
+            index = found_index + 1;
+        }
+        tracks
+    }
+
+    fn set_audio_quality(&mut self, bitrate: Bitrate) {
+        let metadata = self.device.mut_metadata();
+        let index = metadata
+            .iter()
+            .position(|entry| entry.get_field_type() == AUDIO_QUALITY_METADATA);
+        let entry = match index {
+            Some(index) => &mut metadata[index],
+            None => metadata.push_default(),
+        };
+        entry.set_field_type(AUDIO_QUALITY_METADATA.to_owned());
+        entry.set_metadata(bitrate.kbps().to_string());
+    }
+
+    fn handle_shutdown(&mut self) {
+        CommandSender::new(self, MessageType::kMessageTypeGoodbye).send();
+        self.shutdown = true;
+        if let Some(rx) = self.commands.as_mut() {
+            rx.close()
         }
     }
 
@@ -592,6 +884,7 @@ impl SpircTask {
                         SpircPlayStatus::Stopped => (),
                         _ => {
                             warn!("The player has stopped unexpectedly.");
+                            self.track_transition(None, TrackTransitionReason::Error);
                             self.state.set_status(PlayStatus::kPlayStatusStop);
                             self.ensure_mixer_stopped();
                             self.notify(None, true);
@@ -600,6 +893,10 @@ impl SpircTask {
                     },
                     PlayerEvent::TimeToPreloadNextTrack { .. } => self.handle_preload_next_track(),
                     PlayerEvent::Unavailable { track_id, .. } => self.handle_unavailable(track_id),
+                    PlayerEvent::OutputChanged { .. }
+                    | PlayerEvent::SinkStalled { paused: true, .. } => {
+                        self.handle_paused_by_player()
+                    }
                     _ => (),
                 }
             }
@@ -635,21 +932,56 @@ impl SpircTask {
                 self.notify(Some(frame.get_ident()), true);
             }
 
+            MessageType::kMessageTypeLoad if self.passive && !self.device.get_is_active() => {
+                info!(
+                    "Not taking over playback from {:?}, the device is passive",
+                    frame.get_device_state().get_name()
+                );
+                // Reset the state the client assumed.
+                self.notify(None, true);
+            }
+
+            // Clients jump to a track of the queue by loading it with another track playing.
+            // Keep the context instead of resolving it again.
+            MessageType::kMessageTypeLoad
+                if self.device.get_is_active() && self.is_jump(frame.get_state()) =>
+            {
+                let state = frame.get_state();
+                self.state
+                    .set_playing_track_index(state.get_playing_track_index());
+                self.load_track(
+                    state.get_status() == PlayStatus::kPlayStatusPlay,
+                    state.get_position_ms(),
+                    TrackTransitionReason::Skipped,
+                );
+                self.notify(None, true);
+            }
+
             MessageType::kMessageTypeLoad => {
+                self.remote_state = None;
                 if !self.device.get_is_active() {
                     let now = self.now_ms();
                     self.device.set_is_active(true);
                     self.device.set_became_active_at(now);
                 }
 
+                let remote_device_name = frame.get_device_state().get_name();
+                if !remote_device_name.is_empty() {
+                    self.remote_device_name = Some(remote_device_name.to_owned());
+                }
                 self.update_tracks(&frame);
 
                 if !self.state.get_track().is_empty() {
                     let start_playing =
                         frame.get_state().get_status() == PlayStatus::kPlayStatusPlay;
-                    self.load_track(start_playing, frame.get_state().get_position_ms());
+                    self.load_track(
+                        start_playing,
+                        frame.get_state().get_position_ms(),
+                        TrackTransitionReason::RemoteChange,
+                    );
                 } else {
                     info!("No more tracks left in queue");
+                    self.track_transition(None, TrackTransitionReason::RemoteChange);
                     self.state.set_status(PlayStatus::kPlayStatusStop);
                     self.player.stop();
                     self.mixer.stop();
@@ -675,7 +1007,7 @@ impl SpircTask {
             }
 
             MessageType::kMessageTypeNext => {
-                self.handle_next();
+                self.handle_next(TrackTransitionReason::Skipped);
                 self.notify(None, true);
             }
 
@@ -684,22 +1016,22 @@ impl SpircTask {
                 self.notify(None, true);
             }
 
-            MessageType::kMessageTypeVolumeUp => {
+            MessageType::kMessageTypeVolumeUp if self.config.capabilities.volume_control => {
                 self.handle_volume_up();
                 self.notify(None, true);
             }
 
-            MessageType::kMessageTypeVolumeDown => {
+            MessageType::kMessageTypeVolumeDown if self.config.capabilities.volume_control => {
                 self.handle_volume_down();
                 self.notify(None, true);
             }
 
-            MessageType::kMessageTypeRepeat => {
+            MessageType::kMessageTypeRepeat if self.config.capabilities.repeat => {
                 self.state.set_repeat(frame.get_state().get_repeat());
                 self.notify(None, true);
             }
 
-            MessageType::kMessageTypeShuffle => {
+            MessageType::kMessageTypeShuffle if self.config.capabilities.shuffle => {
                 self.state.set_shuffle(frame.get_state().get_shuffle());
                 if self.state.get_shuffle() {
                     let current_index = self.state.get_playing_track_index();
@@ -746,22 +1078,52 @@ impl SpircTask {
                 }
             }
 
-            MessageType::kMessageTypeVolume => {
+            MessageType::kMessageTypeVolume if self.config.capabilities.volume_control => {
                 self.set_volume(frame.get_volume() as u16);
                 self.notify(None, true);
             }
 
+            MessageType::kMessageTypeVolume
+            | MessageType::kMessageTypeVolumeUp
+            | MessageType::kMessageTypeVolumeDown
+            | MessageType::kMessageTypeRepeat
+            | MessageType::kMessageTypeShuffle => {
+                debug!("Ignoring {:?}, the control is disabled", frame.get_typ());
+                // Reset the state the client assumed.
+                self.notify(None, true);
+            }
+
+            MessageType::kMessageTypeLogout if self.config.capabilities.logout => {
+                info!("Logged out by {:?}", frame.get_device_state().get_name());
+                if let Some(cache) = self.session.cache() {
+                    if let Err(e) = cache.remove_credentials() {
+                        warn!("Cannot remove cached credentials: {}", e);
+                    }
+                }
+                self.shared_state.lock().unwrap().logged_out = true;
+                self.handle_shutdown();
+            }
+
             MessageType::kMessageTypeNotify => {
                 if self.device.get_is_active()
                     && frame.get_device_state().get_is_active()
                     && self.device.get_became_active_at()
                         <= frame.get_device_state().get_became_active_at()
                 {
-                    self.device.set_is_active(false);
-                    self.state.set_status(PlayStatus::kPlayStatusStop);
-                    self.player.stop();
-                    self.ensure_mixer_stopped();
-                    self.play_status = SpircPlayStatus::Stopped;
+                    self.handle_playback_moved(frame.get_device_state().get_name());
+                } else if !self.device.get_is_active() && frame.get_device_state().get_is_active() {
+                    self.remote_state =
+                        Some((frame.get_ident().to_owned(), frame.get_state().clone()));
+                    if self.passive {
+                        self.mirror_state(&frame);
+                    }
+                } else if self
+                    .remote_state
+                    .as_ref()
+                    .map_or(false, |(ident, _)| ident == frame.get_ident())
+                {
+                    // The device stopped playing.
+                    self.remote_state = None;
                 }
             }
 
@@ -769,6 +1131,58 @@ impl SpircTask {
         }
     }
 
+    // Another device of the account became active after this one.
+    fn handle_playback_moved(&mut self, device_name: &str) {
+        match self.playback_moved_policy {
+            PlaybackMovedPolicy::PauseLocal => self.yield_playback(),
+            PlaybackMovedPolicy::KeepPlaying => {
+                debug!("Keeping playback, ignoring {:?}", device_name);
+            }
+            PlaybackMovedPolicy::ReclaimAfterSeconds(seconds) => {
+                let reclaim = matches!(
+                    self.play_status,
+                    SpircPlayStatus::Playing { .. } | SpircPlayStatus::LoadingPlay { .. }
+                );
+                let position_ms = self.position();
+                self.yield_playback();
+                if reclaim {
+                    info!(
+                        "Taking playback back from {:?} in {} s",
+                        device_name, seconds
+                    );
+                    let delay = Duration::from_secs(seconds as u64);
+                    self.reclaim_fut =
+                        Box::pin(tokio::time::sleep(delay).map(move |_| position_ms).fuse());
+                }
+            }
+        }
+    }
+
+    fn yield_playback(&mut self) {
+        self.device.set_is_active(false);
+        self.track_transition(None, TrackTransitionReason::RemoteChange);
+        self.state.set_status(PlayStatus::kPlayStatusStop);
+        self.player.stop();
+        self.ensure_mixer_stopped();
+        self.play_status = SpircPlayStatus::Stopped;
+        self.remote_device_name = None;
+        self.update_shared_state();
+    }
+
+    // Resumes the track that played before another device took over, unless something was
+    // loaded onto this device since.
+    fn reclaim_playback(&mut self, position_ms: u32) {
+        if self.device.get_is_active() || self.state.get_track().is_empty() {
+            return;
+        }
+        info!("Taking playback back");
+        let now = self.now_ms();
+        self.device.set_is_active(true);
+        self.device.set_became_active_at(now);
+        self.load_track(true, position_ms, TrackTransitionReason::RemoteChange);
+        self.notify(None, true);
+    }
+
     fn handle_play(&mut self) {
         match self.play_status {
             SpircPlayStatus::Paused {
@@ -828,6 +1242,25 @@ impl SpircTask {
         }
     }
 
+    // The player paused by itself, because the output device changed or the sink stalled.
+    // The position is corrected by the "Paused" event that follows.
+    fn handle_paused_by_player(&mut self) {
+        if let SpircPlayStatus::Playing {
+            nominal_start_time,
+            preloading_of_next_track_triggered,
+        } = self.play_status
+        {
+            self.state.set_status(PlayStatus::kPlayStatusPause);
+            let position_ms = (self.now_ms() - nominal_start_time) as u32;
+            self.update_state_position(position_ms);
+            self.play_status = SpircPlayStatus::Paused {
+                position_ms,
+                preloading_of_next_track_triggered,
+            };
+            self.notify(None, true);
+        }
+    }
+
     fn handle_seek(&mut self, position_ms: u32) {
         self.update_state_position(position_ms);
         self.player.seek(position_ms);
@@ -914,7 +1347,7 @@ impl SpircTask {
         self.handle_preload_next_track();
     }
 
-    fn handle_next(&mut self) {
+    fn handle_next(&mut self, reason: TrackTransitionReason) {
         let mut new_index = self.consume_queued_track() as u32;
         let mut continue_playing = true;
         let tracks_len = self.state.get_track().len() as u32;
@@ -949,9 +1382,10 @@ impl SpircTask {
 
         if tracks_len > 0 {
             self.state.set_playing_track_index(new_index);
-            self.load_track(continue_playing, 0);
+            self.load_track(continue_playing, 0, reason);
         } else {
             info!("Not playing next track because there are no more tracks left in queue.");
+            self.track_transition(None, reason);
             self.state.set_playing_track_index(0);
             self.state.set_status(PlayStatus::kPlayStatusStop);
             self.player.stop();
@@ -993,14 +1427,64 @@ impl SpircTask {
 
             self.state.set_playing_track_index(new_index);
 
-            self.load_track(true, 0);
+            self.load_track(true, 0, TrackTransitionReason::Skipped);
         } else {
             self.handle_seek(0);
         }
     }
 
+    fn handle_jump_to_index(&mut self, index: u32) {
+        let tracks_len = self.state.get_track().len() as u32;
+        if index >= tracks_len {
+            warn!(
+                "Cannot jump to track {} of a queue of {} tracks",
+                index, tracks_len
+            );
+            return;
+        }
+        self.state.set_playing_track_index(index);
+        self.load_track(true, 0, TrackTransitionReason::Skipped);
+    }
+
+    fn send_jump_to_index(&mut self, index: u32) {
+        let (ident, mut state) = match self.remote_state.clone() {
+            Some(remote_state) => remote_state,
+            None => {
+                warn!("Cannot jump to track {}, no device is playing", index);
+                return;
+            }
+        };
+        let tracks_len = state.get_track().len() as u32;
+        if index >= tracks_len {
+            warn!(
+                "Cannot jump to track {} of a queue of {} tracks",
+                index, tracks_len
+            );
+            return;
+        }
+        state.set_playing_track_index(index);
+        state.set_position_ms(0);
+        state.set_status(PlayStatus::kPlayStatusPlay);
+        CommandSender::new(self, MessageType::kMessageTypeLoad)
+            .recipient(&ident)
+            .state(state)
+            .send();
+    }
+
+    // Whether a load is of the context and queue played, with another track of it playing.
+    fn is_jump(&self, state: &State) -> bool {
+        state.get_context_uri() == self.state.get_context_uri()
+            && state.get_track() == self.state.get_track()
+            && state.get_playing_track_index() != self.state.get_playing_track_index()
+            && state.get_playing_track_index() < self.state.get_track().len() as u32
+    }
+
+    fn volume_step(&self) -> u32 {
+        0x10000 / self.config.volume_steps.max(1) as u32
+    }
+
     fn handle_volume_up(&mut self) {
-        let mut volume: u32 = self.device.get_volume() as u32 + 4096;
+        let mut volume: u32 = self.device.get_volume() as u32 + self.volume_step();
         if volume > 0xFFFF {
             volume = 0xFFFF;
         }
@@ -1008,7 +1492,7 @@ impl SpircTask {
     }
 
     fn handle_volume_down(&mut self) {
-        let mut volume: i32 = self.device.get_volume() as i32 - 4096;
+        let mut volume: i32 = self.device.get_volume() as i32 - self.volume_step() as i32;
         if volume < 0 {
             volume = 0;
         }
@@ -1016,7 +1500,14 @@ impl SpircTask {
     }
 
     fn handle_end_of_track(&mut self) {
-        self.handle_next();
+        // The player also ends a track it failed to load.
+        let reason = match self.play_status {
+            SpircPlayStatus::LoadingPlay { .. } | SpircPlayStatus::LoadingPause { .. } => {
+                TrackTransitionReason::Error
+            }
+            _ => TrackTransitionReason::Finished,
+        };
+        self.handle_next(reason);
         self.notify(None, true);
     }
 
@@ -1055,7 +1546,7 @@ impl SpircTask {
                     Ok(autoplay_uri)
                 } else {
                     warn!("No autoplay_uri found");
-                    Err(MercuryError)
+                    Err(MercuryError::Failed)
                 }
             }
             .fuse(),
@@ -1081,6 +1572,94 @@ impl SpircTask {
         )
     }
 
+    fn resolve_context(
+        &self,
+        uri: String,
+        start_index: u32,
+        position_ms: u32,
+    ) -> BoxedFuture<Result<LoadedContext, Error>> {
+        let session = self.session.clone();
+
+        Box::pin(
+            async move {
+                // The id is the last part of the URI, also for e.g. spotify:user:<name>:playlist:<id>.
+                let parts: Vec<&str> = uri.rsplitn(3, ':').collect();
+                let invalid = || Error::Protocol(format!("invalid context URI {}", uri));
+                let (kind, id) = match parts[..] {
+                    [id, kind, _] => (kind, SpotifyId::from_base62(id).map_err(|_| invalid())?),
+                    _ => return Err(invalid()),
+                };
+
+                let tracks = match kind {
+                    "track" | "episode" => vec![SpotifyId::from_uri(&uri)?],
+                    "album" => Album::get(&session, id).await?.tracks,
+                    "playlist" => Playlist::get(&session, id).await?.tracks,
+                    "artist" => Artist::get(&session, id).await?.top_tracks,
+                    "show" => Show::get(&session, id)
+                        .await?
+                        .episodes
+                        .into_iter()
+                        .map(|episode| SpotifyId {
+                            audio_type: SpotifyAudioType::Podcast,
+                            ..episode
+                        })
+                        .collect(),
+                    _ => return Err(invalid()),
+                };
+
+                Ok(LoadedContext {
+                    uri,
+                    tracks,
+                    start_index,
+                    position_ms,
+                })
+            }
+            .fuse(),
+        )
+    }
+
+    fn handle_context_loaded(&mut self, context: LoadedContext) {
+        if context.tracks.is_empty() {
+            warn!("Context <{}> has no tracks", context.uri);
+            return;
+        }
+        info!(
+            "Loading {} tracks of <{}>",
+            context.tracks.len(),
+            context.uri
+        );
+
+        if !self.device.get_is_active() {
+            let now = self.now_ms();
+            self.device.set_is_active(true);
+            self.device.set_became_active_at(now);
+        }
+
+        // Load it the same way as a context sent by a Connect client.
+        let mut frame = Frame::new();
+        {
+            let state = frame.mut_state();
+            state.set_context_uri(context.uri);
+            state.set_playing_track_index(context.start_index.min(context.tracks.len() as u32 - 1));
+            for track_id in context.tracks {
+                let mut track_ref = TrackRef::new();
+                track_ref.set_gid(track_id.to_raw().to_vec());
+                track_ref.set_uri(track_id.to_uri());
+                state.mut_track().push(track_ref);
+            }
+        }
+
+        self.context = None;
+        self.remote_device_name = None;
+        self.update_tracks(&frame);
+        self.load_track(
+            true,
+            context.position_ms,
+            TrackTransitionReason::RemoteChange,
+        );
+        self.notify(None, true);
+    }
+
     fn update_tracks_from_context(&mut self) {
         if let Some(ref context) = self.context {
             self.context_fut = self.resolve_uri(&context.next_page_url);
@@ -1215,12 +1794,13 @@ impl SpircTask {
         }
     }
 
-    fn load_track(&mut self, start_playing: bool, position_ms: u32) {
+    fn load_track(&mut self, start_playing: bool, position_ms: u32, reason: TrackTransitionReason) {
         let index = self.state.get_playing_track_index();
 
         match self.get_track_id_to_play_from_playlist(index) {
             Some((track, index)) => {
                 self.state.set_playing_track_index(index);
+                self.track_transition(Some(track), reason);
 
                 self.play_request_id = Some(self.player.load(track, start_playing, position_ms));
 
@@ -1234,6 +1814,7 @@ impl SpircTask {
                 }
             }
             None => {
+                self.track_transition(None, reason);
                 self.state.set_status(PlayStatus::kPlayStatusStop);
                 self.player.stop();
                 self.ensure_mixer_stopped();
@@ -1242,6 +1823,13 @@ impl SpircTask {
         }
     }
 
+    // Reports that playback moves on from the playing track, if there is one, to `to`.
+    fn track_transition(&mut self, to: Option<SpotifyId>, reason: TrackTransitionReason) {
+        if let Some(from) = std::mem::replace(&mut self.playing_track, to) {
+            self.player.emit_track_transition_event(from, to, reason);
+        }
+    }
+
     fn hello(&mut self) {
         CommandSender::new(self, MessageType::kMessageTypeHello).send();
     }
@@ -1257,6 +1845,7 @@ impl SpircTask {
             PlayStatus::kPlayStatusPlay => "kPlayStatusPlay",
         };
         trace!("Sending status to server: [{}]", status_string);
+        self.update_shared_state();
         let mut cs = CommandSender::new(self, MessageType::kMessageTypeNotify);
         if let Some(s) = recipient {
             cs = cs.recipient(&s);
@@ -1264,10 +1853,32 @@ impl SpircTask {
         cs.send();
     }
 
+    fn update_shared_state(&self) {
+        let mut shared_state = self.shared_state.lock().unwrap();
+        shared_state.context_uri = self.state.get_context_uri().to_owned();
+        shared_state.queue = queued_tracks(self.state.get_track());
+        shared_state.playing_track_index = self.state.get_playing_track_index() as usize;
+        shared_state.remote_device_name = self.remote_device_name.clone();
+    }
+
+    // Shows the state of the active device through the Spirc handle, while the device is
+    // passive.
+    fn mirror_state(&self, frame: &Frame) {
+        let state = frame.get_state();
+        let mut shared_state = self.shared_state.lock().unwrap();
+        shared_state.context_uri = state.get_context_uri().to_owned();
+        shared_state.queue = queued_tracks(state.get_track());
+        shared_state.playing_track_index = state.get_playing_track_index() as usize;
+        shared_state.remote_device_name = Some(frame.get_device_state().get_name().to_owned());
+    }
+
     fn set_volume(&mut self, volume: u16) {
         self.device.set_volume(volume as u32);
-        self.mixer
-            .set_volume(volume_to_mixer(volume, &self.config.volume_ctrl));
+        self.mixer.set_volume(volume_to_mixer(
+            volume,
+            &self.config.volume_ctrl,
+            self.config.mixer_volume_range,
+        ));
         if let Some(cache) = self.session.cache() {
             cache.save_volume(volume)
         }
@@ -1275,12 +1886,6 @@ impl SpircTask {
     }
 }
 
-impl Drop for SpircTask {
-    fn drop(&mut self) {
-        debug!("drop Spirc[{}]", self.session.session_id());
-    }
-}
-
 struct CommandSender<'a> {
     spirc: &'a mut SpircTask,
     frame: protocol::spirc::Frame,
@@ -1304,7 +1909,16 @@ impl<'a> CommandSender<'a> {
         self
     }
 
-    #[allow(dead_code)]
+    fn position(mut self, position_ms: u32) -> CommandSender<'a> {
+        self.frame.set_position(position_ms);
+        self
+    }
+
+    fn volume(mut self, volume: u16) -> CommandSender<'a> {
+        self.frame.set_volume(volume as u32);
+        self
+    }
+
     fn state(mut self, state: protocol::spirc::State) -> CommandSender<'a> {
         self.frame.set_state(state);
         self
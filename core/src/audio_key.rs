@@ -1,9 +1,10 @@
-use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
 use tokio::sync::oneshot;
 
+use crate::packet::PacketBuilder;
 use crate::spotify_id::{FileId, SpotifyId};
 use crate::util::SeqGenerator;
 
@@ -13,10 +14,40 @@ pub struct AudioKey(pub [u8; 16]);
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone)]
 pub struct AudioKeyError;
 
+// The number of keys kept in memory.
+const KEY_CACHE_SIZE: usize = 256;
+
+/// The keys of the files played last, so that playing them again needs no round trip. The
+/// keys are also saved next to the cached audio files they decrypt, which outlasts a restart.
+#[derive(Default)]
+struct KeyCache {
+    // The least recently used key first.
+    keys: VecDeque<((SpotifyId, FileId), AudioKey)>,
+}
+
+impl KeyCache {
+    fn get(&mut self, id: (SpotifyId, FileId)) -> Option<AudioKey> {
+        let index = self.keys.iter().position(|(key_id, _)| *key_id == id)?;
+        let entry = self.keys.remove(index)?;
+        self.keys.push_back(entry);
+        Some(entry.1)
+    }
+
+    fn insert(&mut self, id: (SpotifyId, FileId), key: AudioKey) {
+        if let Some(index) = self.keys.iter().position(|(key_id, _)| *key_id == id) {
+            self.keys.remove(index);
+        } else if self.keys.len() >= KEY_CACHE_SIZE {
+            self.keys.pop_front();
+        }
+        self.keys.push_back((id, key));
+    }
+}
+
 component! {
     AudioKeyManager : AudioKeyManagerInner {
         sequence: SeqGenerator<u32> = SeqGenerator::new(0),
         pending: HashMap<u32, oneshot::Sender<Result<AudioKey, AudioKeyError>>> = HashMap::new(),
+        keys: KeyCache = KeyCache::default(),
     }
 }
 
@@ -46,7 +77,57 @@ impl AudioKeyManager {
         }
     }
 
+    /// Requests the key of an audio file. Keys that were requested before are answered from
+    /// memory or the cache without a round trip.
     pub async fn request(&self, track: SpotifyId, file: FileId) -> Result<AudioKey, AudioKeyError> {
+        if let Some(key) = self.cached(track, file) {
+            return Ok(key);
+        }
+
+        let key = self.request_uncached(track, file).await?;
+        self.lock(|inner| inner.keys.insert((track, file), key));
+        if let Some(cache) = self.session().cache() {
+            cache.save_audio_key(track, file, key);
+        }
+        Ok(key)
+    }
+
+    /// Requests the key of an audio file in the background, so that a later `request` for it
+    /// completes immediately.
+    pub fn prefetch(&self, track: SpotifyId, file: FileId) {
+        if self.cached(track, file).is_some() {
+            return;
+        }
+
+        let session = self.session();
+        self.session().spawn(async move {
+            if session.audio_key().request(track, file).await.is_err() {
+                debug!("Unable to prefetch audio key of <{}>", track.to_uri());
+            }
+        });
+    }
+
+    fn cached(&self, track: SpotifyId, file: FileId) -> Option<AudioKey> {
+        if let Some(key) = self.lock(|inner| inner.keys.get((track, file))) {
+            return Some(key);
+        }
+
+        let key = self.session().cache()?.audio_key(track, file)?;
+        self.lock(|inner| inner.keys.insert((track, file), key));
+        Some(key)
+    }
+
+    async fn request_uncached(
+        &self,
+        track: SpotifyId,
+        file: FileId,
+    ) -> Result<AudioKey, AudioKeyError> {
+        let delay = self.session().throttle().reserve();
+        if delay > Duration::default() {
+            let sleep = self.session().sleep(delay);
+            sleep.await;
+        }
+
         let (tx, rx) = oneshot::channel();
 
         let seq = self.lock(move |inner| {
@@ -60,12 +141,38 @@ impl AudioKeyManager {
     }
 
     fn send_key_request(&self, seq: u32, track: SpotifyId, file: FileId) {
-        let mut data: Vec<u8> = Vec::new();
-        data.write(&file.0).unwrap();
-        data.write(&track.to_raw()).unwrap();
-        data.write_u32::<BigEndian>(seq).unwrap();
-        data.write_u16::<BigEndian>(0x0000).unwrap();
+        let data = PacketBuilder::new()
+            .bytes(&file.0)
+            .bytes(&track.to_raw())
+            .u32(seq)
+            .u16(0x0000)
+            .finish();
+
+        self.session().send_packet_bytes(0xc, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: usize) -> (SpotifyId, FileId) {
+        let mut file = [0; 20];
+        file[..8].copy_from_slice(&(n as u64).to_be_bytes());
+        (SpotifyId::from_raw(&[0; 16]).unwrap(), FileId(file))
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_key() {
+        let mut cache = KeyCache::default();
+        for n in 0..KEY_CACHE_SIZE {
+            cache.insert(id(n), AudioKey([n as u8; 16]));
+        }
+        assert_eq!(cache.get(id(0)), Some(AudioKey([0; 16])));
 
-        self.session().send_packet(0xc, data)
+        cache.insert(id(KEY_CACHE_SIZE), AudioKey([0xff; 16]));
+        assert_eq!(cache.keys.len(), KEY_CACHE_SIZE);
+        assert_eq!(cache.get(id(0)), Some(AudioKey([0; 16])));
+        assert_eq!(cache.get(id(1)), None);
     }
 }
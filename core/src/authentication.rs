@@ -7,9 +7,18 @@ use pbkdf2::pbkdf2;
 use protobuf::ProtobufEnum;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use thiserror::Error;
 
 use crate::protocol::authentication::AuthenticationType;
 
+#[derive(Debug, Error)]
+pub enum CredentialsImportError {
+    #[error("credentials are not valid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("invalid credentials: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
 /// The credentials are used to log into the Spotify API.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Credentials {
@@ -42,6 +51,17 @@ impl Credentials {
         }
     }
 
+    /// Intialize these credentials from an OAuth access token with the `streaming` scope.
+    /// The username is filled in by the server, the session's reusable credentials
+    /// hold the actual username.
+    pub fn with_access_token(token: impl Into<String>) -> Credentials {
+        Credentials {
+            username: String::new(),
+            auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+            auth_data: token.into().into_bytes(),
+        }
+    }
+
     pub fn with_blob(username: String, encrypted_blob: &str, device_id: &str) -> Credentials {
         fn read_u8<R: Read>(stream: &mut R) -> io::Result<u8> {
             let mut data = [0u8];
@@ -117,6 +137,20 @@ impl Credentials {
             auth_data,
         }
     }
+
+    /// Encodes these credentials as a single printable string, e.g. to authenticate
+    /// other instances with the reusable credentials obtained by one of them.
+    ///
+    /// Anyone holding the string can log into the account, so treat it like a password.
+    pub fn export(&self) -> String {
+        base64::encode(serde_json::to_vec(self).expect("Credentials can be serialized"))
+    }
+
+    /// Restores credentials previously encoded with [`Credentials::export`].
+    pub fn import(exported: &str) -> Result<Credentials, CredentialsImportError> {
+        let data = base64::decode(exported.trim())?;
+        Ok(serde_json::from_slice(&data)?)
+    }
 }
 
 fn serialize_protobuf_enum<T, S>(v: &T, ser: S) -> Result<S::Ok, S::Error>
@@ -1,20 +1,78 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs;
 use std::fs::File;
-use std::io::{self, Error, ErrorKind, Read, Write};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
+use tokio::sync::watch;
+
+use crate::audio_key::AudioKey;
 use crate::authentication::Credentials;
-use crate::spotify_id::FileId;
+use crate::cache_backend::{is_key_file, key_file_path, CacheBackend, CacheKey, FileBackend};
+use crate::credentials_store::CredentialsStore;
+use crate::spotify_id::{FileId, SpotifyId};
+
+/// An audio file in the cache.
+#[derive(Debug, Clone)]
+pub struct CachedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl CachedFile {
+    /// The time since the file was written to the cache.
+    pub fn age(&self) -> Duration {
+        self.modified.elapsed().unwrap_or_default()
+    }
+}
+
+// The downloads of an audio file to the cache. The channel closes when the last one ends.
+struct Downloads {
+    count: usize,
+    _ended_tx: watch::Sender<()>,
+    ended_rx: watch::Receiver<()>,
+}
+
+/// Marks an audio file as being downloaded to the cache, until it is dropped. See
+/// `Cache::ensure_cached`.
+pub struct CacheDownload {
+    downloads: Arc<Mutex<HashMap<FileId, Downloads>>>,
+    file: FileId,
+}
+
+impl Drop for CacheDownload {
+    fn drop(&mut self) {
+        let mut downloads = self.downloads.lock().unwrap();
+        let ended = match downloads.get_mut(&self.file) {
+            Some(file_downloads) => {
+                file_downloads.count -= 1;
+                file_downloads.count == 0
+            }
+            None => false,
+        };
+        if ended {
+            downloads.remove(&self.file);
+        }
+    }
+}
 
-/// A cache for volume, credentials and audio files.
+/// A cache for volume, credentials, the device id, audio keys, playback positions and audio
+/// files.
 #[derive(Clone)]
 pub struct Cache {
-    credentials_location: Option<PathBuf>,
-    volume_location: Option<PathBuf>,
+    backend: Arc<dyn CacheBackend>,
+    credentials_store: Option<Arc<dyn CredentialsStore>>,
     audio_location: Option<PathBuf>,
+    downloads: Arc<Mutex<HashMap<FileId, Downloads>>>,
 }
 
 impl Cache {
+    /// Creates a cache in directories, with a `FileBackend`.
     pub fn new<P: AsRef<Path>>(
         system_location: Option<P>,
         audio_location: Option<P>,
@@ -27,152 +85,383 @@ impl Cache {
             fs::create_dir_all(location)?;
         }
 
+        let system_location = system_location.map(|p| p.as_ref().to_owned());
         let audio_location = audio_location.map(|p| p.as_ref().to_owned());
-        let volume_location = system_location.as_ref().map(|p| p.as_ref().join("volume"));
-        let credentials_location = system_location
-            .as_ref()
-            .map(|p| p.as_ref().join("credentials.json"));
 
         let cache = Cache {
-            credentials_location,
-            volume_location,
+            backend: Arc::new(FileBackend::new(system_location, audio_location.clone())),
+            credentials_store: None,
             audio_location,
+            downloads: Arc::default(),
         };
 
         Ok(cache)
     }
 
-    pub fn credentials(&self) -> Option<Credentials> {
-        let location = self.credentials_location.as_ref()?;
-
-        // This closure is just convencience to enable the question mark operator
-        let read = || {
-            let mut file = File::open(location)?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))
-        };
+    /// Creates a cache that keeps its entries in `backend`, e.g. a database. The audio files
+    /// of such a cache can't be listed or pruned, its backend has to limit its size.
+    pub fn with_backend<B: CacheBackend>(backend: B) -> Self {
+        Cache {
+            backend: Arc::new(backend),
+            credentials_store: None,
+            audio_location: None,
+            downloads: Arc::default(),
+        }
+    }
 
-        match read() {
-            Ok(c) => Some(c),
+    /// Keeps the credentials in `store` instead of the backend, e.g. in the keyring of the
+    /// OS.
+    pub fn with_credentials_store<S: CredentialsStore>(mut self, store: S) -> Self {
+        self.credentials_store = Some(Arc::new(store));
+        self
+    }
+
+    // Reads an entry, logging errors other than a missing entry.
+    fn get(&self, key: CacheKey, what: &str) -> Option<Vec<u8>> {
+        match self.backend.get(&key) {
+            Ok(data) => data,
             Err(e) => {
-                // If the file did not exist, the file was probably not written
-                // before. Otherwise, log the error.
-                if e.kind() != ErrorKind::NotFound {
-                    warn!("Error reading credentials from cache: {}", e);
-                }
+                warn!("Error reading {} from cache: {}", what, e);
                 None
             }
         }
     }
 
-    pub fn save_credentials(&self, cred: &Credentials) {
-        if let Some(location) = &self.credentials_location {
-            let result = File::create(location).and_then(|mut file| {
-                let data = serde_json::to_string(cred)?;
-                write!(file, "{}", data)
-            });
-
-            if let Err(e) = result {
-                warn!("Cannot save credentials to cache: {}", e)
+    // Reads an entry that was saved with `Display`.
+    fn parse<T: FromStr>(&self, key: CacheKey, what: &str) -> Option<T> {
+        let data = self.get(key, what)?;
+        let parsed = str::from_utf8(&data)
+            .ok()
+            .and_then(|contents| contents.trim().parse().ok());
+        if parsed.is_none() {
+            warn!("Error reading {} from cache: invalid data", what);
+        }
+        parsed
+    }
+
+    fn put(&self, key: CacheKey, mut data: &[u8], what: &str) {
+        if let Err(e) = self.backend.put(&key, &mut data) {
+            warn!("Cannot save {} to cache: {}", what, e);
+        }
+    }
+
+    fn remove(&self, key: CacheKey, what: &str) -> bool {
+        match self.backend.remove(&key) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Cannot remove {} from cache: {}", what, e);
+                false
             }
         }
     }
 
-    pub fn volume(&self) -> Option<u16> {
-        let location = self.volume_location.as_ref()?;
-
-        let read = || {
-            let mut file = File::open(location)?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            contents
-                .parse()
-                .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    pub fn credentials(&self) -> Option<Credentials> {
+        let store = match &self.credentials_store {
+            Some(store) => store,
+            None => {
+                let data = self.get(CacheKey::Credentials, "credentials")?;
+                return match serde_json::from_slice(&data) {
+                    Ok(credentials) => Some(credentials),
+                    Err(e) => {
+                        warn!("Error reading credentials from cache: {}", e);
+                        None
+                    }
+                };
+            }
         };
 
-        match read() {
-            Ok(v) => Some(v),
+        match store.load() {
+            Ok(credentials) => credentials,
             Err(e) => {
-                if e.kind() != ErrorKind::NotFound {
-                    warn!("Error reading volume from cache: {}", e);
-                }
+                warn!("Error reading credentials from cache: {}", e);
                 None
             }
         }
     }
 
+    pub fn save_credentials(&self, cred: &Credentials) {
+        let result = match &self.credentials_store {
+            Some(store) => store.save(cred),
+            None => serde_json::to_vec(cred)
+                .map_err(io::Error::from)
+                .and_then(|data| {
+                    self.backend
+                        .put(&CacheKey::Credentials, &mut data.as_slice())
+                }),
+        };
+        if let Err(e) = result {
+            warn!("Cannot save credentials to cache: {}", e)
+        }
+    }
+
+    pub fn remove_credentials(&self) -> io::Result<()> {
+        match &self.credentials_store {
+            Some(store) => store.remove(),
+            None => self.backend.remove(&CacheKey::Credentials),
+        }
+    }
+
+    pub fn volume(&self) -> Option<u16> {
+        self.parse(CacheKey::Volume, "volume")
+    }
+
     pub fn save_volume(&self, volume: u16) {
-        if let Some(ref location) = self.volume_location {
-            let result = File::create(location).and_then(|mut file| write!(file, "{}", volume));
-            if let Err(e) = result {
-                warn!("Cannot save volume to cache: {}", e);
+        self.put(CacheKey::Volume, volume.to_string().as_bytes(), "volume");
+    }
+
+    pub fn device_id(&self) -> Option<String> {
+        let device_id: String = self.parse(CacheKey::DeviceId, "device id")?;
+        if device_id.is_empty() {
+            warn!("Error reading device id from cache: empty device id");
+            None
+        } else {
+            Some(device_id)
+        }
+    }
+
+    pub fn save_device_id(&self, device_id: &str) {
+        self.put(CacheKey::DeviceId, device_id.as_bytes(), "device id");
+    }
+
+    pub fn audio_key(&self, track: SpotifyId, file: FileId) -> Option<AudioKey> {
+        let data = self.get(CacheKey::AudioKey(track, file), "audio key")?;
+        match <[u8; 16]>::try_from(data.as_slice()) {
+            Ok(key) => Some(AudioKey(key)),
+            Err(_) => {
+                warn!("Error reading audio key from cache: invalid length");
+                None
             }
         }
     }
 
-    fn file_path(&self, file: FileId) -> Option<PathBuf> {
-        self.audio_location.as_ref().map(|location| {
-            let name = file.to_base16();
-            let mut path = location.join(&name[0..2]);
-            path.push(&name[2..]);
-            path
-        })
+    pub fn save_audio_key(&self, track: SpotifyId, file: FileId, key: AudioKey) {
+        self.put(CacheKey::AudioKey(track, file), &key.0, "audio key");
+    }
+
+    /// The saved playback position of a track or episode in milliseconds.
+    pub fn position(&self, id: SpotifyId) -> Option<u32> {
+        self.parse(CacheKey::Position(id), "playback position")
+    }
+
+    pub fn save_position(&self, id: SpotifyId, position_ms: u32) {
+        self.put(
+            CacheKey::Position(id),
+            position_ms.to_string().as_bytes(),
+            "playback position",
+        );
+    }
+
+    pub fn remove_position(&self, id: SpotifyId) {
+        self.remove(CacheKey::Position(id), "playback position");
+    }
+
+    /// The directory of the audio files, if they are cached in one.
+    pub fn audio_location(&self) -> Option<&Path> {
+        self.audio_location.as_deref()
     }
 
     pub fn file(&self, file: FileId) -> Option<File> {
-        File::open(self.file_path(file)?)
-            .map_err(|e| {
-                if e.kind() != ErrorKind::NotFound {
-                    warn!("Error reading file from cache: {}", e)
-                }
+        match self.backend.open(&CacheKey::AudioFile(file)) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Error reading file from cache: {}", e);
+                None
+            }
+        }
+    }
+
+    /// The path of a complete cached audio file, e.g. for a media server to serve it from
+    /// disk. `None` if it isn't cached or the backend doesn't keep it in a file.
+    pub fn audio_file_path(&self, file: FileId) -> Option<PathBuf> {
+        self.backend.file_path(&CacheKey::AudioFile(file))
+    }
+
+    /// Waits for the downloads of an audio file that are in progress to end, then returns
+    /// its path like `audio_file_path`. `None` if a download failed or none was started, a
+    /// player has to play the file to cache it.
+    pub async fn ensure_cached(&self, file: FileId) -> Option<PathBuf> {
+        let ended_rx = self
+            .downloads
+            .lock()
+            .unwrap()
+            .get(&file)
+            .map(|downloads| downloads.ended_rx.clone());
+        if let Some(mut ended_rx) = ended_rx {
+            // Nothing is sent, it fails once the channel is closed.
+            while ended_rx.changed().await.is_ok() {}
+        }
+        self.audio_file_path(file)
+    }
+
+    /// Marks an audio file as being downloaded until the returned value is dropped, which
+    /// should be after it is saved with `save_file`.
+    pub fn start_download(&self, file: FileId) -> CacheDownload {
+        let mut downloads = self.downloads.lock().unwrap();
+        let file_downloads = downloads.entry(file).or_insert_with(|| {
+            let (ended_tx, ended_rx) = watch::channel(());
+            Downloads {
+                count: 0,
+                _ended_tx: ended_tx,
+                ended_rx,
+            }
+        });
+        file_downloads.count += 1;
+        CacheDownload {
+            downloads: self.downloads.clone(),
+            file,
+        }
+    }
+
+    /// The size of a cached audio file in bytes, `None` if it isn't cached.
+    pub fn file_size(&self, file: FileId) -> Option<u64> {
+        self.backend
+            .len(&CacheKey::AudioFile(file))
+            .unwrap_or_else(|e| {
+                warn!("Error reading file from cache: {}", e);
+                None
             })
-            .ok()
     }
 
     pub fn save_file<F: Read>(&self, file: FileId, contents: &mut F) {
-        let path = if let Some(path) = self.file_path(file) {
-            path
-        } else {
-            return;
+        if let Err(e) = self.backend.put(&CacheKey::AudioFile(file), contents) {
+            warn!("Cannot save file to cache: {}", e)
+        }
+    }
+
+    pub fn remove_file(&self, file: FileId) -> bool {
+        self.remove(CacheKey::AudioFile(file), "file")
+    }
+
+    /// Lists the audio files in the audio cache directory, in no particular order. Empty for
+    /// caches without one.
+    pub fn audio_files(&self) -> io::Result<Vec<CachedFile>> {
+        let location = match &self.audio_location {
+            Some(location) => location,
+            None => return Ok(Vec::new()),
         };
-        let parent = path.parent().unwrap();
 
-        let result = fs::create_dir_all(parent)
-            .and_then(|_| File::create(&path))
-            .and_then(|mut file| io::copy(contents, &mut file));
+        let mut files = Vec::new();
+        for dir in fs::read_dir(location)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
 
-        if let Err(e) = result {
-            if e.kind() == ErrorKind::Other {
-                // Perhaps there's no space left in the cache
-                // TODO: try to narrow down the error (platform-dependently)
-                info!("An error occured while writing to cache, trying to flush the cache");
-
-                if fs::remove_dir_all(self.audio_location.as_ref().unwrap())
-                    .and_then(|_| fs::create_dir_all(parent))
-                    .and_then(|_| File::create(&path))
-                    .and_then(|mut file| io::copy(contents, &mut file))
-                    .is_ok()
-                {
-                    // It worked, there's no need to print a warning
-                    return;
+            for entry in fs::read_dir(dir.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                // The keys saved next to the files go along with them, see `prune`.
+                if metadata.is_file() && !is_key_file(&entry.path()) {
+                    files.push(CachedFile {
+                        path: entry.path(),
+                        size: metadata.len(),
+                        modified: metadata.modified()?,
+                    });
                 }
             }
-
-            warn!("Cannot save file to cache: {}", e)
         }
+
+        Ok(files)
     }
 
-    pub fn remove_file(&self, file: FileId) -> bool {
-        if let Some(path) = self.file_path(file) {
-            if let Err(err) = fs::remove_file(path) {
-                warn!("Unable to remove file from cache: {}", err);
-                false
-            } else {
-                true
+    /// The total size of the audio files in the cache in bytes.
+    pub fn audio_size(&self) -> io::Result<u64> {
+        Ok(self.audio_files()?.iter().map(|file| file.size).sum())
+    }
+
+    /// Removes the audio files older than `max_age`, then the oldest ones until the audio
+    /// files take up at most `max_size` bytes. Returns the removed files.
+    pub fn prune(
+        &self,
+        max_size: Option<u64>,
+        max_age: Option<Duration>,
+    ) -> io::Result<Vec<CachedFile>> {
+        let mut files = self.audio_files()?;
+        files.sort_by_key(|file| file.modified);
+
+        let mut size: u64 = files.iter().map(|file| file.size).sum();
+        let mut removed = Vec::new();
+
+        for file in files {
+            let too_old = max_age.map_or(false, |max_age| file.age() > max_age);
+            let too_big = max_size.map_or(false, |max_size| size > max_size);
+            if !too_old && !too_big {
+                // Files are sorted by age, so the remaining ones are kept too.
+                break;
             }
-        } else {
-            false
+
+            fs::remove_file(&file.path)?;
+            let _ = fs::remove_file(key_file_path(&file.path));
+            size -= file.size;
+            removed.push(file);
         }
+
+        Ok(removed)
+    }
+
+    /// Removes all audio files from the cache. Returns the removed files.
+    pub fn clear_audio_files(&self) -> io::Result<Vec<CachedFile>> {
+        self.prune(Some(0), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_backend::MemoryBackend;
+
+    #[test]
+    fn keeps_entries_in_backend() {
+        let cache = Cache::with_backend(MemoryBackend::new());
+        let id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+
+        assert_eq!(cache.volume(), None);
+        cache.save_volume(42);
+        assert_eq!(cache.volume(), Some(42));
+
+        cache.save_position(id, 12345);
+        assert_eq!(cache.position(id), Some(12345));
+        cache.remove_position(id);
+        assert_eq!(cache.position(id), None);
+    }
+
+    #[tokio::test]
+    async fn ensure_cached_waits_for_downloads() {
+        let location = std::env::temp_dir().join(format!("librespot-cache-{}", std::process::id()));
+        let cache = Cache::new(None, Some(&location)).unwrap();
+        let file = FileId([7; 20]);
+
+        let download = cache.start_download(file);
+        let cached = tokio::spawn({
+            let cache = cache.clone();
+            async move { cache.ensure_cached(file).await }
+        });
+        tokio::task::yield_now().await;
+        cache.save_file(file, &mut &b"audio"[..]);
+        drop(download);
+
+        let path = cached.await.unwrap().unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"audio");
+        assert_eq!(cache.audio_file_path(file), cache.ensure_cached(file).await);
+
+        fs::remove_dir_all(location).unwrap();
+    }
+
+    #[test]
+    fn prunes_audio_keys_with_their_files() {
+        let location = std::env::temp_dir().join(format!("librespot-keys-{}", std::process::id()));
+        let cache = Cache::new(None, Some(&location)).unwrap();
+        let id = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let file = FileId([7; 20]);
+
+        cache.save_file(file, &mut &b"audio"[..]);
+        cache.save_audio_key(id, file, AudioKey([1; 16]));
+        assert_eq!(cache.audio_key(id, file), Some(AudioKey([1; 16])));
+        assert_eq!(cache.audio_files().unwrap().len(), 1);
+
+        cache.clear_audio_files().unwrap();
+        assert_eq!(cache.audio_key(id, file), None);
+
+        fs::remove_dir_all(location).unwrap();
     }
 }
@@ -0,0 +1,271 @@
+//! Where the `Cache` keeps its entries: the credentials, the volume, the device id, audio
+//! keys, playback positions and audio files.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::spotify_id::{FileId, SpotifyId};
+use crate::util::create_private_file;
+
+/// An entry of the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheKey {
+    /// The reusable credentials as JSON, unless the cache has a `CredentialsStore`.
+    Credentials,
+    /// The volume as a decimal number.
+    Volume,
+    DeviceId,
+    /// The 16 bytes of the key of a file of a track.
+    AudioKey(SpotifyId, FileId),
+    /// The playback position of a track or episode in milliseconds, as a decimal number.
+    Position(SpotifyId),
+    /// An encrypted audio file, as it was downloaded.
+    AudioFile(FileId),
+}
+
+/// Stores the entries of a `Cache`, e.g. in a database or an object storage for diskless
+/// deployments. `Cache::new` uses a `FileBackend`, `Cache::with_backend` others.
+pub trait CacheBackend: Send + Sync + 'static {
+    /// Returns `None` if nothing is saved under `key`.
+    fn get(&self, key: &CacheKey) -> io::Result<Option<Vec<u8>>>;
+
+    /// Replaces the entry under `key`. Readers must never see a partially written entry.
+    fn put(&self, key: &CacheKey, data: &mut dyn Read) -> io::Result<()>;
+
+    /// Succeeds if nothing is saved under `key`.
+    fn remove(&self, key: &CacheKey) -> io::Result<()>;
+
+    /// The size of the entry under `key` in bytes, `None` if nothing is saved under it.
+    fn len(&self, key: &CacheKey) -> io::Result<Option<u64>>;
+
+    /// The path of the file the entry is kept in, `None` if it isn't saved or not kept in a
+    /// file. The file must be complete.
+    fn file_path(&self, _key: &CacheKey) -> Option<PathBuf> {
+        None
+    }
+
+    /// Opens an entry as a file, which audio files are played from. The default copies the
+    /// entry to a temporary file that is deleted when it is closed.
+    fn open(&self, key: &CacheKey) -> io::Result<Option<File>> {
+        match self.get(key)? {
+            Some(data) => temporary_file(&data).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Keeps the entries as files in the system cache directory, and the audio files in the
+/// audio cache directory. Entries in a directory that is `None` are not saved.
+#[derive(Debug, Clone)]
+pub struct FileBackend {
+    system_location: Option<PathBuf>,
+    audio_location: Option<PathBuf>,
+}
+
+impl FileBackend {
+    pub fn new(system_location: Option<PathBuf>, audio_location: Option<PathBuf>) -> Self {
+        Self {
+            system_location,
+            audio_location,
+        }
+    }
+
+    pub fn audio_location(&self) -> Option<&Path> {
+        self.audio_location.as_deref()
+    }
+
+    fn path(&self, key: &CacheKey) -> Option<PathBuf> {
+        let system_location = self.system_location.as_ref();
+        match *key {
+            CacheKey::Credentials => system_location.map(|p| p.join("credentials.json")),
+            CacheKey::Volume => system_location.map(|p| p.join("volume")),
+            CacheKey::DeviceId => system_location.map(|p| p.join("device_id")),
+            CacheKey::Position(id) => {
+                system_location.map(|p| p.join("positions").join(id.to_base16()))
+            }
+            // Next to the audio file, a file has the same key whichever track it is played
+            // for. `Cache::prune` removes it along with the file.
+            CacheKey::AudioKey(_, file) => self
+                .path(&CacheKey::AudioFile(file))
+                .map(|path| key_file_path(&path)),
+            CacheKey::AudioFile(file) => self.audio_location.as_ref().map(|location| {
+                let name = file.to_base16();
+                let mut path = location.join(&name[0..2]);
+                path.push(&name[2..]);
+                path
+            }),
+        }
+    }
+}
+
+/// The path of the key saved next to the audio file at `path`.
+pub(crate) fn key_file_path(path: &Path) -> PathBuf {
+    path.with_extension("key")
+}
+
+pub(crate) fn is_key_file(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |extension| extension == "key")
+}
+
+impl CacheBackend for FileBackend {
+    fn get(&self, key: &CacheKey) -> io::Result<Option<Vec<u8>>> {
+        match self.open(key)? {
+            Some(mut file) => {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)?;
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, key: &CacheKey, data: &mut dyn Read) -> io::Result<()> {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let parent = path.parent().unwrap();
+
+        // Read everything first, the write is retried after flushing the cache.
+        let mut contents = Vec::new();
+        data.read_to_end(&mut contents)?;
+
+        let private = *key == CacheKey::Credentials;
+        let result = fs::create_dir_all(parent)
+            .and_then(|_| write_atomically(&path, &mut &contents[..], private));
+        match (result, key, &self.audio_location) {
+            // Perhaps there's no space left in the cache.
+            // TODO: try to narrow down the error (platform-dependently)
+            (Err(e), CacheKey::AudioFile(_), Some(audio_location))
+                if e.kind() == ErrorKind::Other =>
+            {
+                info!("An error occured while writing to cache, trying to flush the cache");
+                fs::remove_dir_all(audio_location)
+                    .and_then(|_| fs::create_dir_all(parent))
+                    .and_then(|_| write_atomically(&path, &mut &contents[..], private))
+                    .map_err(|_| e)
+            }
+            (result, _, _) => result,
+        }
+    }
+
+    fn remove(&self, key: &CacheKey) -> io::Result<()> {
+        match self.path(key).map(fs::remove_file) {
+            Some(Err(e)) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn len(&self, key: &CacheKey) -> io::Result<Option<u64>> {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn file_path(&self, key: &CacheKey) -> Option<PathBuf> {
+        // Entries are renamed into place once they are written, see write_atomically.
+        self.path(key).filter(|path| path.is_file())
+    }
+
+    fn open(&self, key: &CacheKey) -> io::Result<Option<File>> {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match File::open(path) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Keeps the entries in memory only, e.g. for tests.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    entries: Mutex<HashMap<CacheKey, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CacheBackend for MemoryBackend {
+    fn get(&self, key: &CacheKey) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &CacheKey, data: &mut dyn Read) -> io::Result<()> {
+        let mut entry = Vec::new();
+        data.read_to_end(&mut entry)?;
+        self.entries.lock().unwrap().insert(*key, entry);
+        Ok(())
+    }
+
+    fn remove(&self, key: &CacheKey) -> io::Result<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn len(&self, key: &CacheKey) -> io::Result<Option<u64>> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|entry| entry.len() as u64))
+    }
+}
+
+// Writes to a temporary file that is renamed to `path` when complete, so that players saving
+// the same file concurrently don't interleave their writes and readers never see a partial file.
+// Private files, i.e. of credentials, can only be read by their owner.
+fn write_atomically(path: &Path, contents: &mut dyn Read, private: bool) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".{:08x}.tmp", rand::random::<u32>()));
+    let temp_path = PathBuf::from(temp_path);
+
+    let file = if private {
+        create_private_file(&temp_path)
+    } else {
+        File::create(&temp_path)
+    };
+    let result = file
+        .and_then(|mut file| io::copy(contents, &mut file))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn temporary_file(data: &[u8]) -> io::Result<File> {
+    let path = std::env::temp_dir().join(format!("librespot-{:016x}", rand::random::<u64>()));
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // FILE_FLAG_DELETE_ON_CLOSE, open files can't be removed on Windows.
+        options.custom_flags(0x0400_0000);
+    }
+
+    let mut file = options.open(&path)?;
+    #[cfg(not(windows))]
+    fs::remove_file(&path)?;
+    file.write_all(data)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
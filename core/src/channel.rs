@@ -1,7 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use std::time::Instant;
+use std::time::Duration;
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
@@ -9,6 +9,7 @@ use futures_core::Stream;
 use futures_util::lock::BiLock;
 use futures_util::StreamExt;
 use tokio::sync::mpsc;
+use tokio::time::Instant;
 
 use crate::util::SeqGenerator;
 
@@ -16,16 +17,78 @@ component! {
     ChannelManager : ChannelManagerInner {
         sequence: SeqGenerator<u16> = SeqGenerator::new(0),
         channels: HashMap<u16, mpsc::UnboundedSender<(u8, Bytes)>> = HashMap::new(),
-        download_rate_estimate: usize = 0,
-        download_measurement_start: Option<Instant> = None,
-        download_measurement_bytes: usize = 0,
+        download_rate: DownloadRate = DownloadRate::new(DEFAULT_DOWNLOAD_RATE_WINDOW),
         invalid: bool = false,
     }
 }
 
+/// The window download rates are estimated over unless configured otherwise.
+pub const DEFAULT_DOWNLOAD_RATE_WINDOW: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone)]
 pub struct ChannelError;
 
+/// Estimates a download rate from the bytes received within a rolling time window. It follows
+/// the clock of tokio, which simulations pause.
+#[derive(Debug, Clone)]
+pub struct DownloadRate {
+    window: Duration,
+    started: Option<Instant>,
+    samples: VecDeque<(Instant, usize)>,
+    bytes: usize,
+}
+
+impl DownloadRate {
+    pub fn new(window: Duration) -> Self {
+        DownloadRate {
+            window,
+            started: None,
+            samples: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+        self.expire(Instant::now());
+    }
+
+    pub fn record(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.started.get_or_insert(now);
+        self.samples.push_back((now, bytes));
+        self.bytes += bytes;
+        self.expire(now);
+    }
+
+    /// The download rate in bytes per second, or 0 until data was received for a whole window.
+    pub fn estimate(&mut self) -> usize {
+        let now = Instant::now();
+        self.expire(now);
+
+        match self.started {
+            Some(started) if now - started >= self.window => {
+                (self.bytes as u128 * 1000 / self.window.as_millis().max(1)) as usize
+            }
+            _ => 0,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(time, bytes)) = self.samples.front() {
+            if now - time <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+            self.bytes -= bytes;
+        }
+    }
+}
+
 pub struct Channel {
     receiver: mpsc::UnboundedReceiver<(u8, Bytes)>,
     state: ChannelState,
@@ -72,19 +135,7 @@ impl ChannelManager {
         let id: u16 = BigEndian::read_u16(data.split_to(2).as_ref());
 
         self.lock(|inner| {
-            let current_time = Instant::now();
-            if let Some(download_measurement_start) = inner.download_measurement_start {
-                if (current_time - download_measurement_start).as_millis() > 1000 {
-                    inner.download_rate_estimate = 1000 * inner.download_measurement_bytes
-                        / (current_time - download_measurement_start).as_millis() as usize;
-                    inner.download_measurement_start = Some(current_time);
-                    inner.download_measurement_bytes = 0;
-                }
-            } else {
-                inner.download_measurement_start = Some(current_time);
-            }
-
-            inner.download_measurement_bytes += data.len();
+            inner.download_rate.record(data.len());
 
             if let Entry::Occupied(entry) = inner.channels.entry(id) {
                 let _ = entry.get().send((cmd, data));
@@ -92,8 +143,20 @@ impl ChannelManager {
         });
     }
 
+    /// The rate in bytes per second at which data of all channels of the session was received
+    /// within the download rate window.
     pub fn get_download_rate_estimate(&self) -> usize {
-        self.lock(|inner| inner.download_rate_estimate)
+        self.lock(|inner| inner.download_rate.estimate())
+    }
+
+    pub fn download_rate_window(&self) -> Duration {
+        self.lock(|inner| inner.download_rate.window())
+    }
+
+    /// Sets the window over which download rates are estimated. Longer windows give steadier
+    /// but slower reacting estimates.
+    pub fn set_download_rate_window(&self, window: Duration) {
+        self.lock(|inner| inner.download_rate.set_window(window))
     }
 
     pub(crate) fn shutdown(&self) {
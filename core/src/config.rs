@@ -1,5 +1,6 @@
 use std::fmt;
 use std::str::FromStr;
+use std::time::Duration;
 use url::Url;
 
 #[derive(Clone, Debug)]
@@ -8,6 +9,34 @@ pub struct SessionConfig {
     pub device_id: String,
     pub proxy: Option<Url>,
     pub ap_port: Option<u16>,
+    /// The window over which download rates are estimated, see
+    /// `ChannelManager::get_download_rate_estimate`.
+    pub download_rate_window: Duration,
+    /// The interval the access point pings the session at, at least
+    /// `MIN_KEEP_ALIVE_INTERVAL`.
+    pub keep_alive_interval: Duration,
+    /// How late a ping, or the acknowledgement of the pong answering it, may be before the
+    /// connection is considered dead and closed. `None` waits for the TCP connection to fail
+    /// instead, which can take minutes.
+    pub keep_alive_timeout: Option<Duration>,
+    /// How long `Session::connect` may take to connect and authenticate, `None` for no limit.
+    pub connect_timeout: Option<Duration>,
+    /// The brand of the device, shown by Connect clients that discover it over zeroconf.
+    pub client_brand: String,
+    /// The model of the device, sent when logging in and shown by Connect clients that
+    /// discover it over zeroconf.
+    pub client_model: String,
+    /// The description of the operating system and the build of the client, sent when
+    /// logging in.
+    pub system_information: String,
+    /// The version of the client, sent when logging in and to Connect clients.
+    pub version_string: String,
+}
+
+impl SessionConfig {
+    /// Roughly the period the access point pings sessions at. A shorter keep-alive interval
+    /// would close connections that are alive.
+    pub const MIN_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(100);
 }
 
 impl Default for SessionConfig {
@@ -18,6 +47,18 @@ impl Default for SessionConfig {
             device_id,
             proxy: None,
             ap_port: None,
+            download_rate_window: Duration::from_secs(1),
+            keep_alive_interval: Duration::from_secs(120),
+            keep_alive_timeout: Some(Duration::from_secs(20)),
+            connect_timeout: Some(Duration::from_secs(30)),
+            client_brand: "librespot".to_string(),
+            client_model: "librespot".to_string(),
+            system_information: format!(
+                "librespot_{}_{}",
+                crate::version::SHA_SHORT,
+                crate::version::BUILD_ID
+            ),
+            version_string: crate::version::VERSION_STRING.to_string(),
         }
     }
 }
@@ -110,7 +151,49 @@ pub struct ConnectConfig {
     pub device_type: DeviceType,
     pub volume: u16,
     pub volume_ctrl: VolumeCtrl,
+    // The number of volume steps offered to Connect clients, also used by volume up and down.
+    pub volume_steps: u16,
+    // The range of the mixer the Connect volume is mapped onto, e.g. to limit the output of a
+    // fixed gain amplifier. Both ends are in 0..=0xFFFF.
+    pub mixer_volume_range: (u16, u16),
     pub autoplay: bool,
+    // The device is announced and shows the state of the active device through the Spirc
+    // handle, but doesn't take over playback when a client loads something onto it, until
+    // `Spirc::set_passive(false)` is called. For monitoring and testing.
+    pub passive: bool,
+    pub capabilities: ConnectCapabilities,
+    pub playback_moved_policy: PlaybackMovedPolicy,
+}
+
+impl ConnectConfig {
+    pub const DEFAULT_VOLUME_STEPS: u16 = 16;
+}
+
+/// The controls Connect clients offer for the device. Commands of disabled controls are
+/// ignored.
+#[derive(Clone, Debug)]
+pub struct ConnectCapabilities {
+    /// Always disabled with `VolumeCtrl::Fixed`.
+    pub volume_control: bool,
+    pub shuffle: bool,
+    pub repeat: bool,
+    /// Whether clients can log the device out, which removes the cached credentials and shuts
+    /// down the Connect session.
+    pub logout: bool,
+    /// Whether the player plays consecutive tracks without a gap, see `PlayerConfig::gapless`.
+    pub gapless: bool,
+}
+
+impl Default for ConnectCapabilities {
+    fn default() -> ConnectCapabilities {
+        ConnectCapabilities {
+            volume_control: true,
+            shuffle: true,
+            repeat: true,
+            logout: false,
+            gapless: true,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -138,3 +221,37 @@ impl Default for VolumeCtrl {
         VolumeCtrl::Log
     }
 }
+
+/// What the device does when another device of the same account takes over playback from it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaybackMovedPolicy {
+    /// Stops playing and leaves playback to the other device.
+    PauseLocal,
+    /// Ignores the other device and keeps playing.
+    KeepPlaying,
+    /// Stops playing like `PauseLocal`, but takes playback back after this many seconds and
+    /// resumes where it stopped, unless something was loaded onto the device in between.
+    ReclaimAfterSeconds(u32),
+}
+
+impl FromStr for PlaybackMovedPolicy {
+    type Err = ();
+    /// Parses `pause`, `keep` or `reclaim:SECONDS`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::PlaybackMovedPolicy::*;
+        match s.to_lowercase().as_ref() {
+            "pause" => Ok(PauseLocal),
+            "keep" => Ok(KeepPlaying),
+            s => match s.strip_prefix("reclaim:") {
+                Some(seconds) => seconds.parse().map(ReclaimAfterSeconds).map_err(|_| ()),
+                None => Err(()),
+            },
+        }
+    }
+}
+
+impl Default for PlaybackMovedPolicy {
+    fn default() -> PlaybackMovedPolicy {
+        PlaybackMovedPolicy::PauseLocal
+    }
+}
@@ -4,6 +4,10 @@ use shannon::Shannon;
 use std::io;
 use tokio_util::codec::{Decoder, Encoder};
 
+use crate::packet::Payload;
+#[cfg(feature = "packet-trace")]
+use crate::packet_trace::{self, Direction};
+
 const HEADER_SIZE: usize = 3;
 const MAC_SIZE: usize = 4;
 
@@ -35,17 +39,24 @@ impl ApCodec {
     }
 }
 
-impl Encoder<(u8, Vec<u8>)> for ApCodec {
+impl<P: Into<Payload>> Encoder<(u8, P)> for ApCodec {
     type Error = io::Error;
 
-    fn encode(&mut self, item: (u8, Vec<u8>), buf: &mut BytesMut) -> io::Result<()> {
+    fn encode(&mut self, item: (u8, P), buf: &mut BytesMut) -> io::Result<()> {
         let (cmd, payload) = item;
+        let payload: Payload = payload.into();
         let offset = buf.len();
 
-        buf.reserve(3 + payload.len());
+        let data = payload.as_slice();
+
+        #[cfg(feature = "packet-trace")]
+        packet_trace::record(Direction::Sent, cmd, data);
+
+        buf.reserve(3 + data.len());
         buf.put_u8(cmd);
-        buf.put_u16(payload.len() as u16);
-        buf.extend_from_slice(&payload);
+        buf.put_u16(data.len() as u16);
+        buf.extend_from_slice(data);
+        payload.recycle();
 
         self.encode_cipher.nonce_u32(self.encode_nonce);
         self.encode_nonce += 1;
@@ -92,6 +103,9 @@ impl Decoder for ApCodec {
                 let mac = payload.split_off(size);
                 self.decode_cipher.check_mac(mac.as_ref())?;
 
+                #[cfg(feature = "packet-trace")]
+                packet_trace::record(Direction::Received, cmd, &payload);
+
                 return Ok(Some((cmd, payload.freeze())));
             }
         }
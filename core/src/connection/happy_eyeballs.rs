@@ -0,0 +1,138 @@
+//! Connecting to a host with several addresses the way RFC 8305 ("Happy Eyeballs") describes:
+//! IPv6 and IPv4 addresses are tried alternately, and the next attempt starts when the
+//! previous one fails or takes longer than a short delay. The first connection wins.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+
+// The delay before the next address is tried while an attempt is pending.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+// How long a single attempt may take.
+const CONNECTION_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves both the IPv6 and IPv4 addresses of `host` and connects to one of them.
+pub async fn connect_host<A: ToSocketAddrs>(host: A) -> io::Result<TcpStream> {
+    connect_any(lookup_host(host).await?.collect()).await
+}
+
+/// Connects to one of `addrs`, racing them in the order of `interleave_families`.
+pub async fn connect_any(addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let mut pending = interleave_families(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = pending.next() {
+            attempts.push(connect_addr(addr));
+        }
+        if attempts.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "No address to connect to")
+            }));
+        }
+
+        let more_pending = pending.peek().is_some();
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(socket) => return Ok(socket),
+                // The next address is tried right away.
+                Err(e) => last_error = Some(e),
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if more_pending => (),
+        }
+    }
+}
+
+async fn connect_addr(addr: SocketAddr) -> io::Result<TcpStream> {
+    let result =
+        match tokio::time::timeout(CONNECTION_ATTEMPT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Connection attempt timed out",
+            )),
+        };
+    if let Err(ref e) = result {
+        debug!("Cannot connect to {}: {}", addr, e);
+    }
+    result
+}
+
+/// Orders addresses to alternate between IPv6 and IPv4, starting with the family of the
+/// first address. Addresses of the same family keep their order.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    let mut ordered = Vec::new();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let addrs = vec![
+            addr("[::1]:1"),
+            addr("[::1]:2"),
+            addr("[::1]:3"),
+            addr("127.0.0.1:1"),
+            addr("127.0.0.1:2"),
+        ];
+        assert_eq!(
+            interleave_families(addrs),
+            vec![
+                addr("[::1]:1"),
+                addr("127.0.0.1:1"),
+                addr("[::1]:2"),
+                addr("127.0.0.1:2"),
+                addr("[::1]:3"),
+            ]
+        );
+
+        let addrs = vec![addr("127.0.0.1:1"), addr("[::1]:1")];
+        assert_eq!(interleave_families(addrs.clone()), addrs);
+    }
+
+    #[tokio::test]
+    async fn connects_after_failed_attempts() {
+        // A port nothing listens on anymore.
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let socket = connect_any(vec![closed, closed, listener.local_addr().unwrap()])
+            .await
+            .unwrap();
+        assert_eq!(socket.peer_addr().unwrap(), listener.local_addr().unwrap());
+
+        assert!(connect_any(vec![closed]).await.is_err());
+        assert!(connect_any(Vec::new()).await.is_err());
+    }
+}
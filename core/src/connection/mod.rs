@@ -1,11 +1,11 @@
 mod codec;
 mod handshake;
+mod happy_eyeballs;
 
 pub use self::codec::ApCodec;
 pub use self::handshake::handshake;
 
 use std::io::{self, ErrorKind};
-use std::net::ToSocketAddrs;
 
 use futures_util::{SinkExt, StreamExt};
 use protobuf::{self, Message, ProtobufError};
@@ -15,9 +15,9 @@ use tokio_util::codec::Framed;
 use url::Url;
 
 use crate::authentication::Credentials;
+use crate::config::SessionConfig;
 use crate::protocol::keyexchange::{APLoginFailed, ErrorCode};
 use crate::proxytunnel;
-use crate::version;
 
 pub type Transport = Framed<TcpStream, ApCodec>;
 
@@ -38,8 +38,18 @@ fn login_error_message(code: &ErrorCode) -> &'static str {
     }
 }
 
+/// Why the access point refused a login. The common reasons have a variant of their own, so
+/// that applications can tell the user what to do.
 #[derive(Debug, Error)]
 pub enum AuthenticationError {
+    #[error("Login failed with reason: Bad credentials")]
+    BadCredentials,
+    #[error("Login failed with reason: Premium account required")]
+    PremiumRequired,
+    #[error("Login failed with reason: Travel restriction")]
+    TravelRestriction,
+    #[error("Login failed with reason: Try another AP")]
+    TryAnotherAP,
     #[error("Login failed with reason: {}", login_error_message(.0))]
     LoginFailed(ErrorCode),
     #[error("Authentication failed: {0}")]
@@ -54,7 +64,13 @@ impl From<ProtobufError> for AuthenticationError {
 
 impl From<APLoginFailed> for AuthenticationError {
     fn from(login_failure: APLoginFailed) -> Self {
-        Self::LoginFailed(login_failure.get_error_code())
+        match login_failure.get_error_code() {
+            ErrorCode::BadCredentials => Self::BadCredentials,
+            ErrorCode::PremiumAccountRequired => Self::PremiumRequired,
+            ErrorCode::TravelRestriction => Self::TravelRestriction,
+            ErrorCode::TryAnotherAP => Self::TryAnotherAP,
+            code => Self::LoginFailed(code),
+        }
     }
 }
 
@@ -62,15 +78,7 @@ pub async fn connect(addr: String, proxy: Option<&Url>) -> io::Result<Transport>
     let socket = if let Some(proxy_url) = proxy {
         info!("Using proxy \"{}\"", proxy_url);
 
-        let socket_addr = proxy_url.socket_addrs(|| None).and_then(|addrs| {
-            addrs.into_iter().next().ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Can't resolve proxy server address",
-                )
-            })
-        })?;
-        let socket = TcpStream::connect(&socket_addr).await?;
+        let socket = happy_eyeballs::connect_any(proxy_url.socket_addrs(|| None)?).await?;
 
         let uri = addr.parse::<http::Uri>().map_err(|_| {
             io::Error::new(
@@ -93,14 +101,7 @@ pub async fn connect(addr: String, proxy: Option<&Url>) -> io::Result<Transport>
 
         proxytunnel::proxy_connect(socket, host, port.as_str()).await?
     } else {
-        let socket_addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::NotFound,
-                "Can't resolve access point address",
-            )
-        })?;
-
-        TcpStream::connect(&socket_addr).await?
+        happy_eyeballs::connect_host(addr.as_str()).await?
     };
 
     handshake(socket).await
@@ -109,7 +110,7 @@ pub async fn connect(addr: String, proxy: Option<&Url>) -> io::Result<Transport>
 pub async fn authenticate(
     transport: &mut Transport,
     credentials: Credentials,
-    device_id: &str,
+    config: &SessionConfig,
 ) -> Result<Credentials, AuthenticationError> {
     use crate::protocol::authentication::{APWelcome, ClientResponseEncrypted, CpuFamily, Os};
 
@@ -129,15 +130,12 @@ pub async fn authenticate(
     packet.mut_system_info().set_os(Os::OS_UNKNOWN);
     packet
         .mut_system_info()
-        .set_system_information_string(format!(
-            "librespot_{}_{}",
-            version::SHA_SHORT,
-            version::BUILD_ID
-        ));
+        .set_system_information_string(config.system_information.clone());
     packet
         .mut_system_info()
-        .set_device_id(device_id.to_string());
-    packet.set_version_string(version::VERSION_STRING.to_string());
+        .set_device_id(config.device_id.clone());
+    packet.set_platform_model(config.client_model.clone());
+    packet.set_version_string(config.version_string.clone());
 
     let cmd = 0xab;
     let data = packet.write_to_bytes().unwrap();
@@ -0,0 +1,184 @@
+//! Where the reusable credentials of a session are kept between runs.
+
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::authentication::Credentials;
+use crate::util::create_private_file;
+
+/// Loads and saves the reusable credentials.
+///
+/// Unless a store is set with `Cache::with_credentials_store`, the `Cache` keeps the
+/// credentials in its backend, in the system cache directory with the default `FileBackend`.
+pub trait CredentialsStore: Send + Sync + 'static {
+    /// Returns `None` if no credentials were saved.
+    fn load(&self) -> io::Result<Option<Credentials>>;
+
+    fn save(&self, credentials: &Credentials) -> io::Result<()>;
+
+    /// Succeeds if no credentials were saved.
+    fn remove(&self) -> io::Result<()>;
+}
+
+/// Keeps the credentials as plain JSON in a file only its owner can read.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl CredentialsStore for FileStore {
+    fn load(&self) -> io::Result<Option<Credentials>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            // The file was probably not written before.
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn save(&self, credentials: &Credentials) -> io::Result<()> {
+        let data = serde_json::to_string(credentials)?;
+        let mut file = create_private_file(&self.path)?;
+        write!(file, "{}", data)
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Keeps the credentials in memory only, e.g. for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    credentials: Mutex<Option<Credentials>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CredentialsStore for MemoryStore {
+    fn load(&self) -> io::Result<Option<Credentials>> {
+        Ok(self.credentials.lock().unwrap().clone())
+    }
+
+    fn save(&self, credentials: &Credentials) -> io::Result<()> {
+        *self.credentials.lock().unwrap() = Some(credentials.clone());
+        Ok(())
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        self.credentials.lock().unwrap().take();
+        Ok(())
+    }
+}
+
+/// Keeps the credentials in the keyring of the OS: the Secret Service on Linux, the Keychain
+/// on macOS and the Credential Manager on Windows.
+#[cfg(feature = "keyring")]
+#[derive(Debug, Clone)]
+pub struct KeyringStore {
+    service: String,
+    account: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringStore {
+    /// The credentials are saved as the password of `account` of `service`. Use different
+    /// accounts to keep the credentials of several instances apart.
+    pub fn new(service: impl Into<String>, account: impl Into<String>) -> Self {
+        Self {
+            service: service.into(),
+            account: account.into(),
+        }
+    }
+
+    fn entry(&self) -> keyring::Entry {
+        keyring::Entry::new(&self.service, &self.account)
+    }
+
+    fn io_error(e: keyring::Error) -> io::Error {
+        io::Error::new(ErrorKind::Other, e)
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl Default for KeyringStore {
+    fn default() -> Self {
+        Self::new("librespot", "default")
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl CredentialsStore for KeyringStore {
+    fn load(&self) -> io::Result<Option<Credentials>> {
+        match self.entry().get_password() {
+            Ok(data) => serde_json::from_str(&data)
+                .map(Some)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(Self::io_error(e)),
+        }
+    }
+
+    fn save(&self, credentials: &Credentials) -> io::Result<()> {
+        let data = serde_json::to_string(credentials)?;
+        self.entry().set_password(&data).map_err(Self::io_error)
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        match self.entry().delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(Self::io_error(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip<S: CredentialsStore>(store: S) {
+        let credentials = Credentials::with_password("user", "password");
+
+        assert!(store.load().unwrap().is_none());
+        store.save(&credentials).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.username, credentials.username);
+        assert_eq!(loaded.auth_data, credentials.auth_data);
+
+        store.remove().unwrap();
+        assert!(store.load().unwrap().is_none());
+        store.remove().unwrap();
+    }
+
+    #[test]
+    fn memory_store_roundtrip() {
+        roundtrip(MemoryStore::new());
+    }
+
+    #[test]
+    fn file_store_roundtrip() {
+        let path =
+            std::env::temp_dir().join(format!("librespot-credentials-{}.json", std::process::id()));
+        roundtrip(FileStore::new(path));
+    }
+}
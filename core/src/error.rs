@@ -0,0 +1,67 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::audio_key::AudioKeyError;
+use crate::channel::ChannelError;
+use crate::mercury::MercuryError;
+use crate::session::SessionError;
+use crate::spotify_id::SpotifyIdError;
+
+/// The error type of operations that talk to Spotify, e.g. fetching metadata or audio files.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("{0} is unavailable")]
+    Unavailable(String),
+    #[error("{0} is explicit and filtered")]
+    ExplicitContent(String),
+    #[error("rate limited by the server")]
+    RateLimited,
+    #[error("request failed")]
+    RequestFailed,
+    #[error("channel closed")]
+    ChannelClosed,
+    #[error("cannot get the audio key")]
+    AudioKey,
+    #[error(transparent)]
+    Session(#[from] SessionError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<MercuryError> for Error {
+    fn from(err: MercuryError) -> Self {
+        match err {
+            MercuryError::Failed => Error::RequestFailed,
+            MercuryError::RateLimited => Error::RateLimited,
+        }
+    }
+}
+
+impl From<ChannelError> for Error {
+    fn from(_: ChannelError) -> Self {
+        Error::ChannelClosed
+    }
+}
+
+impl From<AudioKeyError> for Error {
+    fn from(_: AudioKeyError) -> Self {
+        Error::AudioKey
+    }
+}
+
+impl From<SpotifyIdError> for Error {
+    fn from(_: SpotifyIdError) -> Self {
+        Error::Protocol("invalid Spotify id".to_owned())
+    }
+}
+
+impl From<protobuf::ProtobufError> for Error {
+    fn from(err: protobuf::ProtobufError) -> Self {
+        Error::Protocol(err.to_string())
+    }
+}
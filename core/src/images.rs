@@ -0,0 +1,42 @@
+//! Downloads of the images of the metadata, e.g. covers, through the proxy of the session.
+
+use std::io;
+
+use bytes::Bytes;
+use hyper::{Body, Client, Uri};
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use hyper_rustls::HttpsConnector;
+use url::Url;
+
+use crate::Error;
+
+pub(crate) async fn fetch(url: &str, proxy: Option<&Url>) -> Result<Bytes, Error> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|_| Error::Protocol(format!("invalid image URL {}", url)))?;
+
+    let connector = HttpsConnector::with_webpki_roots();
+    let response = if let Some(url) = proxy {
+        // Panic safety: all URLs are valid URIs
+        let proxy = Proxy::new(Intercept::All, url.to_string().parse().unwrap());
+        let proxy_connector = ProxyConnector::from_proxy(connector, proxy)?;
+        Client::builder()
+            .build::<_, Body>(proxy_connector)
+            .get(uri)
+            .await
+    } else {
+        Client::builder().build::<_, Body>(connector).get(uri).await
+    }
+    .map_err(request_error)?;
+
+    if !response.status().is_success() {
+        return Err(Error::RequestFailed);
+    }
+    hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(request_error)
+}
+
+fn request_error(e: hyper::Error) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::Other, e))
+}
@@ -22,5 +22,5 @@ pub async fn get_token(
     );
     let response = session.mercury().get(url).await?;
     let data = response.payload.first().expect("Empty payload");
-    serde_json::from_slice(data.as_ref()).map_err(|_| MercuryError)
+    serde_json::from_slice(data.as_ref()).map_err(|_| MercuryError::Failed)
 }
@@ -11,20 +11,32 @@ mod component;
 pub mod audio_key;
 pub mod authentication;
 pub mod cache;
+pub mod cache_backend;
 pub mod channel;
 pub mod config;
 mod connection;
+pub mod credentials_store;
 #[doc(hidden)]
 pub mod diffie_hellman;
+pub mod error;
+#[cfg(feature = "images")]
+mod images;
 pub mod keymaster;
 pub mod mercury;
+pub mod packet;
+#[cfg(feature = "packet-trace")]
+pub mod packet_trace;
 mod proxytunnel;
 pub mod session;
+pub mod spawner;
 pub mod spotify_id;
+pub mod throttle;
 #[doc(hidden)]
 pub mod util;
 pub mod version;
 
+pub use crate::error::Error;
+
 const AP_FALLBACK: &str = "ap.spotify.com:443";
 
 #[cfg(feature = "apresolve")]
@@ -1,15 +1,19 @@
+use std::cmp;
 use std::collections::HashMap;
 use std::future::Future;
 use std::mem;
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Duration;
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
 use tokio::sync::{mpsc, oneshot};
+use tokio::time::Sleep;
 
 use crate::protocol;
+use crate::session::Session;
 use crate::util::SeqGenerator;
 
 mod types;
@@ -18,12 +22,32 @@ pub use self::types::*;
 mod sender;
 pub use self::sender::MercurySender;
 
+mod subscription;
+pub use self::subscription::{MercuryUpdate, Subscription};
+
+const MERCURY_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// The time after which a request without a (complete) response is considered lost.
+
+const MERCURY_GET_MAX_RETRIES: u8 = 2;
+// How often a timed out GET request is sent again before giving up. Other methods
+// are not idempotent and are never retried.
+
+const MERCURY_RATE_LIMIT_MAX_RETRIES: u8 = 5;
+// How often a request that was rejected because of rate limiting is sent again.
+
+const MERCURY_RATE_LIMIT_BACK_OFF: Duration = Duration::from_secs(1);
+// The initial time to hold back requests after being rate limited, if the server
+// didn't tell us how long to wait. It doubles for every consecutive rejection.
+
+const STATUS_TOO_MANY_REQUESTS: i32 = 429;
+
 component! {
     MercuryManager : MercuryManagerInner {
         sequence: SeqGenerator<u64> = SeqGenerator::new(0),
         pending: HashMap<Vec<u8>, MercuryPending> = HashMap::new(),
         subscriptions: Vec<(String, mpsc::UnboundedSender<MercuryResponse>)> = Vec::new(),
         invalid: bool = false,
+        rate_limited: u32 = 0,
     }
 }
 
@@ -33,18 +57,101 @@ pub struct MercuryPending {
     callback: Option<oneshot::Sender<Result<MercuryResponse, MercuryError>>>,
 }
 
-pub struct MercuryFuture<T> {
-    receiver: oneshot::Receiver<Result<T, MercuryError>>,
+pub struct MercuryFuture {
+    manager: MercuryManager,
+    request: MercuryRequest,
+    timeout_retries_left: u8,
+    rate_limit_retries_left: u8,
+    seq: Option<Vec<u8>>,
+    receiver: Option<oneshot::Receiver<Result<MercuryResponse, MercuryError>>>,
+    // Delays sending the request while it is throttled, times out the response once it is sent.
+    timer: Pin<Box<Sleep>>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl MercuryFuture {
+    fn send(&mut self) {
+        let (seq, receiver) = self.manager.send_request(&self.request);
+        self.seq = Some(seq);
+        self.receiver = Some(receiver);
+        self.timer = Box::pin(self.manager.session().sleep(MERCURY_REQUEST_TIMEOUT));
+    }
 }
 
-impl<T> Future for MercuryFuture<T> {
-    type Output = Result<T, MercuryError>;
+impl Future for MercuryFuture {
+    type Output = Result<MercuryResponse, MercuryError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match Pin::new(&mut self.receiver).poll(cx) {
-            Poll::Ready(Ok(x)) => Poll::Ready(x),
-            Poll::Ready(Err(_)) => Poll::Ready(Err(MercuryError)),
-            Poll::Pending => Poll::Pending,
+        let this = &mut *self;
+
+        #[cfg(feature = "tracing")]
+        let _entered = this.span.clone().entered();
+
+        loop {
+            let receiver = match this.receiver.as_mut() {
+                Some(receiver) => receiver,
+                None => {
+                    if this.timer.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    this.send();
+                    continue;
+                }
+            };
+
+            if let Poll::Ready(result) = Pin::new(receiver).poll(cx) {
+                this.seq = None;
+                this.receiver = None;
+
+                match result {
+                    Ok(Ok(response)) if response.status_code == STATUS_TOO_MANY_REQUESTS => {
+                        if this.rate_limit_retries_left == 0 {
+                            warn!("Mercury request for {} was rate limited", this.request.uri);
+                            return Poll::Ready(Err(MercuryError::RateLimited));
+                        }
+
+                        debug!(
+                            "Mercury request for {} was rate limited, retrying",
+                            this.request.uri
+                        );
+                        this.rate_limit_retries_left -= 1;
+                        this.timer = this.manager.throttle_delay();
+                        continue;
+                    }
+                    result => return Poll::Ready(result.unwrap_or(Err(MercuryError::Failed))),
+                }
+            }
+
+            if this.timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            if let Some(seq) = this.seq.take() {
+                this.manager.cancel(&seq);
+            }
+            this.receiver = None;
+
+            if this.timeout_retries_left == 0 {
+                warn!("Mercury request for {} timed out", this.request.uri);
+                return Poll::Ready(Err(MercuryError::Failed));
+            }
+
+            warn!(
+                "Mercury request for {} timed out, retrying",
+                this.request.uri
+            );
+            this.timeout_retries_left -= 1;
+            this.timer = this.manager.throttle_delay();
+        }
+    }
+}
+
+impl Drop for MercuryFuture {
+    fn drop(&mut self) {
+        if let Some(seq) = self.seq.take() {
+            // Nobody is waiting for the response anymore, so don't keep track of it.
+            self.manager.cancel(&seq);
         }
     }
 }
@@ -56,7 +163,50 @@ impl MercuryManager {
         seq
     }
 
-    fn request(&self, req: MercuryRequest) -> MercuryFuture<MercuryResponse> {
+    fn request(&self, req: MercuryRequest) -> MercuryFuture {
+        let timeout_retries = if req.method == MercuryMethod::Get {
+            MERCURY_GET_MAX_RETRIES
+        } else {
+            0
+        };
+
+        let session = self.session();
+        let delay = session.throttle().reserve();
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("mercury_request", method = ?req.method, uri = %req.uri);
+
+        let mut future = MercuryFuture {
+            #[cfg(feature = "tracing")]
+            span,
+            manager: self.clone(),
+            request: req,
+            timeout_retries_left: timeout_retries,
+            rate_limit_retries_left: MERCURY_RATE_LIMIT_MAX_RETRIES,
+            seq: None,
+            receiver: None,
+            timer: Box::pin(session.sleep(delay)),
+        };
+
+        // Send right away when not throttled, so requests go out even if the future
+        // isn't polled immediately.
+        if delay == Duration::default() {
+            #[cfg(feature = "tracing")]
+            let _entered = future.span.clone().entered();
+
+            future.send();
+        }
+
+        future
+    }
+
+    fn send_request(
+        &self,
+        req: &MercuryRequest,
+    ) -> (
+        Vec<u8>,
+        oneshot::Receiver<Result<MercuryResponse, MercuryError>>,
+    ) {
         let (tx, rx) = oneshot::channel();
 
         let pending = MercuryPending {
@@ -76,10 +226,22 @@ impl MercuryManager {
         let data = req.encode(&seq);
 
         self.session().send_packet(cmd, data);
-        MercuryFuture { receiver: rx }
+        (seq, rx)
+    }
+
+    fn throttle_delay(&self) -> Pin<Box<Sleep>> {
+        let session = self.session();
+        let delay = session.throttle().reserve();
+        Box::pin(session.sleep(delay))
+    }
+
+    fn cancel(&self, seq: &[u8]) {
+        self.lock(|inner| {
+            inner.pending.remove(seq);
+        });
     }
 
-    pub fn get<T: Into<String>>(&self, uri: T) -> MercuryFuture<MercuryResponse> {
+    pub fn get<T: Into<String>>(&self, uri: T) -> MercuryFuture {
         self.request(MercuryRequest {
             method: MercuryMethod::Get,
             uri: uri.into(),
@@ -88,7 +250,7 @@ impl MercuryManager {
         })
     }
 
-    pub fn send<T: Into<String>>(&self, uri: T, data: Vec<u8>) -> MercuryFuture<MercuryResponse> {
+    pub fn send<T: Into<String>>(&self, uri: T, data: Vec<u8>) -> MercuryFuture {
         self.request(MercuryRequest {
             method: MercuryMethod::Send,
             uri: uri.into(),
@@ -101,12 +263,17 @@ impl MercuryManager {
         MercurySender::new(self.clone(), uri.into())
     }
 
-    pub fn subscribe<T: Into<String>>(
+    /// Subscribes to the updates of `uri`, decoding them as `T`.
+    pub fn subscribe<T: protobuf::Message, U: Into<String>>(&self, uri: U) -> Subscription<T> {
+        Subscription::new(self.clone(), uri.into())
+    }
+
+    fn subscribe_channel(
         &self,
-        uri: T,
-    ) -> impl Future<Output = Result<mpsc::UnboundedReceiver<MercuryResponse>, MercuryError>> + 'static
-    {
-        let uri = uri.into();
+        uri: String,
+    ) -> impl Future<Output = Result<mpsc::UnboundedReceiver<MercuryResponse>, MercuryError>>
+           + Send
+           + 'static {
         let request = self.request(MercuryRequest {
             method: MercuryMethod::Sub,
             uri: uri.clone(),
@@ -162,7 +329,8 @@ impl MercuryManager {
                 callback: None,
             },
             None => {
-                warn!("Ignore seq {:?} cmd {:x}", seq, cmd);
+                // Also happens for requests that timed out or were cancelled.
+                debug!("Ignore seq {:?} cmd {:x}", seq, cmd);
                 return;
             }
         };
@@ -193,6 +361,16 @@ impl MercuryManager {
         data.split_to(size).as_ref().to_owned()
     }
 
+    fn retry_after(header: &protocol::mercury::Header) -> Option<Duration> {
+        header
+            .get_user_fields()
+            .iter()
+            .find(|field| field.get_key().eq_ignore_ascii_case("Retry-After"))
+            .and_then(|field| std::str::from_utf8(field.get_value()).ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_secs)
+    }
+
     fn complete_request(&self, cmd: u8, mut pending: MercuryPending) {
         let header_data = pending.parts.remove(0);
         let header: protocol::mercury::Header = protobuf::parse_from_bytes(&header_data).unwrap();
@@ -203,12 +381,31 @@ impl MercuryManager {
             payload: pending.parts,
         };
 
-        if response.status_code >= 500 {
-            panic!("Spotify servers returned an error. Restart librespot.");
-        } else if response.status_code >= 400 {
+        if response.status_code == STATUS_TOO_MANY_REQUESTS {
+            // Hold back all requests, the rejected one is retried by its future.
+            let consecutive = self.lock(|inner| {
+                inner.rate_limited += 1;
+                inner.rate_limited
+            });
+            let delay = Self::retry_after(&header).unwrap_or_else(|| {
+                MERCURY_RATE_LIMIT_BACK_OFF * 2u32.pow(cmp::min(consecutive - 1, 6))
+            });
+            self.session().throttle().back_off(delay);
+
+            if let Some(cb) = pending.callback {
+                let _ = cb.send(Ok(response));
+            }
+            return;
+        }
+
+        if response.status_code < 400 {
+            self.lock(|inner| inner.rate_limited = 0);
+        }
+
+        if response.status_code >= 400 {
             warn!("error {} for uri {}", response.status_code, &response.uri);
             if let Some(cb) = pending.callback {
-                let _ = cb.send(Err(MercuryError));
+                let _ = cb.send(Err(MercuryError::Failed));
             }
         } else if cmd == 0xb5 {
             self.lock(|inner| {
@@ -248,6 +445,14 @@ impl MercuryManager {
         }
     }
 
+    // The session, unless it was shut down or dropped.
+    fn valid_session(&self) -> Option<Session> {
+        if self.lock(|inner| inner.invalid) {
+            return None;
+        }
+        (self.0).0.try_upgrade()
+    }
+
     pub(crate) fn shutdown(&self) {
         self.lock(|inner| {
             inner.invalid = true;
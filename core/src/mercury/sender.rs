@@ -5,7 +5,7 @@ use super::*;
 pub struct MercurySender {
     mercury: MercuryManager,
     uri: String,
-    pending: VecDeque<MercuryFuture<MercuryResponse>>,
+    pending: VecDeque<MercuryFuture>,
 }
 
 impl MercurySender {
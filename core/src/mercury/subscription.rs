@@ -0,0 +1,180 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::stream::FusedStream;
+use futures_core::Stream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Sleep;
+
+use super::{MercuryError, MercuryManager, MercuryResponse};
+use crate::session::{Session, SessionWeak};
+
+// The time to wait before subscribing again after the subscription failed.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+type SubscribeFuture = Pin<
+    Box<dyn Future<Output = Result<mpsc::UnboundedReceiver<MercuryResponse>, MercuryError>> + Send>,
+>;
+
+type SuccessorFuture = Pin<Box<dyn Future<Output = Option<Session>> + Send>>;
+
+/// An update sent to a subscription, with the first part of its payload decoded.
+#[derive(Debug, Clone)]
+pub struct MercuryUpdate<T> {
+    /// The URI the update is about, which may be below the subscribed URI.
+    pub uri: String,
+    pub message: T,
+}
+
+enum State {
+    Subscribing(SubscribeFuture),
+    Subscribed(mpsc::UnboundedReceiver<MercuryResponse>),
+    Waiting(Pin<Box<Sleep>>),
+    // The session is lost, waiting for the session replacing it.
+    Reconnecting(SuccessorFuture),
+    Closed,
+}
+
+/// The updates of a Mercury URI, e.g. of a playlist or the root list of a user, returned by
+/// `MercuryManager::subscribe`.
+///
+/// A subscription that fails is made again after a short delay. When the session is lost,
+/// the subscription is made again with the session set by `Session::set_successor`, and the
+/// stream ends if the session is dropped without one. `resubscribe` continues the stream
+/// with any other session.
+pub struct Subscription<T> {
+    mercury: MercuryManager,
+    successor: Option<watch::Receiver<Option<SessionWeak>>>,
+    uri: String,
+    state: State,
+    message: PhantomData<fn() -> T>,
+}
+
+impl<T: protobuf::Message> Subscription<T> {
+    pub(super) fn new(mercury: MercuryManager, uri: String) -> Self {
+        let state = State::Subscribing(Box::pin(mercury.subscribe_channel(uri.clone())));
+        let successor = mercury.valid_session().map(|session| session.successor());
+        Subscription {
+            mercury,
+            successor,
+            uri,
+            state,
+            message: PhantomData,
+        }
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// Subscribes again with the Mercury manager of `session`, e.g. after reconnecting.
+    pub fn resubscribe(&mut self, session: &Session) {
+        self.mercury = session.mercury().clone();
+        self.successor = Some(session.successor());
+        self.state = State::Subscribing(Box::pin(self.mercury.subscribe_channel(self.uri.clone())));
+    }
+
+    // Waits before subscribing again, or for the successor if the session is gone.
+    fn retry_later(&mut self) {
+        self.state = match (self.mercury.valid_session(), self.successor.take()) {
+            (Some(session), successor) => {
+                self.successor = successor;
+                State::Waiting(Box::pin(session.sleep(RESUBSCRIBE_DELAY)))
+            }
+            (None, Some(successor)) => {
+                debug!("Subscription to {} waits for a new session", self.uri);
+                State::Reconnecting(Box::pin(wait_for_successor(successor)))
+            }
+            (None, None) => State::Closed,
+        };
+    }
+}
+
+// Resolves to the successor of a session, or `None` if the session is dropped without one.
+async fn wait_for_successor(
+    mut successor: watch::Receiver<Option<SessionWeak>>,
+) -> Option<Session> {
+    loop {
+        let session = successor.borrow().as_ref().map(SessionWeak::try_upgrade);
+        match session {
+            Some(session) => return session,
+            None => {
+                if successor.changed().await.is_err() {
+                    return successor
+                        .borrow()
+                        .as_ref()
+                        .and_then(SessionWeak::try_upgrade);
+                }
+            }
+        }
+    }
+}
+
+impl<T: protobuf::Message> Stream for Subscription<T> {
+    type Item = MercuryUpdate<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.state {
+                State::Subscribing(ref mut subscribe) => match subscribe.as_mut().poll(cx) {
+                    Poll::Ready(Ok(updates)) => this.state = State::Subscribed(updates),
+                    Poll::Ready(Err(e)) => {
+                        warn!("Cannot subscribe to {}: {:?}", this.uri, e);
+                        this.retry_later();
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Subscribed(ref mut updates) => match updates.poll_recv(cx) {
+                    Poll::Ready(Some(response)) => {
+                        let data = response.payload.first().map_or(&[][..], Vec::as_slice);
+                        match protobuf::parse_from_bytes::<T>(data) {
+                            Ok(message) => {
+                                return Poll::Ready(Some(MercuryUpdate {
+                                    uri: response.uri,
+                                    message,
+                                }))
+                            }
+                            Err(e) => warn!("Ignoring invalid update of {}: {}", response.uri, e),
+                        }
+                    }
+                    Poll::Ready(None) => {
+                        debug!("Subscription to {} ended", this.uri);
+                        this.retry_later();
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Waiting(ref mut delay) => match delay.as_mut().poll(cx) {
+                    Poll::Ready(()) => {
+                        this.state = State::Subscribing(Box::pin(
+                            this.mercury.subscribe_channel(this.uri.clone()),
+                        ))
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Reconnecting(ref mut successor) => match successor.as_mut().poll(cx) {
+                    Poll::Ready(Some(session)) => {
+                        debug!(
+                            "Subscribing to {} with Session[{}]",
+                            this.uri,
+                            session.session_id()
+                        );
+                        this.resubscribe(&session);
+                    }
+                    Poll::Ready(None) => this.state = State::Closed,
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Closed => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<T: protobuf::Message> FusedStream for Subscription<T> {
+    fn is_terminated(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+}
@@ -28,7 +28,12 @@ pub struct MercuryResponse {
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Copy, Clone)]
-pub struct MercuryError;
+pub enum MercuryError {
+    /// The request failed, timed out or the server returned an error status.
+    Failed,
+    /// The request was still rate limited after retrying.
+    RateLimited,
+}
 
 impl ToString for MercuryMethod {
     fn to_string(&self) -> String {
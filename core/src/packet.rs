@@ -0,0 +1,101 @@
+//! Building packets for the access point connection without allocating for each of them.
+
+use std::sync::Mutex;
+
+use bytes::{BufMut, BytesMut};
+use once_cell::sync::Lazy;
+
+// The number of buffers kept for reuse.
+const POOL_SIZE: usize = 64;
+// Larger buffers, e.g. of big Mercury requests, are not kept.
+const MAX_POOLED_CAPACITY: usize = 1024;
+const INITIAL_CAPACITY: usize = 64;
+
+static POOL: Lazy<Mutex<Vec<BytesMut>>> = Lazy::new(|| Mutex::new(Vec::with_capacity(POOL_SIZE)));
+
+/// Writes the payload of a packet into a buffer taken from a pool. The buffer returns to the
+/// pool once the packet has been written to the connection.
+pub struct PacketBuilder(BytesMut);
+
+impl PacketBuilder {
+    pub fn new() -> Self {
+        let buf = POOL.lock().unwrap().pop();
+        PacketBuilder(buf.unwrap_or_else(|| BytesMut::with_capacity(INITIAL_CAPACITY)))
+    }
+
+    pub fn u8(mut self, value: u8) -> Self {
+        self.0.put_u8(value);
+        self
+    }
+
+    pub fn u16(mut self, value: u16) -> Self {
+        self.0.put_u16(value);
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.0.put_u32(value);
+        self
+    }
+
+    pub fn bytes(mut self, value: &[u8]) -> Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    pub fn finish(self) -> BytesMut {
+        self.0
+    }
+}
+
+impl Default for PacketBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The payload of a packet to send: either a plain buffer, or one taken from the pool by a
+/// `PacketBuilder`, which returns to the pool once written.
+pub enum Payload {
+    Vec(Vec<u8>),
+    Pooled(BytesMut),
+}
+
+impl Payload {
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        match self {
+            Payload::Vec(data) => data,
+            Payload::Pooled(data) => data,
+        }
+    }
+
+    pub(crate) fn recycle(self) {
+        if let Payload::Pooled(buf) = self {
+            recycle(buf);
+        }
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(data: Vec<u8>) -> Self {
+        Payload::Vec(data)
+    }
+}
+
+impl From<BytesMut> for Payload {
+    fn from(data: BytesMut) -> Self {
+        Payload::Pooled(data)
+    }
+}
+
+fn recycle(mut buf: BytesMut) {
+    if buf.capacity() > MAX_POOLED_CAPACITY {
+        return;
+    }
+    buf.clear();
+
+    let mut pool = POOL.lock().unwrap();
+    if pool.len() < POOL_SIZE {
+        pool.push(buf);
+    }
+}
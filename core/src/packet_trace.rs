@@ -0,0 +1,148 @@
+//! Tracing of the decrypted packets of the access point connection, for debugging the
+//! protocol. Every packet sent or received is logged at the trace level with its command and
+//! length, and is written to a capture file if one was opened with `capture_to_file`.
+//!
+//! Capture files are in the pcap format with the link type `USER0` (147), so they can be
+//! opened with Wireshark or tcpdump. Each frame is a header of the direction (0 received,
+//! 1 sent), the command and the big endian length of the payload, followed by the payload.
+//! The payloads of the login and welcome packets are left out, as they hold credentials, and
+//! the file is only readable by its owner.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use once_cell::sync::Lazy;
+
+use crate::util::create_private_file;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_LINKTYPE_USER0: u32 = 147;
+// The frame header and the largest payload.
+const SNAPLEN: u32 = 4 + 0xffff;
+// The login and APWelcome packets, whose payloads hold credentials.
+const REDACTED_COMMANDS: [u8; 2] = [0xab, 0xac];
+
+static CAPTURE: Lazy<Mutex<Option<PcapWriter<BufWriter<File>>>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received = 0,
+    Sent = 1,
+}
+
+/// Writes the packets of all sessions to a new capture file at `path`, replacing a capture
+/// that is in progress.
+pub fn capture_to_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let file = create_private_file(path.as_ref())?;
+    let writer = PcapWriter::new(BufWriter::new(file))?;
+    *CAPTURE.lock().unwrap() = Some(writer);
+    Ok(())
+}
+
+/// Stops writing packets to the capture file and flushes it.
+pub fn stop_capture() -> io::Result<()> {
+    match CAPTURE.lock().unwrap().take() {
+        Some(mut writer) => writer.0.flush(),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn record(direction: Direction, cmd: u8, payload: &[u8]) {
+    trace!(
+        "{} packet 0x{:02x} of {} bytes",
+        match direction {
+            Direction::Received => "Received",
+            Direction::Sent => "Sent",
+        },
+        cmd,
+        payload.len()
+    );
+
+    let mut capture = CAPTURE.lock().unwrap();
+    if let Some(writer) = capture.as_mut() {
+        if let Err(e) = writer.write_packet(direction, cmd, payload) {
+            warn!("Cannot write packet capture, stopping it: {}", e);
+            *capture = None;
+        }
+    }
+}
+
+struct PcapWriter<W: Write>(W);
+
+impl<W: Write> PcapWriter<W> {
+    fn new(mut output: W) -> io::Result<Self> {
+        output.write_u32::<LittleEndian>(PCAP_MAGIC)?;
+        output.write_u16::<LittleEndian>(2)?;
+        output.write_u16::<LittleEndian>(4)?;
+        // The time zone offset and the accuracy of the timestamps.
+        output.write_i32::<LittleEndian>(0)?;
+        output.write_u32::<LittleEndian>(0)?;
+        output.write_u32::<LittleEndian>(SNAPLEN)?;
+        output.write_u32::<LittleEndian>(PCAP_LINKTYPE_USER0)?;
+        Ok(PcapWriter(output))
+    }
+
+    fn write_packet(&mut self, direction: Direction, cmd: u8, payload: &[u8]) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // The header keeps the length of a redacted payload.
+        let header_length = payload.len();
+        let payload: &[u8] = if REDACTED_COMMANDS.contains(&cmd) {
+            &[]
+        } else {
+            payload
+        };
+        let length = 4 + payload.len() as u32;
+
+        let output = &mut self.0;
+        output.write_u32::<LittleEndian>(time.as_secs() as u32)?;
+        output.write_u32::<LittleEndian>(time.subsec_micros())?;
+        output.write_u32::<LittleEndian>(length)?;
+        output.write_u32::<LittleEndian>(length)?;
+        output.write_all(&[direction as u8, cmd])?;
+        output.write_all(&(header_length as u16).to_be_bytes())?;
+        output.write_all(payload)?;
+        // Keep the capture complete up to a stall or crash being debugged.
+        output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_pcap_frames() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write_packet(Direction::Sent, 0x49, &[0, 0, 0, 0])
+            .unwrap();
+        let output = writer.0;
+
+        assert_eq!(output.len(), 24 + 16 + 8);
+        assert_eq!(output[..4], PCAP_MAGIC.to_le_bytes());
+        assert_eq!(output[20..24], PCAP_LINKTYPE_USER0.to_le_bytes());
+        // Both lengths of the frame, then its header.
+        assert_eq!(output[32..36], 8u32.to_le_bytes());
+        assert_eq!(output[36..40], 8u32.to_le_bytes());
+        assert_eq!(output[40..44], [1, 0x49, 0, 4]);
+    }
+
+    #[test]
+    fn leaves_out_credentials() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write_packet(Direction::Received, 0xac, &[1, 2, 3])
+            .unwrap();
+        let output = writer.0;
+
+        assert_eq!(output.len(), 24 + 16 + 4);
+        assert_eq!(output[32..36], 4u32.to_le_bytes());
+        assert_eq!(output[40..44], [0, 0xac, 0, 3]);
+    }
+}
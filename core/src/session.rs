@@ -1,19 +1,23 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::future::Future;
 use std::io;
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::task::Context;
 use std::task::Poll;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use byteorder::{BigEndian, ByteOrder};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures_core::TryStream;
 use futures_util::{future, StreamExt, TryStreamExt};
 use once_cell::sync::OnceCell;
 use thiserror::Error;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{Instant, Sleep};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 use crate::apresolve::apresolve;
@@ -22,8 +26,12 @@ use crate::authentication::Credentials;
 use crate::cache::Cache;
 use crate::channel::ChannelManager;
 use crate::config::SessionConfig;
-use crate::connection::{self, AuthenticationError};
+use crate::connection;
+pub use crate::connection::AuthenticationError;
 use crate::mercury::MercuryManager;
+use crate::packet::Payload;
+use crate::spawner::Spawn;
+use crate::throttle::Throttle;
 
 #[derive(Debug, Error)]
 pub enum SessionError {
@@ -31,27 +39,114 @@ pub enum SessionError {
     AuthenticationError(#[from] AuthenticationError),
     #[error("Cannot create session: {0}")]
     IoError(#[from] io::Error),
+    #[error("Cannot create session: timed out")]
+    TimedOut,
 }
 
+/// The attributes of the user's account sent by the server, e.g. `type` (the product) and
+/// `filter-explicit-content`.
+pub type UserAttributes = HashMap<String, String>;
+
+/// Information about the logged in user, updated whenever the server sends it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserData {
+    pub country: String,
+    pub attributes: UserAttributes,
+}
+
+impl UserData {
+    /// The product of the account, e.g. `premium` or `free`.
+    pub fn product(&self) -> Option<&str> {
+        self.attributes.get("type").map(String::as_str)
+    }
+
+    pub fn is_premium(&self) -> bool {
+        self.product() == Some("premium")
+    }
+
+    /// Whether explicit content must not be played, e.g. for a child in a family plan.
+    pub fn filter_explicit_content(&self) -> bool {
+        matches!(
+            self.attributes
+                .get("filter-explicit-content")
+                .map(String::as_str),
+            Some("1") | Some("true")
+        )
+    }
+}
+
+/// Why the server ended or took over a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KickReason {
+    /// The account started playing on another device.
+    PlayTokenLost,
+}
+
+impl fmt::Display for KickReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KickReason::PlayTokenLost => f.write_str("the account is playing on another device"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    Kicked {
+        reason: KickReason,
+    },
+    /// The access point stopped answering keep-alives, so the connection was closed. The
+    /// session is invalid, the application may connect a new one.
+    ConnectionTimedOut,
+}
+
+pub type SessionEventChannel = mpsc::UnboundedReceiver<SessionEvent>;
+
 struct SessionData {
-    country: String,
     time_delta: i64,
     canonical_username: String,
     invalid: bool,
+    kicked: Option<KickReason>,
+    // When the access point last pinged, and when the pong it acknowledges was sent if the
+    // acknowledgement is pending.
+    last_ping: Instant,
+    pong_sent: Option<Instant>,
 }
 
 struct SessionInternal {
     config: SessionConfig,
     data: RwLock<SessionData>,
+    credentials: Credentials,
 
-    tx_connection: mpsc::UnboundedSender<(u8, Vec<u8>)>,
+    tx_connection: mpsc::UnboundedSender<(u8, Payload)>,
 
     audio_key: OnceCell<AudioKeyManager>,
     channel: OnceCell<ChannelManager>,
     mercury: OnceCell<MercuryManager>,
     cache: Option<Arc<Cache>>,
-
-    handle: tokio::runtime::Handle,
+    throttle: Throttle,
+
+    // The receiver is kept so that sending never fails for lack of subscribers.
+    user_data_tx: watch::Sender<UserData>,
+    user_data_rx: watch::Receiver<UserData>,
+    event_senders: Mutex<Vec<mpsc::UnboundedSender<SessionEvent>>>,
+    // The session replacing this one once it is lost, see `set_successor`.
+    successor_tx: watch::Sender<Option<SessionWeak>>,
+    successor_rx: watch::Receiver<Option<SessionWeak>>,
+
+    spawner: Arc<dyn Spawn>,
+    // The runtime of the spawner, timers are registered with it.
+    handle: Option<tokio::runtime::Handle>,
+
+    // Set to true when the session is shut down.
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+    // Set to true when the connection task has finished.
+    closed_rx: watch::Receiver<bool>,
+    // Every task spawned by the session holds a clone of the sender, so the receiver
+    // returns `None` once they have all finished after the shutdown.
+    tasks_tx: Mutex<Option<mpsc::Sender<()>>>,
+    tasks_rx: Mutex<Option<mpsc::Receiver<()>>>,
 
     session_id: usize,
 }
@@ -62,30 +157,59 @@ static SESSION_COUNTER: AtomicUsize = AtomicUsize::new(0);
 pub struct Session(Arc<SessionInternal>);
 
 impl Session {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "session_connect",
+            skip_all,
+            fields(username = %credentials.username, device_id = %config.device_id)
+        )
+    )]
     pub async fn connect(
         config: SessionConfig,
         credentials: Credentials,
         cache: Option<Cache>,
     ) -> Result<Session, SessionError> {
-        let ap = apresolve(config.proxy.as_ref(), config.ap_port).await;
+        Self::connect_with_spawner(
+            config,
+            credentials,
+            cache,
+            tokio::runtime::Handle::current(),
+        )
+        .await
+    }
 
-        info!("Connecting to AP \"{}\"", ap);
-        let mut conn = connection::connect(ap, config.proxy.as_ref()).await?;
+    /// Like `connect`, but runs the background tasks of the session with `spawner` instead of
+    /// the runtime `connect` is called from. Pass a `tokio::runtime::Handle` to use a runtime
+    /// owned by the application.
+    pub async fn connect_with_spawner<S: Spawn>(
+        config: SessionConfig,
+        credentials: Credentials,
+        cache: Option<Cache>,
+        spawner: S,
+    ) -> Result<Session, SessionError> {
+        let connect = async {
+            let ap = apresolve(config.proxy.as_ref(), config.ap_port).await;
 
-        let reusable_credentials =
-            connection::authenticate(&mut conn, credentials, &config.device_id).await?;
+            info!("Connecting to AP \"{}\"", ap);
+            let mut conn = connection::connect(ap, config.proxy.as_ref()).await?;
+
+            let reusable_credentials =
+                connection::authenticate(&mut conn, credentials, &config).await?;
+            Ok::<_, SessionError>((conn, reusable_credentials))
+        };
+        let (conn, reusable_credentials) = match config.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| SessionError::TimedOut)??,
+            None => connect.await?,
+        };
         info!("Authenticated as \"{}\" !", reusable_credentials.username);
         if let Some(cache) = &cache {
             cache.save_credentials(&reusable_credentials);
         }
 
-        let session = Session::create(
-            conn,
-            config,
-            cache,
-            reusable_credentials.username,
-            tokio::runtime::Handle::current(),
-        );
+        let session = Session::create(conn, config, cache, reusable_credentials, Arc::new(spawner));
 
         Ok(session)
     }
@@ -94,30 +218,51 @@ impl Session {
         transport: connection::Transport,
         config: SessionConfig,
         cache: Option<Cache>,
-        username: String,
-        handle: tokio::runtime::Handle,
+        credentials: Credentials,
+        spawner: Arc<dyn Spawn>,
     ) -> Session {
         let (sink, stream) = transport.split();
 
         let (sender_tx, sender_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (closed_tx, closed_rx) = watch::channel(false);
+        let (tasks_tx, tasks_rx) = mpsc::channel(1);
+        let (user_data_tx, user_data_rx) = watch::channel(UserData::default());
+        let (successor_tx, successor_rx) = watch::channel(None);
         let session_id = SESSION_COUNTER.fetch_add(1, Ordering::Relaxed);
 
         debug!("new Session[{}]", session_id);
 
+        let canonical_username = credentials.username.clone();
         let session = Session(Arc::new(SessionInternal {
             config,
+            credentials,
             data: RwLock::new(SessionData {
-                country: String::new(),
-                canonical_username: username,
+                canonical_username,
                 invalid: false,
+                kicked: None,
                 time_delta: 0,
+                last_ping: Instant::now(),
+                pong_sent: None,
             }),
             tx_connection: sender_tx,
             cache: cache.map(Arc::new),
             audio_key: OnceCell::new(),
             channel: OnceCell::new(),
             mercury: OnceCell::new(),
-            handle,
+            throttle: Throttle::default(),
+            user_data_tx,
+            user_data_rx,
+            event_senders: Mutex::new(Vec::new()),
+            successor_tx,
+            successor_rx,
+            handle: spawner.handle(),
+            spawner,
+            shutdown_tx,
+            shutdown_rx: shutdown_rx.clone(),
+            closed_rx,
+            tasks_tx: Mutex::new(Some(tasks_tx)),
+            tasks_rx: Mutex::new(Some(tasks_rx)),
             session_id,
         }));
 
@@ -125,14 +270,25 @@ impl Session {
             .map(Ok)
             .forward(sink);
         let receiver_task = DispatchTask(stream, session.weak());
-
-        tokio::spawn(async move {
-            let result = future::try_join(sender_task, receiver_task).await;
-
-            if let Err(e) = result {
-                error!("{}", e);
+        let keep_alive_task = keep_alive(session.weak());
+
+        let mut shutdown_rx = shutdown_rx;
+        session.0.spawner.spawn(Box::pin(async move {
+            tokio::select! {
+                result = future::try_join(sender_task, receiver_task) => {
+                    if let Err(e) = result {
+                        error!("{}", e);
+                    }
+                }
+                // Dropping the transport closes the dead connection.
+                _ = keep_alive_task => (),
+                // Also completes when the session is dropped.
+                _ = shutdown_rx.changed() => {
+                    debug!("Closing connection of Session[{}]", session_id);
+                }
             }
-        });
+            let _ = closed_tx.send(true);
+        }));
 
         session
     }
@@ -144,9 +300,11 @@ impl Session {
     }
 
     pub fn channel(&self) -> &ChannelManager {
-        self.0
-            .channel
-            .get_or_init(|| ChannelManager::new(self.weak()))
+        self.0.channel.get_or_init(|| {
+            let channel = ChannelManager::new(self.weak());
+            channel.set_download_rate_window(self.config().download_rate_window);
+            channel
+        })
     }
 
     pub fn mercury(&self) -> &MercuryManager {
@@ -164,7 +322,45 @@ impl Session {
         T: Future + Send + 'static,
         T::Output: Send + 'static,
     {
-        self.0.handle.spawn(task);
+        let guard = self.0.tasks_tx.lock().unwrap().clone();
+        self.0.spawner.spawn(Box::pin(async move {
+            let _guard = guard;
+            task.await;
+        }));
+    }
+
+    /// Runs the blocking `f`, e.g. file I/O, off the threads of async tasks and returns its
+    /// result. It runs on the blocking pool of the session's runtime, or on a thread of its own
+    /// if the spawner has no runtime.
+    pub async fn spawn_blocking<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        match self.0.handle {
+            Some(ref handle) => handle
+                .spawn_blocking(f)
+                .await
+                .expect("blocking task panicked"),
+            None => {
+                let (tx, rx) = oneshot::channel();
+                thread::spawn(move || {
+                    let _ = tx.send(f());
+                });
+                rx.await.expect("blocking task panicked")
+            }
+        }
+    }
+
+    pub(crate) fn throttle(&self) -> &Throttle {
+        &self.0.throttle
+    }
+
+    pub(crate) fn sleep(&self, duration: Duration) -> Sleep {
+        // Timers may be created on threads outside of the runtime (e.g. the player thread),
+        // so they have to be registered with the session's runtime explicitly.
+        let _guard = self.0.handle.as_ref().map(|handle| handle.enter());
+        tokio::time::sleep(duration)
     }
 
     fn debug_info(&self) {
@@ -187,16 +383,28 @@ impl Session {
                 }
                 .as_secs() as i64;
 
-                self.0.data.write().unwrap().time_delta = server_timestamp - timestamp;
+                {
+                    let mut data = self.0.data.write().unwrap();
+                    data.time_delta = server_timestamp - timestamp;
+                    let now = Instant::now();
+                    data.last_ping = now;
+                    data.pong_sent = Some(now);
+                }
 
                 self.debug_info();
                 self.send_packet(0x49, vec![0, 0, 0, 0]);
             }
-            0x4a => (),
+            0x4a => self.0.data.write().unwrap().pong_sent = None,
+            0x4b => self.kick(KickReason::PlayTokenLost),
             0x1b => {
-                let country = String::from_utf8(data.as_ref().to_owned()).unwrap();
+                let country = String::from_utf8_lossy(data.as_ref()).into_owned();
                 info!("Country: {:?}", country);
-                self.0.data.write().unwrap().country = country;
+                self.update_user_data(|user_data| user_data.country = country);
+            }
+            0x50 => {
+                let attributes = parse_product_info(&String::from_utf8_lossy(data.as_ref()));
+                debug!("User attributes: {:?}", attributes);
+                self.update_user_data(|user_data| user_data.attributes = attributes);
             }
 
             0x9 | 0xa => self.channel().dispatch(cmd, data),
@@ -207,7 +415,12 @@ impl Session {
     }
 
     pub fn send_packet(&self, cmd: u8, data: Vec<u8>) {
-        self.0.tx_connection.send((cmd, data)).unwrap();
+        self.0.tx_connection.send((cmd, data.into())).unwrap();
+    }
+
+    /// Sends a packet built with a `PacketBuilder`.
+    pub fn send_packet_bytes(&self, cmd: u8, data: BytesMut) {
+        self.0.tx_connection.send((cmd, data.into())).unwrap();
     }
 
     pub fn cache(&self) -> Option<&Arc<Cache>> {
@@ -218,43 +431,212 @@ impl Session {
         &self.0.config
     }
 
+    /// Downloads an image, e.g. a cover from `Image::url` of the metadata, through the proxy
+    /// of the session if it has one.
+    #[cfg(feature = "images")]
+    pub async fn fetch_image(&self, url: &str) -> Result<Bytes, crate::Error> {
+        crate::images::fetch(url, self.config().proxy.as_ref()).await
+    }
+
+    /// The reusable credentials returned by the server when this session was authenticated.
+    pub fn credentials(&self) -> &Credentials {
+        &self.0.credentials
+    }
+
     pub fn username(&self) -> String {
         self.0.data.read().unwrap().canonical_username.clone()
     }
 
     pub fn country(&self) -> String {
-        self.0.data.read().unwrap().country.clone()
+        self.0.user_data_rx.borrow().country.clone()
+    }
+
+    /// The country and account attributes of the user as last sent by the server.
+    pub fn user_data(&self) -> UserData {
+        self.0.user_data_rx.borrow().clone()
+    }
+
+    /// A receiver that is notified whenever the country or the attributes of the user change,
+    /// e.g. when the account is upgraded or the explicit content filter is toggled.
+    pub fn user_data_changes(&self) -> watch::Receiver<UserData> {
+        self.0.user_data_rx.clone()
+    }
+
+    fn update_user_data<F: FnOnce(&mut UserData)>(&self, f: F) {
+        let mut user_data = self.user_data();
+        f(&mut user_data);
+        if user_data != *self.0.user_data_rx.borrow() {
+            let _ = self.0.user_data_tx.send(user_data);
+        }
     }
 
     pub fn device_id(&self) -> &str {
         &self.config().device_id
     }
 
+    /// The version of the client, as configured in `SessionConfig::version_string`.
+    pub fn version_string(&self) -> &str {
+        &self.config().version_string
+    }
+
     fn weak(&self) -> SessionWeak {
         SessionWeak(Arc::downgrade(&self.0))
     }
 
+    /// Marks `session` as the replacement of this session, e.g. connected after the
+    /// connection of this one was lost. The Mercury subscriptions of this session are made
+    /// again with it.
+    pub fn set_successor(&self, session: &Session) {
+        let _ = self.0.successor_tx.send(Some(session.weak()));
+    }
+
+    pub(crate) fn successor(&self) -> watch::Receiver<Option<SessionWeak>> {
+        self.0.successor_rx.clone()
+    }
+
     pub fn session_id(&self) -> usize {
         self.0.session_id
     }
 
-    pub fn shutdown(&self) {
+    fn invalidate(&self) {
         debug!("Invalidating session[{}]", self.0.session_id);
         self.0.data.write().unwrap().invalid = true;
         self.mercury().shutdown();
         self.channel().shutdown();
+        self.0.tasks_tx.lock().unwrap().take();
+        let _ = self.0.shutdown_tx.send(true);
+    }
+
+    /// Shuts the session down: closes the connection, fails pending requests and stops
+    /// the tasks spawned by the session, e.g. audio file fetchers. Resolves when all of them
+    /// have terminated, so it must not be awaited from within such a task.
+    pub async fn shutdown(&self) {
+        self.invalidate();
+
+        let mut closed = self.0.closed_rx.clone();
+        while !*closed.borrow() {
+            if closed.changed().await.is_err() {
+                break;
+            }
+        }
+
+        let tasks = self.0.tasks_rx.lock().unwrap().take();
+        if let Some(mut tasks) = tasks {
+            // Only completes when all senders are gone, nothing is ever sent.
+            let _ = tasks.recv().await;
+        }
+        debug!("Session[{}] shut down", self.0.session_id);
+    }
+
+    /// Resolves when the session is shut down. Long running tasks use this to terminate.
+    pub async fn shutdown_requested(&self) {
+        let mut shutdown = self.0.shutdown_rx.clone();
+        while !*shutdown.borrow() {
+            if shutdown.changed().await.is_err() {
+                break;
+            }
+        }
     }
 
     pub fn is_invalid(&self) -> bool {
         self.0.data.read().unwrap().invalid
     }
+
+    /// Returns a channel receiving the events of the session, e.g. when it is kicked.
+    pub fn get_event_channel(&self) -> SessionEventChannel {
+        let (event_sender, event_receiver) = mpsc::unbounded_channel();
+        self.0.event_senders.lock().unwrap().push(event_sender);
+        event_receiver
+    }
+
+    /// Why the session was kicked, if it was.
+    pub fn kick_reason(&self) -> Option<KickReason> {
+        self.0.data.read().unwrap().kicked
+    }
+
+    // When the connection is considered dead if no ping or pong acknowledgement arrives.
+    fn keep_alive_deadline(&self) -> Option<Instant> {
+        let timeout = self.config().keep_alive_timeout?;
+        let data = self.0.data.read().unwrap();
+        let interval = self
+            .config()
+            .keep_alive_interval
+            .max(SessionConfig::MIN_KEEP_ALIVE_INTERVAL);
+        let ping_due = data.last_ping + interval + timeout;
+        Some(match data.pong_sent {
+            Some(sent) => ping_due.min(sent + timeout),
+            None => ping_due,
+        })
+    }
+
+    fn connection_timed_out(&self) {
+        warn!(
+            "Session[{}]: the access point stopped answering keep-alives, closing the connection",
+            self.0.session_id
+        );
+        self.0
+            .event_senders
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send(SessionEvent::ConnectionTimedOut).is_ok());
+        self.invalidate();
+    }
+
+    fn kick(&self, reason: KickReason) {
+        warn!("Session[{}] kicked: {}", self.0.session_id, reason);
+        self.0.data.write().unwrap().kicked = Some(reason);
+        self.0
+            .event_senders
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send(SessionEvent::Kicked { reason }).is_ok());
+    }
+}
+
+/// Parses the attributes of the first product of a product info packet, which looks like
+/// `<products><product><type>premium</type>...</product></products>`.
+fn parse_product_info(xml: &str) -> UserAttributes {
+    let mut attributes = UserAttributes::new();
+
+    let product = match xml.find("<product>") {
+        Some(start) => &xml[start + "<product>".len()..],
+        None => return attributes,
+    };
+    let product = &product[..product.find("</product>").unwrap_or_else(|| product.len())];
+
+    let mut rest = product;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+
+        if tag.starts_with('/') || tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+        if let Some(name) = tag.strip_suffix('/') {
+            attributes.insert(name.trim().to_owned(), String::new());
+            continue;
+        }
+
+        let close = format!("</{}>", tag);
+        if let Some(value_end) = rest.find(&close) {
+            attributes.insert(tag.to_owned(), rest[..value_end].trim().to_owned());
+            rest = &rest[value_end + close.len()..];
+        }
+    }
+
+    attributes
 }
 
 #[derive(Clone)]
 pub struct SessionWeak(Weak<SessionInternal>);
 
 impl SessionWeak {
-    fn try_upgrade(&self) -> Option<Session> {
+    pub(crate) fn try_upgrade(&self) -> Option<Session> {
         self.0.upgrade().map(Session)
     }
 
@@ -269,6 +651,31 @@ impl Drop for SessionInternal {
     }
 }
 
+// Resolves when the connection of the session is found dead, after invalidating the session.
+// Never resolves if keep-alive timeouts are disabled.
+async fn keep_alive(session: SessionWeak) {
+    loop {
+        let deadline = match session.try_upgrade().and_then(|s| s.keep_alive_deadline()) {
+            Some(deadline) => deadline,
+            None => return future::pending().await,
+        };
+        tokio::time::sleep_until(deadline).await;
+
+        let session = match session.try_upgrade() {
+            Some(session) => session,
+            None => return future::pending().await,
+        };
+        // A ping or acknowledgement may have moved the deadline while sleeping.
+        if session
+            .keep_alive_deadline()
+            .map_or(false, |deadline| deadline <= Instant::now())
+        {
+            session.connection_timed_out();
+            return;
+        }
+    }
+}
+
 struct DispatchTask<S>(S, SessionWeak)
 where
     S: TryStream<Ok = (u8, Bytes)> + Unpin;
@@ -291,11 +698,11 @@ where
                 Poll::Ready(Some(Ok(t))) => t,
                 Poll::Ready(None) => {
                     warn!("Connection to server closed.");
-                    session.shutdown();
+                    session.invalidate();
                     return Poll::Ready(Ok(()));
                 }
                 Poll::Ready(Some(Err(e))) => {
-                    session.shutdown();
+                    session.invalidate();
                     return Poll::Ready(Err(e));
                 }
                 Poll::Pending => return Poll::Pending,
@@ -314,3 +721,32 @@ where
         debug!("drop Dispatch");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_product_info() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8" ?><products><product>
+            <type>premium</type><catalogue>premium</catalogue>
+            <filter-explicit-content>1</filter-explicit-content><ads/>
+            </product></products>"#;
+
+        let user_data = UserData {
+            country: "SE".to_owned(),
+            attributes: parse_product_info(xml),
+        };
+        assert_eq!(user_data.product(), Some("premium"));
+        assert!(user_data.is_premium());
+        assert!(user_data.filter_explicit_content());
+        assert_eq!(user_data.attributes.get("ads").map(String::as_str), Some(""));
+        assert_eq!(user_data.attributes.len(), 4);
+    }
+
+    #[test]
+    fn missing_product_yields_no_attributes() {
+        assert!(parse_product_info("<products></products>").is_empty());
+        assert!(!UserData::default().filter_explicit_content());
+    }
+}
@@ -0,0 +1,40 @@
+//! Running the background tasks of a session on an executor of the caller's choice.
+
+use std::future::Future;
+use std::pin::Pin;
+
+pub type BoxedTask = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Spawns the background tasks of a session, i.e. the connection and the audio file
+/// fetchers.
+///
+/// The tasks use tokio's timers and sockets, so they must be polled within the context of a
+/// tokio runtime. This may be a current-thread runtime driven by the application.
+pub trait Spawn: Send + Sync + 'static {
+    fn spawn(&self, task: BoxedTask);
+
+    /// The runtime the tasks run on. Timers created outside of it, e.g. on the player thread,
+    /// are registered with it. Defaults to the runtime the session is created from, if any.
+    fn handle(&self) -> Option<tokio::runtime::Handle> {
+        tokio::runtime::Handle::try_current().ok()
+    }
+}
+
+impl Spawn for tokio::runtime::Handle {
+    fn spawn(&self, task: BoxedTask) {
+        tokio::runtime::Handle::spawn(self, task);
+    }
+
+    fn handle(&self) -> Option<tokio::runtime::Handle> {
+        Some(self.clone())
+    }
+}
+
+impl<F> Spawn for F
+where
+    F: Fn(BoxedTask) + Send + Sync + 'static,
+{
+    fn spawn(&self, task: BoxedTask) {
+        self(task)
+    }
+}
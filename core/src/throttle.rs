@@ -0,0 +1,109 @@
+use std::cmp;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const REQUEST_INTERVAL: Duration = Duration::from_millis(50);
+// The sustained rate at which requests are sent to the access point once the burst allowance
+// has been used up, i.e. 20 requests per second.
+
+const REQUEST_BURST: u32 = 20;
+// The number of requests that can be sent back to back without being delayed.
+
+const MAX_BACK_OFF: Duration = Duration::from_secs(60);
+// Upper bound for a back off requested by the server, to avoid stalling forever on bogus hints.
+
+struct ThrottleState {
+    // The point in time at which the next request would be sent if there was no burst allowance.
+    next: Instant,
+    blocked_until: Option<Instant>,
+}
+
+/// Spaces out requests to the Spotify servers.
+///
+/// Short bursts are let through unchanged, but longer runs of requests (e.g. when loading
+/// a large playlist) are delayed so the account doesn't get temporarily blocked. When the
+/// server signals that we are being rate limited, all requests are held back for a while.
+pub struct Throttle {
+    interval: Duration,
+    burst: u32,
+    state: Mutex<ThrottleState>,
+}
+
+impl Throttle {
+    pub fn new(interval: Duration, burst: u32) -> Throttle {
+        Throttle {
+            interval,
+            burst,
+            state: Mutex::new(ThrottleState {
+                next: Instant::now(),
+                blocked_until: None,
+            }),
+        }
+    }
+
+    /// Reserves a slot for a request and returns how long to wait before sending it.
+    pub fn reserve(&self) -> Duration {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        let blocked_until = match state.blocked_until {
+            Some(instant) if instant > now => instant,
+            _ => {
+                state.blocked_until = None;
+                now
+            }
+        };
+
+        let start = cmp::max(state.next, blocked_until);
+        state.next = start + self.interval;
+
+        let earliest = state
+            .next
+            .checked_sub(self.interval * self.burst)
+            .unwrap_or(now);
+
+        cmp::max(earliest, blocked_until).saturating_duration_since(now)
+    }
+
+    /// Holds back all requests for the given duration, e.g. after the server rejected a request
+    /// because of rate limiting.
+    pub fn back_off(&self, delay: Duration) {
+        let delay = cmp::min(delay, MAX_BACK_OFF);
+        let until = Instant::now() + delay;
+        let mut state = self.state.lock().unwrap();
+
+        if state.blocked_until.map_or(true, |instant| instant < until) {
+            warn!("Rate limited, holding back requests for {:?}", delay);
+            state.blocked_until = Some(until);
+        }
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Throttle {
+        Throttle::new(REQUEST_INTERVAL, REQUEST_BURST)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_not_delayed() {
+        let throttle = Throttle::new(Duration::from_secs(1), 3);
+
+        for _ in 0..3 {
+            assert_eq!(throttle.reserve(), Duration::from_secs(0));
+        }
+        assert!(throttle.reserve() > Duration::from_millis(900));
+    }
+
+    #[test]
+    fn back_off_delays_requests() {
+        let throttle = Throttle::new(Duration::from_millis(1), 10);
+
+        throttle.back_off(Duration::from_secs(5));
+        assert!(throttle.reserve() > Duration::from_secs(4));
+    }
+}
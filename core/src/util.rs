@@ -1,4 +1,9 @@
+use std::fs::{File, OpenOptions, Permissions};
+use std::io;
 use std::mem;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
 
 pub trait Seq {
     fn next(&self) -> Self;
@@ -27,3 +32,17 @@ impl<T: Seq> SeqGenerator<T> {
         mem::replace(&mut self.0, value)
     }
 }
+
+/// Creates or truncates the file at `path` so that only its owner can read and write it, for
+/// files holding credentials.
+pub fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let file = options.open(path)?;
+    // The mode only applies to new files.
+    #[cfg(unix)]
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    Ok(file)
+}
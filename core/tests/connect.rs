@@ -1,6 +1,6 @@
 use librespot_core::authentication::Credentials;
 use librespot_core::config::SessionConfig;
-use librespot_core::session::Session;
+use librespot_core::session::{AuthenticationError, Session, SessionError};
 
 #[tokio::test]
 async fn test_connection() {
@@ -13,6 +13,12 @@ async fn test_connection() {
 
     match result {
         Ok(_) => panic!("Authentication succeeded despite of bad credentials."),
-        Err(e) => assert_eq!(e.to_string(), "Login failed with reason: Bad credentials"),
+        Err(e) => {
+            assert_eq!(e.to_string(), "Login failed with reason: Bad credentials");
+            assert!(matches!(
+                e,
+                SessionError::AuthenticationError(AuthenticationError::BadCredentials)
+            ));
+        }
     };
 }
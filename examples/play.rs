@@ -46,7 +46,9 @@ async fn main() {
     
     let album = Album::get(&session, track_meta.album).await.unwrap();
     println!("album:{}", album.name);
-    println!("cover:{}", album.covers[0]);
+    if let Some(cover) = album.covers.largest() {
+        println!("cover:{}", cover.url());
+    }
 
     for artist_id in album.artists {
         let artist = Artist::get(&session, artist_id).await.unwrap();
@@ -0,0 +1,218 @@
+#ifndef LIBRESPOT_H
+#define LIBRESPOT_H
+
+/* Generated by cbindgen from ffi/src/lib.rs, do not edit. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef enum LibrespotEventType {
+  LIBRESPOT_EVENT_TYPE_STOPPED,
+  LIBRESPOT_EVENT_TYPE_STARTED,
+  LIBRESPOT_EVENT_TYPE_CHANGED,
+  LIBRESPOT_EVENT_TYPE_LOADING,
+  LIBRESPOT_EVENT_TYPE_PRELOADING,
+  LIBRESPOT_EVENT_TYPE_PLAYING,
+  LIBRESPOT_EVENT_TYPE_PAUSED,
+  LIBRESPOT_EVENT_TYPE_TIME_TO_PRELOAD_NEXT_TRACK,
+  LIBRESPOT_EVENT_TYPE_END_OF_TRACK,
+  LIBRESPOT_EVENT_TYPE_UNAVAILABLE,
+  LIBRESPOT_EVENT_TYPE_VOLUME_SET,
+  LIBRESPOT_EVENT_TYPE_TRACK_CHANGED,
+  LIBRESPOT_EVENT_TYPE_LOADING_PROGRESS,
+  LIBRESPOT_EVENT_TYPE_FORMAT_CHANGED,
+  LIBRESPOT_EVENT_TYPE_TRACK_FILTERED,
+  LIBRESPOT_EVENT_TYPE_TRACK_TRANSITION,
+  LIBRESPOT_EVENT_TYPE_TRACK_RELINKED,
+  LIBRESPOT_EVENT_TYPE_OUTPUT_CHANGED,
+  LIBRESPOT_EVENT_TYPE_SINK_STALLED,
+  LIBRESPOT_EVENT_TYPE_SINK_RECOVERED,
+} LibrespotEventType;
+
+/**
+ * Why playback moved on from a track, for `TrackTransition` events.
+ */
+typedef enum LibrespotTransitionReason {
+  /**
+   * The event isn't a `TrackTransition`.
+   */
+  LIBRESPOT_TRANSITION_REASON_NONE,
+  LIBRESPOT_TRANSITION_REASON_FINISHED,
+  LIBRESPOT_TRANSITION_REASON_SKIPPED,
+  LIBRESPOT_TRANSITION_REASON_ERROR,
+  LIBRESPOT_TRANSITION_REASON_REMOTE_CHANGE,
+} LibrespotTransitionReason;
+
+/**
+ * A player of a session.
+ */
+typedef struct LibrespotPlayer LibrespotPlayer;
+
+/**
+ * A connected session.
+ */
+typedef struct LibrespotSession LibrespotSession;
+
+/**
+ * Called from the player thread with interleaved 16 bit stereo samples at 44100 Hz.
+ * `length` is the number of samples, not frames. Blocking in the callback paces playback,
+ * the way a sound card would.
+ */
+typedef void (*LibrespotPcmCallback)(const int16_t *samples, size_t length, void *user_data);
+
+/**
+ * A player event. Fields that do not apply to the event type are zero, or `NULL` for
+ * `track_uri`. The event and its strings are only valid during the callback.
+ */
+typedef struct LibrespotEvent {
+  enum LibrespotEventType event_type;
+  const char *track_uri;
+  uint32_t position_ms;
+  uint32_t duration_ms;
+  uint16_t volume;
+  /**
+   * The track played next for `TrackTransition`, whose `track_uri` is the previous one.
+   */
+  const char *to_track_uri;
+  enum LibrespotTransitionReason reason;
+  /**
+   * The alternative the player loaded for `TrackRelinked`, whose `track_uri` is the
+   * requested track.
+   */
+  const char *relinked_track_uri;
+} LibrespotEvent;
+
+/**
+ * Called with each player event, on a thread owned by the session.
+ */
+typedef void (*LibrespotEventCallback)(const struct LibrespotEvent *event, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Returns a description of the last error on the calling thread, or `NULL`. The string is
+ * valid until the next call into librespot on the same thread.
+ */
+const char *librespot_last_error(void);
+
+/**
+ * Connects to Spotify, blocking until the session is authenticated.
+ *
+ * `cache_dir` may be `NULL`. If it is set, the credentials of a previous session are
+ * reused when `username` and `password` are `NULL`, and audio files are cached.
+ *
+ * # Safety
+ *
+ * All arguments must be `NULL` or valid NUL-terminated strings.
+ */
+struct LibrespotSession *librespot_session_connect(const char *username,
+                                                   const char *password,
+                                                   const char *cache_dir);
+
+/**
+ * Disconnects and frees a session. Its players must be freed first.
+ *
+ * # Safety
+ *
+ * `session` must be `NULL` or returned by `librespot_session_connect`, and is invalid
+ * afterwards.
+ */
+void librespot_session_free(struct LibrespotSession *session);
+
+/**
+ * Creates a player. If `pcm_callback` is `NULL`, audio is played on the default audio
+ * backend, otherwise it is passed to the callback together with `user_data`.
+ *
+ * # Safety
+ *
+ * `session` must be valid. `user_data` must be usable from the player thread.
+ */
+struct LibrespotPlayer *librespot_player_new(const struct LibrespotSession *session,
+                                             LibrespotPcmCallback pcm_callback,
+                                             void *user_data);
+
+/**
+ * Sets the callback receiving player events, `NULL` removes it.
+ *
+ * # Safety
+ *
+ * `player` must be valid. `user_data` must be usable from any thread.
+ */
+void librespot_player_set_event_callback(struct LibrespotPlayer *player,
+                                         LibrespotEventCallback callback,
+                                         void *user_data);
+
+/**
+ * Loads a track by its URI, e.g. `spotify:track:...`.
+ *
+ * # Safety
+ *
+ * `player` must be valid, `uri` a valid NUL-terminated string.
+ */
+int librespot_player_load(struct LibrespotPlayer *player,
+                          const char *uri,
+                          bool start_playing,
+                          uint32_t position_ms);
+
+/**
+ * # Safety
+ *
+ * `player` must be `NULL` or valid.
+ */
+void librespot_player_play(struct LibrespotPlayer *player);
+
+/**
+ * # Safety
+ *
+ * `player` must be `NULL` or valid.
+ */
+void librespot_player_pause(struct LibrespotPlayer *player);
+
+/**
+ * # Safety
+ *
+ * `player` must be `NULL` or valid.
+ */
+void librespot_player_stop(struct LibrespotPlayer *player);
+
+/**
+ * # Safety
+ *
+ * `player` must be `NULL` or valid.
+ */
+void librespot_player_seek(struct LibrespotPlayer *player, uint32_t position_ms);
+
+/**
+ * Attenuates the output to `level`, from 0.0 to 1.0, within `fade_ms` until
+ * `librespot_player_unduck()`, e.g. while a voice assistant talks.
+ *
+ * # Safety
+ *
+ * `player` must be `NULL` or valid.
+ */
+void librespot_player_duck(struct LibrespotPlayer *player, float level, uint32_t fade_ms);
+
+/**
+ * # Safety
+ *
+ * `player` must be `NULL` or valid.
+ */
+void librespot_player_unduck(struct LibrespotPlayer *player);
+
+/**
+ * Stops and frees a player, blocking until its thread has finished.
+ *
+ * # Safety
+ *
+ * `player` must be `NULL` or returned by `librespot_player_new`, and is invalid afterwards.
+ */
+void librespot_player_free(struct LibrespotPlayer *player);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* LIBRESPOT_H */
@@ -0,0 +1,579 @@
+//! C bindings for librespot.
+//!
+//! All functions are declared in `include/librespot.h`, which is generated from this file
+//! by cbindgen. Functions returning a pointer return `NULL` on failure, functions
+//! returning an `int` return `0` on success and `-1` on failure. In both cases
+//! `librespot_last_error()` describes what went wrong.
+
+#[macro_use]
+extern crate log;
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use librespot_audio::{convert, AudioPacket};
+use librespot_core::authentication::Credentials;
+use librespot_core::cache::Cache;
+use librespot_core::config::SessionConfig;
+use librespot_core::session::Session;
+use librespot_core::spotify_id::SpotifyId;
+use librespot_playback::audio_backend::{self, Sink};
+use librespot_playback::config::{AudioFormat, PlayerConfig};
+use librespot_playback::player::{Player, PlayerEvent, TrackTransitionReason};
+use tokio::runtime::Runtime;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error<E: ToString>(error: E) {
+    let message = CString::new(error.to_string().replace('\0', ""))
+        .unwrap_or_else(|_| CString::new("unknown error").unwrap());
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs the body of an exported function. A panic must not unwind into the application, so
+/// it is reported as an error with `error_value` returned instead.
+fn guard<T>(error_value: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|_| {
+        set_last_error("librespot panicked");
+        error_value
+    })
+}
+
+/// Reads a C string argument, `NULL` is read as `None`.
+unsafe fn string_arg(arg: *const c_char) -> Result<Option<String>, &'static str> {
+    if arg.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(arg)
+        .to_str()
+        .map(|s| Some(s.to_owned()))
+        .map_err(|_| "string argument is not valid UTF-8")
+}
+
+/// A pointer to user data, passed back to the callbacks unchanged.
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+
+// The user data is owned by the application, which must make sure it can be used from the
+// threads the callbacks are called on.
+unsafe impl Send for UserData {}
+
+/// A connected session.
+pub struct LibrespotSession {
+    session: Session,
+    runtime: Runtime,
+}
+
+/// A player of a session.
+pub struct LibrespotPlayer {
+    player: Player,
+    event_callback: Arc<Mutex<Option<EventCallback>>>,
+}
+
+type EventCallback = (
+    unsafe extern "C" fn(*const LibrespotEvent, *mut c_void),
+    UserData,
+);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibrespotEventType {
+    Stopped,
+    Started,
+    Changed,
+    Loading,
+    Preloading,
+    Playing,
+    Paused,
+    TimeToPreloadNextTrack,
+    EndOfTrack,
+    Unavailable,
+    VolumeSet,
+    TrackChanged,
+    LoadingProgress,
+    FormatChanged,
+    TrackFiltered,
+    TrackTransition,
+    TrackRelinked,
+    OutputChanged,
+    SinkStalled,
+    SinkRecovered,
+}
+
+/// Why playback moved on from a track, for `TrackTransition` events.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibrespotTransitionReason {
+    /// The event isn't a `TrackTransition`.
+    None,
+    Finished,
+    Skipped,
+    Error,
+    RemoteChange,
+}
+
+/// A player event. Fields that do not apply to the event type are zero, or `NULL` for
+/// `track_uri`. The event and its strings are only valid during the callback.
+#[repr(C)]
+pub struct LibrespotEvent {
+    pub event_type: LibrespotEventType,
+    pub track_uri: *const c_char,
+    pub position_ms: u32,
+    pub duration_ms: u32,
+    pub volume: u16,
+    /// The track played next for `TrackTransition`, whose `track_uri` is the previous one.
+    pub to_track_uri: *const c_char,
+    pub reason: LibrespotTransitionReason,
+    /// The alternative the player loaded for `TrackRelinked`, whose `track_uri` is the
+    /// requested track.
+    pub relinked_track_uri: *const c_char,
+}
+
+/// Called with each player event, on a thread owned by the session.
+pub type LibrespotEventCallback =
+    Option<unsafe extern "C" fn(event: *const LibrespotEvent, user_data: *mut c_void)>;
+
+/// Called from the player thread with interleaved 16 bit stereo samples at 44100 Hz.
+/// `length` is the number of samples, not frames. Blocking in the callback paces playback,
+/// the way a sound card would.
+pub type LibrespotPcmCallback =
+    Option<unsafe extern "C" fn(samples: *const i16, length: usize, user_data: *mut c_void)>;
+
+/// Hands decoded audio to a `LibrespotPcmCallback`.
+struct CallbackSink {
+    callback: unsafe extern "C" fn(*const i16, usize, *mut c_void),
+    user_data: UserData,
+}
+
+impl Sink for CallbackSink {
+    fn start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+        if let AudioPacket::Samples(samples) = packet {
+            let samples = convert::to_s16(samples);
+            unsafe { (self.callback)(samples.as_ptr(), samples.len(), self.user_data.0) };
+        }
+        Ok(())
+    }
+}
+
+impl From<&PlayerEvent> for LibrespotEventType {
+    fn from(event: &PlayerEvent) -> Self {
+        use LibrespotEventType::*;
+        match event {
+            PlayerEvent::Stopped { .. } => Stopped,
+            PlayerEvent::Started { .. } => Started,
+            PlayerEvent::Changed { .. } => Changed,
+            PlayerEvent::Loading { .. } => Loading,
+            PlayerEvent::Preloading { .. } => Preloading,
+            PlayerEvent::Playing { .. } => Playing,
+            PlayerEvent::Paused { .. } => Paused,
+            PlayerEvent::TimeToPreloadNextTrack { .. } => TimeToPreloadNextTrack,
+            PlayerEvent::EndOfTrack { .. } => EndOfTrack,
+            PlayerEvent::Unavailable { .. } => Unavailable,
+            PlayerEvent::VolumeSet { .. } => VolumeSet,
+            PlayerEvent::TrackChanged { .. } => TrackChanged,
+            PlayerEvent::LoadingProgress { .. } => LoadingProgress,
+            PlayerEvent::FormatChanged { .. } => FormatChanged,
+            PlayerEvent::TrackFiltered { .. } => TrackFiltered,
+            PlayerEvent::TrackTransition { .. } => TrackTransition,
+            PlayerEvent::TrackRelinked { .. } => TrackRelinked,
+            PlayerEvent::OutputChanged { .. } => OutputChanged,
+            PlayerEvent::SinkStalled { .. } => SinkStalled,
+            PlayerEvent::SinkRecovered { .. } => SinkRecovered,
+        }
+    }
+}
+
+impl From<Option<TrackTransitionReason>> for LibrespotTransitionReason {
+    fn from(reason: Option<TrackTransitionReason>) -> Self {
+        match reason {
+            None => Self::None,
+            Some(TrackTransitionReason::Finished) => Self::Finished,
+            Some(TrackTransitionReason::Skipped) => Self::Skipped,
+            Some(TrackTransitionReason::Error) => Self::Error,
+            Some(TrackTransitionReason::RemoteChange) => Self::RemoteChange,
+        }
+    }
+}
+
+fn uri_string(id: Option<SpotifyId>) -> Option<CString> {
+    id.and_then(|id| CString::new(id.to_uri()).ok())
+}
+
+fn dispatch_event(event: &PlayerEvent, (callback, user_data): EventCallback) {
+    let fields = event.fields();
+    let track_uri = uri_string(fields.track_id);
+    let to_track_uri = uri_string(fields.to_track_id);
+    let relinked_track_uri = uri_string(fields.relinked_id);
+    let event = LibrespotEvent {
+        event_type: event.into(),
+        track_uri: track_uri.as_ref().map_or(ptr::null(), |uri| uri.as_ptr()),
+        position_ms: fields.position_ms.unwrap_or_default(),
+        duration_ms: fields.duration_ms.unwrap_or_default(),
+        volume: fields.volume.unwrap_or_default(),
+        to_track_uri: to_track_uri
+            .as_ref()
+            .map_or(ptr::null(), |uri| uri.as_ptr()),
+        reason: fields.reason.into(),
+        relinked_track_uri: relinked_track_uri
+            .as_ref()
+            .map_or(ptr::null(), |uri| uri.as_ptr()),
+    };
+
+    unsafe { callback(&event, user_data.0) };
+}
+
+/// Returns a description of the last error on the calling thread, or `NULL`. The string is
+/// valid until the next call into librespot on the same thread.
+#[no_mangle]
+pub extern "C" fn librespot_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Connects to Spotify, blocking until the session is authenticated.
+///
+/// `cache_dir` may be `NULL`. If it is set, the credentials of a previous session are
+/// reused when `username` and `password` are `NULL`, and audio files are cached.
+///
+/// # Safety
+///
+/// All arguments must be `NULL` or valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_session_connect(
+    username: *const c_char,
+    password: *const c_char,
+    cache_dir: *const c_char,
+) -> *mut LibrespotSession {
+    guard(ptr::null_mut(), || {
+        let (username, password, cache_dir) = match (
+            string_arg(username),
+            string_arg(password),
+            string_arg(cache_dir),
+        ) {
+            (Ok(username), Ok(password), Ok(cache_dir)) => (username, password, cache_dir),
+            _ => {
+                set_last_error("string argument is not valid UTF-8");
+                return ptr::null_mut();
+            }
+        };
+
+        let cache = match cache_dir {
+            Some(dir) => {
+                let dir = Path::new(&dir);
+                match Cache::new(Some(dir), Some(dir.join("files").as_path())) {
+                    Ok(cache) => Some(cache),
+                    Err(e) => {
+                        set_last_error(format!("cannot create cache: {}", e));
+                        return ptr::null_mut();
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let credentials = match (username, password) {
+            (Some(username), Some(password)) => Credentials::with_password(username, password),
+            _ => match cache.as_ref().and_then(Cache::credentials) {
+                Some(credentials) => credentials,
+                None => {
+                    set_last_error("no credentials given and none cached");
+                    return ptr::null_mut();
+                }
+            },
+        };
+
+        let runtime = match Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                set_last_error(format!("cannot start the runtime: {}", e));
+                return ptr::null_mut();
+            }
+        };
+
+        let config = SessionConfig {
+            device_id: cache
+                .as_ref()
+                .and_then(Cache::device_id)
+                .unwrap_or_else(|| SessionConfig::default().device_id),
+            ..SessionConfig::default()
+        };
+
+        match runtime.block_on(Session::connect(config, credentials, cache)) {
+            Ok(session) => Box::into_raw(Box::new(LibrespotSession { session, runtime })),
+            Err(e) => {
+                set_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Disconnects and frees a session. Its players must be freed first.
+///
+/// # Safety
+///
+/// `session` must be `NULL` or returned by `librespot_session_connect`, and is invalid
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_session_free(session: *mut LibrespotSession) {
+    guard((), || {
+        if session.is_null() {
+            return;
+        }
+        let session = Box::from_raw(session);
+        session.runtime.block_on(session.session.shutdown());
+    })
+}
+
+/// Creates a player. If `pcm_callback` is `NULL`, audio is played on the default audio
+/// backend, otherwise it is passed to the callback together with `user_data`.
+///
+/// # Safety
+///
+/// `session` must be valid. `user_data` must be usable from the player thread.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_new(
+    session: *const LibrespotSession,
+    pcm_callback: LibrespotPcmCallback,
+    user_data: *mut c_void,
+) -> *mut LibrespotPlayer {
+    guard(ptr::null_mut(), || {
+        let session = match session.as_ref() {
+            Some(session) => session,
+            None => {
+                set_last_error("session is NULL");
+                return ptr::null_mut();
+            }
+        };
+
+        let sink_builder = match pcm_callback {
+            Some(callback) => {
+                let user_data = UserData(user_data);
+                Box::new(move || -> Box<dyn Sink> {
+                    Box::new(CallbackSink {
+                        callback,
+                        user_data,
+                    })
+                }) as Box<dyn FnOnce() -> Box<dyn Sink> + Send>
+            }
+            None => match audio_backend::find(None) {
+                Some(backend) => Box::new(move || backend(None, AudioFormat::default())),
+                None => {
+                    set_last_error("no audio backend available");
+                    return ptr::null_mut();
+                }
+            },
+        };
+
+        let (player, mut events) = Player::new(
+            // Decodes the audio, so that it can be ducked.
+            PlayerConfig::builder().ducking(true).build().unwrap(),
+            session.session.clone(),
+            None,
+            sink_builder,
+        );
+
+        let event_callback: Arc<Mutex<Option<EventCallback>>> = Arc::new(Mutex::new(None));
+        let callback = event_callback.clone();
+        session.runtime.spawn(async move {
+            while let Some(event) = events.recv().await {
+                if let Some(callback) = *callback.lock().unwrap_or_else(PoisonError::into_inner) {
+                    dispatch_event(&event, callback);
+                }
+            }
+            debug!("Player event channel closed");
+        });
+
+        Box::into_raw(Box::new(LibrespotPlayer {
+            player,
+            event_callback,
+        }))
+    })
+}
+
+/// Sets the callback receiving player events, `NULL` removes it.
+///
+/// # Safety
+///
+/// `player` must be valid. `user_data` must be usable from any thread.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_set_event_callback(
+    player: *mut LibrespotPlayer,
+    callback: LibrespotEventCallback,
+    user_data: *mut c_void,
+) {
+    guard((), || {
+        if let Some(player) = player.as_ref() {
+            *player
+                .event_callback
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) =
+                callback.map(|callback| (callback, UserData(user_data)));
+        }
+    })
+}
+
+/// Loads a track by its URI, e.g. `spotify:track:...`.
+///
+/// # Safety
+///
+/// `player` must be valid, `uri` a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_load(
+    player: *mut LibrespotPlayer,
+    uri: *const c_char,
+    start_playing: bool,
+    position_ms: u32,
+) -> c_int {
+    guard(-1, || {
+        let player = match player.as_mut() {
+            Some(player) => player,
+            None => {
+                set_last_error("player is NULL");
+                return -1;
+            }
+        };
+
+        let track_id = match string_arg(uri) {
+            Ok(Some(uri)) => SpotifyId::from_uri(&uri).ok(),
+            _ => None,
+        };
+
+        match track_id {
+            Some(track_id) => {
+                player.player.load(track_id, start_playing, position_ms);
+                0
+            }
+            None => {
+                set_last_error("invalid track URI");
+                -1
+            }
+        }
+    })
+}
+
+/// # Safety
+///
+/// `player` must be `NULL` or valid.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_play(player: *mut LibrespotPlayer) {
+    guard((), || {
+        if let Some(player) = player.as_ref() {
+            player.player.play();
+        }
+    })
+}
+
+/// # Safety
+///
+/// `player` must be `NULL` or valid.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_pause(player: *mut LibrespotPlayer) {
+    guard((), || {
+        if let Some(player) = player.as_ref() {
+            player.player.pause();
+        }
+    })
+}
+
+/// # Safety
+///
+/// `player` must be `NULL` or valid.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_stop(player: *mut LibrespotPlayer) {
+    guard((), || {
+        if let Some(player) = player.as_ref() {
+            player.player.stop();
+        }
+    })
+}
+
+/// # Safety
+///
+/// `player` must be `NULL` or valid.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_seek(player: *mut LibrespotPlayer, position_ms: u32) {
+    guard((), || {
+        if let Some(player) = player.as_ref() {
+            player.player.seek(position_ms);
+        }
+    })
+}
+
+/// Attenuates the output to `level`, from 0.0 to 1.0, within `fade_ms` until
+/// `librespot_player_unduck()`, e.g. while a voice assistant talks.
+///
+/// # Safety
+///
+/// `player` must be `NULL` or valid.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_duck(
+    player: *mut LibrespotPlayer,
+    level: f32,
+    fade_ms: u32,
+) {
+    guard((), || {
+        if let Some(player) = player.as_ref() {
+            player.player.duck(level, fade_ms);
+        }
+    })
+}
+
+/// # Safety
+///
+/// `player` must be `NULL` or valid.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_unduck(player: *mut LibrespotPlayer) {
+    guard((), || {
+        if let Some(player) = player.as_ref() {
+            player.player.unduck();
+        }
+    })
+}
+
+/// Stops and frees a player, blocking until its thread has finished.
+///
+/// # Safety
+///
+/// `player` must be `NULL` or returned by `librespot_player_new`, and is invalid afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn librespot_player_free(player: *mut LibrespotPlayer) {
+    guard((), || {
+        if !player.is_null() {
+            drop(Box::from_raw(player));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn header_is_up_to_date() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/librespot.h"));
+        let checked_in = include_str!("../include/librespot.h");
+        assert!(
+            generated == checked_in,
+            "include/librespot.h is outdated, copy it from {}",
+            env!("OUT_DIR")
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::ops::Deref;
+
+use librespot_core::spotify_id::FileId;
+use librespot_core::Error;
+
+use crate::file_id;
+use crate::protocol;
+
+pub use crate::protocol::metadata::Image_Size as ImageSize;
+
+const IMAGE_URL_PREFIX: &str = "https://i.scdn.co/image/";
+
+/// A cover or portrait in one of the sizes it is available in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Image {
+    pub id: FileId,
+    /// The dimensions in pixels, if the metadata has them.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub size_class: ImageSize,
+}
+
+impl Image {
+    fn from_message(msg: &protocol::metadata::Image) -> Result<Self, Error> {
+        let dimension = |has: bool, value: i32| Some(value as u32).filter(|_| has && value > 0);
+        Ok(Image {
+            id: file_id(msg.get_file_id())?,
+            width: dimension(msg.has_width(), msg.get_width()),
+            height: dimension(msg.has_height(), msg.get_height()),
+            size_class: msg.get_size(),
+        })
+    }
+
+    /// The URL to download the image from.
+    pub fn url(&self) -> String {
+        format!("{}{}", IMAGE_URL_PREFIX, self.id.to_base16())
+    }
+
+    /// The shorter edge in pixels, estimated from the size class if the dimensions are unknown.
+    pub fn min_edge(&self) -> u32 {
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => width.min(height),
+            (Some(edge), None) | (None, Some(edge)) => edge,
+            (None, None) => match self.size_class {
+                ImageSize::SMALL => 64,
+                ImageSize::DEFAULT => 300,
+                ImageSize::LARGE => 640,
+                ImageSize::XLARGE => 1280,
+            },
+        }
+    }
+}
+
+/// The sizes an image is available in, in the order of the metadata.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Images(Vec<Image>);
+
+impl Images {
+    pub(crate) fn parse(images: &[protocol::metadata::Image]) -> Result<Self, Error> {
+        images
+            .iter()
+            .filter(|image| image.has_file_id())
+            .map(Image::from_message)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Images)
+    }
+
+    pub fn largest(&self) -> Option<&Image> {
+        self.0.iter().max_by_key(|image| image.min_edge())
+    }
+
+    pub fn smallest(&self) -> Option<&Image> {
+        self.0.iter().min_by_key(|image| image.min_edge())
+    }
+
+    /// Returns the smallest image at least `px` pixels wide and high, or the largest one if
+    /// none is that large.
+    pub fn at_least(&self, px: u32) -> Option<&Image> {
+        self.0
+            .iter()
+            .filter(|image| image.min_edge() >= px)
+            .min_by_key(|image| image.min_edge())
+            .or_else(|| self.largest())
+    }
+}
+
+impl Deref for Images {
+    type Target = [Image];
+
+    fn deref(&self) -> &[Image] {
+        &self.0
+    }
+}
+
+impl IntoIterator for Images {
+    type Item = Image;
+    type IntoIter = std::vec::IntoIter<Image>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
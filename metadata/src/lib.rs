@@ -7,15 +7,41 @@ extern crate log;
 extern crate async_trait;
 
 pub mod cover;
+mod image;
 
 use std::collections::HashMap;
+use std::fmt;
 
-use librespot_core::mercury::MercuryError;
+use bytes::Bytes;
 use librespot_core::session::Session;
 use librespot_core::spotify_id::{FileId, SpotifyAudioType, SpotifyId};
-use librespot_protocol as protocol;
+use librespot_core::Error;
+/// The protobuf messages of the metadata, see `Metadata::get_message`.
+pub use librespot_protocol as protocol;
 
+pub use crate::protocol::metadata::Album_Type as AlbumType;
 pub use crate::protocol::metadata::AudioFile_Format as FileFormat;
+pub use crate::protocol::metadata::Copyright_Type as CopyrightType;
+
+pub use crate::image::{Image, ImageSize, Images};
+
+fn file_id(raw: &[u8]) -> Result<FileId, Error> {
+    let mut dst = [0u8; 20];
+    if raw.len() != dst.len() {
+        return Err(Error::Protocol(format!(
+            "invalid file id length {}",
+            raw.len()
+        )));
+    }
+    dst.copy_from_slice(raw);
+    Ok(FileId(dst))
+}
+
+fn external_id(ids: &[protocol::metadata::ExternalId], typ: &str) -> Option<String> {
+    ids.iter()
+        .find(|id| id.get_typ().eq_ignore_ascii_case(typ))
+        .map(|id| id.get_id().to_owned())
+}
 
 fn countrylist_contains(list: &str, country: &str) -> bool {
     list.chunks(2).any(|cc| cc == country)
@@ -58,38 +84,42 @@ pub struct AudioItem {
     pub id: SpotifyId,
     pub uri: String,
     pub files: HashMap<FileFormat, FileId>,
+    pub previews: HashMap<FileFormat, FileId>,
     pub name: String,
     pub duration: i32,
     pub available: bool,
+    pub explicit: bool,
     pub alternatives: Option<Vec<SpotifyId>>,
 }
 
 impl AudioItem {
-    pub async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<Self, MercuryError> {
+    pub async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<Self, Error> {
         match id.audio_type {
             SpotifyAudioType::Track => Track::get_audio_item(session, id).await,
             SpotifyAudioType::Podcast => Episode::get_audio_item(session, id).await,
-            SpotifyAudioType::NonPlayable => Err(MercuryError),
+            SpotifyAudioType::NonPlayable => Err(Error::Unavailable(id.to_uri())),
         }
     }
 }
 
 #[async_trait]
 trait AudioFiles {
-    async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<AudioItem, MercuryError>;
+    async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<AudioItem, Error>;
 }
 
 #[async_trait]
 impl AudioFiles for Track {
-    async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<AudioItem, MercuryError> {
+    async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<AudioItem, Error> {
         let item = Self::get(session, id).await?;
         Ok(AudioItem {
             id,
             uri: format!("spotify:track:{}", id.to_base62()),
             files: item.files,
+            previews: item.previews,
             name: item.name,
             duration: item.duration,
             available: item.available,
+            explicit: item.explicit,
             alternatives: Some(item.alternatives),
         })
     }
@@ -97,16 +127,18 @@ impl AudioFiles for Track {
 
 #[async_trait]
 impl AudioFiles for Episode {
-    async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<AudioItem, MercuryError> {
+    async fn get_audio_item(session: &Session, id: SpotifyId) -> Result<AudioItem, Error> {
         let item = Self::get(session, id).await?;
 
         Ok(AudioItem {
             id,
             uri: format!("spotify:episode:{}", id.to_base62()),
             files: item.files,
+            previews: HashMap::new(),
             name: item.name,
             duration: item.duration,
             available: item.available,
+            explicit: item.explicit,
             alternatives: None,
         })
     }
@@ -117,15 +149,27 @@ pub trait Metadata: Send + Sized + 'static {
     type Message: protobuf::Message;
 
     fn request_url(id: SpotifyId) -> String;
-    fn parse(msg: &Self::Message, session: &Session) -> Self;
+    fn parse(msg: &Self::Message, session: &Session) -> Result<Self, Error>;
+
+    async fn get(session: &Session, id: SpotifyId) -> Result<Self, Error> {
+        let msg = Self::get_message(session, id).await?;
+        Self::parse(&msg, &session)
+    }
 
-    async fn get(session: &Session, id: SpotifyId) -> Result<Self, MercuryError> {
+    /// Returns the unparsed protobuf message, e.g. to store it.
+    async fn get_raw(session: &Session, id: SpotifyId) -> Result<Bytes, Error> {
         let uri = Self::request_url(id);
-        let response = session.mercury().get(uri).await?;
-        let data = response.payload.first().expect("Empty payload");
-        let msg: Self::Message = protobuf::parse_from_bytes(data).unwrap();
+        let mut response = session.mercury().get(uri).await?;
+        if response.payload.is_empty() {
+            return Err(Error::Protocol("empty metadata response".to_owned()));
+        }
+        Ok(Bytes::from(response.payload.swap_remove(0)))
+    }
 
-        Ok(Self::parse(&msg, &session))
+    /// Returns the protobuf message, with the fields that `Self` doesn't have.
+    async fn get_message(session: &Session, id: SpotifyId) -> Result<Self::Message, Error> {
+        let data = Self::get_raw(session, id).await?;
+        Ok(protobuf::parse_from_bytes(&data)?)
     }
 }
 
@@ -137,8 +181,22 @@ pub struct Track {
     pub album: SpotifyId,
     pub artists: Vec<SpotifyId>,
     pub files: HashMap<FileFormat, FileId>,
+    /// The 30 second preview clips of the track.
+    pub previews: HashMap<FileFormat, FileId>,
     pub alternatives: Vec<SpotifyId>,
     pub available: bool,
+    pub explicit: bool,
+    /// The position of the track on its disc, starting at 1.
+    pub number: i32,
+    pub disc_number: i32,
+    /// The popularity between 0 and 100, based on recent plays.
+    pub popularity: i32,
+    /// The International Standard Recording Code.
+    pub isrc: Option<String>,
+    /// All external ids by their type, e.g. `isrc`.
+    pub external_ids: HashMap<String, String>,
+    /// When the track was first available, in seconds since the Unix epoch.
+    pub earliest_live_timestamp: Option<i64>,
 }
 
 #[derive(Debug, Clone)]
@@ -146,8 +204,64 @@ pub struct Album {
     pub id: SpotifyId,
     pub name: String,
     pub artists: Vec<SpotifyId>,
+    pub album_type: AlbumType,
+    /// The tracks of all discs, in order.
+    pub tracks: Vec<SpotifyId>,
+    pub discs: Vec<Disc>,
+    pub covers: Images,
+    pub release_date: Option<Date>,
+    pub label: String,
+    pub upc: Option<String>,
+    pub copyrights: Vec<Copyright>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Disc {
+    pub number: i32,
+    pub name: Option<String>,
     pub tracks: Vec<SpotifyId>,
-    pub covers: Vec<FileId>,
+}
+
+/// A date as precise as it is known, e.g. only the year of old releases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: Option<u32>,
+    pub day: Option<u32>,
+}
+
+impl Date {
+    fn from_message(msg: &protocol::metadata::Date) -> Option<Self> {
+        if !msg.has_year() {
+            return None;
+        }
+        let month = Some(msg.get_month() as u32).filter(|_| msg.has_month());
+        Some(Date {
+            year: msg.get_year(),
+            month,
+            day: Some(msg.get_day() as u32).filter(|_| month.is_some() && msg.has_day()),
+        })
+    }
+}
+
+/// Formats the date as `YYYY`, `YYYY-MM` or `YYYY-MM-DD`, depending on its precision.
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}", self.year)?;
+        if let Some(month) = self.month {
+            write!(f, "-{:02}", month)?;
+        }
+        if let Some(day) = self.day {
+            write!(f, "-{:02}", day)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Copyright {
+    pub kind: CopyrightType,
+    pub text: String,
 }
 
 #[derive(Debug, Clone)]
@@ -159,7 +273,7 @@ pub struct Episode {
     pub language: String,
     pub show: SpotifyId,
     pub files: HashMap<FileFormat, FileId>,
-    pub covers: Vec<FileId>,
+    pub covers: Images,
     pub available: bool,
     pub explicit: bool,
 }
@@ -170,7 +284,7 @@ pub struct Show {
     pub name: String,
     pub publisher: String,
     pub episodes: Vec<SpotifyId>,
-    pub covers: Vec<FileId>,
+    pub covers: Images,
 }
 
 #[derive(Debug, Clone)]
@@ -195,41 +309,57 @@ impl Metadata for Track {
         format!("hm://metadata/3/track/{}", id.to_base16())
     }
 
-    fn parse(msg: &Self::Message, session: &Session) -> Self {
+    fn parse(msg: &Self::Message, session: &Session) -> Result<Self, Error> {
         let country = session.country();
 
         let artists = msg
             .get_artist()
             .iter()
             .filter(|artist| artist.has_gid())
-            .map(|artist| SpotifyId::from_raw(artist.get_gid()).unwrap())
-            .collect::<Vec<_>>();
+            .map(|artist| SpotifyId::from_raw(artist.get_gid()))
+            .collect::<Result<Vec<_>, _>>()?;
 
         let files = msg
             .get_file()
             .iter()
             .filter(|file| file.has_file_id())
-            .map(|file| {
-                let mut dst = [0u8; 20];
-                dst.clone_from_slice(file.get_file_id());
-                (file.get_format(), FileId(dst))
-            })
-            .collect();
+            .map(|file| Ok((file.get_format(), file_id(file.get_file_id())?)))
+            .collect::<Result<_, Error>>()?;
+
+        let previews = msg
+            .get_preview()
+            .iter()
+            .filter(|file| file.has_file_id())
+            .map(|file| Ok((file.get_format(), file_id(file.get_file_id())?)))
+            .collect::<Result<_, Error>>()?;
 
-        Track {
-            id: SpotifyId::from_raw(msg.get_gid()).unwrap(),
+        Ok(Track {
+            id: SpotifyId::from_raw(msg.get_gid())?,
             name: msg.get_name().to_owned(),
             duration: msg.get_duration(),
-            album: SpotifyId::from_raw(msg.get_album().get_gid()).unwrap(),
+            album: SpotifyId::from_raw(msg.get_album().get_gid())?,
             artists,
             files,
+            previews,
             alternatives: msg
                 .get_alternative()
                 .iter()
-                .map(|alt| SpotifyId::from_raw(alt.get_gid()).unwrap())
-                .collect(),
+                .map(|alt| SpotifyId::from_raw(alt.get_gid()))
+                .collect::<Result<_, _>>()?,
             available: parse_restrictions(msg.get_restriction(), &country, "premium"),
-        }
+            explicit: msg.get_explicit(),
+            number: msg.get_number(),
+            disc_number: msg.get_disc_number(),
+            popularity: msg.get_popularity(),
+            isrc: external_id(msg.get_external_id(), "isrc"),
+            external_ids: msg
+                .get_external_id()
+                .iter()
+                .map(|id| (id.get_typ().to_lowercase(), id.get_id().to_owned()))
+                .collect(),
+            earliest_live_timestamp: Some(msg.get_earliest_live_timestamp())
+                .filter(|_| msg.has_earliest_live_timestamp()),
+        })
     }
 }
 
@@ -240,41 +370,60 @@ impl Metadata for Album {
         format!("hm://metadata/3/album/{}", id.to_base16())
     }
 
-    fn parse(msg: &Self::Message, _: &Session) -> Self {
+    fn parse(msg: &Self::Message, _: &Session) -> Result<Self, Error> {
         let artists = msg
             .get_artist()
             .iter()
             .filter(|artist| artist.has_gid())
-            .map(|artist| SpotifyId::from_raw(artist.get_gid()).unwrap())
-            .collect::<Vec<_>>();
+            .map(|artist| SpotifyId::from_raw(artist.get_gid()))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let tracks = msg
+        let discs = msg
             .get_disc()
             .iter()
-            .flat_map(|disc| disc.get_track())
-            .filter(|track| track.has_gid())
-            .map(|track| SpotifyId::from_raw(track.get_gid()).unwrap())
-            .collect::<Vec<_>>();
-
-        let covers = msg
-            .get_cover_group()
-            .get_image()
+            .map(|disc| {
+                let tracks = disc
+                    .get_track()
+                    .iter()
+                    .filter(|track| track.has_gid())
+                    .map(|track| SpotifyId::from_raw(track.get_gid()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Disc {
+                    number: disc.get_number(),
+                    name: Some(disc.get_name().to_owned()).filter(|_| disc.has_name()),
+                    tracks,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let tracks = discs
+            .iter()
+            .flat_map(|disc| disc.tracks.iter().copied())
+            .collect();
+
+        let covers = Images::parse(msg.get_cover_group().get_image())?;
+
+        let copyrights = msg
+            .get_copyright()
             .iter()
-            .filter(|image| image.has_file_id())
-            .map(|image| {
-                let mut dst = [0u8; 20];
-                dst.clone_from_slice(image.get_file_id());
-                FileId(dst)
+            .map(|copyright| Copyright {
+                kind: copyright.get_typ(),
+                text: copyright.get_text().to_owned(),
             })
-            .collect::<Vec<_>>();
+            .collect();
 
-        Album {
-            id: SpotifyId::from_raw(msg.get_gid()).unwrap(),
+        Ok(Album {
+            id: SpotifyId::from_raw(msg.get_gid())?,
             name: msg.get_name().to_owned(),
             artists,
+            album_type: msg.get_typ(),
             tracks,
+            discs,
             covers,
-        }
+            release_date: Date::from_message(msg.get_date()),
+            label: msg.get_label().to_owned(),
+            upc: external_id(msg.get_external_id(), "upc"),
+            copyrights,
+        })
     }
 }
 
@@ -285,7 +434,7 @@ impl Metadata for Playlist {
         format!("hm://playlist/v2/playlist/{}", id.to_base62())
     }
 
-    fn parse(msg: &Self::Message, _: &Session) -> Self {
+    fn parse(msg: &Self::Message, _: &Session) -> Result<Self, Error> {
         let tracks = msg
             .get_contents()
             .get_items()
@@ -293,9 +442,12 @@ impl Metadata for Playlist {
             .map(|item| {
                 let uri_split = item.get_uri().split(':');
                 let uri_parts: Vec<&str> = uri_split.collect();
-                SpotifyId::from_base62(uri_parts[2]).unwrap()
+                let id = uri_parts.get(2).ok_or_else(|| {
+                    Error::Protocol(format!("invalid playlist item {}", item.get_uri()))
+                })?;
+                Ok(SpotifyId::from_base62(id)?)
             })
-            .collect::<Vec<_>>();
+            .collect::<Result<Vec<_>, Error>>()?;
 
         if tracks.len() != msg.get_length() as usize {
             warn!(
@@ -305,12 +457,12 @@ impl Metadata for Playlist {
             );
         }
 
-        Playlist {
+        Ok(Playlist {
             revision: msg.get_revision().to_vec(),
             name: msg.get_attributes().get_name().to_owned(),
             tracks,
             user: msg.get_owner_username().to_string(),
-        }
+        })
     }
 }
 
@@ -321,7 +473,7 @@ impl Metadata for Artist {
         format!("hm://metadata/3/artist/{}", id.to_base16())
     }
 
-    fn parse(msg: &Self::Message, session: &Session) -> Self {
+    fn parse(msg: &Self::Message, session: &Session) -> Result<Self, Error> {
         let country = session.country();
 
         let top_tracks: Vec<SpotifyId> = match msg
@@ -333,16 +485,16 @@ impl Metadata for Artist {
                 .get_track()
                 .iter()
                 .filter(|track| track.has_gid())
-                .map(|track| SpotifyId::from_raw(track.get_gid()).unwrap())
-                .collect::<Vec<_>>(),
+                .map(|track| SpotifyId::from_raw(track.get_gid()))
+                .collect::<Result<Vec<_>, _>>()?,
             None => Vec::new(),
         };
 
-        Artist {
-            id: SpotifyId::from_raw(msg.get_gid()).unwrap(),
+        Ok(Artist {
+            id: SpotifyId::from_raw(msg.get_gid())?,
             name: msg.get_name().to_owned(),
             top_tracks,
-        }
+        })
     }
 }
 
@@ -354,44 +506,30 @@ impl Metadata for Episode {
         format!("hm://metadata/3/episode/{}", id.to_base16())
     }
 
-    fn parse(msg: &Self::Message, session: &Session) -> Self {
+    fn parse(msg: &Self::Message, session: &Session) -> Result<Self, Error> {
         let country = session.country();
 
         let files = msg
             .get_file()
             .iter()
             .filter(|file| file.has_file_id())
-            .map(|file| {
-                let mut dst = [0u8; 20];
-                dst.clone_from_slice(file.get_file_id());
-                (file.get_format(), FileId(dst))
-            })
-            .collect();
+            .map(|file| Ok((file.get_format(), file_id(file.get_file_id())?)))
+            .collect::<Result<_, Error>>()?;
 
-        let covers = msg
-            .get_covers()
-            .get_image()
-            .iter()
-            .filter(|image| image.has_file_id())
-            .map(|image| {
-                let mut dst = [0u8; 20];
-                dst.clone_from_slice(image.get_file_id());
-                FileId(dst)
-            })
-            .collect::<Vec<_>>();
+        let covers = Images::parse(msg.get_covers().get_image())?;
 
-        Episode {
-            id: SpotifyId::from_raw(msg.get_gid()).unwrap(),
+        Ok(Episode {
+            id: SpotifyId::from_raw(msg.get_gid())?,
             name: msg.get_name().to_owned(),
             external_url: msg.get_external_url().to_owned(),
             duration: msg.get_duration().to_owned(),
             language: msg.get_language().to_owned(),
-            show: SpotifyId::from_raw(msg.get_show().get_gid()).unwrap(),
+            show: SpotifyId::from_raw(msg.get_show().get_gid())?,
             covers,
             files,
             available: parse_restrictions(msg.get_restriction(), &country, "premium"),
             explicit: msg.get_explicit().to_owned(),
-        }
+        })
     }
 }
 
@@ -402,33 +540,23 @@ impl Metadata for Show {
         format!("hm://metadata/3/show/{}", id.to_base16())
     }
 
-    fn parse(msg: &Self::Message, _: &Session) -> Self {
+    fn parse(msg: &Self::Message, _: &Session) -> Result<Self, Error> {
         let episodes = msg
             .get_episode()
             .iter()
             .filter(|episode| episode.has_gid())
-            .map(|episode| SpotifyId::from_raw(episode.get_gid()).unwrap())
-            .collect::<Vec<_>>();
+            .map(|episode| SpotifyId::from_raw(episode.get_gid()))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let covers = msg
-            .get_covers()
-            .get_image()
-            .iter()
-            .filter(|image| image.has_file_id())
-            .map(|image| {
-                let mut dst = [0u8; 20];
-                dst.clone_from_slice(image.get_file_id());
-                FileId(dst)
-            })
-            .collect::<Vec<_>>();
+        let covers = Images::parse(msg.get_covers().get_image())?;
 
-        Show {
-            id: SpotifyId::from_raw(msg.get_gid()).unwrap(),
+        Ok(Show {
+            id: SpotifyId::from_raw(msg.get_gid())?,
             name: msg.get_name().to_owned(),
             publisher: msg.get_publisher().to_owned(),
             episodes,
             covers,
-        }
+        })
     }
 }
 
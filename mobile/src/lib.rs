@@ -0,0 +1,278 @@
+//! Kotlin and Swift bindings for librespot, generated by UniFFI from `src/librespot.udl`.
+
+#[macro_use]
+extern crate log;
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use librespot_core::authentication::Credentials;
+use librespot_core::cache::Cache;
+use librespot_core::config::SessionConfig;
+use librespot_core::spotify_id::SpotifyId;
+use librespot_playback::audio_backend;
+use librespot_playback::config::{AudioFormat, PlayerConfig};
+use librespot_playback::player;
+use thiserror::Error;
+use tokio::runtime::Runtime;
+
+#[derive(Debug, Error)]
+pub enum LibrespotError {
+    #[error("no credentials given and none cached")]
+    NoCredentials,
+    #[error("cannot create cache: {0}")]
+    Cache(String),
+    #[error("cannot connect: {0}")]
+    Connection(String),
+    #[error("cannot start the runtime: {0}")]
+    Runtime(String),
+    #[error("invalid track URI")]
+    InvalidUri,
+    #[error("no audio backend available")]
+    NoAudioBackend,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerEventType {
+    Stopped,
+    Started,
+    Changed,
+    Loading,
+    Preloading,
+    Playing,
+    Paused,
+    TimeToPreloadNextTrack,
+    EndOfTrack,
+    Unavailable,
+    VolumeSet,
+    TrackChanged,
+    LoadingProgress,
+    FormatChanged,
+    TrackFiltered,
+    TrackTransition,
+    TrackRelinked,
+    OutputChanged,
+    SinkStalled,
+    SinkRecovered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackTransitionReason {
+    Finished,
+    Skipped,
+    Error,
+    RemoteChange,
+}
+
+impl From<player::TrackTransitionReason> for TrackTransitionReason {
+    fn from(reason: player::TrackTransitionReason) -> Self {
+        use player::TrackTransitionReason as R;
+        match reason {
+            R::Finished => Self::Finished,
+            R::Skipped => Self::Skipped,
+            R::Error => Self::Error,
+            R::RemoteChange => Self::RemoteChange,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayerEvent {
+    pub event_type: PlayerEventType,
+    pub track_uri: Option<String>,
+    pub position_ms: u32,
+    pub duration_ms: u32,
+    pub volume: u16,
+    pub to_track_uri: Option<String>,
+    pub reason: Option<TrackTransitionReason>,
+    pub relinked_track_uri: Option<String>,
+}
+
+impl From<player::PlayerEvent> for PlayerEvent {
+    fn from(event: player::PlayerEvent) -> Self {
+        use player::PlayerEvent as E;
+        use PlayerEventType::*;
+
+        let event_type = match event {
+            E::Stopped { .. } => Stopped,
+            E::Started { .. } => Started,
+            E::Changed { .. } => Changed,
+            E::Loading { .. } => Loading,
+            E::Preloading { .. } => Preloading,
+            E::Playing { .. } => Playing,
+            E::Paused { .. } => Paused,
+            E::TimeToPreloadNextTrack { .. } => TimeToPreloadNextTrack,
+            E::EndOfTrack { .. } => EndOfTrack,
+            E::Unavailable { .. } => Unavailable,
+            E::VolumeSet { .. } => VolumeSet,
+            E::TrackChanged { .. } => TrackChanged,
+            E::LoadingProgress { .. } => LoadingProgress,
+            E::FormatChanged { .. } => FormatChanged,
+            E::TrackFiltered { .. } => TrackFiltered,
+            E::TrackTransition { .. } => TrackTransition,
+            E::TrackRelinked { .. } => TrackRelinked,
+            E::OutputChanged { .. } => OutputChanged,
+            E::SinkStalled { .. } => SinkStalled,
+            E::SinkRecovered { .. } => SinkRecovered,
+        };
+        let fields = event.fields();
+
+        PlayerEvent {
+            event_type,
+            track_uri: fields.track_id.map(|id| id.to_uri()),
+            position_ms: fields.position_ms.unwrap_or_default(),
+            duration_ms: fields.duration_ms.unwrap_or_default(),
+            volume: fields.volume.unwrap_or_default(),
+            to_track_uri: fields.to_track_id.map(|id| id.to_uri()),
+            reason: fields.reason.map(Into::into),
+            relinked_track_uri: fields.relinked_id.map(|id| id.to_uri()),
+        }
+    }
+}
+
+pub trait PlayerEventListener: Send + Sync {
+    fn on_event(&self, event: PlayerEvent);
+}
+
+pub struct Session {
+    session: librespot_core::session::Session,
+    runtime: Runtime,
+}
+
+impl Session {
+    pub fn new(
+        username: Option<String>,
+        password: Option<String>,
+        cache_dir: Option<String>,
+    ) -> Result<Self, LibrespotError> {
+        let cache = cache_dir
+            .map(|dir| {
+                let dir = Path::new(&dir);
+                Cache::new(Some(dir), Some(dir.join("files").as_path()))
+                    .map_err(|e| LibrespotError::Cache(e.to_string()))
+            })
+            .transpose()?;
+
+        let credentials = match (username, password) {
+            (Some(username), Some(password)) => Credentials::with_password(username, password),
+            _ => cache
+                .as_ref()
+                .and_then(Cache::credentials)
+                .ok_or(LibrespotError::NoCredentials)?,
+        };
+
+        let config = SessionConfig {
+            device_id: cache
+                .as_ref()
+                .and_then(Cache::device_id)
+                .unwrap_or_else(|| SessionConfig::default().device_id),
+            ..SessionConfig::default()
+        };
+
+        let runtime = Runtime::new().map_err(|e| LibrespotError::Runtime(e.to_string()))?;
+        let session = runtime
+            .block_on(librespot_core::session::Session::connect(
+                config,
+                credentials,
+                cache,
+            ))
+            .map_err(|e| LibrespotError::Connection(e.to_string()))?;
+
+        Ok(Session { session, runtime })
+    }
+
+    pub fn username(&self) -> String {
+        self.session.username()
+    }
+
+    pub fn country(&self) -> String {
+        self.session.country()
+    }
+
+    pub fn product(&self) -> Option<String> {
+        self.session.user_data().product().map(str::to_owned)
+    }
+
+    pub fn filter_explicit_content(&self) -> bool {
+        self.session.user_data().filter_explicit_content()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.runtime.block_on(self.session.shutdown());
+    }
+}
+
+pub struct Player {
+    // The generated bindings only hand out shared references.
+    player: Mutex<player::Player>,
+}
+
+impl Player {
+    pub fn new(
+        session: Arc<Session>,
+        listener: Box<dyn PlayerEventListener>,
+    ) -> Result<Self, LibrespotError> {
+        let backend = audio_backend::find(None).ok_or(LibrespotError::NoAudioBackend)?;
+
+        let (player, mut events) = player::Player::new(
+            // Decodes the audio, so that it can be ducked.
+            PlayerConfig::builder().ducking(true).build().unwrap(),
+            session.session.clone(),
+            None,
+            move || backend(None, AudioFormat::default()),
+        );
+
+        session.runtime.spawn(async move {
+            while let Some(event) = events.recv().await {
+                listener.on_event(event.into());
+            }
+            debug!("Player event channel closed");
+        });
+
+        Ok(Player {
+            player: Mutex::new(player),
+        })
+    }
+
+    pub fn load(
+        &self,
+        uri: String,
+        start_playing: bool,
+        position_ms: u32,
+    ) -> Result<(), LibrespotError> {
+        let track_id = SpotifyId::from_uri(&uri).map_err(|_| LibrespotError::InvalidUri)?;
+        self.player
+            .lock()
+            .unwrap()
+            .load(track_id, start_playing, position_ms);
+        Ok(())
+    }
+
+    pub fn play(&self) {
+        self.player.lock().unwrap().play();
+    }
+
+    pub fn pause(&self) {
+        self.player.lock().unwrap().pause();
+    }
+
+    pub fn stop(&self) {
+        self.player.lock().unwrap().stop();
+    }
+
+    pub fn seek(&self, position_ms: u32) {
+        self.player.lock().unwrap().seek(position_ms);
+    }
+
+    pub fn duck(&self, level: f32, fade_ms: u32) {
+        self.player.lock().unwrap().duck(level, fade_ms);
+    }
+
+    pub fn unduck(&self) {
+        self.player.lock().unwrap().unduck();
+    }
+}
+
+uniffi_macros::include_scaffolding!("librespot");
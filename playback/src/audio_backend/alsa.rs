@@ -9,6 +9,7 @@ use std::cmp::min;
 use std::ffi::CString;
 use std::io;
 use std::process::exit;
+use std::time::Duration;
 
 const BUFFERED_LATENCY: f32 = 0.125; // seconds
 const BUFFERED_PERIODS: Frames = 4;
@@ -132,6 +133,21 @@ impl Sink for AlsaSink {
     }
 
     sink_as_bytes!();
+
+    fn latency(&self) -> Duration {
+        let pcm = match &self.pcm {
+            Some(pcm) => pcm,
+            None => return Duration::default(),
+        };
+        // The frames in the period buffer, and those the device hasn't played yet.
+        let buffered = self.buffer.len() / (self.format.size() * NUM_CHANNELS as usize);
+        let delay = pcm.delay().unwrap_or(0).max(0) as usize;
+        Duration::from_micros((buffered + delay) as u64 * 1_000_000 / SAMPLE_RATE as u64)
+    }
+
+    fn output(&mut self) -> Option<String> {
+        Some(self.device.clone())
+    }
 }
 
 impl SinkAsBytes for AlsaSink {
@@ -0,0 +1,296 @@
+use super::{Open, Sink, OUTPUT_CHECK_INTERVAL};
+use crate::audio::AudioPacket;
+use crate::config::AudioFormat;
+use crate::player::{NUM_CHANNELS, SAMPLES_PER_SECOND, SAMPLE_RATE};
+use core_foundation_sys::base::CFRelease;
+use core_foundation_sys::string::{
+    kCFStringEncodingUTF8, CFStringGetCString, CFStringGetLength,
+    CFStringGetMaximumSizeForEncoding, CFStringRef,
+};
+use coreaudio::audio_unit::macos_helpers::{
+    audio_unit_from_device_id, get_audio_device_ids, get_default_device_id, get_device_name,
+    get_hogging_pid, set_device_sample_rate, toggle_hog_mode,
+};
+use coreaudio::audio_unit::render_callback::{self, data};
+use coreaudio::audio_unit::{AudioUnit, Element, Scope};
+use coreaudio::sys::{
+    kAudioDevicePropertyDeviceUID, kAudioFormatFlagIsFloat, kAudioFormatFlagIsPacked,
+    kAudioFormatLinearPCM, kAudioObjectPropertyElementMaster, kAudioObjectPropertyScopeGlobal,
+    kAudioUnitProperty_StreamFormat, AudioDeviceID, AudioObjectGetPropertyData,
+    AudioObjectPropertyAddress, AudioStreamBasicDescription,
+};
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::os::raw::{c_char, c_void};
+use std::process::exit;
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// The audio buffered for the render callback. Writes wait while it is full.
+const BUFFERED_SAMPLES: usize = SAMPLES_PER_SECOND as usize / 4;
+// The prefix of a device that is to be used exclusively, e.g. `hog:BuiltInSpeakerDevice`.
+const HOG_PREFIX: &str = "hog:";
+
+#[derive(Default)]
+struct SampleBuffer {
+    samples: Mutex<VecDeque<f32>>,
+    consumed: Condvar,
+}
+
+/// Plays through an AudioUnit of a CoreAudio output device.
+///
+/// The device is selected by its UID or its name, `?` lists them. With the `hog:` prefix,
+/// the device is used exclusively ("hog mode") while the sink exists. The sample rate of the
+/// device is switched to that of the audio, so that CoreAudio doesn't resample it.
+pub struct CoreAudioSink {
+    audio_unit: AudioUnit,
+    device_id: AudioDeviceID,
+    hogging: bool,
+    buffer: Arc<SampleBuffer>,
+    // The default output device, if the sink was opened without a device, and when it was
+    // last checked for changes.
+    default_device_id: Option<AudioDeviceID>,
+    last_output_check: Instant,
+}
+
+impl Open for CoreAudioSink {
+    fn open(device: Option<String>, format: AudioFormat) -> Self {
+        info!("Using CoreAudio sink with format: {:?}", format);
+
+        if format != AudioFormat::F32 {
+            warn!(
+                "CoreAudio sink only supports F32 output, ignoring {:?}",
+                format
+            );
+        }
+
+        let (device, hog) = match device.as_deref() {
+            Some("?") => {
+                list_outputs();
+                exit(0)
+            }
+            Some(device) => match device.strip_prefix(HOG_PREFIX) {
+                Some(device) if device.is_empty() => (None, true),
+                Some(device) => (Some(device), true),
+                None => (Some(device), false),
+            },
+            None => (None, false),
+        };
+
+        let device_id = match device {
+            Some(device) => find_output(device),
+            None => get_default_device_id(false),
+        }
+        .expect("could not find CoreAudio device");
+        let default_device_id = if device.is_none() {
+            Some(device_id)
+        } else {
+            None
+        };
+
+        let hogging = hog && hog_device(device_id);
+
+        if let Err(e) = set_device_sample_rate(device_id, SAMPLE_RATE as f64) {
+            warn!(
+                "Could not switch the device to {} Hz, CoreAudio resamples: {:?}",
+                SAMPLE_RATE, e
+            );
+        }
+
+        let mut audio_unit =
+            audio_unit_from_device_id(device_id, false).expect("could not open CoreAudio device");
+
+        let bytes_per_frame = NUM_CHANNELS as u32 * mem::size_of::<f32>() as u32;
+        let stream_format = AudioStreamBasicDescription {
+            mSampleRate: SAMPLE_RATE as f64,
+            mFormatID: kAudioFormatLinearPCM,
+            mFormatFlags: kAudioFormatFlagIsFloat | kAudioFormatFlagIsPacked,
+            mBytesPerPacket: bytes_per_frame,
+            mFramesPerPacket: 1,
+            mBytesPerFrame: bytes_per_frame,
+            mChannelsPerFrame: NUM_CHANNELS as u32,
+            mBitsPerChannel: 32,
+            mReserved: 0,
+        };
+        audio_unit
+            .set_property(
+                kAudioUnitProperty_StreamFormat,
+                Scope::Input,
+                Element::Output,
+                Some(&stream_format),
+            )
+            .expect("could not set the CoreAudio stream format");
+
+        let buffer = Arc::new(SampleBuffer::default());
+        let callback_buffer = buffer.clone();
+        audio_unit
+            .set_render_callback(move |args: render_callback::Args<data::Interleaved<f32>>| {
+                let mut samples = callback_buffer.samples.lock().unwrap();
+                for output in args.data.buffer.iter_mut() {
+                    // Silence while the player doesn't keep up.
+                    *output = samples.pop_front().unwrap_or(0.0);
+                }
+                callback_buffer.consumed.notify_all();
+                Ok(())
+            })
+            .expect("could not set the CoreAudio render callback");
+
+        CoreAudioSink {
+            audio_unit,
+            device_id,
+            hogging,
+            buffer,
+            default_device_id,
+            last_output_check: Instant::now(),
+        }
+    }
+}
+
+impl Sink for CoreAudioSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.audio_unit
+            .start()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let result = self
+            .audio_unit
+            .stop()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)));
+        self.buffer.samples.lock().unwrap().clear();
+        self.buffer.consumed.notify_all();
+        result
+    }
+
+    fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+        let mut samples = self.buffer.samples.lock().unwrap();
+        // A packet larger than the buffer is taken once the buffer is empty.
+        while !samples.is_empty() && samples.len() + packet.samples().len() > BUFFERED_SAMPLES {
+            samples = self.buffer.consumed.wait(samples).unwrap();
+        }
+        samples.extend(packet.samples());
+        Ok(())
+    }
+
+    // Without the latency of the device itself.
+    fn latency(&self) -> Duration {
+        let samples = self.buffer.samples.lock().unwrap().len() as u64;
+        Duration::from_millis(samples * 1000 / SAMPLES_PER_SECOND as u64)
+    }
+
+    fn output_changed(&mut self) -> bool {
+        let default_device_id = match self.default_device_id {
+            Some(device_id) if self.last_output_check.elapsed() >= OUTPUT_CHECK_INTERVAL => {
+                device_id
+            }
+            _ => return false,
+        };
+        self.last_output_check = Instant::now();
+        match get_default_device_id(false) {
+            Some(device_id) if device_id != default_device_id => {
+                self.default_device_id = Some(device_id);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Drop for CoreAudioSink {
+    fn drop(&mut self) {
+        let _ = self.audio_unit.stop();
+        if self.hogging {
+            if let Err(e) = toggle_hog_mode(self.device_id) {
+                warn!("Could not release the CoreAudio device: {:?}", e);
+            }
+        }
+    }
+}
+
+// Takes the device for exclusive use. Returns whether this process has it.
+fn hog_device(device_id: AudioDeviceID) -> bool {
+    let pid = std::process::id() as i32;
+    match get_hogging_pid(device_id) {
+        Ok(-1) => match toggle_hog_mode(device_id) {
+            Ok(hogging_pid) if hogging_pid == pid => true,
+            Ok(_) => false,
+            Err(e) => {
+                warn!("Could not use the CoreAudio device exclusively: {:?}", e);
+                false
+            }
+        },
+        Ok(hogging_pid) if hogging_pid == pid => true,
+        Ok(hogging_pid) => {
+            warn!(
+                "CoreAudio device is used exclusively by process {}",
+                hogging_pid
+            );
+            false
+        }
+        Err(e) => {
+            warn!("Could not use the CoreAudio device exclusively: {:?}", e);
+            false
+        }
+    }
+}
+
+fn find_output(device: &str) -> Option<AudioDeviceID> {
+    get_audio_device_ids().ok()?.into_iter().find(|&device_id| {
+        device_uid(device_id).as_deref() == Some(device)
+            || get_device_name(device_id).ok().as_deref() == Some(device)
+    })
+}
+
+fn list_outputs() {
+    println!("Listing available CoreAudio outputs (name and UID):");
+    for device_id in get_audio_device_ids().unwrap_or_default() {
+        if let (Ok(name), Some(uid)) = (get_device_name(device_id), device_uid(device_id)) {
+            println!("- {} ({})", name, uid);
+        }
+    }
+}
+
+fn device_uid(device_id: AudioDeviceID) -> Option<String> {
+    let address = AudioObjectPropertyAddress {
+        mSelector: kAudioDevicePropertyDeviceUID,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+    let mut uid: CFStringRef = ptr::null();
+    let mut size = mem::size_of::<CFStringRef>() as u32;
+    let status = unsafe {
+        AudioObjectGetPropertyData(
+            device_id,
+            &address,
+            0,
+            ptr::null(),
+            &mut size,
+            &mut uid as *mut CFStringRef as *mut c_void,
+        )
+    };
+    if status != 0 || uid.is_null() {
+        return None;
+    }
+
+    unsafe {
+        let capacity =
+            CFStringGetMaximumSizeForEncoding(CFStringGetLength(uid), kCFStringEncodingUTF8) + 1;
+        let mut bytes = vec![0 as c_char; capacity as usize];
+        let converted =
+            CFStringGetCString(uid, bytes.as_mut_ptr(), capacity, kCFStringEncodingUTF8) != 0;
+        CFRelease(uid as *const c_void);
+        if converted {
+            Some(
+                CStr::from_ptr(bytes.as_ptr())
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        } else {
+            None
+        }
+    }
+}
@@ -1,6 +1,17 @@
 use crate::audio::AudioPacket;
 use crate::config::AudioFormat;
+use crate::core::spotify_id::SpotifyId;
 use std::io;
+use std::time::Duration;
+
+// How often sinks following the default output check whether it changed.
+#[cfg(any(
+    all(feature = "coreaudio-backend", target_os = "macos"),
+    feature = "pulseaudio-backend",
+    feature = "rodio-backend",
+    feature = "rodiojack-backend"
+))]
+const OUTPUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub trait Open {
     fn open(_: Option<String>, format: AudioFormat) -> Self;
@@ -10,6 +21,35 @@ pub trait Sink {
     fn start(&mut self) -> io::Result<()>;
     fn stop(&mut self) -> io::Result<()>;
     fn write(&mut self, packet: &AudioPacket) -> io::Result<()>;
+    /// Called when a track starts playing, before its audio is written. Most sinks ignore it.
+    fn set_track(&mut self, _track: &SinkTrack) {}
+    /// The time until audio written now is heard, i.e. the audio buffered by the sink and the
+    /// device. The player reports the position of the audio that is heard. Zero if unknown.
+    fn latency(&self) -> Duration {
+        Duration::default()
+    }
+    /// Whether the output changed since the last call, e.g. because headphones were unplugged
+    /// and the default output device moved to the speakers. Called for every packet while
+    /// playing. Sinks playing to a chosen device, or that can't tell, return false.
+    fn output_changed(&mut self) -> bool {
+        false
+    }
+    /// The name of the output the sink plays to, e.g. the PulseAudio sink and its port, which
+    /// picks the output gain of the player. None if unknown.
+    fn output(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// The track the audio written to a sink is of.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SinkTrack {
+    pub track_id: SpotifyId,
+    pub name: String,
+    /// The artists of a track, or the publisher of an episode. Empty if they couldn't be
+    /// fetched.
+    pub artists: Vec<String>,
+    pub duration_ms: u32,
 }
 
 pub type SinkBuilder = fn(Option<String>, AudioFormat) -> Box<dyn Sink>;
@@ -25,7 +65,10 @@ fn mk_sink<S: Sink + Open + 'static>(device: Option<String>, format: AudioFormat
 // reuse code for various backends
 macro_rules! sink_as_bytes {
     () => {
-        fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+        sink_as_bytes!(write);
+    };
+    ($name:ident) => {
+        fn $name(&mut self, packet: &AudioPacket) -> io::Result<()> {
             use crate::audio::convert::{self, i24};
             use zerocopy::AsBytes;
             match packet {
@@ -70,6 +113,11 @@ mod alsa;
 #[cfg(feature = "alsa-backend")]
 use self::alsa::AlsaSink;
 
+#[cfg(all(feature = "coreaudio-backend", target_os = "macos"))]
+mod coreaudio;
+#[cfg(all(feature = "coreaudio-backend", target_os = "macos"))]
+use self::coreaudio::CoreAudioSink;
+
 #[cfg(feature = "portaudio-backend")]
 mod portaudio;
 #[cfg(feature = "portaudio-backend")]
@@ -102,11 +150,13 @@ mod pipe;
 use self::pipe::StdoutSink;
 
 mod subprocess;
-use self::subprocess::SubprocessSink;
+pub use self::subprocess::SubprocessSink;
 
 pub const BACKENDS: &[(&str, SinkBuilder)] = &[
     #[cfg(feature = "alsa-backend")]
     ("alsa", mk_sink::<AlsaSink>),
+    #[cfg(all(feature = "coreaudio-backend", target_os = "macos"))]
+    ("coreaudio", mk_sink::<CoreAudioSink>),
     #[cfg(feature = "portaudio-backend")]
     ("portaudio", mk_sink::<PortAudioSink>),
     #[cfg(feature = "pulseaudio-backend")]
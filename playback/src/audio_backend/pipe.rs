@@ -1,36 +1,101 @@
-use super::{Open, Sink, SinkAsBytes};
+use super::{Open, Sink, SinkAsBytes, SinkTrack};
 use crate::audio::AudioPacket;
 use crate::config::AudioFormat;
-use std::fs::OpenOptions;
+use crate::filename::{Filenames, TrackFields};
+use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
+use std::path::PathBuf;
 
 pub struct StdoutSink {
-    output: Box<dyn Write>,
+    output: Option<Box<dyn Write>>,
     format: AudioFormat,
+    // If the path is a directory, each track is written to a file of its own in it.
+    directory: Option<PathBuf>,
+    filenames: Filenames,
+    track: Option<SinkTrack>,
 }
 
 impl Open for StdoutSink {
     fn open(path: Option<String>, format: AudioFormat) -> Self {
         info!("Using pipe sink with format: {:?}", format);
 
-        let output: Box<dyn Write> = match path {
-            Some(path) => Box::new(OpenOptions::new().write(true).open(path).unwrap()),
-            _ => Box::new(io::stdout()),
+        let directory = path
+            .as_ref()
+            .map(PathBuf::from)
+            .filter(|path| path.is_dir());
+        let output: Option<Box<dyn Write>> = match path {
+            _ if directory.is_some() => None,
+            Some(path) => Some(Box::new(OpenOptions::new().write(true).open(path).unwrap())),
+            _ => Some(Box::new(io::stdout())),
         };
 
-        Self { output, format }
+        Self {
+            output,
+            format,
+            directory,
+            filenames: Filenames::default(),
+            track: None,
+        }
     }
 }
 
+impl StdoutSink {
+    // Creates the file of the current track, the Ogg data of passthrough as `.ogg` and samples
+    // in the chosen format as `.raw`.
+    fn create_file(&mut self, directory: PathBuf, packet: &AudioPacket) -> io::Result<File> {
+        let extension = match packet {
+            AudioPacket::Samples(_) => "raw",
+            AudioPacket::OggData(_) => "ogg",
+        };
+        let (title, artists) = match &self.track {
+            Some(track) if !track.name.is_empty() => (track.name.clone(), &track.artists[..]),
+            Some(track) => (track.track_id.to_base62(), &track.artists[..]),
+            None => ("librespot".to_owned(), &[][..]),
+        };
+        let path = self.filenames.path(
+            &directory,
+            &TrackFields {
+                artists,
+                title: &title,
+                extension,
+                ..TrackFields::default()
+            },
+        );
+        info!("Writing to {}", path.display());
+        File::create(path)
+    }
+
+    sink_as_bytes!(write_packet);
+}
+
 impl Sink for StdoutSink {
     start_stop_noop!();
-    sink_as_bytes!();
+
+    fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+        if self.output.is_none() {
+            if let Some(directory) = self.directory.clone() {
+                let file = self.create_file(directory, packet)?;
+                self.output = Some(Box::new(file));
+            }
+        }
+        self.write_packet(packet)
+    }
+
+    fn set_track(&mut self, track: &SinkTrack) {
+        if self.directory.is_some() {
+            // The next write starts the file of the track.
+            self.output = None;
+            self.track = Some(track.clone());
+        }
+    }
 }
 
 impl SinkAsBytes for StdoutSink {
     fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
-        self.output.write_all(data)?;
-        self.output.flush()?;
+        if let Some(output) = &mut self.output {
+            output.write_all(data)?;
+            output.flush()?;
+        }
         Ok(())
     }
 }
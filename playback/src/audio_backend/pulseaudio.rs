@@ -1,10 +1,20 @@
-use super::{Open, Sink, SinkAsBytes};
+use super::{Open, Sink, SinkAsBytes, OUTPUT_CHECK_INTERVAL};
 use crate::audio::AudioPacket;
 use crate::config::AudioFormat;
 use crate::player::{NUM_CHANNELS, SAMPLE_RATE};
+use libpulse_binding::callbacks::ListResult;
+use libpulse_binding::context::{self, Context};
+use libpulse_binding::mainloop::standard::{IterateResult, Mainloop};
+use libpulse_binding::operation::{self, Operation};
 use libpulse_binding::{self as pulse, stream::Direction};
 use libpulse_simple_binding::Simple;
+use std::cell::RefCell;
 use std::io;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 const APP_NAME: &str = "librespot";
 const STREAM_NAME: &str = "Spotify endpoint";
@@ -14,6 +24,8 @@ pub struct PulseAudioSink {
     ss: pulse::sample::Spec,
     device: Option<String>,
     format: AudioFormat,
+    // Watches the default sink while the stream plays to it, started by the first check.
+    output_monitor: Option<OutputMonitor>,
 }
 
 impl Open for PulseAudioSink {
@@ -41,6 +53,7 @@ impl Open for PulseAudioSink {
             ss,
             device,
             format,
+            output_monitor: None,
         }
     }
 }
@@ -76,10 +89,40 @@ impl Sink for PulseAudioSink {
 
     fn stop(&mut self) -> io::Result<()> {
         self.s = None;
+        self.output_monitor = None;
         Ok(())
     }
 
     sink_as_bytes!();
+
+    fn latency(&self) -> Duration {
+        self.s
+            .as_ref()
+            .and_then(|s| s.get_latency().ok())
+            .map(|latency| Duration::from_micros(latency.0))
+            .unwrap_or_default()
+    }
+
+    fn output_changed(&mut self) -> bool {
+        if self.s.is_none() || self.device.is_some() {
+            return false;
+        }
+        match &self.output_monitor {
+            Some(monitor) => monitor.changed.swap(false, Ordering::Relaxed),
+            None => {
+                self.output_monitor = Some(OutputMonitor::start());
+                false
+            }
+        }
+    }
+
+    fn output(&mut self) -> Option<String> {
+        if self.device.is_some() || self.s.is_none() {
+            return self.device.clone();
+        }
+        let monitor = self.output_monitor.get_or_insert_with(OutputMonitor::start);
+        monitor.output.lock().unwrap().clone()
+    }
 }
 
 impl SinkAsBytes for PulseAudioSink {
@@ -100,3 +143,131 @@ impl SinkAsBytes for PulseAudioSink {
         }
     }
 }
+
+// The default sink and its active port, e.g. speakers or headphones. Unplugging headphones
+// usually switches the port of the same sink.
+type Output = (Option<String>, Option<String>);
+
+// Checks the default sink from a thread with its own connection to the server, as the simple
+// API can't tell where its stream plays.
+struct OutputMonitor {
+    changed: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    // The name of the default sink and its port, e.g. `alsa_output.pci.hdmi-stereo:hdmi-output-0`.
+    output: Arc<Mutex<Option<String>>>,
+}
+
+impl OutputMonitor {
+    fn start() -> Self {
+        let changed = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let output = Arc::new(Mutex::new(None));
+        let monitor = OutputMonitor {
+            changed: changed.clone(),
+            stopped: stopped.clone(),
+            output: output.clone(),
+        };
+        thread::spawn(move || {
+            if watch_output(&changed, &stopped, &output).is_none() {
+                warn!("Cannot watch the PulseAudio output for changes");
+            }
+        });
+        monitor
+    }
+}
+
+impl Drop for OutputMonitor {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+fn watch_output(
+    changed: &AtomicBool,
+    stopped: &AtomicBool,
+    name: &Mutex<Option<String>>,
+) -> Option<()> {
+    let mut mainloop = Mainloop::new()?;
+    let mut context = Context::new(&mainloop, APP_NAME)?;
+    context
+        .connect(None, context::FlagSet::NOFLAGS, None)
+        .ok()?;
+    loop {
+        match context.get_state() {
+            context::State::Ready => break,
+            context::State::Failed | context::State::Terminated => return None,
+            _ => iterate(&mut mainloop)?,
+        }
+    }
+
+    let mut output = current_output(&mut mainloop, &context)?;
+    *name.lock().unwrap() = output_name(&output);
+    while !stopped.load(Ordering::Relaxed) {
+        thread::sleep(OUTPUT_CHECK_INTERVAL);
+        let current = current_output(&mut mainloop, &context)?;
+        if current != output {
+            debug!(
+                "PulseAudio output changed from {:?} to {:?}",
+                output, current
+            );
+            *name.lock().unwrap() = output_name(&current);
+            changed.store(true, Ordering::Relaxed);
+            output = current;
+        }
+    }
+    Some(())
+}
+
+fn output_name(output: &Output) -> Option<String> {
+    match output {
+        (Some(sink), Some(port)) => Some(format!("{}:{}", sink, port)),
+        (sink, _) => sink.clone(),
+    }
+}
+
+fn current_output(mainloop: &mut Mainloop, context: &Context) -> Option<Output> {
+    let introspector = context.introspect();
+
+    let sink_name = Rc::new(RefCell::new(None));
+    let result = sink_name.clone();
+    let operation = introspector.get_server_info(move |info| {
+        *result.borrow_mut() = info.default_sink_name.as_ref().map(|name| name.to_string());
+    });
+    wait_for(mainloop, &operation)?;
+    let sink_name = sink_name.borrow_mut().take();
+
+    let port_name = Rc::new(RefCell::new(None));
+    if let Some(sink_name) = &sink_name {
+        let result = port_name.clone();
+        let operation = introspector.get_sink_info_by_name(sink_name, move |info| {
+            if let ListResult::Item(info) = info {
+                *result.borrow_mut() = info
+                    .active_port
+                    .as_ref()
+                    .and_then(|port| port.name.as_ref())
+                    .map(|name| name.to_string());
+            }
+        });
+        wait_for(mainloop, &operation)?;
+    }
+    let port_name = port_name.borrow_mut().take();
+
+    Some((sink_name, port_name))
+}
+
+fn wait_for<C: ?Sized>(mainloop: &mut Mainloop, operation: &Operation<C>) -> Option<()> {
+    while let operation::State::Running = operation.get_state() {
+        iterate(mainloop)?;
+    }
+    match operation.get_state() {
+        operation::State::Done => Some(()),
+        _ => None,
+    }
+}
+
+fn iterate(mainloop: &mut Mainloop) -> Option<()> {
+    match mainloop.iterate(true) {
+        IterateResult::Success(_) => Some(()),
+        IterateResult::Quit(_) | IterateResult::Err(_) => None,
+    }
+}
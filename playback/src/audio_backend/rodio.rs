@@ -4,7 +4,7 @@ use std::{io, thread, time};
 use cpal::traits::{DeviceTrait, HostTrait};
 use thiserror::Error;
 
-use super::Sink;
+use super::{Sink, OUTPUT_CHECK_INTERVAL};
 use crate::audio::{convert, AudioPacket};
 use crate::config::AudioFormat;
 use crate::player::{NUM_CHANNELS, SAMPLE_RATE};
@@ -47,6 +47,11 @@ pub struct RodioSink {
     rodio_sink: rodio::Sink,
     format: AudioFormat,
     _stream: rodio::OutputStream,
+    host: cpal::Host,
+    // The name of the default device, if the sink was opened without a device, and when it
+    // was last checked for changes.
+    default_device_name: Option<String>,
+    last_output_check: time::Instant,
 }
 
 fn list_formats(device: &rodio::Device) {
@@ -151,6 +156,11 @@ fn create_sink(
     Ok((sink, stream))
 }
 
+fn default_device_name(host: &cpal::Host) -> Option<String> {
+    host.default_output_device()
+        .and_then(|device| device.name().ok())
+}
+
 pub fn open(host: cpal::Host, device: Option<String>, format: AudioFormat) -> RodioSink {
     info!(
         "Using Rodio sink with format {:?} and cpal host: {}",
@@ -162,6 +172,11 @@ pub fn open(host: cpal::Host, device: Option<String>, format: AudioFormat) -> Ro
         unimplemented!("Rodio currently only supports F32 and S16 formats");
     }
 
+    let default_device_name = if device.is_none() {
+        default_device_name(&host)
+    } else {
+        None
+    };
     let (sink, stream) = create_sink(&host, device).unwrap();
 
     debug!("Rodio sink was created");
@@ -169,6 +184,9 @@ pub fn open(host: cpal::Host, device: Option<String>, format: AudioFormat) -> Ro
         rodio_sink: sink,
         format,
         _stream: stream,
+        host,
+        default_device_name,
+        last_output_check: time::Instant::now(),
     }
 }
 
@@ -204,4 +222,25 @@ impl Sink for RodioSink {
         }
         Ok(())
     }
+
+    fn output_changed(&mut self) -> bool {
+        if self.default_device_name.is_none()
+            || self.last_output_check.elapsed() < OUTPUT_CHECK_INTERVAL
+        {
+            return false;
+        }
+        self.last_output_check = time::Instant::now();
+        match default_device_name(&self.host) {
+            Some(name) if Some(&name) != self.default_device_name.as_ref() => {
+                self.default_device_name = Some(name);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // As of the last check for changes.
+    fn output(&mut self) -> Option<String> {
+        self.default_device_name.clone()
+    }
 }
@@ -3,6 +3,8 @@ use crate::audio::{convert, AudioPacket};
 use crate::config::AudioFormat;
 use crate::player::{NUM_CHANNELS, SAMPLE_RATE};
 use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+use std::process::exit;
 use std::{io, thread, time};
 
 pub enum SdlSink {
@@ -15,15 +17,17 @@ impl Open for SdlSink {
     fn open(device: Option<String>, format: AudioFormat) -> Self {
         info!("Using SDL sink with format: {:?}", format);
 
-        if device.is_some() {
-            warn!("SDL sink does not support specifying a device name");
-        }
-
+        // Shares the SDL context of an application that already initialized SDL.
         let ctx = sdl2::init().expect("could not initialize SDL");
         let audio = ctx
             .audio()
             .expect("could not initialize SDL audio subsystem");
 
+        if device.as_deref() == Some("?") {
+            list_outputs(&audio);
+            exit(0)
+        }
+
         let desired_spec = AudioSpecDesired {
             freq: Some(SAMPLE_RATE as i32),
             channels: Some(NUM_CHANNELS),
@@ -33,7 +37,7 @@ impl Open for SdlSink {
         macro_rules! open_sink {
             ($sink: expr, $type: ty) => {{
                 let queue: AudioQueue<$type> = audio
-                    .open_queue(None, &desired_spec)
+                    .open_queue(device.as_deref(), &desired_spec)
                     .expect("could not open SDL audio device");
                 $sink(queue)
             }};
@@ -91,7 +95,7 @@ impl Sink for SdlSink {
         }
 
         let samples = packet.samples();
-        match self {
+        let queued = match self {
             Self::F32(queue) => {
                 drain_sink!(queue, AudioFormat::F32.size());
                 queue.queue(samples)
@@ -107,6 +111,19 @@ impl Sink for SdlSink {
                 queue.queue(samples_s16)
             }
         };
-        Ok(())
+        if queued {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, sdl2::get_error()))
+        }
+    }
+}
+
+fn list_outputs(audio: &AudioSubsystem) {
+    println!("Listing available SDL outputs:");
+    for index in 0..audio.num_audio_playback_devices().unwrap_or(0) {
+        if let Ok(name) = audio.audio_playback_device_name(index) {
+            println!("- {}", name);
+        }
     }
 }
@@ -1,15 +1,32 @@
-use super::{Open, Sink, SinkAsBytes};
+use super::{Open, Sink, SinkAsBytes, SinkTrack};
 use crate::audio::AudioPacket;
 use crate::config::AudioFormat;
 use shell_words::split;
 
-use std::io::{self, Write};
-use std::process::{Child, Command, Stdio};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
+// The restarts of a crashed child in a row, unless it is created with `with_max_restarts`.
+const DEFAULT_MAX_RESTARTS: u32 = 3;
+// A child that ran this long before crashing doesn't count towards the restarts in a row.
+const STABLE_RUNTIME: Duration = Duration::from_secs(30);
+
+/// Pipes the audio to the stdin of a program, e.g. an ffmpeg encoding it for a stream.
+///
+/// The program is restarted if it exits while playing. Its stderr is logged, and the track
+/// that is playing when it is started is passed in the `TRACK_ID`, `TRACK_URI`, `NAME`,
+/// `ARTISTS` and `DURATION_MS` environment variables, with the artists on separate lines like
+/// for the `--onevent` program.
 pub struct SubprocessSink {
     shell_command: String,
     child: Option<Child>,
     format: AudioFormat,
+    max_restarts: u32,
+    restarts: u32,
+    started_at: Instant,
+    track: Option<SinkTrack>,
 }
 
 impl Open for SubprocessSink {
@@ -17,30 +34,66 @@ impl Open for SubprocessSink {
         info!("Using subprocess sink with format: {:?}", format);
 
         if let Some(shell_command) = shell_command {
-            SubprocessSink {
-                shell_command,
-                child: None,
-                format,
-            }
+            Self::with_max_restarts(shell_command, format, DEFAULT_MAX_RESTARTS)
         } else {
             panic!("subprocess sink requires specifying a shell command");
         }
     }
 }
 
-impl Sink for SubprocessSink {
-    fn start(&mut self) -> io::Result<()> {
-        let args = split(&self.shell_command).unwrap();
-        self.child = Some(
-            Command::new(&args[0])
-                .args(&args[1..])
-                .stdin(Stdio::piped())
-                .spawn()?,
-        );
+impl SubprocessSink {
+    /// Creates a sink that restarts its program up to `max_restarts` times in a row if it
+    /// exits, 0 to fail the writes instead.
+    pub fn with_max_restarts(
+        shell_command: String,
+        format: AudioFormat,
+        max_restarts: u32,
+    ) -> Self {
+        SubprocessSink {
+            shell_command,
+            child: None,
+            format,
+            max_restarts,
+            restarts: 0,
+            started_at: Instant::now(),
+            track: None,
+        }
+    }
+
+    fn spawn(&mut self) -> io::Result<()> {
+        let args = split(&self.shell_command)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if args.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty subprocess command",
+            ));
+        }
+
+        let mut command = Command::new(&args[0]);
+        command
+            .args(&args[1..])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(track) = &self.track {
+            command
+                .env("TRACK_ID", track.track_id.to_base62())
+                .env("TRACK_URI", track.track_id.to_uri())
+                .env("NAME", &track.name)
+                .env("ARTISTS", track.artists.join("\n"))
+                .env("DURATION_MS", track.duration_ms.to_string());
+        }
+
+        let mut child = command.spawn()?;
+        if let Some(stderr) = child.stderr.take() {
+            log_stderr(args[0].clone(), stderr);
+        }
+        self.child = Some(child);
+        self.started_at = Instant::now();
         Ok(())
     }
 
-    fn stop(&mut self) -> io::Result<()> {
+    fn kill(&mut self) -> io::Result<()> {
         if let Some(child) = &mut self.child.take() {
             child.kill()?;
             child.wait()?;
@@ -48,16 +101,76 @@ impl Sink for SubprocessSink {
         Ok(())
     }
 
+    // Restarts the child after it exited, unless it exited too often in a row.
+    fn restart(&mut self, error: io::Error) -> io::Result<()> {
+        if let Some(child) = &mut self.child {
+            if let Ok(Some(status)) = child.try_wait() {
+                warn!("{} exited with {}", self.shell_command, status);
+            }
+        }
+        if self.started_at.elapsed() >= STABLE_RUNTIME {
+            self.restarts = 0;
+        }
+        if self.restarts >= self.max_restarts {
+            return Err(error);
+        }
+
+        self.restarts += 1;
+        warn!(
+            "Restarting {} ({} of {})",
+            self.shell_command, self.restarts, self.max_restarts
+        );
+        let _ = self.kill();
+        self.spawn()
+    }
+}
+
+impl Sink for SubprocessSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.restarts = 0;
+        self.spawn()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.kill()
+    }
+
+    fn set_track(&mut self, track: &SinkTrack) {
+        self.track = Some(track.clone());
+    }
+
     sink_as_bytes!();
 }
 
 impl SinkAsBytes for SubprocessSink {
     fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
-        if let Some(child) = &mut self.child {
+        loop {
+            let child = match &mut self.child {
+                Some(child) => child,
+                None => return Ok(()),
+            };
             let child_stdin = child.stdin.as_mut().unwrap();
-            child_stdin.write_all(data)?;
-            child_stdin.flush()?;
+            match child_stdin
+                .write_all(data)
+                .and_then(|_| child_stdin.flush())
+            {
+                Ok(()) => return Ok(()),
+                // The data is written again to the new child.
+                Err(e) => self.restart(e)?,
+            }
         }
-        Ok(())
     }
 }
+
+fn log_stderr(program: String, stderr: ChildStderr) {
+    thread::spawn(move || {
+        // Not `lines`, which stops at invalid UTF-8 and would leave the child blocked on a
+        // full pipe.
+        for line in BufReader::new(stderr).split(b'\n') {
+            match line {
+                Ok(line) => warn!("{}: {}", program, String::from_utf8_lossy(&line).trim_end()),
+                Err(_) => break,
+            }
+        }
+    });
+}
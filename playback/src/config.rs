@@ -1,6 +1,14 @@
 use crate::audio::convert::i24;
+pub use crate::audio::{FetchConfig, FetchProfile};
+use crate::core::spotify_id::FileId;
+use crate::metadata::FileFormat;
+use crate::player::NormalisationData;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
 use std::mem;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 #[derive(Clone, Copy, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
@@ -22,12 +30,161 @@ impl FromStr for Bitrate {
     }
 }
 
+impl Bitrate {
+    pub fn kbps(self) -> u32 {
+        match self {
+            Self::Bitrate96 => 96,
+            Self::Bitrate160 => 160,
+            Self::Bitrate320 => 320,
+        }
+    }
+}
+
 impl Default for Bitrate {
     fn default() -> Self {
         Self::Bitrate320
     }
 }
 
+/// Selects the audio file of a track that is played.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileFormatPolicy {
+    /// The formats to play, in order of preference.
+    pub formats: Vec<FileFormat>,
+    /// Whether to play any other supported format if a track is available in none of
+    /// `formats`, instead of treating the track as unavailable.
+    pub fallback: bool,
+}
+
+impl FileFormatPolicy {
+    // The formats the player can decode.
+    const SUPPORTED: [FileFormat; 3] = [
+        FileFormat::OGG_VORBIS_320,
+        FileFormat::OGG_VORBIS_160,
+        FileFormat::OGG_VORBIS_96,
+    ];
+
+    pub fn select(&self, files: &HashMap<FileFormat, FileId>) -> Option<(FileFormat, FileId)> {
+        let fallback: &[FileFormat] = if self.fallback {
+            &Self::SUPPORTED[..]
+        } else {
+            &[]
+        };
+
+        self.formats
+            .iter()
+            .chain(fallback)
+            .find_map(|format| files.get(format).map(|&file_id| (*format, file_id)))
+    }
+}
+
+impl From<Bitrate> for FileFormatPolicy {
+    fn from(bitrate: Bitrate) -> Self {
+        let formats = match bitrate {
+            Bitrate::Bitrate96 => [
+                FileFormat::OGG_VORBIS_96,
+                FileFormat::OGG_VORBIS_160,
+                FileFormat::OGG_VORBIS_320,
+            ],
+            Bitrate::Bitrate160 => [
+                FileFormat::OGG_VORBIS_160,
+                FileFormat::OGG_VORBIS_96,
+                FileFormat::OGG_VORBIS_320,
+            ],
+            Bitrate::Bitrate320 => [
+                FileFormat::OGG_VORBIS_320,
+                FileFormat::OGG_VORBIS_160,
+                FileFormat::OGG_VORBIS_96,
+            ],
+        };
+
+        Self {
+            formats: formats.to_vec(),
+            fallback: false,
+        }
+    }
+}
+
+/// Parses a comma separated list of bitrates, e.g. "320,160".
+impl FromStr for FileFormatPolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let formats = s
+            .split(',')
+            .map(|bitrate| match Bitrate::from_str(bitrate.trim())? {
+                Bitrate::Bitrate96 => Ok(FileFormat::OGG_VORBIS_96),
+                Bitrate::Bitrate160 => Ok(FileFormat::OGG_VORBIS_160),
+                Bitrate::Bitrate320 => Ok(FileFormat::OGG_VORBIS_320),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            formats,
+            fallback: true,
+        })
+    }
+}
+
+/// Whether the player plays the next tracks at another bitrate of the format policy when the
+/// download rate doesn't match the bitrate of the current track.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum AdaptiveBitrate {
+    Disabled,
+    // Switch to a lower bitrate when the download can't keep up.
+    Downgrade,
+    // Also switch back to a higher bitrate when the download recovers.
+    Full,
+}
+
+impl FromStr for AdaptiveBitrate {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "downgrade" => Ok(Self::Downgrade),
+            "full" => Ok(Self::Full),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for AdaptiveBitrate {
+    fn default() -> Self {
+        Self::Disabled
+    }
+}
+
+/// What the player does when the sink stalls while the decode-ahead buffer is full, e.g.
+/// during a hiccup of the Bluetooth stack.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    // Wait for the sink. The player holds off decoding meanwhile, but still handles commands.
+    Block,
+    // Keep decoding at the pace of playback and drop the oldest buffered audio, so the
+    // latency doesn't grow.
+    DropOldest,
+    // Pause playback.
+    Pause,
+}
+
+impl FromStr for BackpressurePolicy {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "pause" => Ok(Self::Pause),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for BackpressurePolicy {
+    fn default() -> Self {
+        Self::Block
+    }
+}
+
 #[derive(Clone, Copy, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
 pub enum AudioFormat {
     F32,
@@ -115,35 +272,458 @@ impl Default for NormalisationMethod {
     }
 }
 
+/// The output gain of particular outputs, e.g. -6 dB for those whose name contains `hdmi`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputGain {
+    /// A part of the name of the outputs, see `Sink::output`. Case is ignored.
+    pub output: String,
+    pub gain_db: f32,
+}
+
 #[derive(Clone, Debug)]
 pub struct PlayerConfig {
     pub bitrate: Bitrate,
+    // Overrides `bitrate` if set.
+    pub file_format_policy: Option<FileFormatPolicy>,
+    pub fetch_profile: FetchProfile,
+    // The directory of the temporary files of downloads, see `FetchConfig::tmp_dir`.
+    pub fetch_tmp_dir: Option<PathBuf>,
+    pub adaptive_bitrate: AdaptiveBitrate,
     pub normalisation: bool,
     pub normalisation_type: NormalisationType,
     pub normalisation_method: NormalisationMethod,
-    pub normalisation_pregain: f32,
+    // The loudness tracks are normalised to, in LUFS.
+    pub normalisation_target_lufs: f32,
     pub normalisation_threshold: f32,
     pub normalisation_attack: f32,
     pub normalisation_release: f32,
     pub normalisation_knee: f32,
+    // Gain in dB applied after normalisation, e.g. -6 for an output whose input clips. Not
+    // used with passthrough.
+    pub output_gain_db: f32,
+    // Overrides `output_gain_db` for the outputs they match, the first that matches applies.
+    pub output_gains: Vec<OutputGain>,
+    // Whether `Player::duck` attenuates the output. Not used with passthrough.
+    pub ducking: bool,
     pub gapless: bool,
     pub passthrough: bool,
+    // Audio decoded ahead of the sink in milliseconds, see `decode_ahead`. 0 writes each
+    // packet to the sink as soon as it is decoded. Not used with passthrough.
+    pub decode_ahead_ms: u32,
+    // What happens when the sink stalls, see `BackpressurePolicy`. Only used with decode
+    // ahead, whose writer thread keeps the player going while the sink is stalled.
+    pub backpressure_policy: BackpressurePolicy,
+    // How long the sink takes no audio with the decode-ahead buffer full before it counts as
+    // stalled, in milliseconds. Sinks with large latencies, e.g. Bluetooth ones, need longer.
+    pub sink_stall_timeout_ms: u32,
+    // Refuse to play explicit tracks. They are also refused if the account has the
+    // `filter-explicit-content` attribute set.
+    pub filter_explicit_content: bool,
+    // Pause when the default output device changes, e.g. when headphones are unplugged,
+    // instead of playing on through the speakers. Only some sinks detect it.
+    pub pause_on_output_change: bool,
 }
 
 impl Default for PlayerConfig {
     fn default() -> PlayerConfig {
         PlayerConfig {
             bitrate: Bitrate::default(),
+            file_format_policy: None,
+            fetch_profile: FetchProfile::default(),
+            fetch_tmp_dir: None,
+            adaptive_bitrate: AdaptiveBitrate::default(),
             normalisation: false,
             normalisation_type: NormalisationType::default(),
             normalisation_method: NormalisationMethod::default(),
-            normalisation_pregain: 0.0,
+            normalisation_target_lufs: NORMALISATION_REFERENCE_LUFS,
             normalisation_threshold: -1.0,
             normalisation_attack: 0.005,
             normalisation_release: 0.1,
             normalisation_knee: 1.0,
+            output_gain_db: 0.0,
+            output_gains: Vec::new(),
+            ducking: false,
             gapless: true,
             passthrough: true,
+            decode_ahead_ms: 0,
+            backpressure_policy: BackpressurePolicy::default(),
+            sink_stall_timeout_ms: 500,
+            filter_explicit_content: false,
+            pause_on_output_change: false,
         }
     }
 }
+
+/// The loudness the gain data of Spotify's files brings tracks to, in LUFS.
+pub const NORMALISATION_REFERENCE_LUFS: f32 = -14.0;
+
+impl PlayerConfig {
+    /// A builder of a validated configuration. It starts from the defaults, except that
+    /// passthrough is off.
+    pub fn builder() -> PlayerConfigBuilder {
+        let default = PlayerConfig::default();
+        PlayerConfigBuilder(PlayerConfig {
+            passthrough: false,
+            // The default is in dB.
+            normalisation_threshold: NormalisationData::db_to_ratio(
+                default.normalisation_threshold,
+            ),
+            ..default
+        })
+    }
+
+    /// Checks for settings that contradict each other or can't be used for playback.
+    pub fn validate(&self) -> Result<(), PlayerConfigError> {
+        if self.passthrough && self.normalisation {
+            return Err(PlayerConfigError::PassthroughWithNormalisation);
+        }
+        if self.passthrough && self.decode_ahead_ms > 0 {
+            return Err(PlayerConfigError::PassthroughWithDecodeAhead);
+        }
+        if self.decode_ahead_ms == 0 && self.backpressure_policy != BackpressurePolicy::Block {
+            return Err(PlayerConfigError::BackpressureWithoutDecodeAhead);
+        }
+        let gains = || {
+            let output_gains = self.output_gains.iter().map(|gain| gain.gain_db);
+            Some(self.output_gain_db).into_iter().chain(output_gains)
+        };
+        if !gains().all(f32::is_finite) {
+            return Err(PlayerConfigError::InvalidOutputGain);
+        }
+        if self.passthrough && gains().any(|gain| gain != 0.0) {
+            return Err(PlayerConfigError::PassthroughWithOutputGain);
+        }
+        if self.passthrough && self.ducking {
+            return Err(PlayerConfigError::PassthroughWithDucking);
+        }
+        if let Some(policy) = &self.file_format_policy {
+            if policy.formats.is_empty() && !policy.fallback {
+                return Err(PlayerConfigError::NoFileFormat);
+            }
+        }
+        if self.normalisation {
+            if !self.normalisation_target_lufs.is_finite() {
+                return Err(PlayerConfigError::InvalidNormalisation("target"));
+            }
+            if !(self.normalisation_threshold > 0.0 && self.normalisation_threshold <= 1.0) {
+                return Err(PlayerConfigError::InvalidNormalisation("threshold"));
+            }
+            if !(self.normalisation_attack > 0.0 && self.normalisation_attack.is_finite()) {
+                return Err(PlayerConfigError::InvalidNormalisation("attack"));
+            }
+            if !(self.normalisation_release > 0.0 && self.normalisation_release.is_finite()) {
+                return Err(PlayerConfigError::InvalidNormalisation("release"));
+            }
+            if !(self.normalisation_knee >= 0.0 && self.normalisation_knee.is_finite()) {
+                return Err(PlayerConfigError::InvalidNormalisation("knee"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The output gain in dB of the output named `output`: that of the first of `output_gains`
+    /// that matches it, otherwise `output_gain_db`.
+    pub fn output_gain_db_of(&self, output: Option<&str>) -> f32 {
+        let output = output.unwrap_or_default().to_lowercase();
+        self.output_gains
+            .iter()
+            .find(|gain| output.contains(&gain.output.to_lowercase()))
+            .map_or(self.output_gain_db, |gain| gain.gain_db)
+    }
+
+    /// The gain in dB applied on top of the gain data to reach the target loudness.
+    pub fn normalisation_pregain_db(&self) -> f32 {
+        self.normalisation_target_lufs - NORMALISATION_REFERENCE_LUFS
+    }
+
+    pub fn format_policy(&self) -> FileFormatPolicy {
+        self.file_format_policy
+            .clone()
+            .unwrap_or_else(|| self.bitrate.into())
+    }
+
+    pub fn fetch_config(&self) -> FetchConfig {
+        FetchConfig {
+            profile: self.fetch_profile,
+            tmp_dir: self.fetch_tmp_dir.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlayerConfigError {
+    /// Passthrough plays the Ogg data as is, so it can't be normalised.
+    PassthroughWithNormalisation,
+    /// Passthrough doesn't decode, so there is nothing to decode ahead.
+    PassthroughWithDecodeAhead,
+    /// Only the writer thread of decode ahead can drop audio or pause while the sink stalls.
+    BackpressureWithoutDecodeAhead,
+    /// Passthrough doesn't decode, so no gain can be applied.
+    PassthroughWithOutputGain,
+    /// The output gain is infinite or NaN.
+    InvalidOutputGain,
+    /// Passthrough doesn't decode, so the output can't be ducked.
+    PassthroughWithDucking,
+    /// The file format policy has no formats and no fallback, so no track can be played.
+    NoFileFormat,
+    /// A normalisation setting is out of range, e.g. a negative attack time.
+    InvalidNormalisation(&'static str),
+}
+
+impl fmt::Display for PlayerConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PassthroughWithNormalisation => {
+                write!(f, "passthrough can't be used with normalisation")
+            }
+            Self::PassthroughWithDecodeAhead => {
+                write!(f, "passthrough can't be used with decode ahead")
+            }
+            Self::BackpressureWithoutDecodeAhead => {
+                write!(
+                    f,
+                    "a backpressure policy other than block requires decode ahead"
+                )
+            }
+            Self::PassthroughWithOutputGain => {
+                write!(f, "passthrough can't be used with an output gain")
+            }
+            Self::InvalidOutputGain => write!(f, "invalid output gain"),
+            Self::PassthroughWithDucking => write!(f, "passthrough can't be used with ducking"),
+            Self::NoFileFormat => write!(f, "the file format policy allows no format"),
+            Self::InvalidNormalisation(setting) => {
+                write!(f, "invalid normalisation {}", setting)
+            }
+        }
+    }
+}
+
+impl Error for PlayerConfigError {}
+
+/// Builds a `PlayerConfig` that is checked with `PlayerConfig::validate`, see
+/// `PlayerConfig::builder`.
+#[derive(Clone, Debug)]
+pub struct PlayerConfigBuilder(PlayerConfig);
+
+impl PlayerConfigBuilder {
+    pub fn bitrate(mut self, bitrate: Bitrate) -> Self {
+        self.0.bitrate = bitrate;
+        self
+    }
+
+    pub fn file_format_policy(mut self, policy: FileFormatPolicy) -> Self {
+        self.0.file_format_policy = Some(policy);
+        self
+    }
+
+    pub fn fetch_profile(mut self, profile: FetchProfile) -> Self {
+        self.0.fetch_profile = profile;
+        self
+    }
+
+    pub fn fetch_tmp_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.0.fetch_tmp_dir = Some(dir.into());
+        self
+    }
+
+    pub fn adaptive_bitrate(mut self, adaptive_bitrate: AdaptiveBitrate) -> Self {
+        self.0.adaptive_bitrate = adaptive_bitrate;
+        self
+    }
+
+    pub fn normalisation(mut self, normalisation: bool) -> Self {
+        self.0.normalisation = normalisation;
+        self
+    }
+
+    pub fn normalisation_type(mut self, normalisation_type: NormalisationType) -> Self {
+        self.0.normalisation_type = normalisation_type;
+        self
+    }
+
+    pub fn normalisation_method(mut self, method: NormalisationMethod) -> Self {
+        self.0.normalisation_method = method;
+        self
+    }
+
+    pub fn normalisation_target_lufs(mut self, target: f32) -> Self {
+        self.0.normalisation_target_lufs = target;
+        self
+    }
+
+    /// The level the limiter of dynamic normalisation starts at, in dBFS.
+    pub fn normalisation_threshold_db(mut self, threshold: f32) -> Self {
+        self.0.normalisation_threshold = NormalisationData::db_to_ratio(threshold);
+        self
+    }
+
+    /// The attack time of the limiter in seconds.
+    pub fn normalisation_attack(mut self, attack: f32) -> Self {
+        self.0.normalisation_attack = attack;
+        self
+    }
+
+    /// The release time of the limiter in seconds.
+    pub fn normalisation_release(mut self, release: f32) -> Self {
+        self.0.normalisation_release = release;
+        self
+    }
+
+    pub fn normalisation_knee(mut self, knee: f32) -> Self {
+        self.0.normalisation_knee = knee;
+        self
+    }
+
+    /// The gain applied after normalisation, in dB.
+    pub fn output_gain_db(mut self, gain: f32) -> Self {
+        self.0.output_gain_db = gain;
+        self
+    }
+
+    /// The gain applied after normalisation for the outputs whose name contains `output`, in
+    /// dB. Gains added before take precedence.
+    pub fn output_gain(mut self, output: &str, gain_db: f32) -> Self {
+        self.0.output_gains.push(OutputGain {
+            output: output.to_owned(),
+            gain_db,
+        });
+        self
+    }
+
+    /// Lets `Player::duck` attenuate the output.
+    pub fn ducking(mut self, ducking: bool) -> Self {
+        self.0.ducking = ducking;
+        self
+    }
+
+    pub fn gapless(mut self, gapless: bool) -> Self {
+        self.0.gapless = gapless;
+        self
+    }
+
+    pub fn passthrough(mut self, passthrough: bool) -> Self {
+        self.0.passthrough = passthrough;
+        self
+    }
+
+    pub fn decode_ahead_ms(mut self, ms: u32) -> Self {
+        self.0.decode_ahead_ms = ms;
+        self
+    }
+
+    pub fn backpressure_policy(mut self, policy: BackpressurePolicy) -> Self {
+        self.0.backpressure_policy = policy;
+        self
+    }
+
+    pub fn sink_stall_timeout_ms(mut self, ms: u32) -> Self {
+        self.0.sink_stall_timeout_ms = ms;
+        self
+    }
+
+    pub fn filter_explicit_content(mut self, filter: bool) -> Self {
+        self.0.filter_explicit_content = filter;
+        self
+    }
+
+    pub fn pause_on_output_change(mut self, pause: bool) -> Self {
+        self.0.pause_on_output_change = pause;
+        self
+    }
+
+    pub fn build(self) -> Result<PlayerConfig, PlayerConfigError> {
+        self.0.validate()?;
+        Ok(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_valid_config() {
+        let config = PlayerConfig::builder()
+            .bitrate(Bitrate::Bitrate160)
+            .normalisation(true)
+            .decode_ahead_ms(500)
+            .build()
+            .unwrap();
+        assert_eq!(config.bitrate, Bitrate::Bitrate160);
+        assert!(config.normalisation);
+        assert!(!config.passthrough);
+    }
+
+    #[test]
+    fn rejects_contradicting_settings() {
+        let builder = PlayerConfig::builder().passthrough(true);
+        assert_eq!(
+            builder.clone().normalisation(true).build().unwrap_err(),
+            PlayerConfigError::PassthroughWithNormalisation
+        );
+        assert_eq!(
+            builder.clone().decode_ahead_ms(500).build().unwrap_err(),
+            PlayerConfigError::PassthroughWithDecodeAhead
+        );
+        assert_eq!(
+            builder.clone().output_gain_db(-6.0).build().unwrap_err(),
+            PlayerConfigError::PassthroughWithOutputGain
+        );
+        assert_eq!(
+            builder.clone().ducking(true).build().unwrap_err(),
+            PlayerConfigError::PassthroughWithDucking
+        );
+        assert_eq!(
+            builder.output_gain("hdmi", -6.0).build().unwrap_err(),
+            PlayerConfigError::PassthroughWithOutputGain
+        );
+
+        let policy = FileFormatPolicy {
+            formats: Vec::new(),
+            fallback: false,
+        };
+        assert_eq!(
+            PlayerConfig::builder()
+                .file_format_policy(policy)
+                .build()
+                .unwrap_err(),
+            PlayerConfigError::NoFileFormat
+        );
+
+        assert_eq!(
+            PlayerConfig::builder()
+                .backpressure_policy(BackpressurePolicy::Pause)
+                .build()
+                .unwrap_err(),
+            PlayerConfigError::BackpressureWithoutDecodeAhead
+        );
+
+        assert_eq!(
+            PlayerConfig::builder()
+                .normalisation(true)
+                .normalisation_attack(0.0)
+                .build()
+                .unwrap_err(),
+            PlayerConfigError::InvalidNormalisation("attack")
+        );
+        // Normalisation settings are only checked when normalising.
+        assert!(PlayerConfig::builder()
+            .normalisation_attack(0.0)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn picks_output_gain_of_output() {
+        let config = PlayerConfig::builder()
+            .output_gain_db(-3.0)
+            .output_gain("HDMI", -6.0)
+            .output_gain("hdmi-output-1", 0.0)
+            .build()
+            .unwrap();
+        let hdmi = "alsa_output.pci-0000_00_03.0.hdmi-stereo:hdmi-output-1";
+        assert_eq!(config.output_gain_db_of(Some(hdmi)), -6.0);
+        assert_eq!(config.output_gain_db_of(Some("default")), -3.0);
+        assert_eq!(config.output_gain_db_of(None), -3.0);
+    }
+}
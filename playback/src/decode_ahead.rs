@@ -0,0 +1,662 @@
+//! A buffer of decoded audio between the player and the sink.
+//!
+//! The player decodes on its own thread and writes each packet to the sink as soon as it is
+//! decoded. A short CPU spike while decoding then starves the sound card. With a decode-ahead
+//! buffer, the sink is driven by a writer thread of its own, and the player decodes up to the
+//! depth of the buffer ahead of it.
+//!
+//! The buffer also keeps a stalling sink from stalling the player, e.g. during a hiccup of the
+//! Bluetooth stack, depending on the `BackpressurePolicy`.
+//!
+//! The volume is applied by the writer, so that it changes without the delay of the buffer.
+
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio::AudioPacket;
+use crate::audio_backend::{Sink, SinkTrack};
+use crate::config::BackpressurePolicy;
+use crate::mixer::AudioFilter;
+use crate::player::SAMPLES_PER_SECOND;
+
+/// The number of samples of `ms` milliseconds of audio.
+pub fn samples_for_ms(ms: u32) -> usize {
+    (ms as u64 * SAMPLES_PER_SECOND as u64 / 1000) as usize
+}
+
+/// The duration of `samples` samples of audio.
+pub fn duration_of(samples: usize) -> Duration {
+    Duration::from_micros(samples as u64 * 1_000_000 / SAMPLES_PER_SECOND as u64)
+}
+
+struct State {
+    packets: VecDeque<Vec<f32>>,
+    buffered_samples: usize,
+    // The samples taken into the buffer, and those written to the sink or discarded, since
+    // the sink was created.
+    queued_total: u64,
+    done_total: u64,
+    // Woken when samples were written or discarded, see `poll_played` and `poll_writable`.
+    played_waker: Option<Waker>,
+    // Whether the writer is writing a packet it took from the buffer.
+    writing: bool,
+    // Whether the sink should be running, and whether it is.
+    running: bool,
+    sink_running: bool,
+    // The result of the last start or stop of the sink.
+    control_result: Option<io::Result<()>>,
+    // An error writing a packet, returned by the next write.
+    write_error: Option<io::Error>,
+    // A track to pass on to the sink.
+    track: Option<SinkTrack>,
+    // The latency of the sink after the last write.
+    sink_latency: Duration,
+    // Whether the sink reported an output change that wasn't passed on yet.
+    output_changed: bool,
+    // The output of the sink after the last write.
+    output: Option<String>,
+    // When the writer last took a packet or finished writing one.
+    last_progress: Instant,
+    // Whether the sink is stalled, and whether the player was told so.
+    stalled: bool,
+    reported_stalled: bool,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    // Counts samples as played out, whether written or discarded.
+    fn played(&self, state: &mut State, samples: usize) {
+        state.done_total += samples as u64;
+        if let Some(waker) = state.played_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Controls the buffer of a `DecodeAheadSink` from the player.
+#[derive(Clone)]
+pub struct DecodeAheadControl(Arc<Shared>);
+
+impl DecodeAheadControl {
+    /// Discards the buffered audio, e.g. after seeking.
+    pub fn clear(&self) {
+        let mut state = self.0.lock();
+        state.packets.clear();
+        let cleared = mem::replace(&mut state.buffered_samples, 0);
+        self.0.played(&mut state, cleared);
+        self.0.changed.notify_all();
+    }
+
+    /// A mark of the audio buffered so far, to pass to `poll_played`.
+    pub fn mark(&self) -> u64 {
+        self.0.lock().queued_total
+    }
+
+    /// Whether the audio buffered up to `mark` was written to the sink or discarded. Otherwise
+    /// the task of `cx` is woken once more audio was.
+    pub fn poll_played(&self, cx: &mut Context<'_>, mark: u64) -> Poll<()> {
+        let mut state = self.0.lock();
+        if state.done_total >= mark {
+            return Poll::Ready(());
+        }
+        state.played_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Waits until the buffered audio was written to the sink. Returns at once if the sink
+    /// is stopped.
+    pub fn drain(&self) {
+        let mut state = self.0.lock();
+        while state.running && state.sink_running && (state.writing || !state.packets.is_empty()) {
+            state = self.0.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Whether the sink takes audio. Otherwise, while it is stalled, the task of `cx` is woken
+    /// once it recovers.
+    pub fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.0.lock();
+        if !state.stalled {
+            return Poll::Ready(());
+        }
+        // The sink recovers once it finishes writing a packet.
+        state.played_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Returns whether the sink is stalled if that changed since the last call.
+    pub fn stall_changed(&self) -> Option<bool> {
+        let mut state = self.0.lock();
+        if state.stalled == state.reported_stalled {
+            return None;
+        }
+        state.reported_stalled = state.stalled;
+        Some(state.stalled)
+    }
+}
+
+/// A sink buffering up to a given number of samples, which a writer thread writes to the
+/// actual sink. It takes decoded samples only, not the Ogg data of passthrough.
+///
+/// Stopping the sink pauses the writer and keeps the buffered audio, so that playback
+/// continues where it left off when the sink is started again. Use `control()` to discard
+/// or play out the buffered audio.
+pub struct DecodeAheadSink {
+    shared: Arc<Shared>,
+    capacity: usize,
+    policy: BackpressurePolicy,
+    stall_timeout: Duration,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl DecodeAheadSink {
+    /// Buffers up to `capacity` samples for the sink of `sink_builder`, which is built on the
+    /// writer thread. `policy` applies once the sink took no audio for `stall_timeout` with
+    /// the buffer full. The writer applies `audio_filter`, e.g. the volume of a soft mixer, to
+    /// the audio it writes.
+    pub fn new<F>(
+        sink_builder: F,
+        capacity: usize,
+        policy: BackpressurePolicy,
+        stall_timeout: Duration,
+        audio_filter: Option<Box<dyn AudioFilter + Send>>,
+    ) -> DecodeAheadSink
+    where
+        F: FnOnce() -> Box<dyn Sink> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                packets: VecDeque::new(),
+                buffered_samples: 0,
+                queued_total: 0,
+                done_total: 0,
+                played_waker: None,
+                writing: false,
+                running: false,
+                sink_running: false,
+                control_result: None,
+                write_error: None,
+                track: None,
+                sink_latency: Duration::default(),
+                output_changed: false,
+                output: None,
+                last_progress: Instant::now(),
+                stalled: false,
+                reported_stalled: false,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let writer_shared = shared.clone();
+        let writer =
+            thread::spawn(move || write_packets(sink_builder(), audio_filter, &writer_shared));
+
+        DecodeAheadSink {
+            shared,
+            capacity,
+            policy,
+            stall_timeout,
+            writer: Some(writer),
+        }
+    }
+
+    pub fn control(&self) -> DecodeAheadControl {
+        DecodeAheadControl(self.shared.clone())
+    }
+
+    // Starts or stops the sink on the writer thread and waits for the result.
+    fn set_running(&mut self, running: bool) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        state.running = running;
+        state.control_result = None;
+        self.shared.changed.notify_all();
+        // A stalled sink is started or stopped once its write returns, without stalling the
+        // player until then.
+        if state.stalled {
+            return Ok(());
+        }
+        loop {
+            if let Some(result) = state.control_result.take() {
+                return result;
+            }
+            if state.sink_running == running {
+                return Ok(());
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Sink for DecodeAheadSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.set_running(true)
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.set_running(false)
+    }
+
+    fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+        let samples = packet.samples();
+        let mut state = self.shared.lock();
+        // Whether the write already waited for the duration of its packet while stalled.
+        let mut paced = false;
+        loop {
+            if let Some(e) = state.write_error.take() {
+                return Err(e);
+            }
+            if !state.running {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "write to a stopped sink",
+                ));
+            }
+            // A packet larger than the buffer is taken once the buffer is empty.
+            if state.buffered_samples == 0
+                || state.buffered_samples + samples.len() <= self.capacity
+            {
+                break;
+            }
+
+            let stalled_for = state.last_progress.elapsed();
+            if stalled_for < self.stall_timeout {
+                state = self
+                    .shared
+                    .changed
+                    .wait_timeout(state, self.stall_timeout - stalled_for)
+                    .unwrap()
+                    .0;
+                continue;
+            }
+
+            state.stalled = true;
+            match self.policy {
+                // Keeps the pace of playback unless the sink recovers meanwhile.
+                BackpressurePolicy::DropOldest if !paced => {
+                    paced = true;
+                    state = self
+                        .shared
+                        .changed
+                        .wait_timeout(state, duration_of(samples.len()))
+                        .unwrap()
+                        .0;
+                }
+                BackpressurePolicy::DropOldest => {
+                    while state.buffered_samples + samples.len() > self.capacity {
+                        match state.packets.pop_front() {
+                            Some(dropped) => {
+                                state.buffered_samples -= dropped.len();
+                                self.shared.played(&mut state, dropped.len());
+                            }
+                            None => break,
+                        }
+                    }
+                    break;
+                }
+                // Taken beyond the capacity. Once the player learns of the stall, it holds off
+                // decoding until the sink recovers, or pauses.
+                BackpressurePolicy::Block | BackpressurePolicy::Pause => break,
+            }
+        }
+
+        state.buffered_samples += samples.len();
+        state.queued_total += samples.len() as u64;
+        state.packets.push_back(samples.to_vec());
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn set_track(&mut self, track: &SinkTrack) {
+        self.shared.lock().track = Some(track.clone());
+        self.shared.changed.notify_all();
+    }
+
+    fn latency(&self) -> Duration {
+        let state = self.shared.lock();
+        duration_of(state.buffered_samples) + state.sink_latency
+    }
+
+    fn output_changed(&mut self) -> bool {
+        mem::replace(&mut self.shared.lock().output_changed, false)
+    }
+
+    fn output(&mut self) -> Option<String> {
+        self.shared.lock().output.clone()
+    }
+}
+
+impl Drop for DecodeAheadSink {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.changed.notify_all();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_packets(
+    mut sink: Box<dyn Sink>,
+    audio_filter: Option<Box<dyn AudioFilter + Send>>,
+    shared: &Shared,
+) {
+    let mut state = shared.lock();
+    loop {
+        if state.shutdown {
+            if state.sink_running {
+                drop(state);
+                let _ = sink.stop();
+            }
+            return;
+        }
+
+        if state.running != state.sink_running {
+            let running = state.running;
+            drop(state);
+            let result = if running { sink.start() } else { sink.stop() };
+            state = shared.lock();
+            // The sink stays stopped if it couldn't be started.
+            state.sink_running = running && result.is_ok();
+            state.last_progress = Instant::now();
+            if result.is_err() {
+                state.running = state.sink_running;
+            }
+            state.control_result = Some(result);
+            shared.changed.notify_all();
+            continue;
+        }
+
+        // Passed on right away, possibly before the buffered end of the previous track.
+        if let Some(track) = state.track.take() {
+            drop(state);
+            sink.set_track(&track);
+            state = shared.lock();
+            continue;
+        }
+
+        if state.sink_running {
+            if let Some(mut samples) = state.packets.pop_front() {
+                state.buffered_samples -= samples.len();
+                state.writing = true;
+                state.last_progress = Instant::now();
+                shared.changed.notify_all();
+                drop(state);
+                if let Some(ref audio_filter) = audio_filter {
+                    audio_filter.modify_stream(&mut samples);
+                }
+                let written = samples.len();
+                let result = sink.write(&AudioPacket::Samples(samples));
+                let latency = sink.latency();
+                let output_changed = sink.output_changed();
+                let output = sink.output();
+                state = shared.lock();
+                shared.played(&mut state, written);
+                state.writing = false;
+                state.last_progress = Instant::now();
+                state.stalled = false;
+                state.sink_latency = latency;
+                state.output_changed |= output_changed;
+                state.output = output;
+                shared.changed.notify_all();
+                if let Err(e) = result {
+                    state.write_error = Some(e);
+                }
+                continue;
+            }
+        }
+
+        state = shared.changed.wait(state).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    // Records the written samples. Each write waits for a permit, so that the tests control
+    // what is buffered. Writing a negative sample reports an output change.
+    struct GatedSink {
+        written: Arc<Mutex<Vec<f32>>>,
+        writing: mpsc::Sender<()>,
+        permits: mpsc::Receiver<()>,
+    }
+
+    impl Sink for GatedSink {
+        fn start(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn stop(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+            let _ = self.writing.send(());
+            let _ = self.permits.recv();
+            self.written
+                .lock()
+                .unwrap()
+                .extend_from_slice(packet.samples());
+            Ok(())
+        }
+
+        fn output_changed(&mut self) -> bool {
+            self.written
+                .lock()
+                .unwrap()
+                .last()
+                .map_or(false, |&sample| sample < 0.0)
+        }
+    }
+
+    struct Gate {
+        written: Arc<Mutex<Vec<f32>>>,
+        writing: mpsc::Receiver<()>,
+        permits: mpsc::Sender<()>,
+    }
+
+    fn gated_sink(capacity: usize, policy: BackpressurePolicy) -> (DecodeAheadSink, Gate) {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let (writing_tx, writing_rx) = mpsc::channel();
+        let (permits_tx, permits_rx) = mpsc::channel();
+        let sink_written = written.clone();
+        let sink = DecodeAheadSink::new(
+            move || {
+                Box::new(GatedSink {
+                    written: sink_written,
+                    writing: writing_tx,
+                    permits: permits_rx,
+                }) as Box<dyn Sink>
+            },
+            capacity,
+            policy,
+            Duration::from_millis(100),
+            None,
+        );
+        let gate = Gate {
+            written,
+            writing: writing_rx,
+            permits: permits_tx,
+        };
+        (sink, gate)
+    }
+
+    #[test]
+    fn writes_packets_in_order() {
+        let (mut sink, gate) = gated_sink(4, BackpressurePolicy::Block);
+        for _ in 0..10 {
+            gate.permits.send(()).unwrap();
+        }
+
+        sink.start().unwrap();
+        for i in 0..10 {
+            sink.write(&AudioPacket::Samples(vec![i as f32; 3]))
+                .unwrap();
+        }
+        sink.control().drain();
+
+        let expected: Vec<f32> = (0..10).flat_map(|i| vec![i as f32; 3]).collect();
+        assert_eq!(*gate.written.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn clear_discards_buffered_audio() {
+        let (mut sink, gate) = gated_sink(100, BackpressurePolicy::Block);
+        sink.start().unwrap();
+
+        sink.write(&AudioPacket::Samples(vec![1.0])).unwrap();
+        // The first packet is being written, the next ones are buffered.
+        gate.writing.recv().unwrap();
+        sink.write(&AudioPacket::Samples(vec![2.0])).unwrap();
+        sink.write(&AudioPacket::Samples(vec![3.0])).unwrap();
+        sink.control().clear();
+        sink.write(&AudioPacket::Samples(vec![4.0])).unwrap();
+
+        gate.permits.send(()).unwrap();
+        gate.permits.send(()).unwrap();
+        sink.control().drain();
+        assert_eq!(*gate.written.lock().unwrap(), vec![1.0, 4.0]);
+    }
+
+    #[test]
+    fn reports_buffered_audio_as_latency() {
+        let second = SAMPLES_PER_SECOND as usize;
+        let (mut sink, gate) = gated_sink(2 * second, BackpressurePolicy::Block);
+        sink.start().unwrap();
+
+        sink.write(&AudioPacket::Samples(vec![1.0])).unwrap();
+        gate.writing.recv().unwrap();
+        sink.write(&AudioPacket::Samples(vec![2.0; second]))
+            .unwrap();
+        assert_eq!(sink.latency(), Duration::from_secs(1));
+
+        gate.permits.send(()).unwrap();
+        gate.permits.send(()).unwrap();
+        sink.control().drain();
+        assert_eq!(sink.latency(), Duration::default());
+    }
+
+    #[test]
+    fn polls_whether_marked_audio_was_played() {
+        let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+
+        let (mut sink, gate) = gated_sink(100, BackpressurePolicy::Block);
+        let control = sink.control();
+        sink.start().unwrap();
+
+        sink.write(&AudioPacket::Samples(vec![1.0])).unwrap();
+        gate.writing.recv().unwrap();
+        sink.write(&AudioPacket::Samples(vec![2.0])).unwrap();
+        let mark = control.mark();
+        sink.write(&AudioPacket::Samples(vec![3.0])).unwrap();
+        assert_eq!(control.poll_played(&mut cx, mark), Poll::Pending);
+
+        gate.permits.send(()).unwrap();
+        gate.writing.recv().unwrap();
+        assert_eq!(control.poll_played(&mut cx, mark), Poll::Pending);
+
+        // The last packet is being written once the marked ones were.
+        gate.permits.send(()).unwrap();
+        gate.writing.recv().unwrap();
+        assert_eq!(control.poll_played(&mut cx, mark), Poll::Ready(()));
+        gate.permits.send(()).unwrap();
+    }
+
+    #[test]
+    fn passes_on_output_changes_once() {
+        let (mut sink, gate) = gated_sink(100, BackpressurePolicy::Block);
+        for _ in 0..3 {
+            gate.permits.send(()).unwrap();
+        }
+        sink.start().unwrap();
+
+        sink.write(&AudioPacket::Samples(vec![1.0])).unwrap();
+        sink.control().drain();
+        assert!(!sink.output_changed());
+
+        sink.write(&AudioPacket::Samples(vec![-1.0])).unwrap();
+        sink.write(&AudioPacket::Samples(vec![1.0])).unwrap();
+        sink.control().drain();
+        assert!(sink.output_changed());
+        assert!(!sink.output_changed());
+    }
+
+    #[test]
+    fn refuses_writes_while_stopped() {
+        let (mut sink, _gate) = gated_sink(100, BackpressurePolicy::Block);
+        assert!(sink.write(&AudioPacket::Samples(vec![1.0])).is_err());
+        sink.start().unwrap();
+        sink.stop().unwrap();
+        assert!(sink.write(&AudioPacket::Samples(vec![1.0])).is_err());
+    }
+
+    #[test]
+    fn drops_oldest_audio_while_stalled() {
+        let (mut sink, gate) = gated_sink(2, BackpressurePolicy::DropOldest);
+        let control = sink.control();
+        sink.start().unwrap();
+
+        sink.write(&AudioPacket::Samples(vec![1.0])).unwrap();
+        // The sink stalls writing the first packet.
+        gate.writing.recv().unwrap();
+        sink.write(&AudioPacket::Samples(vec![2.0])).unwrap();
+        sink.write(&AudioPacket::Samples(vec![3.0])).unwrap();
+        assert_eq!(control.stall_changed(), None);
+
+        sink.write(&AudioPacket::Samples(vec![4.0])).unwrap();
+        assert_eq!(control.stall_changed(), Some(true));
+        assert_eq!(control.stall_changed(), None);
+
+        for _ in 0..3 {
+            gate.permits.send(()).unwrap();
+        }
+        control.drain();
+        assert_eq!(*gate.written.lock().unwrap(), vec![1.0, 3.0, 4.0]);
+        assert_eq!(control.stall_changed(), Some(false));
+    }
+
+    #[test]
+    fn holds_off_writes_while_stalled() {
+        let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+
+        let (mut sink, gate) = gated_sink(2, BackpressurePolicy::Block);
+        let control = sink.control();
+        sink.start().unwrap();
+
+        sink.write(&AudioPacket::Samples(vec![1.0])).unwrap();
+        // The sink stalls writing the first packet, the write returns after the timeout.
+        gate.writing.recv().unwrap();
+        sink.write(&AudioPacket::Samples(vec![2.0])).unwrap();
+        sink.write(&AudioPacket::Samples(vec![3.0])).unwrap();
+        assert_eq!(control.poll_writable(&mut cx), Poll::Ready(()));
+        sink.write(&AudioPacket::Samples(vec![4.0])).unwrap();
+        assert_eq!(control.stall_changed(), Some(true));
+        assert_eq!(control.poll_writable(&mut cx), Poll::Pending);
+
+        gate.permits.send(()).unwrap();
+        gate.writing.recv().unwrap();
+        assert_eq!(control.poll_writable(&mut cx), Poll::Ready(()));
+        for _ in 0..3 {
+            gate.permits.send(()).unwrap();
+        }
+        control.drain();
+        assert_eq!(*gate.written.lock().unwrap(), vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(control.stall_changed(), Some(false));
+    }
+}
@@ -0,0 +1,140 @@
+//! Temporary attenuation of the output, e.g. while a voice assistant talks over the music.
+//!
+//! The gain ramps linearly to its target, so ducking and unducking don't click.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::mixer::AudioFilter;
+use crate::player::{NUM_CHANNELS, SAMPLE_RATE};
+
+/// The gain applied to the samples and the ramp towards its target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ducking {
+    gain: f32,
+    target: f32,
+    // The change of the gain per frame while ramping.
+    step: f32,
+    // The duration of the last fade, which is also used to unduck.
+    fade_ms: u32,
+}
+
+impl Default for Ducking {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            target: 1.0,
+            step: 0.0,
+            fade_ms: 0,
+        }
+    }
+}
+
+impl Ducking {
+    /// Ramps the gain to `level`, clamped to [0.0, 1.0], within `fade_ms`.
+    pub fn fade_to(&mut self, level: f32, fade_ms: u32) {
+        self.target = level.clamp(0.0, 1.0);
+        self.fade_ms = fade_ms;
+        let frames = fade_ms as u64 * SAMPLE_RATE as u64 / 1000;
+        if frames == 0 {
+            self.gain = self.target;
+            self.step = 0.0;
+        } else {
+            self.step = (self.target - self.gain).abs() / frames as f32;
+        }
+    }
+
+    /// Ramps the gain back to 1.0 within the duration of the last fade.
+    pub fn release(&mut self) {
+        self.fade_to(1.0, self.fade_ms);
+    }
+
+    /// Whether the samples are left unchanged.
+    pub fn is_inactive(&self) -> bool {
+        self.gain >= 1.0 && self.target >= 1.0
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Applies the gain to interleaved stereo samples, advancing the ramp.
+    pub fn apply(&mut self, samples: &mut [f32]) {
+        if self.is_inactive() {
+            return;
+        }
+
+        for frame in samples.chunks_mut(NUM_CHANNELS as usize) {
+            if self.gain < self.target {
+                self.gain = (self.gain + self.step).min(self.target);
+            } else if self.gain > self.target {
+                self.gain = (self.gain - self.step).max(self.target);
+            }
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
+
+/// A `Ducking` shared between the player and the audio filter that applies it.
+#[derive(Debug, Clone, Default)]
+pub struct SharedDucking(Arc<Mutex<Ducking>>);
+
+impl SharedDucking {
+    pub fn lock(&self) -> MutexGuard<'_, Ducking> {
+        self.0.lock().unwrap()
+    }
+}
+
+/// Applies the ducking after another audio filter, e.g. the volume of a soft mixer. The writer
+/// of decode ahead applies it, so that ducking takes effect without the delay of the buffer.
+pub struct DuckingFilter {
+    pub filter: Option<Box<dyn AudioFilter + Send>>,
+    pub ducking: SharedDucking,
+}
+
+impl AudioFilter for DuckingFilter {
+    fn modify_stream(&self, data: &mut [f32]) {
+        if let Some(filter) = &self.filter {
+            filter.modify_stream(data);
+        }
+        self.ducking.lock().apply(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ramps_to_the_level() {
+        let mut ducking = Ducking::default();
+        ducking.fade_to(0.2, 10);
+        let frames = SAMPLE_RATE as usize / 100;
+
+        let mut samples = vec![1.0; frames * 2];
+        ducking.apply(&mut samples);
+        assert!(samples[0] < 1.0 && samples[0] > 0.99);
+        assert!(samples.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!((ducking.gain() - 0.2).abs() < 1e-4);
+
+        let mut samples = vec![1.0; 4];
+        ducking.apply(&mut samples);
+        assert!(samples.iter().all(|sample| (sample - 0.2).abs() < 1e-4));
+    }
+
+    #[test]
+    fn unducks_without_fade() {
+        let mut ducking = Ducking::default();
+        ducking.fade_to(0.0, 0);
+        let mut samples = vec![0.5; 4];
+        ducking.apply(&mut samples);
+        assert_eq!(samples, vec![0.0; 4]);
+
+        ducking.release();
+        assert!(ducking.is_inactive());
+        let mut samples = vec![0.5; 4];
+        ducking.apply(&mut samples);
+        assert_eq!(samples, vec![0.5; 4]);
+    }
+}
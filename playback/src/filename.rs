@@ -0,0 +1,242 @@
+//! File names for tracks written to disk, e.g. by the download command.
+//!
+//! A template like `{artist} - {title}.{ext}` is filled in with the fields of a track, and the
+//! result is sanitized for the file system of the platform, so that it can't add directories
+//! or be invalid.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_TEMPLATE: &str = "{artist} - {title}.{ext}";
+
+// The length limit of a file name on common file systems, in bytes.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// The fields of a track that templates refer to.
+#[derive(Debug, Clone, Default)]
+pub struct TrackFields<'a> {
+    /// `{artist}`, the artists separated by commas.
+    pub artists: &'a [String],
+    /// `{title}`
+    pub title: &'a str,
+    /// `{album}`
+    pub album: &'a str,
+    /// `{track}`, with two digits, empty if unknown.
+    pub track_number: Option<u32>,
+    /// `{ext}`, without the dot.
+    pub extension: &'a str,
+}
+
+/// Generates the file names of tracks from a template. Names it generated before in the same
+/// directory get a counter, e.g. `Artist - Title (2).ogg`, so tracks with the same fields
+/// don't overwrite each other. Files that exist already are not avoided, so that a rerun
+/// generates the same names and finds them.
+#[derive(Debug, Clone)]
+pub struct Filenames {
+    template: String,
+    generated: HashMap<PathBuf, u32>,
+}
+
+impl Default for Filenames {
+    fn default() -> Self {
+        Self::new(DEFAULT_TEMPLATE)
+    }
+}
+
+impl Filenames {
+    /// Unknown placeholders are kept as they are.
+    pub fn new(template: &str) -> Self {
+        Filenames {
+            template: template.to_owned(),
+            generated: HashMap::new(),
+        }
+    }
+
+    /// The path of a track in `directory`.
+    pub fn path(&mut self, directory: &Path, track: &TrackFields) -> PathBuf {
+        let name = self.name(track);
+        let path = directory.join(&name);
+
+        let count = self.generated.entry(path.clone()).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            return path;
+        }
+
+        let suffix = format!(" ({})", count);
+        let (stem, extension) = split_extension(&name);
+        let length = MAX_NAME_LENGTH.saturating_sub(extension.len() + suffix.len());
+        let stem = truncate(stem, length);
+        directory.join(format!("{}{}{}", stem, suffix, extension))
+    }
+
+    /// The file name of a track, without a counter.
+    pub fn name(&self, track: &TrackFields) -> String {
+        let mut name = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            name.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+            match field(track, &rest[1..end]) {
+                Some(value) => name.push_str(&value),
+                None => name.push_str(&rest[..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+        name.push_str(rest);
+
+        let name = sanitize(&name);
+        let (stem, extension) = split_extension(&name);
+        let stem = truncate(stem, MAX_NAME_LENGTH.saturating_sub(extension.len()));
+        format!("{}{}", stem, extension)
+    }
+}
+
+fn field(track: &TrackFields, name: &str) -> Option<String> {
+    let value = match name {
+        "artist" => track.artists.join(", "),
+        "title" => track.title.to_owned(),
+        "album" => track.album.to_owned(),
+        "track" => track
+            .track_number
+            .map(|number| format!("{:02}", number))
+            .unwrap_or_default(),
+        "ext" => track.extension.to_owned(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Makes `name` a valid file name, e.g. for a directory. Replaces control characters and
+/// `/\:*?"<>|` on every platform, since FAT and SMB file systems don't allow them either. The
+/// name isn't empty, `.` or `..`, and on Windows not a reserved device name like `NUL` and
+/// without trailing dots and spaces.
+pub fn sanitize(name: &str) -> String {
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| if is_forbidden(c) { '_' } else { c })
+        .collect();
+    #[cfg(windows)]
+    let name = {
+        let name = name.trim_end_matches(|c: char| c == '.' || c == ' ');
+        if is_reserved(name) {
+            format!("_{}", name)
+        } else {
+            name.to_owned()
+        }
+    };
+
+    if name.trim_matches('.').is_empty() {
+        name.replace('.', "_") + "_"
+    } else {
+        name
+    }
+}
+
+fn is_forbidden(c: char) -> bool {
+    matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control()
+}
+
+// CON, NUL, COM1 and so on, also with an extension.
+#[cfg(windows)]
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let stem = stem.to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            (stem.starts_with("COM") || stem.starts_with("LPT"))
+                && stem.len() == 4
+                && stem.as_bytes()[3].is_ascii_digit()
+        }
+    }
+}
+
+// Splits off the extension including the dot, if the name has one that isn't the whole name.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+// Cuts `s` to at most `length` bytes at a character boundary.
+fn truncate(s: &str, length: usize) -> &str {
+    if s.len() <= length {
+        return s;
+    }
+    let mut end = length;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track<'a>(artists: &'a [String], title: &'a str) -> TrackFields<'a> {
+        TrackFields {
+            artists,
+            title,
+            album: "Album",
+            track_number: Some(3),
+            extension: "ogg",
+        }
+    }
+
+    #[test]
+    fn fills_in_template() {
+        let artists = vec!["A".to_owned(), "B".to_owned()];
+        let filenames = Filenames::new("{track}. {artist} - {title} {unknown}.{ext}");
+        assert_eq!(
+            filenames.name(&track(&artists, "AC/DC")),
+            "03. A, B - AC_DC {unknown}.ogg"
+        );
+        assert_eq!(
+            filenames.name(&track(&artists, "What?: <Live>")),
+            "03. A, B - What__ _Live_ {unknown}.ogg"
+        );
+    }
+
+    #[test]
+    fn counts_collisions() {
+        let artists = vec!["Artist".to_owned()];
+        let mut filenames = Filenames::default();
+        let directory = Path::new("music");
+        assert_eq!(
+            filenames.path(directory, &track(&artists, "Title")),
+            directory.join("Artist - Title.ogg")
+        );
+        assert_eq!(
+            filenames.path(directory, &track(&artists, "Title")),
+            directory.join("Artist - Title (2).ogg")
+        );
+        assert_eq!(
+            filenames.path(Path::new("other"), &track(&artists, "Title")),
+            Path::new("other").join("Artist - Title.ogg")
+        );
+    }
+
+    #[test]
+    fn limits_length() {
+        let artists = vec!["ä".repeat(200)];
+        let name = Filenames::default().name(&track(&artists, "Title"));
+        assert!(name.len() <= MAX_NAME_LENGTH);
+        assert!(name.ends_with(".ogg"));
+    }
+
+    #[test]
+    fn avoids_special_names() {
+        let filenames = Filenames::new("{title}");
+        assert_eq!(filenames.name(&track(&[], "..")), "___");
+        assert_eq!(filenames.name(&track(&[], "")), "_");
+    }
+}
@@ -7,5 +7,12 @@ use librespot_metadata as metadata;
 
 pub mod audio_backend;
 pub mod config;
+pub mod decode_ahead;
+pub mod ducking;
+pub mod filename;
+pub mod listen;
 pub mod mixer;
+pub mod pacing;
+pub mod pcm_source;
 pub mod player;
+pub mod sync;
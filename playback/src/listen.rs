@@ -0,0 +1,220 @@
+//! Derives how long each track was listened to from the player events, e.g. to scrobble
+//! tracks to Last.fm or ListenBrainz.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::core::spotify_id::SpotifyId;
+use crate::player::PlayerEvent;
+
+/// A track that was played, reported when it ends, is skipped or playback stops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listen {
+    pub track_id: SpotifyId,
+    /// When playback of the track started.
+    pub started_at: SystemTime,
+    pub duration_ms: u32,
+    /// The time the track was actually playing. Pauses are left out, seeking is not counted
+    /// as listening.
+    pub listened_ms: u32,
+    /// Whether the track was played to its end.
+    pub completed: bool,
+    /// The position the track was left at if it was not completed.
+    pub skipped_at_ms: Option<u32>,
+}
+
+struct CurrentListen {
+    track_id: SpotifyId,
+    started_at: SystemTime,
+    duration_ms: u32,
+    listened: Duration,
+    position_ms: u32,
+    // Since when the track is playing from `position_ms`, `None` while paused.
+    playing_since: Option<Instant>,
+}
+
+impl CurrentListen {
+    fn position_ms(&self, now: Instant) -> u32 {
+        let playing = self
+            .playing_since
+            .map_or(Duration::from_secs(0), |since| now.duration_since(since));
+        (self.position_ms + playing.as_millis() as u32).min(self.duration_ms)
+    }
+
+    fn listened(&self, now: Instant) -> Duration {
+        self.listened
+            + self
+                .playing_since
+                .map_or(Duration::from_secs(0), |since| now.duration_since(since))
+    }
+
+    fn finish(self, now: Instant, completed: bool) -> Listen {
+        let listened_ms = (self.listened(now).as_millis() as u32).min(self.duration_ms);
+        Listen {
+            track_id: self.track_id,
+            started_at: self.started_at,
+            duration_ms: self.duration_ms,
+            listened_ms,
+            completed,
+            skipped_at_ms: if completed {
+                None
+            } else {
+                Some(self.position_ms(now))
+            },
+        }
+    }
+}
+
+/// Turns player events into a `Listen` per played track. The player does this for the
+/// receivers of `Player::get_listen_channel`, use it directly for other event sources.
+#[derive(Default)]
+pub struct ListenTracker {
+    current: Option<CurrentListen>,
+}
+
+impl ListenTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the listen of a track that ended with this event.
+    pub fn handle_event(&mut self, event: &PlayerEvent) -> Option<Listen> {
+        self.handle_event_at(event, Instant::now())
+    }
+
+    fn handle_event_at(&mut self, event: &PlayerEvent, now: Instant) -> Option<Listen> {
+        match *event {
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                let finished = self.finish_other(track_id, now);
+                match self.current {
+                    Some(ref mut current) => {
+                        // Playback resumed or the position was reported after a seek.
+                        current.listened = current.listened(now);
+                        current.position_ms = position_ms;
+                        current.playing_since = Some(now);
+                    }
+                    None => {
+                        self.current = Some(CurrentListen {
+                            track_id,
+                            started_at: SystemTime::now(),
+                            duration_ms,
+                            listened: Duration::from_secs(0),
+                            position_ms,
+                            playing_since: Some(now),
+                        })
+                    }
+                }
+                finished
+            }
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                let finished = self.finish_other(track_id, now);
+                if let Some(ref mut current) = self.current {
+                    current.listened = current.listened(now);
+                    current.position_ms = position_ms;
+                    current.playing_since = None;
+                }
+                finished
+            }
+            PlayerEvent::EndOfTrack { track_id, .. } => match self.current {
+                Some(ref current) if current.track_id == track_id => {
+                    self.current.take().map(|current| current.finish(now, true))
+                }
+                _ => None,
+            },
+            PlayerEvent::Stopped { .. } => self.finish(now),
+            PlayerEvent::Loading { track_id, .. } | PlayerEvent::Started { track_id, .. } => {
+                self.finish_other(track_id, now)
+            }
+            PlayerEvent::Changed { new_track_id, .. } => self.finish_other(new_track_id, now),
+            _ => None,
+        }
+    }
+
+    fn finish(&mut self, now: Instant) -> Option<Listen> {
+        self.current
+            .take()
+            .map(|current| current.finish(now, false))
+    }
+
+    // Finishes the current listen if another track is played now.
+    fn finish_other(&mut self, track_id: SpotifyId, now: Instant) -> Option<Listen> {
+        match self.current {
+            Some(ref current) if current.track_id != track_id => self.finish(now),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(n: u8) -> SpotifyId {
+        SpotifyId::from_raw(&[n; 16]).unwrap()
+    }
+
+    fn playing(track_id: SpotifyId, position_ms: u32) -> PlayerEvent {
+        PlayerEvent::Playing {
+            play_request_id: 0,
+            track_id,
+            position_ms,
+            duration_ms: 200_000,
+        }
+    }
+
+    #[test]
+    fn completed_track_leaves_out_pauses() {
+        let mut tracker = ListenTracker::new();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(tracker
+            .handle_event_at(&playing(track(1), 0), at(0))
+            .is_none());
+        let paused = PlayerEvent::Paused {
+            play_request_id: 0,
+            track_id: track(1),
+            position_ms: 50_000,
+            duration_ms: 200_000,
+        };
+        assert!(tracker.handle_event_at(&paused, at(50)).is_none());
+        assert!(tracker
+            .handle_event_at(&playing(track(1), 50_000), at(100))
+            .is_none());
+
+        let end = PlayerEvent::EndOfTrack {
+            play_request_id: 0,
+            track_id: track(1),
+        };
+        let listen = tracker.handle_event_at(&end, at(250)).unwrap();
+        assert_eq!(listen.listened_ms, 200_000);
+        assert!(listen.completed);
+        assert_eq!(listen.skipped_at_ms, None);
+    }
+
+    #[test]
+    fn next_track_skips_the_current_one() {
+        let mut tracker = ListenTracker::new();
+        let start = Instant::now();
+
+        tracker.handle_event_at(&playing(track(1), 0), start);
+        // Seeking ahead isn't listening.
+        tracker.handle_event_at(&playing(track(1), 100_000), start + Duration::from_secs(10));
+
+        let listen = tracker
+            .handle_event_at(&playing(track(2), 0), start + Duration::from_secs(40))
+            .unwrap();
+        assert_eq!(listen.track_id, track(1));
+        assert_eq!(listen.listened_ms, 40_000);
+        assert!(!listen.completed);
+        assert_eq!(listen.skipped_at_ms, Some(130_000));
+    }
+}
@@ -3,6 +3,7 @@ use std::sync::Arc;
 
 use super::AudioFilter;
 use super::{Mixer, MixerConfig};
+use crate::audio::simd;
 
 #[derive(Clone)]
 pub struct SoftMixer {
@@ -38,10 +39,7 @@ impl AudioFilter for SoftVolumeApplier {
     fn modify_stream(&self, data: &mut [f32]) {
         let volume = self.volume.load(Ordering::Relaxed) as u16;
         if volume != 0xFFFF {
-            let volume_factor = volume as f64 / 0xFFFF as f64;
-            for x in data.iter_mut() {
-                *x = (*x as f64 * volume_factor) as f32;
-            }
+            simd::scale(data, volume as f32 / 0xFFFF as f32);
         }
     }
 }
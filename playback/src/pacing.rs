@@ -0,0 +1,208 @@
+//! Even pacing of the writes to a sink, for outputs that glitch with bursty writes such as
+//! Bluetooth (A2DP) speakers behind BlueZ.
+//!
+//! The player writes packets of varying size as fast as the sink takes them. A `PacedSink`
+//! instead writes chunks of a fixed duration at the pace of playback, keeping a steady amount
+//! of audio ahead of what is heard. If the sink falls behind, e.g. because the link dropped
+//! for a moment, the pace restarts from the current time instead of catching up in a burst.
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio::AudioPacket;
+use crate::audio_backend::{Sink, SinkTrack};
+use crate::decode_ahead::{duration_of, samples_for_ms};
+
+/// The duration of the chunks written to the sink.
+pub const DEFAULT_CHUNK: Duration = Duration::from_millis(20);
+
+/// The audio kept written ahead of what is heard. Bluetooth sinks add latencies of a few
+/// hundred milliseconds, which this covers.
+pub const DEFAULT_LEAD: Duration = Duration::from_millis(250);
+
+/// A sink writing the decoded audio to another sink in evenly timed chunks of a fixed size.
+/// The Ogg data of passthrough is written as is.
+pub struct PacedSink {
+    sink: Box<dyn Sink>,
+    chunk_samples: usize,
+    lead: Duration,
+    pending: Vec<f32>,
+    // When the audio written since then would have been heard, if it was played right away.
+    start: Option<Instant>,
+    written: Duration,
+}
+
+impl PacedSink {
+    /// Writes chunks of `chunk` to `sink`, up to `lead` ahead of the pace of playback.
+    pub fn new(sink: Box<dyn Sink>, chunk: Duration, lead: Duration) -> PacedSink {
+        let chunk_samples = samples_for_ms(chunk.as_millis() as u32).max(2);
+        PacedSink {
+            sink,
+            // Whole frames of both channels.
+            chunk_samples: chunk_samples - chunk_samples % 2,
+            lead,
+            pending: Vec::new(),
+            start: None,
+            written: Duration::default(),
+        }
+    }
+
+    fn write_chunk(&mut self, samples: Vec<f32>) -> io::Result<()> {
+        let now = Instant::now();
+        let start = match self.start {
+            Some(start) if start + self.written >= now => start,
+            _ => {
+                // Fell behind, so the audio ahead was played out: restart the pace.
+                self.written = Duration::default();
+                now
+            }
+        };
+        self.start = Some(start);
+
+        // Due once the audio ahead of what is heard drops to the lead.
+        if let Some(due) = (start + self.written).checked_sub(self.lead) {
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+
+        self.written += duration_of(samples.len());
+        self.sink.write(&AudioPacket::Samples(samples))
+    }
+}
+
+impl Sink for PacedSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.start = None;
+        self.sink.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let rest = std::mem::take(&mut self.pending);
+            self.write_chunk(rest)?;
+        }
+        self.start = None;
+        self.sink.stop()
+    }
+
+    fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+        let samples = match packet {
+            AudioPacket::Samples(samples) => samples,
+            AudioPacket::OggData(_) => return self.sink.write(packet),
+        };
+
+        self.pending.extend_from_slice(samples);
+        while self.pending.len() >= self.chunk_samples {
+            let rest = self.pending.split_off(self.chunk_samples);
+            let chunk = std::mem::replace(&mut self.pending, rest);
+            self.write_chunk(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn set_track(&mut self, track: &SinkTrack) {
+        self.sink.set_track(track)
+    }
+
+    fn latency(&self) -> Duration {
+        self.sink.latency() + duration_of(self.pending.len())
+    }
+
+    fn output_changed(&mut self) -> bool {
+        self.sink.output_changed()
+    }
+
+    fn output(&mut self) -> Option<String> {
+        self.sink.output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Writes = Arc<Mutex<Vec<(usize, Instant)>>>;
+
+    // Records the size and time of each write.
+    struct RecordingSink(Writes);
+
+    impl Sink for RecordingSink {
+        fn start(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn stop(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+            let write = (packet.samples().len(), Instant::now());
+            self.0.lock().unwrap().push(write);
+            Ok(())
+        }
+    }
+
+    fn paced_sink(chunk_ms: u64, lead_ms: u64) -> (PacedSink, Writes) {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let sink = PacedSink::new(
+            Box::new(RecordingSink(writes.clone())),
+            Duration::from_millis(chunk_ms),
+            Duration::from_millis(lead_ms),
+        );
+        (sink, writes)
+    }
+
+    #[test]
+    fn writes_evenly_timed_chunks() {
+        let (mut sink, writes) = paced_sink(10, 0);
+        let chunk_samples = samples_for_ms(10);
+        sink.start().unwrap();
+
+        let started = Instant::now();
+        sink.write(&AudioPacket::Samples(vec![0.0; chunk_samples * 5 / 2]))
+            .unwrap();
+        sink.write(&AudioPacket::Samples(vec![0.0; chunk_samples * 5 / 2]))
+            .unwrap();
+
+        let writes = writes.lock().unwrap();
+        assert!(writes.iter().all(|&(length, _)| length == chunk_samples));
+        assert_eq!(writes.len(), 5);
+        assert!(writes[4].1 - started >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn writes_the_lead_at_once() {
+        let (mut sink, writes) = paced_sink(10, 50);
+        sink.start().unwrap();
+
+        let started = Instant::now();
+        sink.write(&AudioPacket::Samples(vec![0.0; samples_for_ms(50)]))
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(40));
+
+        sink.write(&AudioPacket::Samples(vec![0.0; samples_for_ms(10)]))
+            .unwrap();
+        sink.stop().unwrap();
+        assert_eq!(writes.lock().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn restarts_the_pace_after_falling_behind() {
+        let (mut sink, writes) = paced_sink(10, 0);
+        let chunk = vec![0.0; samples_for_ms(10)];
+        sink.start().unwrap();
+        sink.write(&AudioPacket::Samples(chunk.clone())).unwrap();
+
+        // The audio written was played out long ago, so the next chunks don't burst.
+        thread::sleep(Duration::from_millis(50));
+        for _ in 0..3 {
+            sink.write(&AudioPacket::Samples(chunk.clone())).unwrap();
+        }
+
+        let writes = writes.lock().unwrap();
+        assert!(writes[3].1 - writes[1].1 >= Duration::from_millis(20));
+    }
+}
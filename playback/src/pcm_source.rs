@@ -0,0 +1,176 @@
+//! The decoded audio of a player for the application to pull, instead of a sink the player
+//! writes to, e.g. for game engines and mixers that request audio at their own rate.
+
+use std::cmp::min;
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::FutureExt;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::audio::AudioPacket;
+use crate::audio_backend::Sink;
+
+// The packets decoded ahead of the application. The player waits while the buffer is full.
+const BUFFERED_PACKETS: usize = 16;
+
+/// Creates a sink for a player and the source the application pulls its audio from.
+pub fn pcm_channel() -> (PcmSink, PcmSource) {
+    let (tx, rx) = mpsc::channel(BUFFERED_PACKETS);
+    (
+        PcmSink(tx),
+        PcmSource {
+            packets: rx,
+            pending: Vec::new(),
+            pending_offset: 0,
+        },
+    )
+}
+
+/// The sink of a player whose audio is pulled from a `PcmSource`. Writes wait until the
+/// application has taken enough of the buffered audio.
+pub struct PcmSink(mpsc::Sender<Vec<f32>>);
+
+impl Sink for PcmSink {
+    fn start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+        let samples = match packet {
+            AudioPacket::Samples(samples) => samples.clone(),
+            AudioPacket::OggData(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a PCM source can't pass through Ogg data",
+                ))
+            }
+        };
+        self.0
+            .blocking_send(samples)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "PCM source dropped"))
+    }
+}
+
+/// The decoded audio of a player as packets of interleaved stereo samples at 44.1 kHz.
+///
+/// Iterating waits for the next packet and ends when the player is dropped. Reading with
+/// `Read` or `AsyncRead` returns the samples as little endian `f32` bytes. Nothing arrives
+/// while the player is paused or stopped, so the application plays silence in between.
+pub struct PcmSource {
+    packets: mpsc::Receiver<Vec<f32>>,
+    // The bytes of a packet that were not read yet.
+    pending: Vec<u8>,
+    pending_offset: usize,
+}
+
+impl PcmSource {
+    /// Returns the next packet if one is buffered, without waiting.
+    pub fn try_next(&mut self) -> Option<Vec<f32>> {
+        self.packets.recv().now_or_never().flatten()
+    }
+
+    fn set_pending(&mut self, samples: Vec<f32>) {
+        self.pending = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes().to_vec())
+            .collect();
+        self.pending_offset = 0;
+    }
+
+    fn read_pending(&mut self, output: &mut [u8]) -> usize {
+        let length = min(output.len(), self.pending.len() - self.pending_offset);
+        output[..length]
+            .copy_from_slice(&self.pending[self.pending_offset..self.pending_offset + length]);
+        self.pending_offset += length;
+        length
+    }
+}
+
+impl Iterator for PcmSource {
+    type Item = Vec<f32>;
+
+    /// Waits for the next packet. Must not be called from an async context, use `AsyncRead`
+    /// there.
+    fn next(&mut self) -> Option<Vec<f32>> {
+        self.packets.blocking_recv()
+    }
+}
+
+impl Read for PcmSource {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if output.is_empty() {
+            return Ok(0);
+        }
+        if self.pending_offset == self.pending.len() {
+            match self.packets.blocking_recv() {
+                Some(samples) => self.set_pending(samples),
+                None => return Ok(0),
+            }
+        }
+        Ok(self.read_pending(output))
+    }
+}
+
+impl AsyncRead for PcmSource {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if self.pending_offset == self.pending.len() {
+            match self.packets.poll_recv(cx) {
+                Poll::Ready(Some(samples)) => self.set_pending(samples),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let length = self.read_pending(buf.initialize_unfilled());
+        buf.advance(length);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn pulls_written_packets() {
+        let (mut sink, mut source) = pcm_channel();
+        let writer = thread::spawn(move || {
+            for i in 0..100 {
+                sink.write(&AudioPacket::Samples(vec![i as f32; 4]))
+                    .unwrap();
+            }
+        });
+
+        assert_eq!(source.next(), Some(vec![0.0; 4]));
+        let mut bytes = [0u8; 6];
+        source.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes[..4], 1.0f32.to_le_bytes());
+        // The rest of a packet is read before the next one.
+        source.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes[2..], 1.0f32.to_le_bytes());
+
+        assert_eq!(source.count(), 98);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn write_fails_without_source() {
+        let (mut sink, source) = pcm_channel();
+        drop(source);
+        assert!(sink.write(&AudioPacket::Samples(vec![0.0])).is_err());
+    }
+}
@@ -2,6 +2,7 @@ use std::cmp::max;
 use std::future::Future;
 use std::io::{self, Read, Seek, SeekFrom};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 use std::{mem, thread};
@@ -11,33 +12,51 @@ use futures_util::stream::futures_unordered::FuturesUnordered;
 use futures_util::{future, StreamExt, TryFutureExt};
 use tokio::sync::{mpsc, oneshot};
 
+use crate::audio::{simd, AudioDecrypt, AudioFile, StreamLoaderController};
 use crate::audio::{AudioDecoder, AudioError, AudioPacket, PassthroughDecoder, VorbisDecoder};
-use crate::audio::{AudioDecrypt, AudioFile, StreamLoaderController};
-use crate::audio::{
-    READ_AHEAD_BEFORE_PLAYBACK_ROUNDTRIPS, READ_AHEAD_BEFORE_PLAYBACK_SECONDS,
-    READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS, READ_AHEAD_DURING_PLAYBACK_SECONDS,
+use crate::audio_backend::{Sink, SinkTrack};
+use crate::config::{
+    AdaptiveBitrate, BackpressurePolicy, Bitrate, FileFormatPolicy, NormalisationMethod,
+    NormalisationType, PlayerConfig,
 };
-use crate::audio_backend::Sink;
-use crate::config::{Bitrate, NormalisationMethod, NormalisationType, PlayerConfig};
 use crate::core::session::Session;
-use crate::core::spotify_id::SpotifyId;
+use crate::core::spotify_id::{SpotifyAudioType, SpotifyId};
 use crate::core::util::SeqGenerator;
-use crate::metadata::{AudioItem, FileFormat};
+use crate::core::Error;
+use crate::decode_ahead::{self, DecodeAheadControl, DecodeAheadSink};
+use crate::ducking::{DuckingFilter, SharedDucking};
+use crate::listen::{Listen, ListenTracker};
+use crate::metadata::{Artist, AudioItem, Episode, FileFormat, Metadata, Show, Track};
 use crate::mixer::AudioFilter;
+use crate::pcm_source::{self, PcmSource};
 
 pub const SAMPLE_RATE: u32 = 44100;
 pub const NUM_CHANNELS: u8 = 2;
 pub const SAMPLES_PER_SECOND: u32 = SAMPLE_RATE as u32 * NUM_CHANNELS as u32;
 
 const PRELOAD_NEXT_TRACK_BEFORE_END_DURATION_MS: u32 = 30000;
+const PREVIEW_DURATION_MS: u32 = 30000;
+const POSITION_SAVE_INTERVAL_MS: u32 = 10000;
+// How long the download rate must not match the bitrate before switching to another one.
+const ADAPTIVE_BITRATE_SUSTAIN: Duration = Duration::from_secs(10);
+// How much faster than a higher bitrate the download must be to switch to it.
+const ADAPTIVE_BITRATE_UPGRADE_HEADROOM: f64 = 1.5;
 const DB_VOLTAGE_RATIO: f32 = 20.0;
 
+/// Plays tracks of a session. Several players can share a session, each with its own sink,
+/// mixer and state.
 pub struct Player {
     commands: Option<mpsc::UnboundedSender<PlayerCommand>>,
     thread_handle: Option<thread::JoinHandle<()>>,
+    thread_finished: Option<oneshot::Receiver<()>>,
     play_request_id_generator: SeqGenerator<u64>,
+    player_id: usize,
+    // The audio of a player created with `with_pcm_source`, until it is taken.
+    pcm_source: Option<PcmSource>,
 }
 
+static PLAYER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum SinkStatus {
     Running,
@@ -48,6 +67,7 @@ pub enum SinkStatus {
 pub type SinkEventCallback = Box<dyn Fn(SinkStatus) + Send>;
 
 struct PlayerInternal {
+    player_id: usize,
     session: Session,
     config: PlayerConfig,
     commands: mpsc::UnboundedReceiver<PlayerCommand>,
@@ -55,10 +75,37 @@ struct PlayerInternal {
     state: PlayerState,
     preload: PlayerPreload,
     sink: Box<dyn Sink>,
+    // Set if the sink is a DecodeAheadSink.
+    decode_ahead: Option<DecodeAheadControl>,
+    // The EndOfTrack event of a track whose end is still buffered in the DecodeAheadSink, sent
+    // once the audio up to the mark was played.
+    pending_end_of_track: Option<(u64, PlayerEvent)>,
     sink_status: SinkStatus,
     sink_event_callback: Option<SinkEventCallback>,
     audio_filter: Option<Box<dyn AudioFilter + Send>>,
     event_senders: Vec<mpsc::UnboundedSender<PlayerEvent>>,
+    listen_tracker: ListenTracker,
+    listen_senders: Vec<mpsc::UnboundedSender<Listen>>,
+    // Whether the current track is a preview clip, which must not be reused for a full load.
+    playing_preview: bool,
+    // The track loaded with load_resumable() and the position last saved for it.
+    resumable: Option<(SpotifyId, u32)>,
+    // Since when the download rate has been below or well above the bitrate of the current
+    // track, and the format policy of the next tracks picked for the download rate.
+    download_rate_low_since: Option<Instant>,
+    download_rate_high_since: Option<Instant>,
+    adapted_format_policy: Option<FileFormatPolicy>,
+    // The pending switch to another bitrate of reload_current().
+    format_switch: Option<PlayerFormatSwitch>,
+    // The thread downloading tracks for precache(), one after another. It ends when the
+    // sender is dropped, e.g. for another session.
+    precache_tx: Option<std::sync::mpsc::Sender<SpotifyId>>,
+    // The attenuation set with duck() and unduck(). The writer of decode ahead applies it if
+    // there is one.
+    ducking: SharedDucking,
+    // The output the sink played to at the last packet, and the ratio of its output gain.
+    output: Option<String>,
+    output_gain: f32,
 
     limiter_active: bool,
     limiter_attack_counter: u32,
@@ -75,16 +122,69 @@ enum PlayerCommand {
         play: bool,
         position_ms: u32,
     },
+    LoadPreview {
+        track_id: SpotifyId,
+        play_request_id: u64,
+        play: bool,
+    },
+    LoadResumable {
+        track_id: SpotifyId,
+        play_request_id: u64,
+        play: bool,
+    },
     Preload {
         track_id: SpotifyId,
     },
+    Precache {
+        track_id: SpotifyId,
+    },
     Play,
     Pause,
     Stop,
     Seek(u32),
+    ReloadCurrent {
+        bitrate: Bitrate,
+    },
+    SetSession(Session),
     AddEventSender(mpsc::UnboundedSender<PlayerEvent>),
+    AddListenSender(mpsc::UnboundedSender<Listen>),
     SetSinkEventCallback(Option<SinkEventCallback>),
     EmitVolumeSetEvent(u16),
+    Duck {
+        level: f32,
+        fade_ms: u32,
+    },
+    Unduck,
+    EmitTrackTransitionEvent {
+        from: SpotifyId,
+        to: Option<SpotifyId>,
+        reason: TrackTransitionReason,
+    },
+}
+
+/// Why playback moved on from a track, see `PlayerEvent::TrackTransition`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackTransitionReason {
+    /// The track played to its end.
+    Finished,
+    /// The user skipped to the next or previous track.
+    Skipped,
+    /// The track couldn't be loaded.
+    Error,
+    /// Another context or track was loaded, or another device took over playback.
+    RemoteChange,
+}
+
+impl TrackTransitionReason {
+    /// The name of the reason in snake case, e.g. `remote_change`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Finished => "finished",
+            Self::Skipped => "skipped",
+            Self::Error => "error",
+            Self::RemoteChange => "remote_change",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -113,10 +213,34 @@ pub enum PlayerEvent {
         track_id: SpotifyId,
         position_ms: u32,
     },
+    // Loading a track progressed. These events are issued while in the "Loading" state.
+    LoadingProgress {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        stage: LoadingStage,
+    },
     // The player is preloading a track.
     Preloading {
         track_id: SpotifyId,
     },
+    // A newly loaded track is about to be played or paused, in the given file format.
+    // The normalisation data is `None` if the file has no normalisation header.
+    // This is followed up by a "Playing" or "Paused" event.
+    TrackChanged {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        format: FileFormat,
+        normalisation_data: Option<NormalisationData>,
+    },
+    // The player switched the current track to another file format, because another bitrate
+    // was requested with reload_current().
+    // Playback continues at the same position.
+    FormatChanged {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        old_format: FileFormat,
+        new_format: FileFormat,
+    },
     // The player is playing a track.
     // This event is issued at the start of playback of whenever the position must be communicated
     // because it is out of sync. This includes:
@@ -155,10 +279,50 @@ pub enum PlayerEvent {
         play_request_id: u64,
         track_id: SpotifyId,
     },
+    // The player refused to play the requested track because it is explicit and explicit
+    // content is filtered. It is skipped like an unavailable track.
+    TrackFiltered {
+        play_request_id: u64,
+        track_id: SpotifyId,
+    },
+    // The requested track is unavailable in the user's market, so the player loaded one of its
+    // alternatives instead, e.g. the same recording on another album. This is followed up by a
+    // "TrackChanged" event, which keeps the id of the requested track.
+    TrackRelinked {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        relinked_id: SpotifyId,
+    },
+    // The output device changed while playing, e.g. because headphones were unplugged, and
+    // the player paused as the configuration asks. This is followed up by a "Paused" event.
+    OutputChanged {
+        play_request_id: u64,
+        track_id: SpotifyId,
+    },
+    // The sink took no audio for a while with the decode-ahead buffer full, e.g. during a
+    // hiccup of the Bluetooth stack, and the backpressure policy applies. If the policy paused
+    // playback, this is followed up by a "Paused" event.
+    SinkStalled {
+        play_request_id: u64,
+        track_id: SpotifyId,
+        paused: bool,
+    },
+    // The stalled sink takes audio again.
+    SinkRecovered {
+        play_request_id: u64,
+        track_id: SpotifyId,
+    },
     // The mixer volume was set to a new level.
     VolumeSet {
         volume: u16,
     },
+    // Playback moved on from a track to the next one, or to none if it stopped. The reason
+    // tells natural ends from skips. This event is issued by spirc, not by the player.
+    TrackTransition {
+        from: SpotifyId,
+        to: Option<SpotifyId>,
+        reason: TrackTransitionReason,
+    },
 }
 
 impl PlayerEvent {
@@ -171,6 +335,21 @@ impl PlayerEvent {
             | Unavailable {
                 play_request_id, ..
             }
+            | TrackFiltered {
+                play_request_id, ..
+            }
+            | TrackRelinked {
+                play_request_id, ..
+            }
+            | OutputChanged {
+                play_request_id, ..
+            }
+            | SinkStalled {
+                play_request_id, ..
+            }
+            | SinkRecovered {
+                play_request_id, ..
+            }
             | Started {
                 play_request_id, ..
             }
@@ -188,20 +367,154 @@ impl PlayerEvent {
             }
             | Stopped {
                 play_request_id, ..
+            }
+            | TrackChanged {
+                play_request_id, ..
+            }
+            | LoadingProgress {
+                play_request_id, ..
+            }
+            | FormatChanged {
+                play_request_id, ..
             } => Some(*play_request_id),
-            Changed { .. } | Preloading { .. } | VolumeSet { .. } => None,
+            Changed { .. } | Preloading { .. } | VolumeSet { .. } | TrackTransition { .. } => None,
+        }
+    }
+
+    /// The name of the event in snake case, e.g. `track_changed`.
+    pub fn name(&self) -> &'static str {
+        use PlayerEvent::*;
+        match self {
+            Stopped { .. } => "stopped",
+            Started { .. } => "started",
+            Changed { .. } => "changed",
+            Loading { .. } => "loading",
+            LoadingProgress { .. } => "loading_progress",
+            Preloading { .. } => "preloading",
+            TrackChanged { .. } => "track_changed",
+            FormatChanged { .. } => "format_changed",
+            Playing { .. } => "playing",
+            Paused { .. } => "paused",
+            TimeToPreloadNextTrack { .. } => "preload_next",
+            EndOfTrack { .. } => "end_of_track",
+            Unavailable { .. } => "unavailable",
+            TrackFiltered { .. } => "track_filtered",
+            TrackRelinked { .. } => "track_relinked",
+            OutputChanged { .. } => "output_changed",
+            SinkStalled { .. } => "sink_stalled",
+            SinkRecovered { .. } => "sink_recovered",
+            VolumeSet { .. } => "volume_set",
+            TrackTransition { .. } => "track_transition",
+        }
+    }
+
+    /// The fields of the event as the language bindings expose them, flattened into one
+    /// record for all events.
+    pub fn fields(&self) -> PlayerEventFields {
+        use PlayerEvent::*;
+        let track_id = match *self {
+            Changed { new_track_id, .. } => Some(new_track_id),
+            TrackTransition { from, .. } => Some(from),
+            VolumeSet { .. } => None,
+            Stopped { track_id, .. }
+            | Started { track_id, .. }
+            | Loading { track_id, .. }
+            | LoadingProgress { track_id, .. }
+            | Preloading { track_id }
+            | TrackChanged { track_id, .. }
+            | FormatChanged { track_id, .. }
+            | Playing { track_id, .. }
+            | Paused { track_id, .. }
+            | TimeToPreloadNextTrack { track_id, .. }
+            | EndOfTrack { track_id, .. }
+            | Unavailable { track_id, .. }
+            | TrackFiltered { track_id, .. }
+            | TrackRelinked { track_id, .. }
+            | OutputChanged { track_id, .. }
+            | SinkStalled { track_id, .. }
+            | SinkRecovered { track_id, .. } => Some(track_id),
+        };
+        let (position_ms, duration_ms) = match *self {
+            Started { position_ms, .. } | Loading { position_ms, .. } => (Some(position_ms), None),
+            Playing {
+                position_ms,
+                duration_ms,
+                ..
+            }
+            | Paused {
+                position_ms,
+                duration_ms,
+                ..
+            } => (Some(position_ms), Some(duration_ms)),
+            _ => (None, None),
+        };
+        let volume = match *self {
+            VolumeSet { volume } => Some(volume),
+            _ => None,
+        };
+        let (to_track_id, reason) = match *self {
+            TrackTransition { to, reason, .. } => (to, Some(reason)),
+            _ => (None, None),
+        };
+        let relinked_id = match *self {
+            TrackRelinked { relinked_id, .. } => Some(relinked_id),
+            _ => None,
+        };
+
+        PlayerEventFields {
+            track_id,
+            position_ms,
+            duration_ms,
+            volume,
+            to_track_id,
+            reason,
+            relinked_id,
         }
     }
 }
 
+/// The fields of a [`PlayerEvent`] that the bindings expose. Those that don't apply to the
+/// event are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlayerEventFields {
+    /// The track the event is about, the new one for `Changed` and the previous one for
+    /// `TrackTransition`.
+    pub track_id: Option<SpotifyId>,
+    pub position_ms: Option<u32>,
+    pub duration_ms: Option<u32>,
+    pub volume: Option<u16>,
+    /// The track played next for `TrackTransition`, if any.
+    pub to_track_id: Option<SpotifyId>,
+    pub reason: Option<TrackTransitionReason>,
+    /// The alternative the player loaded for `TrackRelinked`, whose `track_id` is the
+    /// requested track.
+    pub relinked_id: Option<SpotifyId>,
+}
+
 pub type PlayerEventChannel = mpsc::UnboundedReceiver<PlayerEvent>;
+pub type ListenChannel = mpsc::UnboundedReceiver<Listen>;
+
+/// The steps of loading a track. Opening the file and receiving the key happen concurrently,
+/// so their order varies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadingStage {
+    // The metadata was fetched and a file was chosen.
+    MetadataResolved,
+    // The beginning of the file is available.
+    FileOpened,
+    // The key to decrypt the file was received.
+    KeyReceived,
+    // The decoder is ready at the requested position, playback can start.
+    DecoderReady,
+}
 
-#[derive(Clone, Copy, Debug)]
+/// The loudness of a track, from the header of its audio file. Peaks are ratios of full scale.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct NormalisationData {
-    track_gain_db: f32,
-    track_peak: f32,
-    album_gain_db: f32,
-    album_peak: f32,
+    pub track_gain_db: f32,
+    pub track_peak: f32,
+    pub album_gain_db: f32,
+    pub album_peak: f32,
 }
 
 impl NormalisationData {
@@ -238,7 +551,7 @@ impl NormalisationData {
             NormalisationType::Track => [data.track_gain_db, data.track_peak],
         };
 
-        let normalisation_power = gain_db + config.normalisation_pregain;
+        let normalisation_power = gain_db + config.normalisation_pregain_db();
         let mut normalisation_factor = Self::db_to_ratio(normalisation_power);
 
         if normalisation_factor * gain_peak > config.normalisation_threshold {
@@ -255,11 +568,15 @@ impl NormalisationData {
                 );
             }
 
-            warn!("Please lower pregain to avoid.");
+            warn!("Please lower the normalisation target to avoid.");
         }
 
         debug!("Normalisation Data: {:?}", data);
         debug!("Normalisation Type: {:?}", config.normalisation_type);
+        debug!(
+            "Normalisation Target: {:.1} LUFS",
+            config.normalisation_target_lufs
+        );
         debug!(
             "Normalisation Threshold: {:.1}",
             Self::ratio_to_db(config.normalisation_threshold)
@@ -289,22 +606,66 @@ impl Player {
     {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let (event_sender, event_receiver) = mpsc::unbounded_channel();
+        let (finished_tx, finished_rx) = oneshot::channel();
 
+        let player_id = PLAYER_COUNTER.fetch_add(1, Ordering::Relaxed);
         let handle = thread::spawn(move || {
-            debug!("new Player[{}]", session.session_id());
+            debug!(
+                "new Player[{}] of Session[{}]",
+                player_id,
+                session.session_id()
+            );
+
+            // The writer of the buffer applies the audio filter and the ducking, so that the
+            // volume changes without the delay of the buffer.
+            let ducking = SharedDucking::default();
+            let (sink, decode_ahead, audio_filter) =
+                if config.decode_ahead_ms > 0 && !config.passthrough {
+                    let filter = DuckingFilter {
+                        filter: audio_filter,
+                        ducking: ducking.clone(),
+                    };
+                    let sink = DecodeAheadSink::new(
+                        sink_builder,
+                        decode_ahead::samples_for_ms(config.decode_ahead_ms),
+                        config.backpressure_policy,
+                        Duration::from_millis(config.sink_stall_timeout_ms.into()),
+                        Some(Box::new(filter)),
+                    );
+                    let control = sink.control();
+                    (Box::new(sink) as Box<dyn Sink>, Some(control), None)
+                } else {
+                    (sink_builder(), None, audio_filter)
+                };
 
+            let output_gain = NormalisationData::db_to_ratio(config.output_gain_db_of(None));
             let internal = PlayerInternal {
+                player_id,
                 session,
                 config,
                 commands: cmd_rx,
 
                 state: PlayerState::Stopped,
                 preload: PlayerPreload::None,
-                sink: sink_builder(),
+                sink,
+                decode_ahead,
+                pending_end_of_track: None,
                 sink_status: SinkStatus::Closed,
                 sink_event_callback: None,
                 audio_filter,
                 event_senders: [event_sender].to_vec(),
+                listen_tracker: ListenTracker::new(),
+                listen_senders: Vec::new(),
+                playing_preview: false,
+                resumable: None,
+                download_rate_low_since: None,
+                download_rate_high_since: None,
+                adapted_format_policy: None,
+                format_switch: None,
+                precache_tx: None,
+                ducking,
+                output: None,
+                output_gain,
 
                 limiter_active: false,
                 limiter_attack_counter: 0,
@@ -318,18 +679,44 @@ impl Player {
             // It must be run by using block_on() in a dedicated thread.
             futures_executor::block_on(internal);
             debug!("PlayerInternal thread finished.");
+            let _ = finished_tx.send(());
         });
 
         (
             Player {
                 commands: Some(cmd_tx),
                 thread_handle: Some(handle),
+                thread_finished: Some(finished_rx),
                 play_request_id_generator: SeqGenerator::new(0),
+                player_id,
+                pcm_source: None,
             },
             event_receiver,
         )
     }
 
+    /// Creates a player without a sink, whose audio the application pulls from the source
+    /// returned by `take_pcm_source`. Passthrough is not supported and is turned off.
+    pub fn with_pcm_source(
+        mut config: PlayerConfig,
+        session: Session,
+        audio_filter: Option<Box<dyn AudioFilter + Send>>,
+    ) -> (Player, PlayerEventChannel) {
+        config.passthrough = false;
+        let (sink, source) = pcm_source::pcm_channel();
+        let (mut player, events) = Self::new(config, session, audio_filter, move || {
+            Box::new(sink) as Box<dyn Sink>
+        });
+        player.pcm_source = Some(source);
+        (player, events)
+    }
+
+    /// Takes the source of the decoded audio of a player created with `with_pcm_source`.
+    /// Returns `None` for other players and once it was taken.
+    pub fn take_pcm_source(&mut self) -> Option<PcmSource> {
+        self.pcm_source.take()
+    }
+
     fn command(&self, cmd: PlayerCommand) {
         self.commands.as_ref().unwrap().send(cmd).unwrap();
     }
@@ -346,10 +733,48 @@ impl Player {
         play_request_id
     }
 
+    /// Loads the 30 second preview clip of a track. Previews are not encrypted, so this
+    /// skips the audio key request and also works for tracks the account cannot play.
+    pub fn load_preview(&mut self, track_id: SpotifyId, start_playing: bool) -> u64 {
+        let play_request_id = self.play_request_id_generator.get();
+        self.command(PlayerCommand::LoadPreview {
+            track_id,
+            play_request_id,
+            play: start_playing,
+        });
+
+        play_request_id
+    }
+
+    /// Loads a track or episode at the position it was left at, and keeps saving the
+    /// position in the cache while it plays. Without a cache it starts from the beginning.
+    pub fn load_resumable(&mut self, track_id: SpotifyId, start_playing: bool) -> u64 {
+        let play_request_id = self.play_request_id_generator.get();
+        self.command(PlayerCommand::LoadResumable {
+            track_id,
+            play_request_id,
+            play: start_playing,
+        });
+
+        play_request_id
+    }
+
+    /// Identifies the player in log messages.
+    pub fn player_id(&self) -> usize {
+        self.player_id
+    }
+
     pub fn preload(&self, track_id: SpotifyId) {
         self.command(PlayerCommand::Preload { track_id });
     }
 
+    /// Downloads the audio file and the key of a track into the cache without playing it, so
+    /// that it plays later without waiting for the network, e.g. the rest of the queue before
+    /// leaving WiFi. Tracks are downloaded one after another. Does nothing without a cache.
+    pub fn precache(&self, track_id: SpotifyId) {
+        self.command(PlayerCommand::Precache { track_id });
+    }
+
     pub fn play(&self) {
         self.command(PlayerCommand::Play)
     }
@@ -362,16 +787,42 @@ impl Player {
         self.command(PlayerCommand::Stop)
     }
 
+    /// Stops playback, flushes the sink and resolves when the player thread has finished.
+    /// Unlike dropping the player, this doesn't block the calling thread.
+    pub async fn shutdown(mut self) {
+        debug!("Shutting down Player[{}] thread ...", self.player_id);
+        self.commands = None;
+        if let Some(finished) = self.thread_finished.take() {
+            let _ = finished.await;
+        }
+    }
+
     pub fn seek(&self, position_ms: u32) {
         self.command(PlayerCommand::Seek(position_ms));
     }
 
+    /// Continues the current track at `bitrate` from the current position, e.g. for a quality
+    /// toggle. Unlike loading the track again, this doesn't stop playback or emit the events
+    /// of loading; a `FormatChanged` event is emitted once the new file plays. Later tracks
+    /// are played at `bitrate` as well.
+    pub fn reload_current(&self, bitrate: Bitrate) {
+        self.command(PlayerCommand::ReloadCurrent { bitrate });
+    }
+
     pub fn get_player_event_channel(&self) -> PlayerEventChannel {
         let (event_sender, event_receiver) = mpsc::unbounded_channel();
         self.command(PlayerCommand::AddEventSender(event_sender));
         event_receiver
     }
 
+    /// Returns a channel receiving a `Listen` for every track that stopped playing, e.g. to
+    /// scrobble it.
+    pub fn get_listen_channel(&self) -> ListenChannel {
+        let (listen_sender, listen_receiver) = mpsc::unbounded_channel();
+        self.command(PlayerCommand::AddListenSender(listen_sender));
+        listen_receiver
+    }
+
     pub async fn await_end_of_track(&self) {
         let mut channel = self.get_player_event_channel();
         while let Some(event) = channel.recv().await {
@@ -388,14 +839,42 @@ impl Player {
         self.command(PlayerCommand::SetSinkEventCallback(callback));
     }
 
+    /// Plays with `session` from now on, e.g. after another user logged in, keeping the
+    /// sink. The current track is stopped, it was loaded with the previous session.
+    pub fn set_session(&self, session: Session) {
+        self.command(PlayerCommand::SetSession(session));
+    }
+
     pub fn emit_volume_set_event(&self, volume: u16) {
         self.command(PlayerCommand::EmitVolumeSetEvent(volume));
     }
+
+    /// Attenuates the output to `level`, from 0.0 for silence to 1.0, ramping to it within
+    /// `fade_ms`, e.g. while a voice assistant talks over the music. Playback continues and
+    /// the volume is left unchanged; the attenuation lasts until `unduck()`. Only if
+    /// `PlayerConfig::ducking` is set.
+    pub fn duck(&self, level: f32, fade_ms: u32) {
+        self.command(PlayerCommand::Duck { level, fade_ms });
+    }
+
+    /// Ramps the output back to full level after `duck()`, as fast as it was ducked.
+    pub fn unduck(&self) {
+        self.command(PlayerCommand::Unduck);
+    }
+
+    pub fn emit_track_transition_event(
+        &self,
+        from: SpotifyId,
+        to: Option<SpotifyId>,
+        reason: TrackTransitionReason,
+    ) {
+        self.command(PlayerCommand::EmitTrackTransitionEvent { from, to, reason });
+    }
 }
 
 impl Drop for Player {
     fn drop(&mut self) {
-        debug!("Shutting down player thread ...");
+        debug!("Shutting down Player[{}] thread ...", self.player_id);
         self.commands = None;
         if let Some(handle) = self.thread_handle.take() {
             match handle.join() {
@@ -411,15 +890,27 @@ struct PlayerLoadedTrackData {
     normalisation_factor: f32,
     stream_loader_controller: StreamLoaderController,
     bytes_per_second: usize,
+    format: FileFormat,
+    normalisation_data: Option<NormalisationData>,
     duration_ms: u32,
     stream_position_pcm: u64,
+    // The alternative that was loaded because the requested track is unavailable.
+    relinked_id: Option<SpotifyId>,
+    // Passed to the sink with the track.
+    name: String,
+    artists: Vec<String>,
+}
+
+struct PlayerFormatSwitch {
+    play_request_id: u64,
+    loader: Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, LoadError>> + Send>>,
 }
 
 enum PlayerPreload {
     None,
     Loading {
         track_id: SpotifyId,
-        loader: Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, ()>> + Send>>,
+        loader: Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, LoadError>> + Send>>,
     },
     Ready {
         track_id: SpotifyId,
@@ -435,7 +926,7 @@ enum PlayerState {
         track_id: SpotifyId,
         play_request_id: u64,
         start_playback: bool,
-        loader: Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, ()>> + Send>>,
+        loader: Pin<Box<dyn Future<Output = Result<PlayerLoadedTrackData, LoadError>> + Send>>,
     },
     Paused {
         track_id: SpotifyId,
@@ -444,6 +935,8 @@ enum PlayerState {
         normalisation_factor: f32,
         stream_loader_controller: StreamLoaderController,
         bytes_per_second: usize,
+        format: FileFormat,
+        normalisation_data: Option<NormalisationData>,
         duration_ms: u32,
         stream_position_pcm: u64,
         suggested_to_preload_next_track: bool,
@@ -455,6 +948,8 @@ enum PlayerState {
         normalisation_factor: f32,
         stream_loader_controller: StreamLoaderController,
         bytes_per_second: usize,
+        format: FileFormat,
+        normalisation_data: Option<NormalisationData>,
         duration_ms: u32,
         stream_position_pcm: u64,
         reported_nominal_start_time: Option<Instant>,
@@ -478,7 +973,6 @@ impl PlayerState {
         }
     }
 
-    #[allow(dead_code)]
     fn is_stopped(&self) -> bool {
         use self::PlayerState::*;
         matches!(self, Stopped)
@@ -489,6 +983,11 @@ impl PlayerState {
         matches!(self, Loading { .. })
     }
 
+    fn is_end_of_track(&self) -> bool {
+        use self::PlayerState::*;
+        matches!(self, EndOfTrack { .. })
+    }
+
     fn decoder(&mut self) -> Option<&mut Decoder> {
         use self::PlayerState::*;
         match *self {
@@ -528,6 +1027,8 @@ impl PlayerState {
                 decoder,
                 duration_ms,
                 bytes_per_second,
+                format,
+                normalisation_data,
                 normalisation_factor,
                 stream_loader_controller,
                 stream_position_pcm,
@@ -540,9 +1041,14 @@ impl PlayerState {
                         decoder,
                         duration_ms,
                         bytes_per_second,
+                        format,
+                        normalisation_data,
                         normalisation_factor,
                         stream_loader_controller,
                         stream_position_pcm,
+                        relinked_id: None,
+                        name: String::new(),
+                        artists: Vec::new(),
                     },
                 };
             }
@@ -561,6 +1067,8 @@ impl PlayerState {
                 stream_loader_controller,
                 duration_ms,
                 bytes_per_second,
+                format,
+                normalisation_data,
                 stream_position_pcm,
                 suggested_to_preload_next_track,
             } => {
@@ -572,6 +1080,8 @@ impl PlayerState {
                     stream_loader_controller,
                     duration_ms,
                     bytes_per_second,
+                    format,
+                    normalisation_data,
                     stream_position_pcm,
                     reported_nominal_start_time: None,
                     suggested_to_preload_next_track,
@@ -592,6 +1102,8 @@ impl PlayerState {
                 stream_loader_controller,
                 duration_ms,
                 bytes_per_second,
+                format,
+                normalisation_data,
                 stream_position_pcm,
                 reported_nominal_start_time: _,
                 suggested_to_preload_next_track,
@@ -604,6 +1116,8 @@ impl PlayerState {
                     stream_loader_controller,
                     duration_ms,
                     bytes_per_second,
+                    format,
+                    normalisation_data,
                     stream_position_pcm,
                     suggested_to_preload_next_track,
                 };
@@ -613,12 +1127,96 @@ impl PlayerState {
     }
 }
 
+// Why a track could not be loaded.
+#[derive(Debug)]
+enum LoadError {
+    Failed,
+    ExplicitContent,
+}
+
 struct PlayerTrackLoader {
     session: Session,
     config: PlayerConfig,
+    // Receive the LoadingProgress events of loads, but not of preloads.
+    progress: Option<(u64, Vec<mpsc::UnboundedSender<PlayerEvent>>)>,
 }
 
 impl PlayerTrackLoader {
+    fn report(&self, track_id: SpotifyId, stage: LoadingStage) {
+        if let Some((play_request_id, senders)) = &self.progress {
+            for sender in senders {
+                let _ = sender.send(PlayerEvent::LoadingProgress {
+                    play_request_id: *play_request_id,
+                    track_id,
+                    stage,
+                });
+            }
+        }
+    }
+
+    fn filter_explicit_content(&self) -> bool {
+        self.config.filter_explicit_content || self.session.user_data().filter_explicit_content()
+    }
+
+    fn check_explicit(&self, audio: &AudioItem) -> Result<(), Error> {
+        if audio.explicit && self.filter_explicit_content() {
+            info!("<{}> is explicit, refusing to play it", audio.name);
+            return Err(Error::ExplicitContent(audio.uri.clone()));
+        }
+        Ok(())
+    }
+
+    // Downloads the file and the key of a track to the cache, see Player::precache().
+    async fn precache(&self, spotify_id: SpotifyId) -> Result<(), Error> {
+        let audio = AudioItem::get_audio_item(&self.session, spotify_id).await?;
+        let audio = self
+            .find_available_alternative(audio)
+            .await
+            .ok_or_else(|| Error::Unavailable(spotify_id.to_uri()))?;
+        let (format, file_id) = self
+            .config
+            .format_policy()
+            .select(&audio.files)
+            .ok_or_else(|| {
+                Error::Unavailable(format!("<{}> in any of the allowed formats", audio.name))
+            })?;
+
+        let fetch_config = self.config.fetch_config();
+        let encrypted_file = AudioFile::open_with_config(
+            &self.session,
+            file_id,
+            Self::stream_data_rate(format),
+            true,
+            &fetch_config,
+        );
+        // The key is saved to the cache as it is received.
+        let key = async {
+            self.session
+                .audio_key()
+                .request(spotify_id, file_id)
+                .await?;
+            Ok::<_, Error>(())
+        };
+        let (encrypted_file, _) = future::try_join(encrypted_file, key).await?;
+        if encrypted_file.is_cached() {
+            return Ok(());
+        }
+
+        debug!("Precaching <{}>", audio.uri);
+        let controller = encrypted_file.get_stream_loader_controller();
+        controller.set_stream_mode();
+        if !controller.fetch_all().await {
+            return Err(Error::RequestFailed);
+        }
+
+        // The file is saved to the cache once the download ends, which needs the file open.
+        if let Some(cache) = self.session.cache() {
+            cache.ensure_cached(file_id).await;
+        }
+        drop(encrypted_file);
+        Ok(())
+    }
+
     async fn find_available_alternative(&self, audio: AudioItem) -> Option<AudioItem> {
         if audio.available {
             Some(audio)
@@ -638,7 +1236,7 @@ impl PlayerTrackLoader {
         }
     }
 
-    fn stream_data_rate(&self, format: FileFormat) -> usize {
+    fn stream_data_rate(format: FileFormat) -> usize {
         match format {
             FileFormat::OGG_VORBIS_96 => 12 * 1024,
             FileFormat::OGG_VORBIS_160 => 20 * 1024,
@@ -657,87 +1255,86 @@ impl PlayerTrackLoader {
         }
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "load_track",
+            skip(self, spotify_id),
+            fields(track = %spotify_id.to_uri())
+        )
+    )]
     async fn load_track(
         &self,
         spotify_id: SpotifyId,
         position_ms: u32,
-    ) -> Option<PlayerLoadedTrackData> {
-        let audio = match AudioItem::get_audio_item(&self.session, spotify_id).await {
-            Ok(audio) => audio,
-            Err(_) => {
-                error!("Unable to load audio item.");
-                return None;
-            }
-        };
+    ) -> Result<PlayerLoadedTrackData, Error> {
+        let audio = AudioItem::get_audio_item(&self.session, spotify_id).await?;
 
         info!("Loading <{}> with Spotify URI <{}>", audio.name, audio.uri);
 
-        let audio = match self.find_available_alternative(audio).await {
-            Some(audio) => audio,
-            None => {
-                warn!("<{}> is not available", spotify_id.to_uri());
-                return None;
-            }
+        let audio = self
+            .find_available_alternative(audio)
+            .await
+            .ok_or_else(|| Error::Unavailable(spotify_id.to_uri()))?;
+        self.check_explicit(&audio)?;
+        let relinked_id = if audio.id != spotify_id {
+            info!(
+                "<{}> is unavailable, loading <{}> instead",
+                spotify_id.to_uri(),
+                audio.uri
+            );
+            Some(audio.id)
+        } else {
+            None
         };
 
-        assert!(audio.duration >= 0);
+        if audio.duration < 0 {
+            return Err(Error::Protocol(format!(
+                "negative duration of <{}>",
+                audio.uri
+            )));
+        }
         let duration_ms = audio.duration as u32;
 
-        // (Most) podcasts seem to support only 96 bit Vorbis, so fall back to it
-        let formats = match self.config.bitrate {
-            Bitrate::Bitrate96 => [
-                FileFormat::OGG_VORBIS_96,
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_320,
-            ],
-            Bitrate::Bitrate160 => [
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_96,
-                FileFormat::OGG_VORBIS_320,
-            ],
-            Bitrate::Bitrate320 => [
-                FileFormat::OGG_VORBIS_320,
-                FileFormat::OGG_VORBIS_160,
-                FileFormat::OGG_VORBIS_96,
-            ],
-        };
-
-        let entry = formats.iter().find_map(|format| {
-            if let Some(&file_id) = audio.files.get(format) {
-                Some((*format, file_id))
-            } else {
-                None
-            }
-        });
-
-        let (format, file_id) = match entry {
-            Some(t) => t,
-            None => {
-                warn!("<{}> is not available in any supported format", audio.name);
-                return None;
-            }
-        };
-
-        let bytes_per_second = self.stream_data_rate(format);
+        let (format, file_id) = self
+            .config
+            .format_policy()
+            .select(&audio.files)
+            .ok_or_else(|| {
+                Error::Unavailable(format!("<{}> in any of the allowed formats", audio.name))
+            })?;
+        debug!("Playing <{}> as {:?}", audio.uri, format);
+        self.report(spotify_id, LoadingStage::MetadataResolved);
+
+        let bytes_per_second = Self::stream_data_rate(format);
         let play_from_beginning = position_ms == 0;
+        let fetch_config = self.config.fetch_config();
 
         // This is only a loop to be able to reload the file if an error occured
         // while opening a cached file.
         loop {
-            let encrypted_file = AudioFile::open(
+            let encrypted_file = AudioFile::open_with_config(
                 &self.session,
                 file_id,
                 bytes_per_second,
                 play_from_beginning,
-            );
-
-            let encrypted_file = match encrypted_file.await {
-                Ok(encrypted_file) => encrypted_file,
-                Err(_) => {
-                    error!("Unable to load encrypted file.");
-                    return None;
-                }
+                &fetch_config,
+            )
+            .inspect_ok(|_| self.report(spotify_id, LoadingStage::FileOpened));
+
+            // Request the key while the file is being opened, it is answered from memory or the
+            // cache for tracks that were played, preloaded or precached before.
+            let key = async {
+                let key = self
+                    .session
+                    .audio_key()
+                    .request(spotify_id, file_id)
+                    .await?;
+                self.report(spotify_id, LoadingStage::KeyReceived);
+                Ok::<_, Error>(key)
             };
+
+            let (encrypted_file, key) = future::try_join(encrypted_file, key).await?;
             let is_cached = encrypted_file.is_cached();
 
             let stream_loader_controller = encrypted_file.get_stream_loader_controller();
@@ -750,26 +1347,18 @@ impl PlayerTrackLoader {
                 stream_loader_controller.set_random_access_mode();
             }
 
-            let key = match self.session.audio_key().request(spotify_id, file_id).await {
-                Ok(key) => key,
-                Err(_) => {
-                    error!("Unable to load decryption key");
-                    return None;
-                }
-            };
-
             let mut decrypted_file = AudioDecrypt::new(key, encrypted_file);
 
-            let normalisation_factor = match NormalisationData::parse_from_file(&mut decrypted_file)
-            {
-                Ok(normalisation_data) => {
-                    NormalisationData::get_factor(&self.config, normalisation_data)
-                }
+            let normalisation_data = match NormalisationData::parse_from_file(&mut decrypted_file) {
+                Ok(normalisation_data) => Some(normalisation_data),
                 Err(_) => {
                     warn!("Unable to extract normalisation data, using default value.");
-                    1.0_f32
+                    None
                 }
             };
+            let normalisation_factor = normalisation_data
+                .map(|data| NormalisationData::get_factor(&self.config, data))
+                .unwrap_or(1.0);
 
             let audio_file = Subfile::new(decrypted_file, 0xa7);
 
@@ -793,17 +1382,22 @@ impl PlayerTrackLoader {
                         e
                     );
 
-                    // unwrap safety: The file is cached, so session must have a cache
-                    if !self.session.cache().unwrap().remove_file(file_id) {
-                        return None;
+                    let removed = self
+                        .session
+                        .cache()
+                        .map_or(false, |cache| cache.remove_file(file_id));
+                    if !removed {
+                        return Err(Error::Io(io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("cannot remove invalid cached file {}", file_id),
+                        )));
                     }
 
                     // Just try it again
                     continue;
                 }
                 Err(e) => {
-                    error!("Unable to read audio file: {}", e);
-                    return None;
+                    return Err(Error::Protocol(format!("cannot read audio file: {}", e)));
                 }
             };
 
@@ -815,17 +1409,129 @@ impl PlayerTrackLoader {
             }
             let stream_position_pcm = PlayerInternal::position_ms_to_pcm(position_ms);
             info!("<{}> ({} ms) loaded", audio.name, audio.duration);
+            self.report(spotify_id, LoadingStage::DecoderReady);
+            let artists = self.artist_names(&audio).await;
 
-            return Some(PlayerLoadedTrackData {
+            return Ok(PlayerLoadedTrackData {
                 decoder,
                 normalisation_factor,
                 stream_loader_controller,
                 bytes_per_second,
+                format,
+                normalisation_data,
                 duration_ms,
                 stream_position_pcm,
+                relinked_id,
+                name: audio.name,
+                artists,
             });
         }
     }
+
+    async fn load_preview(&self, spotify_id: SpotifyId) -> Result<PlayerLoadedTrackData, Error> {
+        let audio = AudioItem::get_audio_item(&self.session, spotify_id).await?;
+
+        info!(
+            "Loading preview of <{}> with Spotify URI <{}>",
+            audio.name, audio.uri
+        );
+        self.check_explicit(&audio)?;
+
+        // Any preview is better than none, whatever the configured format policy.
+        let policy = FileFormatPolicy {
+            fallback: true,
+            ..self.config.format_policy()
+        };
+        let (format, file_id) = policy.select(&audio.previews).ok_or_else(|| {
+            Error::Unavailable(format!("preview of <{}> in a supported format", audio.name))
+        })?;
+        debug!("Playing preview of <{}> as {:?}", audio.uri, format);
+        self.report(spotify_id, LoadingStage::MetadataResolved);
+
+        let bytes_per_second = Self::stream_data_rate(format);
+        let mut file = AudioFile::open_with_config(
+            &self.session,
+            file_id,
+            bytes_per_second,
+            true,
+            &self.config.fetch_config(),
+        )
+        .await?;
+        self.report(spotify_id, LoadingStage::FileOpened);
+
+        let stream_loader_controller = file.get_stream_loader_controller();
+        stream_loader_controller.set_stream_mode();
+
+        // Previews are plain Ogg files, but skip the header of the full tracks if there is one.
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        let offset = if &magic == b"OggS" { 0 } else { 0xa7 };
+        let audio_file = Subfile::new(file, offset);
+
+        let decoder: Decoder = if self.config.passthrough {
+            Box::new(PassthroughDecoder::new(audio_file).map_err(|e| {
+                Error::Protocol(format!(
+                    "cannot read preview: {}",
+                    AudioError::PassthroughError(e)
+                ))
+            })?)
+        } else {
+            Box::new(VorbisDecoder::new(audio_file).map_err(|e| {
+                Error::Protocol(format!(
+                    "cannot read preview: {}",
+                    AudioError::VorbisError(e)
+                ))
+            })?)
+        };
+
+        let duration_ms = (audio.duration.max(0) as u32).min(PREVIEW_DURATION_MS);
+        info!("Preview of <{}> loaded", audio.name);
+        self.report(spotify_id, LoadingStage::DecoderReady);
+        let artists = self.artist_names(&audio).await;
+
+        Ok(PlayerLoadedTrackData {
+            decoder,
+            normalisation_factor: 1.0,
+            stream_loader_controller,
+            bytes_per_second,
+            format,
+            normalisation_data: None,
+            duration_ms,
+            stream_position_pcm: 0,
+            relinked_id: None,
+            name: audio.name,
+            artists,
+        })
+    }
+
+    // The artists of a track or the publisher of an episode, for the sink. None are passed
+    // if they can't be fetched, which doesn't keep the track from playing.
+    async fn artist_names(&self, audio: &AudioItem) -> Vec<String> {
+        self.fetch_artist_names(audio).await.unwrap_or_else(|e| {
+            debug!("Cannot get the artists of <{}>: {}", audio.uri, e);
+            Vec::new()
+        })
+    }
+
+    async fn fetch_artist_names(&self, audio: &AudioItem) -> Result<Vec<String>, Error> {
+        match audio.id.audio_type {
+            SpotifyAudioType::Track => {
+                let track = Track::get(&self.session, audio.id).await?;
+                let mut artists = Vec::with_capacity(track.artists.len());
+                for artist in track.artists {
+                    artists.push(Artist::get(&self.session, artist).await?.name);
+                }
+                Ok(artists)
+            }
+            SpotifyAudioType::Podcast => {
+                let episode = Episode::get(&self.session, audio.id).await?;
+                Ok(vec![
+                    Show::get(&self.session, episode.show).await?.publisher,
+                ])
+            }
+            SpotifyAudioType::NonPlayable => Ok(Vec::new()),
+        }
+    }
 }
 
 impl Future for PlayerInternal {
@@ -841,7 +1547,13 @@ impl Future for PlayerInternal {
 
             // process commands that were sent to us
             let cmd = match self.commands.poll_recv(cx) {
-                Poll::Ready(None) => return Poll::Ready(()), // client has disconnected - shut down.
+                Poll::Ready(None) => {
+                    // client has disconnected - let the sink play out what it has and shut down.
+                    self.save_position(0);
+                    self.drain_decoded_audio();
+                    self.ensure_sink_stopped(false);
+                    return Poll::Ready(());
+                }
                 Poll::Ready(Some(cmd)) => {
                     all_futures_completed_or_not_ready = false;
                     Some(cmd)
@@ -863,6 +1575,19 @@ impl Future for PlayerInternal {
             {
                 match loader.as_mut().poll(cx) {
                     Poll::Ready(Ok(loaded_track)) => {
+                        self.send_relinked_event(play_request_id, track_id, &loaded_track);
+                        self.send_event(PlayerEvent::TrackChanged {
+                            play_request_id,
+                            track_id,
+                            format: loaded_track.format,
+                            normalisation_data: loaded_track.normalisation_data,
+                        });
+                        self.sink.set_track(&SinkTrack {
+                            track_id,
+                            name: loaded_track.name.clone(),
+                            artists: loaded_track.artists.clone(),
+                            duration_ms: loaded_track.duration_ms,
+                        });
                         self.start_playback(
                             track_id,
                             play_request_id,
@@ -873,9 +1598,15 @@ impl Future for PlayerInternal {
                             panic!("The state wasn't changed by start_playback()");
                         }
                     }
-                    Poll::Ready(Err(_)) => {
+                    Poll::Ready(Err(err)) => {
                         warn!("Unable to load <{:?}>\nSkipping to next track", track_id);
                         assert!(self.state.is_loading());
+                        if let LoadError::ExplicitContent = err {
+                            self.send_event(PlayerEvent::TrackFiltered {
+                                track_id,
+                                play_request_id,
+                            });
+                        }
                         self.send_event(PlayerEvent::EndOfTrack {
                             track_id,
                             play_request_id,
@@ -899,7 +1630,7 @@ impl Future for PlayerInternal {
                             loaded_track: Box::new(loaded_track),
                         };
                     }
-                    Poll::Ready(Err(_)) => {
+                    Poll::Ready(Err(err)) => {
                         debug!("Unable to preload {:?}", track_id);
                         self.preload = PlayerPreload::None;
                         // Let Spirc know that the track was unavailable.
@@ -910,6 +1641,12 @@ impl Future for PlayerInternal {
                             play_request_id, ..
                         } = self.state
                         {
+                            if let LoadError::ExplicitContent = err {
+                                self.send_event(PlayerEvent::TrackFiltered {
+                                    track_id,
+                                    play_request_id,
+                                });
+                            }
                             self.send_event(PlayerEvent::Unavailable {
                                 track_id,
                                 play_request_id,
@@ -920,8 +1657,32 @@ impl Future for PlayerInternal {
                 }
             }
 
-            if self.state.is_playing() {
+            // handle a pending switch to another bitrate.
+            if let Some(ref mut format_switch) = self.format_switch {
+                if let Poll::Ready(result) = format_switch.loader.as_mut().poll(cx) {
+                    let play_request_id = format_switch.play_request_id;
+                    self.format_switch = None;
+                    match result {
+                        Ok(loaded_track) => self.switch_format(play_request_id, loaded_track),
+                        Err(_) => debug!("Unable to switch to another bitrate"),
+                    }
+                }
+            }
+
+            // With the `Block` backpressure policy, decoding holds off while the sink behind
+            // the decode-ahead buffer is stalled, but commands are still handled.
+            let sink_writable = match self.decode_ahead {
+                Some(ref decode_ahead)
+                    if self.config.backpressure_policy == BackpressurePolicy::Block =>
+                {
+                    decode_ahead.poll_writable(cx).is_ready()
+                }
+                _ => true,
+            };
+
+            if self.state.is_playing() && sink_writable {
                 self.ensure_sink_running();
+                let sink_latency_ms = self.sink_latency_ms();
 
                 if let PlayerState::Playing {
                     track_id,
@@ -940,13 +1701,16 @@ impl Future for PlayerInternal {
                         if let Some(ref packet) = packet {
                             *stream_position_pcm +=
                                 (packet.samples().len() / NUM_CHANNELS as usize) as u64;
+                            // The position that is heard, behind the decoded one by the
+                            // audio buffered in the sink.
                             let stream_position_millis =
-                                Self::position_pcm_to_ms(*stream_position_pcm);
+                                Self::position_pcm_to_ms(*stream_position_pcm)
+                                    .saturating_sub(sink_latency_ms);
 
                             let notify_about_position = match *reported_nominal_start_time {
                                 None => true,
                                 Some(reported_nominal_start_time) => {
-                                    // only notify if we're behind. If we're ahead it's probably due to a buffer of the backend and we;re actually in time.
+                                    // only notify if we're behind. If we're ahead it's probably due to a buffer of the backend that doesn't report its latency and we're actually in time.
                                     let lag = (Instant::now() - reported_nominal_start_time)
                                         .as_millis()
                                         as i64
@@ -976,6 +1740,9 @@ impl Future for PlayerInternal {
                 } else {
                     unreachable!();
                 };
+
+                self.save_position(POSITION_SAVE_INTERVAL_MS);
+                self.check_download_rate();
             }
 
             if let PlayerState::Playing {
@@ -1010,11 +1777,24 @@ impl Future for PlayerInternal {
                 }
             }
 
-            if self.session.is_invalid() {
-                return Poll::Ready(());
+            if let Some((mark, _)) = self.pending_end_of_track {
+                let played = self
+                    .decode_ahead
+                    .as_ref()
+                    .map_or(Poll::Ready(()), |decode_ahead| {
+                        decode_ahead.poll_played(cx, mark)
+                    });
+                if played.is_ready() {
+                    self.send_pending_end_of_track();
+                }
+            }
+
+            // Nothing can be loaded until a new session is set.
+            if self.session.is_invalid() && !self.state.is_stopped() {
+                self.handle_player_stop();
             }
 
-            if (!self.state.is_playing()) && all_futures_completed_or_not_ready {
+            if (!self.state.is_playing() || !sink_writable) && all_futures_completed_or_not_ready {
                 return Poll::Pending;
             }
         }
@@ -1026,6 +1806,10 @@ impl PlayerInternal {
         (position_pcm * 10 / 441) as u32
     }
 
+    fn sink_latency_ms(&self) -> u32 {
+        self.sink.latency().as_millis() as u32
+    }
+
     fn position_ms_to_pcm(position_ms: u32) -> u64 {
         position_ms as u64 * 441 / 10
     }
@@ -1043,7 +1827,30 @@ impl PlayerInternal {
         }
     }
 
-    fn ensure_sink_stopped(&mut self, temporarily: bool) {
+    // Discards the audio decoded ahead of the sink, as it isn't of the position to play. The
+    // end of a track that is discarded isn't reported.
+    fn clear_decoded_audio(&mut self) {
+        if let Some(ref decode_ahead) = self.decode_ahead {
+            decode_ahead.clear();
+        }
+        self.pending_end_of_track = None;
+    }
+
+    // Plays out the audio decoded ahead of the sink, e.g. the end of the last track.
+    fn drain_decoded_audio(&mut self) {
+        if let Some(ref decode_ahead) = self.decode_ahead {
+            decode_ahead.drain();
+        }
+        self.send_pending_end_of_track();
+    }
+
+    fn send_pending_end_of_track(&mut self) {
+        if let Some((_, event)) = self.pending_end_of_track.take() {
+            self.send_event(event);
+        }
+    }
+
+    fn ensure_sink_stopped(&mut self, temporarily: bool) {
         match self.sink_status {
             SinkStatus::Running => {
                 trace!("== Stopping sink ==");
@@ -1069,6 +1876,7 @@ impl PlayerInternal {
         }
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     fn handle_player_stop(&mut self) {
         match self.state {
             PlayerState::Playing {
@@ -1091,6 +1899,12 @@ impl PlayerInternal {
                 play_request_id,
                 ..
             } => {
+                self.save_position(0);
+                if self.state.is_end_of_track() {
+                    self.drain_decoded_audio();
+                } else {
+                    self.clear_decoded_audio();
+                }
                 self.ensure_sink_stopped(false);
                 self.send_event(PlayerEvent::Stopped {
                     track_id,
@@ -1103,6 +1917,7 @@ impl PlayerInternal {
         }
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     fn handle_play(&mut self) {
         if let PlayerState::Paused {
             track_id,
@@ -1114,7 +1929,9 @@ impl PlayerInternal {
         {
             self.state.paused_to_playing();
 
-            let position_ms = Self::position_pcm_to_ms(stream_position_pcm);
+            // The audio buffered in the sink is heard first.
+            let position_ms = Self::position_pcm_to_ms(stream_position_pcm)
+                .saturating_sub(self.sink_latency_ms());
             self.send_event(PlayerEvent::Playing {
                 track_id,
                 play_request_id,
@@ -1127,6 +1944,7 @@ impl PlayerInternal {
         }
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     fn handle_pause(&mut self) {
         if let PlayerState::Playing {
             track_id,
@@ -1137,9 +1955,12 @@ impl PlayerInternal {
         } = self.state
         {
             self.state.playing_to_paused();
+            self.save_position(0);
 
+            // The position that was heard, before the sink is stopped.
+            let position_ms = Self::position_pcm_to_ms(stream_position_pcm)
+                .saturating_sub(self.sink_latency_ms());
             self.ensure_sink_stopped(false);
-            let position_ms = Self::position_pcm_to_ms(stream_position_pcm);
             self.send_event(PlayerEvent::Paused {
                 track_id,
                 play_request_id,
@@ -1151,10 +1972,96 @@ impl PlayerInternal {
         }
     }
 
+    // Pauses instead of playing on through another output, e.g. the speakers after headphones
+    // were unplugged. The packet that was about to be written is dropped.
+    fn handle_output_changed(&mut self) {
+        if let PlayerState::Playing {
+            track_id,
+            play_request_id,
+            ..
+        } = self.state
+        {
+            info!("The output device changed, pausing");
+            self.send_event(PlayerEvent::OutputChanged {
+                track_id,
+                play_request_id,
+            });
+            self.handle_pause();
+        }
+    }
+
+    // Reports a stall of the sink behind the decode-ahead buffer, and pauses if the
+    // backpressure policy asks.
+    fn handle_sink_stall(&mut self, stalled: bool) {
+        let (track_id, play_request_id) = match self.state {
+            PlayerState::Playing {
+                track_id,
+                play_request_id,
+                ..
+            }
+            | PlayerState::Paused {
+                track_id,
+                play_request_id,
+                ..
+            } => (track_id, play_request_id),
+            _ => return,
+        };
+
+        if !stalled {
+            info!("The sink recovered");
+            self.send_event(PlayerEvent::SinkRecovered {
+                track_id,
+                play_request_id,
+            });
+            return;
+        }
+
+        let policy = self.config.backpressure_policy;
+        let paused = policy == BackpressurePolicy::Pause && self.state.is_playing();
+        warn!(
+            "The sink stalled, applying the {:?} backpressure policy",
+            policy
+        );
+        self.send_event(PlayerEvent::SinkStalled {
+            track_id,
+            play_request_id,
+            paused,
+        });
+        if paused {
+            self.handle_pause();
+        }
+    }
+
+    // Picks the output gain of the output the sink plays to now.
+    fn update_output_gain(&mut self) {
+        let output = self.sink.output();
+        if output == self.output {
+            return;
+        }
+        let gain_db = self.config.output_gain_db_of(output.as_deref());
+        debug!("Output gain of {:?}: {} dB", output, gain_db);
+        self.output_gain = NormalisationData::db_to_ratio(gain_db);
+        self.output = output;
+    }
+
     fn handle_packet(&mut self, packet: Option<AudioPacket>, normalisation_factor: f32) {
         match packet {
             Some(mut packet) => {
                 if !packet.is_empty() {
+                    // Checking for changes also keeps the output of some sinks up to date.
+                    let check_output =
+                        self.config.pause_on_output_change || !self.config.output_gains.is_empty();
+                    if check_output
+                        && self.sink.output_changed()
+                        && self.config.pause_on_output_change
+                    {
+                        self.handle_output_changed();
+                        return;
+                    }
+                    if !self.config.output_gains.is_empty() {
+                        self.update_output_gain();
+                    }
+
                     if let AudioPacket::Samples(ref mut data) = packet {
                         if let Some(ref editor) = self.audio_filter {
                             editor.modify_stream(data)
@@ -1272,12 +2179,34 @@ impl PlayerInternal {
                                 }
                             }
                         }
+
+                        if self.output_gain != 1.0 {
+                            simd::scale(data, self.output_gain);
+                            // A positive gain must not clip.
+                            if self.output_gain > 1.0 {
+                                for sample in data.iter_mut() {
+                                    *sample = sample.max(-1.0).min(1.0);
+                                }
+                            }
+                        }
+
+                        if self.decode_ahead.is_none() {
+                            self.ducking.lock().apply(data);
+                        }
                     }
 
                     if let Err(err) = self.sink.write(&packet) {
                         error!("Could not write audio: {}", err);
                         self.ensure_sink_stopped(false);
                     }
+
+                    let stall_changed = self
+                        .decode_ahead
+                        .as_ref()
+                        .and_then(DecodeAheadControl::stall_changed);
+                    if let Some(stalled) = stall_changed {
+                        self.handle_sink_stall(stalled);
+                    }
                 }
             }
 
@@ -1289,10 +2218,25 @@ impl PlayerInternal {
                     ..
                 } = self.state
                 {
-                    self.send_event(PlayerEvent::EndOfTrack {
+                    // A finished track starts from the beginning when it is resumed.
+                    if let Some((resumable_track_id, _)) = self.resumable.take() {
+                        if let Some(cache) = self.session.cache() {
+                            cache.remove_position(resumable_track_id);
+                        }
+                    }
+                    let event = PlayerEvent::EndOfTrack {
                         track_id,
                         play_request_id,
-                    })
+                    };
+                    // Reported once the end is heard rather than decoded. The end of a previous
+                    // track is reported first.
+                    self.send_pending_end_of_track();
+                    match self.decode_ahead {
+                        Some(ref decode_ahead) => {
+                            self.pending_end_of_track = Some((decode_ahead.mark(), event))
+                        }
+                        None => self.send_event(event),
+                    }
                 } else {
                     unreachable!();
                 }
@@ -1316,7 +2260,10 @@ impl PlayerInternal {
         loaded_track: PlayerLoadedTrackData,
         start_playback: bool,
     ) {
-        let position_ms = Self::position_pcm_to_ms(loaded_track.stream_position_pcm);
+        // Behind the loaded position by the audio still in the sink, e.g. the end of the
+        // previous track.
+        let position_ms = Self::position_pcm_to_ms(loaded_track.stream_position_pcm)
+            .saturating_sub(self.sink_latency_ms());
 
         if start_playback {
             self.ensure_sink_running();
@@ -1336,6 +2283,8 @@ impl PlayerInternal {
                 stream_loader_controller: loaded_track.stream_loader_controller,
                 duration_ms: loaded_track.duration_ms,
                 bytes_per_second: loaded_track.bytes_per_second,
+                format: loaded_track.format,
+                normalisation_data: loaded_track.normalisation_data,
                 stream_position_pcm: loaded_track.stream_position_pcm,
                 reported_nominal_start_time: Some(
                     Instant::now() - Duration::from_millis(position_ms as u64),
@@ -1353,6 +2302,8 @@ impl PlayerInternal {
                 stream_loader_controller: loaded_track.stream_loader_controller,
                 duration_ms: loaded_track.duration_ms,
                 bytes_per_second: loaded_track.bytes_per_second,
+                format: loaded_track.format,
+                normalisation_data: loaded_track.normalisation_data,
                 stream_position_pcm: loaded_track.stream_position_pcm,
                 suggested_to_preload_next_track: false,
             };
@@ -1366,6 +2317,14 @@ impl PlayerInternal {
         }
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self, track_id),
+            fields(track = %track_id.to_uri())
+        )
+    )]
     fn handle_command_load(
         &mut self,
         track_id: SpotifyId,
@@ -1373,37 +2332,21 @@ impl PlayerInternal {
         play: bool,
         position_ms: u32,
     ) {
+        self.save_position(0);
+        self.resumable = None;
+        self.reset_adaptive_bitrate();
+        // The end of a finished track still plays before the next one.
+        if !self.state.is_end_of_track() {
+            self.clear_decoded_audio();
+        }
+
         if !self.config.gapless {
             self.ensure_sink_stopped(play);
         }
-        // emit the correct player event
-        match self.state {
-            PlayerState::Playing {
-                track_id: old_track_id,
-                ..
-            }
-            | PlayerState::Paused {
-                track_id: old_track_id,
-                ..
-            }
-            | PlayerState::EndOfTrack {
-                track_id: old_track_id,
-                ..
-            }
-            | PlayerState::Loading {
-                track_id: old_track_id,
-                ..
-            } => self.send_event(PlayerEvent::Changed {
-                old_track_id,
-                new_track_id: track_id,
-            }),
-            PlayerState::Stopped => self.send_event(PlayerEvent::Started {
-                track_id,
-                play_request_id,
-                position_ms,
-            }),
-            PlayerState::Invalid { .. } => panic!("Player is in an invalid state."),
-        }
+        self.send_load_event(track_id, play_request_id, position_ms);
+
+        // A preview clip can't stand in for the full track.
+        let was_preview = mem::replace(&mut self.playing_preview, false);
 
         // Now we check at different positions whether we already have a pre-loaded version
         // of this track somewhere. If so, use it and return.
@@ -1415,7 +2358,7 @@ impl PlayerInternal {
             ..
         } = self.state
         {
-            if previous_track_id == track_id {
+            if previous_track_id == track_id && !was_preview {
                 let mut loaded_track = match mem::replace(&mut self.state, PlayerState::Invalid) {
                     PlayerState::EndOfTrack { loaded_track, .. } => loaded_track,
                     _ => unreachable!(),
@@ -1457,7 +2400,7 @@ impl PlayerInternal {
             ..
         } = self.state
         {
-            if current_track_id == track_id {
+            if current_track_id == track_id && !was_preview {
                 // we can use the current decoder. Ensure it's at the correct position.
                 if Self::position_ms_to_pcm(position_ms) != *stream_position_pcm {
                     stream_loader_controller.set_random_access_mode();
@@ -1475,6 +2418,8 @@ impl PlayerInternal {
                     decoder,
                     stream_loader_controller,
                     bytes_per_second,
+                    format,
+                    normalisation_data,
                     duration_ms,
                     normalisation_factor,
                     ..
@@ -1484,6 +2429,8 @@ impl PlayerInternal {
                     decoder,
                     stream_loader_controller,
                     bytes_per_second,
+                    format,
+                    normalisation_data,
                     duration_ms,
                     normalisation_factor,
                     ..
@@ -1494,8 +2441,13 @@ impl PlayerInternal {
                         normalisation_factor,
                         stream_loader_controller,
                         bytes_per_second,
+                        format,
+                        normalisation_data,
                         duration_ms,
                         stream_position_pcm,
+                        relinked_id: None,
+                        name: String::new(),
+                        artists: Vec::new(),
                     };
 
                     self.preload = PlayerPreload::None;
@@ -1532,6 +2484,19 @@ impl PlayerInternal {
                         let _ = loaded_track.decoder.seek(position_ms as i64); // This may be blocking
                         loaded_track.stream_loader_controller.set_stream_mode();
                     }
+                    self.send_relinked_event(play_request_id, track_id, &loaded_track);
+                    self.send_event(PlayerEvent::TrackChanged {
+                        play_request_id,
+                        track_id,
+                        format: loaded_track.format,
+                        normalisation_data: loaded_track.normalisation_data,
+                    });
+                    self.sink.set_track(&SinkTrack {
+                        track_id,
+                        name: loaded_track.name.clone(),
+                        artists: loaded_track.artists.clone(),
+                        duration_ms: loaded_track.duration_ms,
+                    });
                     self.start_playback(track_id, play_request_id, *loaded_track, play);
                     return;
                 } else {
@@ -1540,6 +2505,47 @@ impl PlayerInternal {
             }
         }
 
+        self.start_loading(track_id, play_request_id, play, position_ms);
+    }
+
+    // Emits the correct player event for a load request.
+    fn send_load_event(&mut self, track_id: SpotifyId, play_request_id: u64, position_ms: u32) {
+        match self.state {
+            PlayerState::Playing {
+                track_id: old_track_id,
+                ..
+            }
+            | PlayerState::Paused {
+                track_id: old_track_id,
+                ..
+            }
+            | PlayerState::EndOfTrack {
+                track_id: old_track_id,
+                ..
+            }
+            | PlayerState::Loading {
+                track_id: old_track_id,
+                ..
+            } => self.send_event(PlayerEvent::Changed {
+                old_track_id,
+                new_track_id: track_id,
+            }),
+            PlayerState::Stopped => self.send_event(PlayerEvent::Started {
+                track_id,
+                play_request_id,
+                position_ms,
+            }),
+            PlayerState::Invalid { .. } => panic!("Player is in an invalid state."),
+        }
+    }
+
+    fn start_loading(
+        &mut self,
+        track_id: SpotifyId,
+        play_request_id: u64,
+        play: bool,
+        position_ms: u32,
+    ) {
         // We need to load the track - either from scratch or by completing a preload.
         // In any case we go into a Loading state to load the track.
         self.ensure_sink_stopped(play);
@@ -1574,7 +2580,9 @@ impl PlayerInternal {
         self.preload = PlayerPreload::None;
 
         // If we don't have a loader yet, create one from scratch.
-        let loader = loader.unwrap_or_else(|| Box::pin(self.load_track(track_id, position_ms)));
+        let loader = loader.unwrap_or_else(|| {
+            Box::pin(self.load_track(track_id, position_ms, Some(play_request_id), false))
+        });
 
         // Set ourselves to a loading state.
         self.state = PlayerState::Loading {
@@ -1585,6 +2593,342 @@ impl PlayerInternal {
         };
     }
 
+    fn handle_command_load_resumable(
+        &mut self,
+        track_id: SpotifyId,
+        play_request_id: u64,
+        play: bool,
+    ) {
+        let position_ms = self
+            .session
+            .cache()
+            .and_then(|cache| cache.position(track_id))
+            .unwrap_or(0);
+        debug!("Resuming <{}> at {} ms", track_id.to_uri(), position_ms);
+
+        self.handle_command_load(track_id, play_request_id, play, position_ms);
+        self.resumable = Some((track_id, position_ms));
+    }
+
+    // Saves the position of a resumable track if it moved by at least min_change_ms.
+    fn save_position(&mut self, min_change_ms: u32) {
+        if let Some((track_id, ref mut saved_position_ms)) = self.resumable {
+            if let PlayerState::Playing {
+                track_id: current_track_id,
+                stream_position_pcm,
+                ..
+            }
+            | PlayerState::Paused {
+                track_id: current_track_id,
+                stream_position_pcm,
+                ..
+            } = self.state
+            {
+                let position_ms = Self::position_pcm_to_ms(stream_position_pcm);
+                let change_ms = (position_ms as i64 - *saved_position_ms as i64).abs();
+                if current_track_id == track_id && change_ms >= min_change_ms as i64 {
+                    if let Some(cache) = self.session.cache() {
+                        cache.save_position(track_id, position_ms);
+                    }
+                    *saved_position_ms = position_ms;
+                }
+            }
+        }
+    }
+
+    fn reset_adaptive_bitrate(&mut self) {
+        self.download_rate_low_since = None;
+        self.download_rate_high_since = None;
+        self.format_switch = None;
+    }
+
+    // Picks the formats of the following tracks if the download rate hasn't matched the bitrate
+    // of the current one for a while. Only formats of the format policy are considered.
+    fn check_download_rate(&mut self) {
+        if self.config.adaptive_bitrate == AdaptiveBitrate::Disabled || self.playing_preview {
+            return;
+        }
+
+        let (bytes_per_second, download_rate) = match self.state {
+            PlayerState::Playing {
+                bytes_per_second,
+                ref stream_loader_controller,
+                ..
+            } => {
+                if !stream_loader_controller.is_fetching() {
+                    // Nothing was asked for, so the download rate says nothing about the
+                    // network.
+                    return;
+                }
+                (
+                    bytes_per_second,
+                    stream_loader_controller.download_rate_estimate(),
+                )
+            }
+            _ => return,
+        };
+
+        if download_rate == 0 {
+            // Nothing was downloaded recently.
+            return;
+        }
+
+        let now = Instant::now();
+        let rate = PlayerTrackLoader::stream_data_rate;
+        let policy = self.config.format_policy();
+        let mut formats: Vec<FileFormat> = if download_rate < bytes_per_second {
+            self.download_rate_high_since = None;
+            let since = *self.download_rate_low_since.get_or_insert(now);
+            if now - since < ADAPTIVE_BITRATE_SUSTAIN {
+                return;
+            }
+            policy
+                .formats
+                .iter()
+                .copied()
+                .filter(|&format| rate(format) < bytes_per_second)
+                .collect()
+        } else {
+            self.download_rate_low_since = None;
+            let formats: Vec<FileFormat> = match self.config.adaptive_bitrate {
+                AdaptiveBitrate::Full => policy
+                    .formats
+                    .iter()
+                    .copied()
+                    .filter(|&format| {
+                        rate(format) > bytes_per_second
+                            && rate(format) as f64 * ADAPTIVE_BITRATE_UPGRADE_HEADROOM
+                                <= download_rate as f64
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            if formats.is_empty() {
+                self.download_rate_high_since = None;
+                return;
+            }
+            let since = *self.download_rate_high_since.get_or_insert(now);
+            if now - since < ADAPTIVE_BITRATE_SUSTAIN {
+                return;
+            }
+            formats
+        };
+
+        self.download_rate_low_since = None;
+        self.download_rate_high_since = None;
+        if formats.is_empty() {
+            return;
+        }
+
+        // Prefer the highest of the candidate bitrates, then the rest of the policy for tracks
+        // that aren't available in any of them.
+        formats.sort_by_key(|&format| std::cmp::Reverse(rate(format)));
+        debug!(
+            "Download rate of {} B/s doesn't match {} B/s, playing the next tracks as one of {:?}",
+            download_rate, bytes_per_second, formats
+        );
+        for format in policy.formats {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+        self.adapted_format_policy = Some(FileFormatPolicy {
+            formats,
+            fallback: policy.fallback,
+        });
+    }
+
+    // Continues playback of the current track from the file loaded by reload_current().
+    fn switch_format(&mut self, play_request_id: u64, loaded_track: PlayerLoadedTrackData) {
+        let (track_id, old_format, new_format) = match self.state {
+            PlayerState::Playing {
+                track_id,
+                play_request_id: current_play_request_id,
+                stream_position_pcm,
+                ref mut decoder,
+                ref mut normalisation_factor,
+                ref mut stream_loader_controller,
+                ref mut bytes_per_second,
+                ref mut format,
+                ref mut normalisation_data,
+                ..
+            }
+            | PlayerState::Paused {
+                track_id,
+                play_request_id: current_play_request_id,
+                stream_position_pcm,
+                ref mut decoder,
+                ref mut normalisation_factor,
+                ref mut stream_loader_controller,
+                ref mut bytes_per_second,
+                ref mut format,
+                ref mut normalisation_data,
+                ..
+            } if current_play_request_id == play_request_id => {
+                let PlayerLoadedTrackData {
+                    decoder: mut new_decoder,
+                    normalisation_factor: new_normalisation_factor,
+                    stream_loader_controller: new_stream_loader_controller,
+                    bytes_per_second: new_bytes_per_second,
+                    format: new_format,
+                    normalisation_data: new_normalisation_data,
+                    stream_position_pcm: loaded_position_pcm,
+                    ..
+                } = loaded_track;
+
+                // Playback went on while the file was loaded.
+                if loaded_position_pcm != stream_position_pcm {
+                    new_stream_loader_controller.set_random_access_mode();
+                    let position_ms = Self::position_pcm_to_ms(stream_position_pcm);
+                    if let Err(err) = new_decoder.seek(position_ms as i64) {
+                        error!("Vorbis error: {}", err);
+                        return;
+                    }
+                    new_stream_loader_controller.set_stream_mode();
+                }
+
+                let old_format = *format;
+                *decoder = new_decoder;
+                *normalisation_factor = new_normalisation_factor;
+                *stream_loader_controller = new_stream_loader_controller;
+                *bytes_per_second = new_bytes_per_second;
+                *format = new_format;
+                *normalisation_data = new_normalisation_data;
+                (track_id, old_format, new_format)
+            }
+            _ => return,
+        };
+
+        info!("Switched from {:?} to {:?}", old_format, new_format);
+        self.send_event(PlayerEvent::FormatChanged {
+            play_request_id,
+            track_id,
+            old_format,
+            new_format,
+        });
+    }
+
+    fn handle_command_reload_current(&mut self, bitrate: Bitrate) {
+        self.config.bitrate = bitrate;
+        self.config.file_format_policy = None;
+
+        if self.playing_preview {
+            return;
+        }
+
+        let (track_id, play_request_id, format, position_ms) = match self.state {
+            PlayerState::Playing {
+                track_id,
+                play_request_id,
+                format,
+                stream_position_pcm,
+                ..
+            }
+            | PlayerState::Paused {
+                track_id,
+                play_request_id,
+                format,
+                stream_position_pcm,
+                ..
+            } => (
+                track_id,
+                play_request_id,
+                format,
+                Self::position_pcm_to_ms(stream_position_pcm),
+            ),
+            _ => return,
+        };
+
+        // Only the formats preferred over the current one are worth switching to.
+        let formats: Vec<FileFormat> = FileFormatPolicy::from(bitrate)
+            .formats
+            .into_iter()
+            .take_while(|&candidate| candidate != format)
+            .collect();
+        if formats.is_empty() {
+            debug!("Already playing {:?}, not reloading", format);
+            return;
+        }
+
+        // The requested bitrate replaces the one picked for the download rate.
+        self.reset_adaptive_bitrate();
+        self.adapted_format_policy = None;
+        let policy = FileFormatPolicy {
+            formats,
+            fallback: false,
+        };
+        let loader = self.load_track_with_policy(track_id, position_ms, policy);
+        self.format_switch = Some(PlayerFormatSwitch {
+            play_request_id,
+            loader: Box::pin(loader),
+        });
+    }
+
+    fn handle_command_load_preview(
+        &mut self,
+        track_id: SpotifyId,
+        play_request_id: u64,
+        play: bool,
+    ) {
+        self.save_position(0);
+        self.resumable = None;
+        self.reset_adaptive_bitrate();
+        if !self.state.is_end_of_track() {
+            self.clear_decoded_audio();
+        }
+
+        self.send_load_event(track_id, play_request_id, 0);
+        self.ensure_sink_stopped(play);
+
+        self.send_event(PlayerEvent::Loading {
+            track_id,
+            play_request_id,
+            position_ms: 0,
+        });
+
+        // A preload of the following track doesn't make sense while browsing previews.
+        self.preload = PlayerPreload::None;
+        self.playing_preview = true;
+
+        let loader = self.load_track(track_id, 0, Some(play_request_id), true);
+        self.state = PlayerState::Loading {
+            track_id,
+            play_request_id,
+            start_playback: play,
+            loader: Box::pin(loader),
+        };
+    }
+
+    fn handle_command_precache(&mut self, track_id: SpotifyId) {
+        if self.session.cache().is_none() {
+            debug!("Not precaching <{}> without a cache", track_id.to_uri());
+            return;
+        }
+
+        if let Some(precache_tx) = &self.precache_tx {
+            if precache_tx.send(track_id).is_ok() {
+                return;
+            }
+        }
+
+        let (precache_tx, precache_rx) = std::sync::mpsc::channel();
+        let _ = precache_tx.send(track_id);
+        self.precache_tx = Some(precache_tx);
+
+        let loader = PlayerTrackLoader {
+            session: self.session.clone(),
+            config: self.config.clone(),
+            progress: None,
+        };
+        std::thread::spawn(move || {
+            for track_id in precache_rx {
+                if let Err(e) = futures_executor::block_on(loader.precache(track_id)) {
+                    warn!("Unable to precache <{}>: {}", track_id.to_uri(), e);
+                }
+            }
+        });
+    }
+
     fn handle_command_preload(&mut self, track_id: SpotifyId) {
         debug!("Preloading track");
         let mut preload_track = true;
@@ -1620,7 +2964,7 @@ impl PlayerInternal {
             ..
         } = self.state
         {
-            if current_track_id == track_id {
+            if current_track_id == track_id && !self.playing_preview {
                 // we already have the requested track loaded.
                 preload_track = false;
             }
@@ -1628,7 +2972,7 @@ impl PlayerInternal {
 
         // schedule the preload of the current track if desired.
         if preload_track {
-            let loader = self.load_track(track_id, 0);
+            let loader = self.load_track(track_id, 0, None, false);
             self.preload = PlayerPreload::Loading {
                 track_id,
                 loader: Box::pin(loader),
@@ -1636,7 +2980,9 @@ impl PlayerInternal {
         }
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     fn handle_command_seek(&mut self, position_ms: u32) {
+        self.clear_decoded_audio();
         if let Some(stream_loader_controller) = self.state.stream_loader_controller() {
             stream_loader_controller.set_random_access_mode();
         }
@@ -1669,6 +3015,8 @@ impl PlayerInternal {
         // ensure we have a bit of a buffer of downloaded data
         self.preload_data_before_playback();
 
+        // The new position is heard once the audio still in the sink played out.
+        let position_ms = position_ms.saturating_sub(self.sink_latency_ms());
         if let PlayerState::Playing {
             track_id,
             play_request_id,
@@ -1712,27 +3060,83 @@ impl PlayerInternal {
                 position_ms,
             } => self.handle_command_load(track_id, play_request_id, play, position_ms),
 
+            PlayerCommand::LoadPreview {
+                track_id,
+                play_request_id,
+                play,
+            } => self.handle_command_load_preview(track_id, play_request_id, play),
+
+            PlayerCommand::LoadResumable {
+                track_id,
+                play_request_id,
+                play,
+            } => self.handle_command_load_resumable(track_id, play_request_id, play),
+
             PlayerCommand::Preload { track_id } => self.handle_command_preload(track_id),
 
+            PlayerCommand::Precache { track_id } => self.handle_command_precache(track_id),
+
             PlayerCommand::Seek(position_ms) => self.handle_command_seek(position_ms),
 
+            PlayerCommand::ReloadCurrent { bitrate } => self.handle_command_reload_current(bitrate),
+
             PlayerCommand::Play => self.handle_play(),
 
             PlayerCommand::Pause => self.handle_pause(),
 
             PlayerCommand::Stop => self.handle_player_stop(),
 
+            PlayerCommand::SetSession(session) => {
+                debug!(
+                    "Player[{}] now uses Session[{}]",
+                    self.player_id,
+                    session.session_id()
+                );
+                self.handle_player_stop();
+                self.preload = PlayerPreload::None;
+                self.precache_tx = None;
+                self.session = session;
+            }
+
             PlayerCommand::AddEventSender(sender) => self.event_senders.push(sender),
 
+            PlayerCommand::AddListenSender(sender) => self.listen_senders.push(sender),
+
             PlayerCommand::SetSinkEventCallback(callback) => self.sink_event_callback = callback,
 
             PlayerCommand::EmitVolumeSetEvent(volume) => {
                 self.send_event(PlayerEvent::VolumeSet { volume })
             }
+
+            PlayerCommand::Duck { .. } | PlayerCommand::Unduck if !self.config.ducking => {
+                warn!("Ducking isn't enabled in the player configuration");
+            }
+
+            PlayerCommand::Duck { level, fade_ms } => {
+                debug!(
+                    "Player[{}] ducks to {:.2} within {} ms",
+                    self.player_id, level, fade_ms
+                );
+                self.ducking.lock().fade_to(level, fade_ms);
+            }
+
+            PlayerCommand::Unduck => {
+                debug!("Player[{}] unducks", self.player_id);
+                self.ducking.lock().release();
+            }
+
+            PlayerCommand::EmitTrackTransitionEvent { from, to, reason } => {
+                self.send_event(PlayerEvent::TrackTransition { from, to, reason })
+            }
         }
     }
 
     fn send_event(&mut self, event: PlayerEvent) {
+        if let Some(listen) = self.listen_tracker.handle_event(&event) {
+            self.listen_senders
+                .retain(|sender| sender.send(listen.clone()).is_ok());
+        }
+
         let mut index = 0;
         while index < self.event_senders.len() {
             match self.event_senders[index].send(event.clone()) {
@@ -1744,32 +3148,96 @@ impl PlayerInternal {
         }
     }
 
+    fn send_relinked_event(
+        &mut self,
+        play_request_id: u64,
+        track_id: SpotifyId,
+        loaded_track: &PlayerLoadedTrackData,
+    ) {
+        if let Some(relinked_id) = loaded_track.relinked_id {
+            self.send_event(PlayerEvent::TrackRelinked {
+                play_request_id,
+                track_id,
+                relinked_id,
+            });
+        }
+    }
+
     fn load_track(
         &self,
         spotify_id: SpotifyId,
         position_ms: u32,
-    ) -> impl Future<Output = Result<PlayerLoadedTrackData, ()>> + Send + 'static {
+        play_request_id: Option<u64>,
+        preview: bool,
+    ) -> impl Future<Output = Result<PlayerLoadedTrackData, LoadError>> + Send + 'static {
         // This method creates a future that returns the loaded stream and associated info.
         // Ideally all work should be done using asynchronous code. However, seek() on the
         // audio stream is implemented in a blocking fashion. Thus, we can't turn it into future
         // easily. Instead we spawn a thread to do the work and return a one-shot channel as the
         // future to work with.
 
+        // Tracks are played in the formats picked for the download rate, if any.
+        let config = match self.adapted_format_policy {
+            Some(ref policy) if !preview => PlayerConfig {
+                file_format_policy: Some(policy.clone()),
+                ..self.config.clone()
+            },
+            _ => self.config.clone(),
+        };
+        let loader = PlayerTrackLoader {
+            session: self.session.clone(),
+            config,
+            progress: play_request_id.map(|id| (id, self.event_senders.clone())),
+        };
+
+        Self::spawn_loader(loader, spotify_id, position_ms, preview)
+    }
+
+    fn load_track_with_policy(
+        &self,
+        spotify_id: SpotifyId,
+        position_ms: u32,
+        policy: FileFormatPolicy,
+    ) -> impl Future<Output = Result<PlayerLoadedTrackData, LoadError>> + Send + 'static {
         let loader = PlayerTrackLoader {
             session: self.session.clone(),
-            config: self.config.clone(),
+            config: PlayerConfig {
+                file_format_policy: Some(policy),
+                ..self.config.clone()
+            },
+            progress: None,
         };
 
+        Self::spawn_loader(loader, spotify_id, position_ms, false)
+    }
+
+    fn spawn_loader(
+        loader: PlayerTrackLoader,
+        spotify_id: SpotifyId,
+        position_ms: u32,
+        preview: bool,
+    ) -> impl Future<Output = Result<PlayerLoadedTrackData, LoadError>> + Send + 'static {
         let (result_tx, result_rx) = oneshot::channel();
 
         std::thread::spawn(move || {
-            let data = futures_executor::block_on(loader.load_track(spotify_id, position_ms));
-            if let Some(data) = data {
-                let _ = result_tx.send(data);
-            }
+            let result = if preview {
+                futures_executor::block_on(loader.load_preview(spotify_id))
+            } else {
+                futures_executor::block_on(loader.load_track(spotify_id, position_ms))
+            };
+            let result = result.map_err(|e| {
+                error!("Unable to load <{}>: {}", spotify_id.to_uri(), e);
+                match e {
+                    Error::ExplicitContent(_) => LoadError::ExplicitContent,
+                    _ => LoadError::Failed,
+                }
+            });
+            let _ = result_tx.send(result);
         });
 
-        result_rx.map_err(|_| ())
+        result_rx
+            .map_err(|_| LoadError::Failed)
+            .and_then(future::ready)
     }
 
     fn preload_data_before_playback(&mut self) {
@@ -1779,21 +3247,23 @@ impl PlayerInternal {
             ..
         } = self.state
         {
+            let profile = &self.config.fetch_profile;
+
             // Request our read ahead range
             let request_data_length = max(
-                (READ_AHEAD_DURING_PLAYBACK_ROUNDTRIPS
+                (profile.read_ahead_during_playback_roundtrips
                     * (0.001 * stream_loader_controller.ping_time_ms() as f64)
                     * bytes_per_second as f64) as usize,
-                (READ_AHEAD_DURING_PLAYBACK_SECONDS * bytes_per_second as f64) as usize,
+                (profile.read_ahead_during_playback_seconds * bytes_per_second as f64) as usize,
             );
             stream_loader_controller.fetch_next(request_data_length);
 
             // Request the part we want to wait for blocking. This effecively means we wait for the previous request to partially complete.
             let wait_for_data_length = max(
-                (READ_AHEAD_BEFORE_PLAYBACK_ROUNDTRIPS
+                (profile.read_ahead_before_playback_roundtrips
                     * (0.001 * stream_loader_controller.ping_time_ms() as f64)
                     * bytes_per_second as f64) as usize,
-                (READ_AHEAD_BEFORE_PLAYBACK_SECONDS * bytes_per_second as f64) as usize,
+                (profile.read_ahead_before_playback_seconds * bytes_per_second as f64) as usize,
             );
             stream_loader_controller.fetch_next_blocking(wait_for_data_length);
         }
@@ -1802,7 +3272,7 @@ impl PlayerInternal {
 
 impl Drop for PlayerInternal {
     fn drop(&mut self) {
-        debug!("drop PlayerInternal[{}]", self.session.session_id());
+        debug!("drop PlayerInternal[{}]", self.player_id);
     }
 }
 
@@ -1820,20 +3290,51 @@ impl ::std::fmt::Debug for PlayerCommand {
                 .field(&play)
                 .field(&position_ms)
                 .finish(),
+            PlayerCommand::LoadPreview { track_id, play, .. } => f
+                .debug_tuple("LoadPreview")
+                .field(&track_id)
+                .field(&play)
+                .finish(),
+            PlayerCommand::LoadResumable { track_id, play, .. } => f
+                .debug_tuple("LoadResumable")
+                .field(&track_id)
+                .field(&play)
+                .finish(),
             PlayerCommand::Preload { track_id } => {
                 f.debug_tuple("Preload").field(&track_id).finish()
             }
+            PlayerCommand::Precache { track_id } => {
+                f.debug_tuple("Precache").field(&track_id).finish()
+            }
             PlayerCommand::Play => f.debug_tuple("Play").finish(),
             PlayerCommand::Pause => f.debug_tuple("Pause").finish(),
             PlayerCommand::Stop => f.debug_tuple("Stop").finish(),
             PlayerCommand::Seek(position) => f.debug_tuple("Seek").field(&position).finish(),
+            PlayerCommand::ReloadCurrent { bitrate } => {
+                f.debug_tuple("ReloadCurrent").field(&bitrate).finish()
+            }
+            PlayerCommand::SetSession(ref session) => f
+                .debug_tuple("SetSession")
+                .field(&session.session_id())
+                .finish(),
             PlayerCommand::AddEventSender(_) => f.debug_tuple("AddEventSender").finish(),
+            PlayerCommand::AddListenSender(_) => f.debug_tuple("AddListenSender").finish(),
             PlayerCommand::SetSinkEventCallback(_) => {
                 f.debug_tuple("SetSinkEventCallback").finish()
             }
             PlayerCommand::EmitVolumeSetEvent(volume) => {
                 f.debug_tuple("VolumeSet").field(&volume).finish()
             }
+            PlayerCommand::Duck { level, fade_ms } => {
+                f.debug_tuple("Duck").field(&level).field(&fade_ms).finish()
+            }
+            PlayerCommand::Unduck => f.debug_tuple("Unduck").finish(),
+            PlayerCommand::EmitTrackTransitionEvent { from, to, reason } => f
+                .debug_tuple("TrackTransition")
+                .field(&from)
+                .field(&to)
+                .field(&reason)
+                .finish(),
         }
     }
 }
@@ -0,0 +1,603 @@
+//! Synchronized playback on several devices.
+//!
+//! The master plays the Connect stream and sends the decoded audio to its followers,
+//! each chunk stamped with the time it is due on the master's clock. Followers estimate
+//! the offset between their clock and the master's, and drop or insert a few samples
+//! now and then to stay in lockstep despite drifting sound card clocks.
+//!
+//! The clocks are monotonic, so that adjustments of the system time don't disturb the
+//! offsets. Pings and pongs go over UDP, so that they don't wait behind the audio.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{
+    Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::audio::{convert, AudioPacket};
+use crate::audio_backend::{Sink, SinkTrack};
+
+const SAMPLE_RATE: i64 = 44100;
+const NUM_CHANNELS: usize = 2;
+
+/// How far ahead of the master's clock the audio is sent, giving the followers time
+/// to receive it.
+pub const DEFAULT_LATENCY: Duration = Duration::from_millis(500);
+
+// Chunks buffered for a follower before it is considered too slow and disconnected.
+const FOLLOWER_QUEUE: usize = 256;
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+// The offset is taken from the ping with the shortest round trip of the last ones.
+const CLOCK_SAMPLES: usize = 8;
+
+// The audio played before the output is considered stable enough to measure drift.
+const WARM_UP_US: i64 = 2_000_000;
+// Drift tolerated before correcting it, and the maximum correction per chunk.
+const DRIFT_THRESHOLD_US: f64 = 10_000.0;
+const MAX_CORRECTION_US: f64 = 2_000.0;
+
+// The longest chunk of audio accepted from the master, so a corrupt length can't make a
+// follower allocate gigabytes.
+const MAX_CHUNK_SAMPLES: usize = 10 * SAMPLE_RATE as usize * NUM_CHANNELS;
+
+// The delay before reconnecting to the master, doubled after each failed attempt.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+const MESSAGE_PING: u8 = 1;
+const MESSAGE_PONG: u8 = 2;
+const MESSAGE_AUDIO: u8 = 3;
+const MESSAGE_STOP: u8 = 4;
+
+// The longest ping or pong.
+const MAX_CONTROL_MESSAGE: usize = 17;
+
+/// A monotonic clock in microseconds since it was created.
+#[derive(Clone, Copy)]
+struct Clock(Instant);
+
+impl Clock {
+    fn new() -> Clock {
+        Clock(Instant::now())
+    }
+
+    fn now_us(&self) -> i64 {
+        self.0.elapsed().as_micros() as i64
+    }
+}
+
+fn samples_to_us(samples: usize) -> i64 {
+    (samples / NUM_CHANNELS) as i64 * 1_000_000 / SAMPLE_RATE
+}
+
+fn us_to_samples(us: i64) -> usize {
+    (us * SAMPLE_RATE / 1_000_000) as usize * NUM_CHANNELS
+}
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    Ping { sent: i64 },
+    Pong { sent: i64, master: i64 },
+    Audio { timestamp: i64, samples: Vec<i16> },
+    Stop,
+}
+
+impl Message {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut data = Vec::new();
+        match self {
+            Message::Ping { sent } => {
+                data.write_u8(MESSAGE_PING)?;
+                data.write_i64::<BigEndian>(*sent)?;
+            }
+            Message::Pong { sent, master } => {
+                data.write_u8(MESSAGE_PONG)?;
+                data.write_i64::<BigEndian>(*sent)?;
+                data.write_i64::<BigEndian>(*master)?;
+            }
+            Message::Audio { timestamp, samples } => {
+                data.reserve(13 + samples.len() * 2);
+                data.write_u8(MESSAGE_AUDIO)?;
+                data.write_i64::<BigEndian>(*timestamp)?;
+                data.write_u32::<BigEndian>(samples.len() as u32)?;
+                for sample in samples {
+                    data.write_i16::<BigEndian>(*sample)?;
+                }
+            }
+            Message::Stop => data.write_u8(MESSAGE_STOP)?,
+        }
+        writer.write_all(&data)
+    }
+
+    fn send_to(&self, socket: &UdpSocket) -> io::Result<()> {
+        let mut data = Vec::with_capacity(MAX_CONTROL_MESSAGE);
+        self.write_to(&mut data)?;
+        socket.send(&data).map(|_| ())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Message> {
+        match reader.read_u8()? {
+            MESSAGE_PING => Ok(Message::Ping {
+                sent: reader.read_i64::<BigEndian>()?,
+            }),
+            MESSAGE_PONG => Ok(Message::Pong {
+                sent: reader.read_i64::<BigEndian>()?,
+                master: reader.read_i64::<BigEndian>()?,
+            }),
+            MESSAGE_AUDIO => {
+                let timestamp = reader.read_i64::<BigEndian>()?;
+                let length = reader.read_u32::<BigEndian>()? as usize;
+                if length > MAX_CHUNK_SAMPLES {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "sync audio chunk too long",
+                    ));
+                }
+                let mut samples = vec![0; length];
+                reader.read_i16_into::<BigEndian>(&mut samples)?;
+                Ok(Message::Audio { timestamp, samples })
+            }
+            MESSAGE_STOP => Ok(Message::Stop),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown sync message",
+            )),
+        }
+    }
+}
+
+/// Accepts followers and sends them the audio written to a [`SyncSink`].
+#[derive(Clone)]
+pub struct SyncMaster {
+    followers: Arc<Mutex<Vec<SyncSender<Arc<Message>>>>>,
+    clock: Clock,
+}
+
+impl SyncMaster {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<SyncMaster> {
+        let listener = TcpListener::bind(address)?;
+        let local_address = listener.local_addr()?;
+        // Pings are answered on the UDP port of the same number.
+        let pings = UdpSocket::bind(local_address)?;
+        info!("Sync master listening on {}", local_address);
+
+        let master = SyncMaster {
+            followers: Arc::new(Mutex::new(Vec::new())),
+            clock: Clock::new(),
+        };
+
+        let clock = master.clock;
+        thread::spawn(move || {
+            let mut data = [0; MAX_CONTROL_MESSAGE];
+            loop {
+                let (length, follower) = match pings.recv_from(&mut data) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("Cannot receive sync pings: {}", e);
+                        return;
+                    }
+                };
+                if let Ok(Message::Ping { sent }) = Message::read_from(&mut &data[..length]) {
+                    let pong = Message::Pong {
+                        sent,
+                        master: clock.now_us(),
+                    };
+                    let mut reply = Vec::with_capacity(MAX_CONTROL_MESSAGE);
+                    let sent = pong
+                        .write_to(&mut reply)
+                        .and_then(|_| pings.send_to(&reply, follower));
+                    if let Err(e) = sent {
+                        debug!("Cannot answer the sync ping of {}: {}", follower, e);
+                    }
+                }
+            }
+        });
+
+        let accepting = master.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(e) = stream.and_then(|stream| accepting.add_follower(stream)) {
+                    warn!("Cannot accept sync follower: {}", e);
+                }
+            }
+        });
+
+        Ok(master)
+    }
+
+    fn add_follower(&self, stream: TcpStream) -> io::Result<()> {
+        let peer = stream.peer_addr()?;
+        info!("Sync follower {} connected", peer);
+        stream.set_nodelay(true)?;
+
+        let mut writer = stream;
+        let (tx, rx) = mpsc::sync_channel::<Arc<Message>>(FOLLOWER_QUEUE);
+        thread::spawn(move || {
+            for message in rx {
+                if let Err(e) = message.write_to(&mut writer) {
+                    debug!("Cannot send to sync follower {}: {}", peer, e);
+                    break;
+                }
+            }
+            info!("Sync follower {} disconnected", peer);
+        });
+
+        self.followers.lock().unwrap().push(tx);
+        Ok(())
+    }
+
+    fn broadcast(&self, message: Message) {
+        let message = Arc::new(message);
+        self.followers.lock().unwrap().retain(|follower| {
+            match follower.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Sync follower cannot keep up, disconnecting it");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// Plays audio locally and on the followers of a [`SyncMaster`] at the same time.
+///
+/// The local output is delayed by the latency as well, so it stays in sync with the followers.
+pub struct SyncSink {
+    sink: Box<dyn Sink>,
+    master: SyncMaster,
+    latency: Duration,
+    // When the first sample since starting is due, and the samples written since.
+    start_timestamp: Option<i64>,
+    written_samples: usize,
+}
+
+impl SyncSink {
+    pub fn new(sink: Box<dyn Sink>, master: SyncMaster, latency: Duration) -> SyncSink {
+        SyncSink {
+            sink,
+            master,
+            latency,
+            start_timestamp: None,
+            written_samples: 0,
+        }
+    }
+}
+
+impl Sink for SyncSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.sink.start()?;
+
+        let latency_us = self.latency.as_micros() as i64;
+        let silence = vec![0.0; us_to_samples(latency_us)];
+        self.start_timestamp = Some(self.master.clock.now_us() + latency_us);
+        self.written_samples = 0;
+        self.sink.write(&AudioPacket::Samples(silence))
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.start_timestamp = None;
+        self.master.broadcast(Message::Stop);
+        self.sink.stop()
+    }
+
+    fn write(&mut self, packet: &AudioPacket) -> io::Result<()> {
+        // Passthrough data can't be synchronized, it is only played locally.
+        if let AudioPacket::Samples(samples) = packet {
+            let latency_us = self.latency.as_micros() as i64;
+            let clock = self.master.clock;
+            let start_timestamp = *self
+                .start_timestamp
+                .get_or_insert_with(|| clock.now_us() + latency_us);
+            let timestamp = start_timestamp + samples_to_us(self.written_samples);
+            self.written_samples += samples.len();
+
+            self.master.broadcast(Message::Audio {
+                timestamp,
+                samples: convert::to_s16(samples),
+            });
+        }
+
+        self.sink.write(packet)
+    }
+
+    fn set_track(&mut self, track: &SinkTrack) {
+        self.sink.set_track(track)
+    }
+
+    // Includes the delay, as silence was written to the sink when it started.
+    fn latency(&self) -> Duration {
+        self.sink.latency()
+    }
+
+    fn output_changed(&mut self) -> bool {
+        self.sink.output_changed()
+    }
+
+    fn output(&mut self) -> Option<String> {
+        self.sink.output()
+    }
+}
+
+/// Estimates the offset between the master's clock and ours.
+#[derive(Default)]
+struct ClockSync {
+    // Round trip time and offset of the last pings.
+    samples: VecDeque<(i64, i64)>,
+}
+
+impl ClockSync {
+    fn update(&mut self, sent: i64, master: i64, received: i64) {
+        let round_trip = received - sent;
+        let offset = master - (sent + received) / 2;
+
+        self.samples.push_back((round_trip, offset));
+        if self.samples.len() > CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The master's time minus ours, from the ping least affected by network delays.
+    fn offset(&self) -> Option<i64> {
+        self.samples
+            .iter()
+            .min_by_key(|(round_trip, _)| *round_trip)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+struct Playout {
+    sink: Box<dyn Sink>,
+    clock: Clock,
+    started: bool,
+    played_us: i64,
+    // How late the output is compared to when it was started, and the smoothed deviation.
+    baseline_us: Option<i64>,
+    drift_us: f64,
+}
+
+impl Playout {
+    fn stop(&mut self) -> io::Result<()> {
+        if self.started {
+            self.started = false;
+            self.sink.stop()?;
+        }
+        Ok(())
+    }
+
+    fn play(&mut self, due: i64, samples: Vec<i16>) -> io::Result<()> {
+        let duration_us = samples_to_us(samples.len());
+        let mut samples: Vec<f32> = samples
+            .iter()
+            .map(|sample| *sample as f32 / 32768.0)
+            .collect();
+
+        if !self.started {
+            let now = self.clock.now_us();
+            if due + duration_us < now {
+                // Too late, wait for a chunk that can still be played in time.
+                return Ok(());
+            } else if due > now {
+                thread::sleep(Duration::from_micros((due - now) as u64));
+            } else {
+                samples.drain(..us_to_samples(now - due).min(samples.len()));
+            }
+
+            self.sink.start()?;
+            self.started = true;
+            self.played_us = 0;
+            self.baseline_us = None;
+            self.drift_us = 0.0;
+        } else if self.drift_us.abs() > DRIFT_THRESHOLD_US {
+            let correction = self.drift_us.abs().min(MAX_CORRECTION_US);
+            let correction_samples = us_to_samples(correction as i64).min(samples.len());
+            if self.drift_us > 0.0 {
+                // Playing late, skip ahead.
+                samples.drain(..correction_samples);
+                self.drift_us -= correction;
+            } else {
+                // Playing early, hold back.
+                samples.splice(0..0, vec![0.0; correction_samples]);
+                self.drift_us += correction;
+            }
+        }
+
+        self.sink.write(&AudioPacket::Samples(samples))?;
+
+        // Writing blocks while the output's buffer is full, so once it filled up, how late
+        // writing returns only changes when the output drifts.
+        let late_us = self.clock.now_us() - (due + duration_us);
+        self.played_us += duration_us;
+        match self.baseline_us {
+            Some(baseline_us) => {
+                self.drift_us = 0.9 * self.drift_us + 0.1 * (late_us - baseline_us) as f64
+            }
+            None if self.played_us >= WARM_UP_US => self.baseline_us = Some(late_us),
+            None => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Follows a [`SyncMaster`], playing its audio on `sink`. Whenever the connection is lost
+/// or can't be established, it reconnects after a delay that grows with each failed attempt.
+pub fn follow<A: ToSocketAddrs>(master: A, sink: Box<dyn Sink>) {
+    let mut playout = Playout {
+        sink,
+        clock: Clock::new(),
+        started: false,
+        played_us: 0,
+        baseline_us: None,
+        drift_us: 0.0,
+    };
+    let mut delay = RECONNECT_DELAY;
+
+    loop {
+        match TcpStream::connect(&master) {
+            Ok(stream) => {
+                delay = RECONNECT_DELAY;
+                match follow_stream(stream, &mut playout) {
+                    Ok(()) => info!("Sync master closed the connection"),
+                    Err(e) => warn!("Lost the connection to the sync master: {}", e),
+                }
+            }
+            Err(e) => warn!("Cannot connect to sync master: {}", e),
+        }
+
+        if let Err(e) = playout.stop() {
+            warn!("Cannot stop the output: {}", e);
+        }
+
+        info!("Reconnecting to the sync master in {} s", delay.as_secs());
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+// Plays the audio of a master until the connection is lost.
+fn follow_stream(stream: TcpStream, playout: &mut Playout) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    info!("Following sync master {}", stream.peer_addr()?);
+
+    let clock_sync = Arc::new(Mutex::new(ClockSync::default()));
+    let clock = playout.clock;
+
+    // Pongs are received on their own thread, so they are timestamped right away even while
+    // the output blocks.
+    let master = stream.peer_addr()?;
+    let unspecified: SocketAddr = match master {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let pings = UdpSocket::bind(unspecified)?;
+    pings.connect(master)?;
+    pings.set_read_timeout(Some(PING_INTERVAL))?;
+    let closed = Arc::new(AtomicBool::new(false));
+
+    let pinger_clock_sync = clock_sync.clone();
+    let pinger_closed = closed.clone();
+    let pinging = thread::spawn(move || {
+        let mut data = [0; MAX_CONTROL_MESSAGE];
+        while !pinger_closed.load(Ordering::Relaxed) {
+            let ping = Message::Ping {
+                sent: clock.now_us(),
+            };
+            if let Err(e) = ping.send_to(&pings) {
+                debug!("Cannot ping the sync master: {}", e);
+            }
+
+            // Waits for the pong until the next ping is due.
+            let next_ping = Instant::now() + PING_INTERVAL;
+            while Instant::now() < next_ping && !pinger_closed.load(Ordering::Relaxed) {
+                let length = match pings.recv(&mut data) {
+                    Ok(length) => length,
+                    Err(_) => continue,
+                };
+                if let Ok(Message::Pong { sent, master }) = Message::read_from(&mut &data[..length])
+                {
+                    let received = clock.now_us();
+                    pinger_clock_sync
+                        .lock()
+                        .unwrap()
+                        .update(sent, master, received);
+                }
+            }
+        }
+    });
+
+    let (tx, rx) = mpsc::channel();
+    let connection = stream.try_clone()?;
+    let mut reader = io::BufReader::new(stream);
+    let reading = thread::spawn(move || loop {
+        match Message::read_from(&mut reader) {
+            Ok(message) => {
+                if tx.send(message).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    });
+
+    let played = rx.iter().try_for_each(|message| match message {
+        Message::Audio { timestamp, samples } => {
+            let offset = clock_sync.lock().unwrap().offset();
+            match offset {
+                Some(offset) => playout.play(timestamp - offset, samples),
+                None => Ok(()),
+            }
+        }
+        Message::Stop => playout.stop(),
+        _ => Ok(()),
+    });
+
+    // Ends the reader if the output failed, and stops the pinger.
+    let _ = connection.shutdown(Shutdown::Both);
+    closed.store(true, Ordering::Relaxed);
+    let _ = pinging.join();
+    let read = reading
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "reader panicked")));
+    played.and(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_roundtrip() {
+        let messages = vec![
+            Message::Ping { sent: 1 },
+            Message::Pong {
+                sent: 1,
+                master: -2,
+            },
+            Message::Audio {
+                timestamp: 3,
+                samples: vec![0, -1, i16::MAX, i16::MIN],
+            },
+            Message::Stop,
+        ];
+
+        let mut data = Vec::new();
+        for message in &messages {
+            message.write_to(&mut data).unwrap();
+        }
+
+        let mut reader = io::Cursor::new(data);
+        for message in messages {
+            assert_eq!(Message::read_from(&mut reader).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn rejects_overlong_chunks() {
+        let mut data = vec![MESSAGE_AUDIO];
+        data.extend_from_slice(&0i64.to_be_bytes());
+        data.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        let error = Message::read_from(&mut io::Cursor::new(data)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn offset_from_shortest_round_trip() {
+        let mut clock = ClockSync::default();
+        clock.update(1000, 6000, 1200);
+        clock.update(2000, 6510, 2020);
+        clock.update(3000, 8000, 3500);
+
+        assert_eq!(clock.offset(), Some(6510 - 2010));
+    }
+}
@@ -0,0 +1,418 @@
+//! Python bindings for librespot.
+//!
+//! Network calls return awaitables running on a tokio runtime owned by the module, and player
+//! events are delivered to callbacks on the asyncio event loop they were registered from.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use librespot_core::authentication::Credentials;
+use librespot_core::cache::Cache;
+use librespot_core::config::SessionConfig;
+use librespot_core::spotify_id::SpotifyId;
+use librespot_metadata::Metadata;
+use librespot_playback::audio_backend;
+use librespot_playback::config::{AudioFormat, PlayerConfig};
+use librespot_playback::player;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+
+create_exception!(pylibrespot, LibrespotError, PyException);
+
+fn spotify_id(uri: &str) -> PyResult<SpotifyId> {
+    SpotifyId::from_uri(uri).map_err(|_| PyValueError::new_err(format!("invalid URI {}", uri)))
+}
+
+fn uris(ids: &[SpotifyId]) -> Vec<String> {
+    ids.iter().map(SpotifyId::to_uri).collect()
+}
+
+/// A connected session, created with `await Session.connect(...)`.
+#[pyclass]
+#[derive(Clone)]
+struct Session {
+    session: librespot_core::session::Session,
+}
+
+#[pymethods]
+impl Session {
+    /// Connects to Spotify. Without username and password, the credentials cached in
+    /// `cache_dir` are used.
+    #[staticmethod]
+    #[args(username = "None", password = "None", cache_dir = "None")]
+    fn connect(
+        py: Python,
+        username: Option<String>,
+        password: Option<String>,
+        cache_dir: Option<String>,
+    ) -> PyResult<&PyAny> {
+        let cache = cache_dir
+            .map(|dir| {
+                let dir = Path::new(&dir);
+                Cache::new(Some(dir), Some(dir.join("files").as_path()))
+                    .map_err(|e| LibrespotError::new_err(format!("cannot create cache: {}", e)))
+            })
+            .transpose()?;
+
+        let credentials = match (username, password) {
+            (Some(username), Some(password)) => Credentials::with_password(username, password),
+            _ => cache
+                .as_ref()
+                .and_then(Cache::credentials)
+                .ok_or_else(|| LibrespotError::new_err("no credentials given and none cached"))?,
+        };
+
+        let config = SessionConfig {
+            device_id: cache
+                .as_ref()
+                .and_then(Cache::device_id)
+                .unwrap_or_else(|| SessionConfig::default().device_id),
+            ..SessionConfig::default()
+        };
+
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            librespot_core::session::Session::connect(config, credentials, cache)
+                .await
+                .map(|session| Session { session })
+                .map_err(|e| LibrespotError::new_err(e.to_string()))
+        })
+    }
+
+    #[getter]
+    fn username(&self) -> String {
+        self.session.username()
+    }
+
+    #[getter]
+    fn country(&self) -> String {
+        self.session.country()
+    }
+
+    #[getter]
+    fn product(&self) -> Option<String> {
+        self.session.user_data().product().map(str::to_owned)
+    }
+
+    #[getter]
+    fn filter_explicit_content(&self) -> bool {
+        self.session.user_data().filter_explicit_content()
+    }
+
+    fn get_track<'p>(&self, py: Python<'p>, uri: &str) -> PyResult<&'p PyAny> {
+        let session = self.session.clone();
+        let id = spotify_id(uri)?;
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            librespot_metadata::Track::get(&session, id)
+                .await
+                .map(Track::from)
+                .map_err(|e| LibrespotError::new_err(e.to_string()))
+        })
+    }
+
+    fn get_album<'p>(&self, py: Python<'p>, uri: &str) -> PyResult<&'p PyAny> {
+        let session = self.session.clone();
+        let id = spotify_id(uri)?;
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            librespot_metadata::Album::get(&session, id)
+                .await
+                .map(Album::from)
+                .map_err(|e| LibrespotError::new_err(e.to_string()))
+        })
+    }
+
+    fn get_artist<'p>(&self, py: Python<'p>, uri: &str) -> PyResult<&'p PyAny> {
+        let session = self.session.clone();
+        let id = spotify_id(uri)?;
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            librespot_metadata::Artist::get(&session, id)
+                .await
+                .map(Artist::from)
+                .map_err(|e| LibrespotError::new_err(e.to_string()))
+        })
+    }
+
+    /// Closes the connection. The returned awaitable completes when all background tasks
+    /// have terminated.
+    fn shutdown<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let session = self.session.clone();
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            session.shutdown().await;
+            Ok(())
+        })
+    }
+}
+
+#[pyclass]
+struct Track {
+    #[pyo3(get)]
+    uri: String,
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    duration_ms: i32,
+    #[pyo3(get)]
+    album: String,
+    #[pyo3(get)]
+    artists: Vec<String>,
+    #[pyo3(get)]
+    available: bool,
+    #[pyo3(get)]
+    explicit: bool,
+    #[pyo3(get)]
+    number: i32,
+    #[pyo3(get)]
+    disc_number: i32,
+    #[pyo3(get)]
+    popularity: i32,
+    #[pyo3(get)]
+    isrc: Option<String>,
+}
+
+impl From<librespot_metadata::Track> for Track {
+    fn from(track: librespot_metadata::Track) -> Self {
+        Track {
+            uri: track.id.to_uri(),
+            name: track.name,
+            duration_ms: track.duration,
+            album: track.album.to_uri(),
+            artists: uris(&track.artists),
+            available: track.available,
+            explicit: track.explicit,
+            number: track.number,
+            disc_number: track.disc_number,
+            popularity: track.popularity,
+            isrc: track.isrc,
+        }
+    }
+}
+
+#[pyclass]
+struct Album {
+    #[pyo3(get)]
+    uri: String,
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    artists: Vec<String>,
+    #[pyo3(get)]
+    tracks: Vec<String>,
+    /// File ids of the covers, see https://i.scdn.co/image/<id>.
+    #[pyo3(get)]
+    covers: Vec<String>,
+    /// `YYYY`, `YYYY-MM` or `YYYY-MM-DD`, as precise as it is known.
+    #[pyo3(get)]
+    release_date: Option<String>,
+    #[pyo3(get)]
+    label: String,
+    #[pyo3(get)]
+    upc: Option<String>,
+    #[pyo3(get)]
+    copyrights: Vec<String>,
+}
+
+impl From<librespot_metadata::Album> for Album {
+    fn from(album: librespot_metadata::Album) -> Self {
+        Album {
+            uri: album.id.to_uri(),
+            name: album.name,
+            artists: uris(&album.artists),
+            tracks: uris(&album.tracks),
+            covers: album
+                .covers
+                .iter()
+                .map(|image| image.id.to_base16())
+                .collect(),
+            release_date: album.release_date.map(|date| date.to_string()),
+            label: album.label,
+            upc: album.upc,
+            copyrights: album
+                .copyrights
+                .into_iter()
+                .map(|copyright| copyright.text)
+                .collect(),
+        }
+    }
+}
+
+#[pyclass]
+struct Artist {
+    #[pyo3(get)]
+    uri: String,
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    top_tracks: Vec<String>,
+}
+
+impl From<librespot_metadata::Artist> for Artist {
+    fn from(artist: librespot_metadata::Artist) -> Self {
+        Artist {
+            uri: artist.id.to_uri(),
+            name: artist.name,
+            top_tracks: uris(&artist.top_tracks),
+        }
+    }
+}
+
+/// A player event. `event_type` is e.g. "playing", fields that do not apply to it are
+/// `None`.
+#[pyclass]
+#[derive(Clone)]
+struct PlayerEvent {
+    #[pyo3(get)]
+    event_type: &'static str,
+    #[pyo3(get)]
+    track_uri: Option<String>,
+    #[pyo3(get)]
+    position_ms: Option<u32>,
+    #[pyo3(get)]
+    duration_ms: Option<u32>,
+    #[pyo3(get)]
+    volume: Option<u16>,
+    /// The track played next for "track_transition", whose `track_uri` is the previous one.
+    #[pyo3(get)]
+    to_track_uri: Option<String>,
+    /// Why playback moved on for "track_transition", e.g. "skipped".
+    #[pyo3(get)]
+    reason: Option<&'static str>,
+    /// The alternative loaded for "track_relinked", whose `track_uri` is the requested track.
+    #[pyo3(get)]
+    relinked_track_uri: Option<String>,
+}
+
+impl From<player::PlayerEvent> for PlayerEvent {
+    fn from(event: player::PlayerEvent) -> Self {
+        let fields = event.fields();
+        PlayerEvent {
+            event_type: event.name(),
+            track_uri: fields.track_id.map(|id| id.to_uri()),
+            position_ms: fields.position_ms,
+            duration_ms: fields.duration_ms,
+            volume: fields.volume,
+            to_track_uri: fields.to_track_id.map(|id| id.to_uri()),
+            reason: fields.reason.map(player::TrackTransitionReason::name),
+            relinked_track_uri: fields.relinked_id.map(|id| id.to_uri()),
+        }
+    }
+}
+
+type Listeners = Arc<Mutex<Vec<(PyObject, PyObject)>>>;
+
+/// Plays tracks of a session on the default audio output.
+#[pyclass]
+struct Player {
+    player: player::Player,
+    listeners: Listeners,
+}
+
+#[pymethods]
+impl Player {
+    #[new]
+    fn new(session: &Session) -> PyResult<Self> {
+        let backend = audio_backend::find(None)
+            .ok_or_else(|| LibrespotError::new_err("no audio backend available"))?;
+
+        let (player, mut events) = player::Player::new(
+            // Decodes the audio, so that it can be ducked.
+            PlayerConfig::builder().ducking(true).build().unwrap(),
+            session.session.clone(),
+            None,
+            move || backend(None, AudioFormat::default()),
+        );
+
+        let listeners: Listeners = Arc::new(Mutex::new(Vec::new()));
+        let dispatch = listeners.clone();
+        pyo3_asyncio::tokio::get_runtime().spawn(async move {
+            while let Some(event) = events.recv().await {
+                let event = PlayerEvent::from(event);
+                Python::with_gil(|py| {
+                    for (callback, event_loop) in dispatch.lock().unwrap().iter() {
+                        let result = Py::new(py, event.clone()).and_then(|event| {
+                            event_loop.call_method1(
+                                py,
+                                "call_soon_threadsafe",
+                                (callback.clone_ref(py), event),
+                            )
+                        });
+                        if let Err(e) = result {
+                            e.print(py);
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Player { player, listeners })
+    }
+
+    /// Registers `callback(event)` to be called on the running event loop for each player
+    /// event. Must be called from a coroutine.
+    fn on_event(&self, py: Python, callback: PyObject) -> PyResult<()> {
+        let event_loop = pyo3_asyncio::get_running_loop(py)?;
+        self.listeners
+            .lock()
+            .unwrap()
+            .push((callback, event_loop.into()));
+        Ok(())
+    }
+
+    #[args(start_playing = "true", position_ms = "0")]
+    fn load(&mut self, uri: &str, start_playing: bool, position_ms: u32) -> PyResult<()> {
+        self.player
+            .load(spotify_id(uri)?, start_playing, position_ms);
+        Ok(())
+    }
+
+    /// Loads a track or episode at the position saved in the session's cache.
+    #[args(start_playing = "true")]
+    fn load_resumable(&mut self, uri: &str, start_playing: bool) -> PyResult<()> {
+        self.player.load_resumable(spotify_id(uri)?, start_playing);
+        Ok(())
+    }
+
+    /// Plays the 30 second preview clip of a track.
+    #[args(start_playing = "true")]
+    fn load_preview(&mut self, uri: &str, start_playing: bool) -> PyResult<()> {
+        self.player.load_preview(spotify_id(uri)?, start_playing);
+        Ok(())
+    }
+
+    fn play(&self) {
+        self.player.play();
+    }
+
+    fn pause(&self) {
+        self.player.pause();
+    }
+
+    fn stop(&self) {
+        self.player.stop();
+    }
+
+    fn seek(&self, position_ms: u32) {
+        self.player.seek(position_ms);
+    }
+
+    /// Attenuates the output to `level`, from 0.0 to 1.0, until `unduck()`.
+    #[args(fade_ms = "300")]
+    fn duck(&self, level: f32, fade_ms: u32) {
+        self.player.duck(level, fade_ms);
+    }
+
+    fn unduck(&self) {
+        self.player.unduck();
+    }
+}
+
+#[pymodule]
+fn pylibrespot(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("LibrespotError", py.get_type::<LibrespotError>())?;
+    m.add_class::<Session>()?;
+    m.add_class::<Track>()?;
+    m.add_class::<Album>()?;
+    m.add_class::<Artist>()?;
+    m.add_class::<PlayerEvent>()?;
+    m.add_class::<Player>()?;
+    Ok(())
+}
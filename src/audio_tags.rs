@@ -0,0 +1,280 @@
+use std::io::{self, Cursor, Write};
+
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+
+const VORBIS_COMMENT_HEADER: &[u8] = b"\x03vorbis";
+
+// The picture type of a front cover, as defined by ID3v2 and used by FLAC and Vorbis comments.
+const FRONT_COVER: u32 = 3;
+
+/// Metadata embedded into written audio files.
+#[derive(Debug, Default, Clone)]
+pub struct Tags {
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub track_number: Option<u32>,
+    /// A JPEG image.
+    pub cover: Option<Vec<u8>>,
+}
+
+impl Tags {
+    fn vorbis_comments(&self) -> Vec<String> {
+        let mut comments = vec![format!("TITLE={}", self.title)];
+        for artist in &self.artists {
+            comments.push(format!("ARTIST={}", artist));
+        }
+        comments.push(format!("ALBUM={}", self.album));
+        if let Some(track_number) = self.track_number {
+            comments.push(format!("TRACKNUMBER={}", track_number));
+        }
+        if let Some(cover) = &self.cover {
+            comments.push(format!(
+                "METADATA_BLOCK_PICTURE={}",
+                base64::encode(picture_block(cover))
+            ));
+        }
+        comments
+    }
+}
+
+/// A FLAC picture metadata block, the way covers are embedded in Vorbis comments.
+fn picture_block(jpeg: &[u8]) -> Vec<u8> {
+    const MIME_TYPE: &[u8] = b"image/jpeg";
+
+    let mut block = Vec::with_capacity(jpeg.len() + 42);
+    block.extend_from_slice(&FRONT_COVER.to_be_bytes());
+    block.extend_from_slice(&(MIME_TYPE.len() as u32).to_be_bytes());
+    block.extend_from_slice(MIME_TYPE);
+    // Empty description, then unknown width, height, color depth and palette size.
+    block.extend_from_slice(&[0; 4 * 5]);
+    block.extend_from_slice(&(jpeg.len() as u32).to_be_bytes());
+    block.extend_from_slice(jpeg);
+    block
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Builds a Vorbis comment header, keeping the vendor string of the original one.
+fn vorbis_comment_header(original: &[u8], tags: &Tags) -> Vec<u8> {
+    let offset = VORBIS_COMMENT_HEADER.len();
+    let vendor = read_u32_le(original, offset)
+        .filter(|_| original.starts_with(VORBIS_COMMENT_HEADER))
+        .and_then(|length| original.get(offset + 4..offset + 4 + length as usize))
+        .unwrap_or(b"librespot");
+
+    let comments = tags.vorbis_comments();
+
+    let mut header = VORBIS_COMMENT_HEADER.to_vec();
+    header.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    header.extend_from_slice(vendor);
+    header.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        header.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        header.extend_from_slice(comment.as_bytes());
+    }
+    // Framing bit
+    header.push(1);
+    header
+}
+
+/// Replaces the Vorbis comments of an Ogg Vorbis file with `tags`.
+pub fn tag_ogg(data: &[u8], tags: &Tags) -> io::Result<Vec<u8>> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let mut reader = PacketReader::new(Cursor::new(data));
+    let mut writer = PacketWriter::new(Vec::with_capacity(data.len()));
+
+    // The second packet of a Vorbis stream is the comment header. Page boundaries and
+    // granule positions of the other packets are kept as they are.
+    let mut index = 0;
+    while let Some(packet) = reader.read_packet().map_err(invalid)? {
+        let end = if packet.last_in_stream() {
+            PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        let serial = packet.stream_serial();
+        let absgp = packet.absgp_page();
+
+        let data = if index == 1 {
+            vorbis_comment_header(&packet.data, tags)
+        } else {
+            packet.data
+        };
+
+        writer.write_packet(data.into_boxed_slice(), serial, end, absgp)?;
+        index += 1;
+    }
+
+    Ok(writer.into_inner())
+}
+
+fn riff_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 9);
+    chunk.extend_from_slice(id);
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    // Chunks are word aligned.
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+// An ID3v2.3 text frame, in UTF-16 which v2.3 readers understand, unlike UTF-8.
+fn id3_text_frame(id: &[u8; 4], value: &str) -> Vec<u8> {
+    // Encoding, then the text with a byte order mark.
+    let mut data = vec![1, 0xff, 0xfe];
+    for unit in value.encode_utf16() {
+        data.extend_from_slice(&unit.to_le_bytes());
+    }
+    id3_frame(id, &data)
+}
+
+fn id3_frame(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 10);
+    frame.extend_from_slice(id);
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    // No flags
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Builds an ID3v2.3 tag, the way WAV files embed metadata that doesn't fit in a `LIST`
+/// `INFO` chunk, such as the cover.
+fn id3_tag(tags: &Tags) -> Vec<u8> {
+    let mut frames = id3_text_frame(b"TIT2", &tags.title);
+    // Artists are separated by slashes in v2.3.
+    frames.extend(id3_text_frame(b"TPE1", &tags.artists.join("/")));
+    frames.extend(id3_text_frame(b"TALB", &tags.album));
+    if let Some(track_number) = tags.track_number {
+        frames.extend(id3_text_frame(b"TRCK", &track_number.to_string()));
+    }
+    if let Some(cover) = &tags.cover {
+        // Latin-1 encoding, the MIME type, the picture type and an empty description.
+        let mut picture = b"\0image/jpeg\0".to_vec();
+        picture.extend_from_slice(&[FRONT_COVER as u8, 0]);
+        picture.extend_from_slice(cover);
+        frames.extend(id3_frame(b"APIC", &picture));
+    }
+
+    // The size is "synchsafe", seven bits per byte.
+    let size = frames.len() as u32;
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    tag.extend_from_slice(&[
+        (size >> 21) as u8 & 0x7f,
+        (size >> 14) as u8 & 0x7f,
+        (size >> 7) as u8 & 0x7f,
+        size as u8 & 0x7f,
+    ]);
+    tag.extend(frames);
+    tag
+}
+
+/// Writes 16 bit PCM samples as a WAV file, with the tags in a `LIST` `INFO` chunk and in an
+/// `id3 ` chunk, which holds the cover too.
+pub fn write_wav<W: Write>(
+    mut out: W,
+    samples: &[i16],
+    channels: u16,
+    sample_rate: u32,
+    tags: &Tags,
+) -> io::Result<()> {
+    const BITS_PER_SAMPLE: u16 = 16;
+
+    let block_align = channels * BITS_PER_SAMPLE / 8;
+    let mut format = Vec::with_capacity(16);
+    // PCM
+    format.extend_from_slice(&1u16.to_le_bytes());
+    format.extend_from_slice(&channels.to_le_bytes());
+    format.extend_from_slice(&sample_rate.to_le_bytes());
+    format.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    format.extend_from_slice(&block_align.to_le_bytes());
+    format.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    let mut info = b"INFO".to_vec();
+    let mut add_info = |id: &[u8; 4], value: &str| {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        info.extend_from_slice(&riff_chunk(id, &value));
+    };
+    add_info(b"INAM", &tags.title);
+    add_info(b"IART", &tags.artists.join(", "));
+    add_info(b"IPRD", &tags.album);
+    if let Some(track_number) = tags.track_number {
+        add_info(b"ITRK", &track_number.to_string());
+    }
+
+    let format = riff_chunk(b"fmt ", &format);
+    let info = riff_chunk(b"LIST", &info);
+    let id3 = riff_chunk(b"id3 ", &id3_tag(tags));
+    let data_size = samples.len() * 2;
+    let riff_size = 4 + format.len() + info.len() + 8 + data_size + id3.len();
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(riff_size as u32).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+    out.write_all(&format)?;
+    out.write_all(&info)?;
+    out.write_all(b"data")?;
+    out.write_all(&(data_size as u32).to_le_bytes())?;
+    for sample in samples {
+        out.write_all(&sample.to_le_bytes())?;
+    }
+    // Readers look for it after the samples.
+    out.write_all(&id3)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comment_header_keeps_vendor() {
+        let mut original = VORBIS_COMMENT_HEADER.to_vec();
+        original.extend_from_slice(&6u32.to_le_bytes());
+        original.extend_from_slice(b"vendor");
+        original.extend_from_slice(&0u32.to_le_bytes());
+        original.push(1);
+
+        let tags = Tags {
+            title: "Title".to_owned(),
+            artists: vec!["A".to_owned(), "B".to_owned()],
+            album: "Album".to_owned(),
+            track_number: Some(3),
+            cover: None,
+        };
+        let header = vorbis_comment_header(&original, &tags);
+
+        assert!(header.starts_with(&original[..17]));
+        assert_eq!(read_u32_le(&header, 17), Some(5));
+        assert_eq!(header.last(), Some(&1));
+    }
+
+    #[test]
+    fn id3_tag_has_frames() {
+        let tags = Tags {
+            title: "Title".to_owned(),
+            artists: vec!["A".to_owned()],
+            album: "Album".to_owned(),
+            track_number: None,
+            cover: Some(vec![0xff; 200]),
+        };
+        let tag = id3_tag(&tags);
+
+        assert!(tag.starts_with(b"ID3\x03"));
+        // 200 bytes of frames and more, in two synchsafe bytes.
+        let size = ((tag[8] as usize) << 7) | tag[9] as usize;
+        assert_eq!(size + 10, tag.len());
+        assert_eq!(&tag[10..14], b"TIT2");
+        assert_eq!(&tag[tag.len() - 200 - 24..tag.len() - 200 - 20], b"APIC");
+    }
+}
@@ -0,0 +1,239 @@
+use std::path::Path;
+use std::time::Duration;
+
+use librespot::core::cache::{Cache, CachedFile};
+
+fn usage(program: &str, opts: &getopts::Options) -> String {
+    let brief = format!(
+        "Usage: {} cache stats|prune|clear [options]\n\n\
+         stats: Show the size and age of the cached audio files.\n\
+         prune: Remove audio files exceeding --max-size or --max-age, oldest first.\n\
+         clear: Remove all audio files, and the credentials with --credentials.",
+        program
+    );
+    opts.usage(&brief)
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix, e.g. `500M`.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_uppercase();
+    let (number, factor) = match size.chars().last()? {
+        'K' => (&size[..size.len() - 1], 1 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        _ => (&size[..], 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(factor)
+}
+
+/// Parses a duration with an `s`, `m`, `h` or `d` suffix, e.g. `30d`.
+fn parse_age(age: &str) -> Option<Duration> {
+    let age = age.trim();
+    let factor = match age.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let number = age[..age.len() - 1].parse::<u64>().ok()?;
+    Some(Duration::from_secs(number.checked_mul(factor)?))
+}
+
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    if secs >= 24 * 60 * 60 {
+        format!("{} days", secs / (24 * 60 * 60))
+    } else if secs >= 60 * 60 {
+        format!("{} hours", secs / (60 * 60))
+    } else {
+        format!("{} minutes", secs / 60)
+    }
+}
+
+fn print_removed(removed: &[CachedFile]) {
+    let size = removed.iter().map(|file| file.size).sum();
+    println!("Removed {} files ({})", removed.len(), format_size(size));
+}
+
+fn stats(cache: &Cache) -> std::io::Result<()> {
+    let files = cache.audio_files()?;
+    let size = files.iter().map(|file| file.size).sum();
+
+    println!("Audio files: {}", files.len());
+    println!("Total size:  {}", format_size(size));
+
+    if let Some(oldest) = files.iter().map(CachedFile::age).max() {
+        println!("Oldest:      {}", format_age(oldest));
+    }
+    if let Some(newest) = files.iter().map(CachedFile::age).min() {
+        println!("Newest:      {}", format_age(newest));
+    }
+
+    Ok(())
+}
+
+/// Runs `librespot cache ...`, returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let program = &args[0];
+
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "c",
+        "cache",
+        "Path to a directory where files are cached.",
+        "CACHE",
+    )
+    .optopt(
+        "",
+        "system-cache",
+        "Path to a directory where system files (credentials, volume) are cached.",
+        "SYSTEMCACHE",
+    )
+    .optopt(
+        "",
+        "max-size",
+        "Prune: keep at most SIZE of audio files, e.g. 2G.",
+        "SIZE",
+    )
+    .optopt(
+        "",
+        "max-age",
+        "Prune: remove audio files older than AGE, e.g. 30d.",
+        "AGE",
+    )
+    .optflag(
+        "",
+        "credentials",
+        "Clear: remove the cached credentials too.",
+    )
+    .optflag("h", "help", "Print this help.");
+
+    let matches = match opts.parse(&args[2..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("error: {}\n{}", f.to_string(), usage(program, &opts));
+            return 1;
+        }
+    };
+
+    if matches.opt_present("help") {
+        println!("{}", usage(program, &opts));
+        return 0;
+    }
+
+    let cache_dir = match matches.opt_str("cache") {
+        Some(cache_dir) => cache_dir,
+        None => {
+            eprintln!("error: Expected --cache\n{}", usage(program, &opts));
+            return 1;
+        }
+    };
+    let audio_dir = Path::new(&cache_dir).join("files");
+    let system_dir = matches
+        .opt_str("system-cache")
+        .unwrap_or_else(|| cache_dir.clone());
+
+    let cache = match Cache::new(Some(Path::new(&system_dir)), Some(audio_dir.as_path())) {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Cannot open cache: {}", e);
+            return 1;
+        }
+    };
+
+    let result = match matches.free.first().map(String::as_str) {
+        Some("stats") => stats(&cache),
+        Some("prune") => {
+            let max_size = match matches.opt_str("max-size").map(|size| parse_size(&size)) {
+                Some(None) => {
+                    eprintln!("error: Invalid --max-size\n{}", usage(program, &opts));
+                    return 1;
+                }
+                max_size => max_size.flatten(),
+            };
+            let max_age = match matches.opt_str("max-age").map(|age| parse_age(&age)) {
+                Some(None) => {
+                    eprintln!("error: Invalid --max-age\n{}", usage(program, &opts));
+                    return 1;
+                }
+                max_age => max_age.flatten(),
+            };
+
+            if max_size.is_none() && max_age.is_none() {
+                eprintln!(
+                    "error: Expected --max-size or --max-age\n{}",
+                    usage(program, &opts)
+                );
+                return 1;
+            }
+
+            cache
+                .prune(max_size, max_age)
+                .map(|removed| print_removed(&removed))
+        }
+        Some("clear") => cache
+            .clear_audio_files()
+            .map(|removed| print_removed(&removed))
+            .and_then(|_| {
+                if matches.opt_present("credentials") {
+                    cache.remove_credentials()?;
+                    println!("Removed the credentials");
+                }
+                Ok(())
+            }),
+        _ => {
+            eprintln!("{}", usage(program, &opts));
+            return 1;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Cannot access cache: {}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("500M"), Some(500 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("x"), None);
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(
+            parse_age("30d"),
+            Some(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_age("12h"), Some(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(parse_age("30"), None);
+    }
+}
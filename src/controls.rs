@@ -0,0 +1,87 @@
+//! Actions of hardware controls such as buttons, media keys or IR remotes, and routing them to
+//! the Spirc.
+
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use librespot::connect::spirc::Spirc;
+use log::debug;
+
+/// A control an input is mapped to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Prev,
+    VolumeUp,
+    VolumeDown,
+}
+
+impl Action {
+    pub fn apply(self, spirc: &Spirc) {
+        match self {
+            Action::Play => spirc.play(),
+            Action::Pause => spirc.pause(),
+            Action::PlayPause => spirc.play_pause(),
+            Action::Next => spirc.next(),
+            Action::Prev => spirc.prev(),
+            Action::VolumeUp => spirc.volume_up(),
+            Action::VolumeDown => spirc.volume_down(),
+        }
+    }
+
+    /// Whether holding a key repeats the action.
+    pub fn repeats(self) -> bool {
+        matches!(self, Action::VolumeUp | Action::VolumeDown)
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "play" => Ok(Action::Play),
+            "pause" => Ok(Action::Pause),
+            "play-pause" => Ok(Action::PlayPause),
+            "next" => Ok(Action::Next),
+            "prev" => Ok(Action::Prev),
+            "volume-up" => Ok(Action::VolumeUp),
+            "volume-down" => Ok(Action::VolumeDown),
+            _ => Err(format!(
+                "unknown action {:?}, expected play, pause, play-pause, next, prev, volume-up or volume-down",
+                s
+            )),
+        }
+    }
+}
+
+/// Parses a binding given as `INPUT=ACTION`, returning the input and the action.
+pub fn split_binding(s: &str) -> Result<(&str, &str), String> {
+    match s.find('=') {
+        Some(index) => Ok((&s[..index], &s[index + 1..])),
+        None => Err(format!("expected INPUT=ACTION, got {:?}", s)),
+    }
+}
+
+/// The Spirc the controls apply to, once connected.
+#[derive(Clone, Default)]
+pub struct Controls(Arc<Mutex<Option<Spirc>>>);
+
+impl Controls {
+    pub fn send(&self, action: Action) {
+        match &*self.0.lock().unwrap() {
+            Some(spirc) => {
+                debug!("Control: {:?}", action);
+                action.apply(spirc);
+            }
+            None => debug!("Control: ignoring {:?} while not connected", action),
+        }
+    }
+
+    /// Routes the controls to a new Spirc, e.g. after reconnecting.
+    pub fn set_spirc(&self, spirc: Spirc) {
+        *self.0.lock().unwrap() = Some(spirc);
+    }
+}
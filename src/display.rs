@@ -0,0 +1,469 @@
+//! Shows what is playing on small displays: a character LCD on the I2C bus, and display clients
+//! that read the screen as lines of text from a TCP socket, e.g. a script driving an SPI OLED.
+//!
+//! The text protocol sends a line for each field of the screen when a client connects and
+//! whenever the field changes:
+//!
+//! * `status playing|paused|stopped`
+//! * `title <name>`, `artist <artists>`, `album <album>`
+//! * `progress <position> <duration>`, in seconds
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use librespot::core::session::Session;
+use librespot::playback::player::PlayerEvent;
+use log::{debug, info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use crate::now_playing::{NowPlaying, PlaybackStatus};
+use crate::track_metadata::TrackMetadata;
+
+// How often the screen is rendered from the playback state, so the progress advances.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The displays to show the playback on.
+#[derive(Clone, Debug, Default)]
+pub struct DisplayConfig {
+    /// The address display clients connect to.
+    pub socket: Option<SocketAddr>,
+    #[cfg(target_os = "linux")]
+    pub lcd: Option<lcd::LcdConfig>,
+}
+
+impl DisplayConfig {
+    pub fn is_enabled(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            if self.lcd.is_some() {
+                return true;
+            }
+        }
+        self.socket.is_some()
+    }
+}
+
+/// The content of the displays.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Screen {
+    pub status: PlaybackStatus,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub position_s: u32,
+    pub duration_s: u32,
+}
+
+impl Screen {
+    fn render(now_playing: &NowPlaying) -> Screen {
+        let (title, artist, album) = match &now_playing.metadata {
+            Some(metadata) => (
+                metadata.name.clone(),
+                metadata.artists.join(", "),
+                metadata.album.clone(),
+            ),
+            None => Default::default(),
+        };
+        Screen {
+            status: now_playing.status,
+            title,
+            artist,
+            album,
+            position_s: now_playing.position_ms() / 1000,
+            duration_s: now_playing.duration_ms / 1000,
+        }
+    }
+
+    /// The lines of the text protocol.
+    pub fn lines(&self) -> Vec<String> {
+        // A newline in a field would end its line early.
+        let field = |text: &str| text.replace(&['\n', '\r'][..], " ");
+        vec![
+            format!("status {}", self.status.as_str().to_lowercase()),
+            format!("title {}", field(&self.title)),
+            format!("artist {}", field(&self.artist)),
+            format!("album {}", field(&self.album)),
+            format!("progress {} {}", self.position_s, self.duration_s),
+        ]
+    }
+
+    /// The position and duration as `m:ss / m:ss`.
+    pub fn progress(&self) -> String {
+        let time = |s: u32| format!("{}:{:02}", s / 60, s % 60);
+        format!("{} / {}", time(self.position_s), time(self.duration_s))
+    }
+}
+
+struct DisplayState {
+    session: Option<Session>,
+    now_playing: NowPlaying,
+}
+
+/// Renders the playback state to the screen of the displays.
+#[derive(Clone)]
+pub struct Display {
+    state: Arc<Mutex<DisplayState>>,
+}
+
+impl Display {
+    pub async fn start(config: DisplayConfig, volume: u16) -> Display {
+        let display = Display {
+            state: Arc::new(Mutex::new(DisplayState {
+                session: None,
+                now_playing: NowPlaying::new(volume),
+            })),
+        };
+
+        let screen = Screen::render(&display.state.lock().unwrap().now_playing);
+        let (screen_tx, screen_rx) = watch::channel(screen.clone());
+
+        if let Some(address) = config.socket {
+            match TcpListener::bind(address).await {
+                Ok(listener) => {
+                    info!("Display socket listening on {}", address);
+                    tokio::spawn(serve(listener, screen_rx.clone()));
+                }
+                Err(e) => warn!("Cannot start display socket on {}: {}", address, e),
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(config) = config.lcd {
+                lcd::start(config, screen_rx.clone());
+            }
+        }
+
+        let state = display.state.clone();
+        tokio::spawn(async move {
+            let mut last_screen = screen;
+            let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let screen = Screen::render(&state.lock().unwrap().now_playing);
+                if screen != last_screen {
+                    last_screen = screen.clone();
+                    // Sending only fails once all displays are gone.
+                    if screen_tx.send(screen).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        display
+    }
+
+    /// Fetches the metadata with `session` after the player switched to it.
+    pub fn set_session(&self, session: Session) {
+        self.state.lock().unwrap().session = Some(session);
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let (update, session) = {
+            let mut state = self.state.lock().unwrap();
+            (state.now_playing.update(event), state.session.clone())
+        };
+
+        let (track_id, session) = match (update.and_then(|update| update.new_track), session) {
+            (Some(track_id), Some(session)) => (track_id, session),
+            _ => return,
+        };
+
+        let display = self.clone();
+        tokio::spawn(async move {
+            match TrackMetadata::get(&session, track_id).await {
+                Ok(metadata) => {
+                    display
+                        .state
+                        .lock()
+                        .unwrap()
+                        .now_playing
+                        .set_metadata(metadata);
+                }
+                Err(_) => debug!("Cannot fetch metadata of <{}>", track_id.to_uri()),
+            }
+        });
+    }
+}
+
+async fn serve(listener: TcpListener, screen: watch::Receiver<Screen>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                debug!("Display client connected from {}", address);
+                tokio::spawn(send_screens(stream, screen.clone()));
+            }
+            Err(e) => warn!("Cannot accept display client: {}", e),
+        }
+    }
+}
+
+// Sends the changed lines of each screen until the client disconnects.
+async fn send_screens(mut stream: TcpStream, mut screen: watch::Receiver<Screen>) {
+    let mut sent: Vec<String> = Vec::new();
+    loop {
+        let lines = screen.borrow().lines();
+        let mut output = String::new();
+        for (index, line) in lines.iter().enumerate() {
+            if sent.get(index) != Some(line) {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+        if stream.write_all(output.as_bytes()).await.is_err() {
+            return;
+        }
+        sent = lines;
+
+        if screen.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub mod lcd {
+    //! HD44780 character LCDs behind a PCF8574 I2C backpack, as sold with most 16x2 and 20x4
+    //! displays.
+
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+
+    use log::{info, warn};
+    use rppal::i2c::{self, I2c};
+    use tokio::sync::watch;
+
+    use super::{PlaybackStatus, Screen};
+
+    // The lines of the PCF8574 besides the 4 data lines.
+    const RS: u8 = 0x01;
+    const ENABLE: u8 = 0x04;
+    const BACKLIGHT: u8 = 0x08;
+
+    const CLEAR: u8 = 0x01;
+    const ENTRY_MODE_INCREMENT: u8 = 0x06;
+    const DISPLAY_ON: u8 = 0x0c;
+    const FUNCTION_4BIT_2LINES: u8 = 0x28;
+    const SET_ADDRESS: u8 = 0x80;
+
+    // How often the screen is checked for changes.
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+    /// An LCD given as `BUS:ADDRESS[:COLSxROWS]`, e.g. `1:0x27:20x4`.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct LcdConfig {
+        pub bus: u8,
+        pub address: u16,
+        pub columns: usize,
+        pub rows: usize,
+    }
+
+    impl FromStr for LcdConfig {
+        type Err = String;
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let parts: Vec<&str> = s.split(':').collect();
+            let (bus, address, size) = match parts[..] {
+                [bus, address] => (bus, address, "16x2"),
+                [bus, address, size] => (bus, address, size),
+                _ => return Err(format!("expected BUS:ADDRESS[:COLSxROWS], got {:?}", s)),
+            };
+
+            let bus = bus
+                .parse()
+                .map_err(|_| format!("invalid I2C bus {:?}", bus))?;
+            let address = match address.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => address.parse(),
+            }
+            .map_err(|_| format!("invalid I2C address {:?}", address))?;
+            let (columns, rows) = match size.find('x') {
+                Some(index) => (size[..index].parse().ok(), size[index + 1..].parse().ok()),
+                None => (None, None),
+            };
+            match (columns, rows) {
+                (Some(columns), Some(rows)) if columns > 0 && (1..=4).contains(&rows) => {
+                    Ok(LcdConfig {
+                        bus,
+                        address,
+                        columns,
+                        rows,
+                    })
+                }
+                _ => Err(format!("invalid LCD size {:?}", size)),
+            }
+        }
+    }
+
+    /// The text of each row: the title and the progress, and on 4 rows also the artist and
+    /// album.
+    pub fn rows(screen: &Screen, config: &LcdConfig) -> Vec<String> {
+        let progress = match screen.status {
+            PlaybackStatus::Stopped => String::new(),
+            PlaybackStatus::Playing => format!("> {}", screen.progress()),
+            PlaybackStatus::Paused => format!("= {}", screen.progress()),
+        };
+        let rows = match config.rows {
+            1 => vec![&screen.title],
+            2 => vec![&screen.title, &progress],
+            3 => vec![&screen.title, &screen.artist, &progress],
+            _ => vec![&screen.title, &screen.artist, &screen.album, &progress],
+        };
+        rows.into_iter()
+            .map(|row| {
+                // The character ROM only matches ASCII.
+                let row: String = row
+                    .chars()
+                    .map(|c| {
+                        if c.is_ascii() && !c.is_control() {
+                            c
+                        } else {
+                            '?'
+                        }
+                    })
+                    .take(config.columns)
+                    .collect();
+                format!("{:width$}", row, width = config.columns)
+            })
+            .collect()
+    }
+
+    struct Lcd {
+        i2c: I2c,
+        config: LcdConfig,
+    }
+
+    impl Lcd {
+        fn open(config: LcdConfig) -> i2c::Result<Lcd> {
+            let mut i2c = I2c::with_bus(config.bus)?;
+            i2c.set_slave_address(config.address)?;
+            let mut lcd = Lcd { i2c, config };
+
+            // Switches to 4 bit mode whatever mode the controller is in, see the datasheet.
+            thread::sleep(Duration::from_millis(50));
+            for &delay in &[4500, 150, 150] {
+                lcd.write_nibble(0x03, 0)?;
+                thread::sleep(Duration::from_micros(delay));
+            }
+            lcd.write_nibble(0x02, 0)?;
+
+            for &command in &[
+                FUNCTION_4BIT_2LINES,
+                DISPLAY_ON,
+                ENTRY_MODE_INCREMENT,
+                CLEAR,
+            ] {
+                lcd.write(command, 0)?;
+            }
+            thread::sleep(Duration::from_millis(2));
+            Ok(lcd)
+        }
+
+        fn write_nibble(&mut self, nibble: u8, mode: u8) -> i2c::Result<()> {
+            let byte = nibble << 4 | mode | BACKLIGHT;
+            self.i2c.write(&[byte | ENABLE])?;
+            self.i2c.write(&[byte])?;
+            Ok(())
+        }
+
+        fn write(&mut self, byte: u8, mode: u8) -> i2c::Result<()> {
+            self.write_nibble(byte >> 4, mode)?;
+            self.write_nibble(byte & 0x0f, mode)
+        }
+
+        fn show(&mut self, rows: &[String]) -> i2c::Result<()> {
+            let columns = self.config.columns as u8;
+            // Rows 3 and 4 continue the memory of rows 1 and 2.
+            let offsets = [0x00, 0x40, columns, 0x40 + columns];
+            for (row, offset) in rows.iter().zip(&offsets) {
+                self.write(SET_ADDRESS | offset, 0)?;
+                for &byte in row.as_bytes() {
+                    self.write(byte, RS)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    pub(super) fn start(config: LcdConfig, screen: watch::Receiver<Screen>) {
+        thread::spawn(move || {
+            let mut lcd = match Lcd::open(config) {
+                Ok(lcd) => lcd,
+                Err(e) => {
+                    warn!(
+                        "Cannot open LCD at {:#x} on I2C bus {}: {}",
+                        config.address, config.bus, e
+                    );
+                    return;
+                }
+            };
+            info!(
+                "Showing playback on the {}x{} LCD at {:#x} on I2C bus {}",
+                config.columns, config.rows, config.address, config.bus
+            );
+
+            let mut shown = Vec::new();
+            loop {
+                let rows = rows(&screen.borrow(), &config);
+                if rows != shown {
+                    if let Err(e) = lcd.show(&rows) {
+                        warn!("Cannot write to LCD: {}", e);
+                    }
+                    shown = rows;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen() -> Screen {
+        Screen {
+            status: PlaybackStatus::Playing,
+            title: "Song\nTitle".to_string(),
+            artist: "Artist".to_string(),
+            album: "Album".to_string(),
+            position_s: 83,
+            duration_s: 225,
+        }
+    }
+
+    #[test]
+    fn renders_lines() {
+        assert_eq!(
+            screen().lines(),
+            vec![
+                "status playing",
+                "title Song Title",
+                "artist Artist",
+                "album Album",
+                "progress 83 225",
+            ]
+        );
+        assert_eq!(screen().progress(), "1:23 / 3:45");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn renders_lcd_rows() {
+        let config: lcd::LcdConfig = "1:0x27".parse().unwrap();
+        assert_eq!((config.address, config.columns, config.rows), (0x27, 16, 2));
+        assert_eq!(
+            lcd::rows(&screen(), &config),
+            vec!["Song?Title      ", "> 1:23 / 3:45   "]
+        );
+
+        let config: lcd::LcdConfig = "0:39:20x4".parse().unwrap();
+        assert_eq!(lcd::rows(&screen(), &config).len(), 4);
+        assert!("1:0x27:20x5".parse::<lcd::LcdConfig>().is_err());
+        assert!("1".parse::<lcd::LcdConfig>().is_err());
+    }
+}
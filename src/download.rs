@@ -0,0 +1,453 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use librespot::audio::{AudioDecoder, AudioDecrypt, AudioFile, AudioPacket, VorbisDecoder};
+use librespot::core::cache::Cache;
+use librespot::core::config::SessionConfig;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{FileId, SpotifyId};
+use librespot::core::version;
+use librespot::core::Error;
+use librespot::metadata::{Album, FileFormat, Metadata, Playlist, Track};
+use librespot::playback::config::Bitrate;
+use librespot::playback::filename::{self, Filenames, TrackFields};
+use log::{debug, warn};
+
+use crate::audio_tags::{self, Tags};
+use crate::track_metadata::{fetch_cover, TrackMetadata};
+
+// Spotify prepends a header of its own to the Ogg Vorbis stream.
+const SPOTIFY_OGG_HEADER_SIZE: usize = 0xa7;
+
+const SAMPLE_RATE: u32 = 44100;
+const NUM_CHANNELS: u16 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// The original Ogg Vorbis stream.
+    Ogg,
+    /// Decoded 16 bit PCM.
+    Wav,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Ogg => "ogg",
+            OutputFormat::Wav => "wav",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ogg" => Ok(OutputFormat::Ogg),
+            "wav" => Ok(OutputFormat::Wav),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum DownloadError {
+    Metadata(Error),
+    Unavailable,
+    Fetch,
+    Key,
+    Decode(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Metadata(e) => write!(f, "cannot fetch metadata: {}", e),
+            DownloadError::Unavailable => write!(f, "not available"),
+            DownloadError::Fetch => write!(f, "cannot fetch audio file"),
+            DownloadError::Key => write!(f, "cannot fetch decryption key"),
+            DownloadError::Decode(e) => write!(f, "cannot decode audio: {}", e),
+            DownloadError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(e: io::Error) -> Self {
+        DownloadError::Io(e)
+    }
+}
+
+struct DownloadOptions {
+    output: PathBuf,
+    format: OutputFormat,
+    bitrate: Bitrate,
+}
+
+fn usage(program: &str, opts: &getopts::Options) -> String {
+    let brief = format!(
+        "Usage: {} download URI [options]\n\n\
+         Downloads a track, or all tracks of an album or playlist, e.g. spotify:album:<id>.",
+        program
+    );
+    opts.usage(&brief)
+}
+
+fn file_formats(bitrate: Bitrate) -> [FileFormat; 3] {
+    match bitrate {
+        Bitrate::Bitrate96 => [
+            FileFormat::OGG_VORBIS_96,
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_320,
+        ],
+        Bitrate::Bitrate160 => [
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_96,
+            FileFormat::OGG_VORBIS_320,
+        ],
+        Bitrate::Bitrate320 => [
+            FileFormat::OGG_VORBIS_320,
+            FileFormat::OGG_VORBIS_160,
+            FileFormat::OGG_VORBIS_96,
+        ],
+    }
+}
+
+/// Finds the track itself or one of its alternatives that can be played in the user's country.
+async fn available_track(session: &Session, id: SpotifyId) -> Result<Track, DownloadError> {
+    let track = Track::get(session, id)
+        .await
+        .map_err(DownloadError::Metadata)?;
+    if track.available {
+        return Ok(track);
+    }
+
+    for alternative in &track.alternatives {
+        if let Ok(track) = Track::get(session, *alternative).await {
+            if track.available {
+                return Ok(track);
+            }
+        }
+    }
+
+    Err(DownloadError::Unavailable)
+}
+
+/// Fetches and decrypts an audio file, without Spotify's header.
+async fn fetch_ogg(
+    session: &Session,
+    track_id: SpotifyId,
+    file_id: FileId,
+) -> Result<Vec<u8>, DownloadError> {
+    // The data rate only matters for prefetching while streaming, it doesn't limit the download.
+    let encrypted_file = AudioFile::open(session, file_id, 40 * 1024, true)
+        .await
+        .map_err(|_| DownloadError::Fetch)?;
+    encrypted_file
+        .get_stream_loader_controller()
+        .set_stream_mode();
+
+    let key = session
+        .audio_key()
+        .request(track_id, file_id)
+        .await
+        .map_err(|_| DownloadError::Key)?;
+
+    // Reading blocks until the data arrives.
+    let data = tokio::task::spawn_blocking(move || {
+        let mut data = Vec::new();
+        AudioDecrypt::new(key, encrypted_file).read_to_end(&mut data)?;
+        Ok::<_, io::Error>(data)
+    })
+    .await
+    .map_err(|_| DownloadError::Fetch)??;
+
+    if data.len() < SPOTIFY_OGG_HEADER_SIZE {
+        return Err(DownloadError::Fetch);
+    }
+    Ok(data[SPOTIFY_OGG_HEADER_SIZE..].to_vec())
+}
+
+fn decode(ogg: Vec<u8>) -> Result<Vec<i16>, DownloadError> {
+    let decode_error = |e: &dyn fmt::Display| DownloadError::Decode(e.to_string());
+
+    let mut decoder = VorbisDecoder::new(Cursor::new(ogg)).map_err(|e| decode_error(&e))?;
+    let mut samples = Vec::new();
+    while let Some(packet) = decoder.next_packet().map_err(|e| decode_error(&e))? {
+        if let AudioPacket::Samples(packet) = packet {
+            samples.extend(
+                packet
+                    .iter()
+                    .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16),
+            );
+        }
+    }
+    Ok(samples)
+}
+
+/// Downloads a track into `directory`, returns the path of the written file.
+async fn download_track(
+    session: &Session,
+    id: SpotifyId,
+    track_number: Option<u32>,
+    directory: &Path,
+    filenames: &mut Filenames,
+    options: &DownloadOptions,
+) -> Result<PathBuf, DownloadError> {
+    let track = available_track(session, id).await?;
+    let metadata = TrackMetadata::get(session, track.id)
+        .await
+        .map_err(DownloadError::Metadata)?;
+
+    let path = filenames.path(
+        directory,
+        &TrackFields {
+            artists: &metadata.artists,
+            title: &metadata.name,
+            album: &metadata.album,
+            track_number,
+            extension: options.format.extension(),
+        },
+    );
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let (file_id, ogg) = match file_formats(options.bitrate)
+        .iter()
+        .find_map(|format| track.files.get(format))
+    {
+        Some(&file_id) => (file_id, fetch_ogg(session, track.id, file_id).await?),
+        None => return Err(DownloadError::Unavailable),
+    };
+    debug!("Fetched file {} of <{}>", file_id, track.id.to_uri());
+
+    let cover = match &metadata.cover {
+        Some(cover) => fetch_cover(session, cover).await,
+        None => None,
+    };
+    if metadata.cover.is_some() && cover.is_none() {
+        warn!("Cannot fetch the cover of <{}>", track.id.to_uri());
+    }
+
+    let tags = Tags {
+        title: metadata.name,
+        artists: metadata.artists,
+        album: metadata.album,
+        track_number,
+        cover,
+    };
+
+    fs::create_dir_all(directory)?;
+    match options.format {
+        OutputFormat::Ogg => fs::write(&path, audio_tags::tag_ogg(&ogg, &tags)?)?,
+        OutputFormat::Wav => {
+            let samples = decode(ogg)?;
+            let file = io::BufWriter::new(fs::File::create(&path)?);
+            audio_tags::write_wav(file, &samples, NUM_CHANNELS, SAMPLE_RATE, &tags)?;
+
+            if let Some(cover) = &tags.cover {
+                fs::write(path.with_extension("jpg"), cover)?;
+            }
+        }
+    }
+
+    Ok(path)
+}
+
+async fn download(
+    session: &Session,
+    uri: &str,
+    filenames: &mut Filenames,
+    options: &DownloadOptions,
+) -> bool {
+    // Accepts both spotify:album:<id> and the legacy spotify:user:<user>:playlist:<id>.
+    let parts: Vec<&str> = uri.split(':').collect();
+    let id = parts.last().and_then(|id| SpotifyId::from_base62(id).ok());
+    let kind = parts.len().checked_sub(2).map(|i| parts[i]);
+
+    let (directory, tracks) = match (kind, id) {
+        (Some("track"), Some(id)) => (options.output.clone(), vec![(id, None)]),
+        (Some("album"), Some(id)) => match Album::get(session, id).await {
+            Ok(album) => (
+                options.output.join(filename::sanitize(&album.name)),
+                album
+                    .tracks
+                    .iter()
+                    .enumerate()
+                    .map(|(i, id)| (*id, Some(i as u32 + 1)))
+                    .collect(),
+            ),
+            Err(_) => {
+                eprintln!("Cannot fetch album {}", uri);
+                return false;
+            }
+        },
+        (Some("playlist"), Some(id)) => match Playlist::get(session, id).await {
+            Ok(playlist) => (
+                options.output.join(filename::sanitize(&playlist.name)),
+                playlist.tracks.iter().map(|id| (*id, None)).collect(),
+            ),
+            Err(_) => {
+                eprintln!("Cannot fetch playlist {}", uri);
+                return false;
+            }
+        },
+        _ => {
+            eprintln!("Expected a track, album or playlist URI, got {}", uri);
+            return false;
+        }
+    };
+
+    let mut success = true;
+    for (i, (id, track_number)) in tracks.iter().enumerate() {
+        match download_track(session, *id, *track_number, &directory, filenames, options).await {
+            Ok(path) => println!("[{}/{}] {}", i + 1, tracks.len(), path.display()),
+            Err(e) => {
+                eprintln!("[{}/{}] <{}>: {}", i + 1, tracks.len(), id.to_uri(), e);
+                success = false;
+            }
+        }
+    }
+    success
+}
+
+/// Runs `librespot download ...`, returns the exit code.
+pub async fn run(args: &[String]) -> i32 {
+    let program = &args[0];
+
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "c",
+        "cache",
+        "Path to a directory where files will be cached.",
+        "CACHE",
+    )
+    .optopt(
+        "",
+        "system-cache",
+        "Path to a directory where system files (credentials, volume) are cached.",
+        "SYSTEMCACHE",
+    )
+    .optopt("u", "username", "Username to sign in with", "USERNAME")
+    .optopt("p", "password", "Password", "PASSWORD")
+    .optopt(
+        "o",
+        "output",
+        "Directory to write the files to. Defaults to the current directory",
+        "DIR",
+    )
+    .optopt(
+        "",
+        "format",
+        "Output format (ogg or wav). Defaults to ogg",
+        "FORMAT",
+    )
+    .optopt(
+        "",
+        "name",
+        "Template of the file names, with {artist}, {title}, {album}, {track} and {ext}. \
+         Defaults to \"{artist} - {title}.{ext}\"",
+        "TEMPLATE",
+    )
+    .optopt(
+        "b",
+        "bitrate",
+        "Bitrate (96, 160 or 320). Defaults to 320",
+        "BITRATE",
+    )
+    .optflag("v", "verbose", "Enable verbose output")
+    .optflag("h", "help", "Print this help.");
+
+    let matches = match opts.parse(&args[2..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("error: {}\n{}", f.to_string(), usage(program, &opts));
+            return 1;
+        }
+    };
+
+    if matches.opt_present("help") || matches.free.is_empty() {
+        println!("{}", usage(program, &opts));
+        return if matches.opt_present("help") { 0 } else { 1 };
+    }
+
+    crate::setup_logging(matches.opt_present("verbose"));
+
+    let options = DownloadOptions {
+        output: matches
+            .opt_str("output")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(".")),
+        format: matches
+            .opt_str("format")
+            .map(|format| OutputFormat::from_str(&format).expect("Invalid output format"))
+            .unwrap_or(OutputFormat::Ogg),
+        bitrate: matches
+            .opt_str("bitrate")
+            .map(|bitrate| Bitrate::from_str(&bitrate).expect("Invalid bitrate"))
+            .unwrap_or(Bitrate::Bitrate320),
+    };
+
+    let cache = matches.opt_str("cache").and_then(|cache_dir| {
+        let system_dir = matches
+            .opt_str("system-cache")
+            .unwrap_or_else(|| cache_dir.clone());
+        let audio_dir = Path::new(&cache_dir).join("files");
+        match Cache::new(Some(Path::new(&system_dir)), Some(audio_dir.as_path())) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                warn!("Cannot create cache: {}", e);
+                None
+            }
+        }
+    });
+
+    let credentials = match crate::get_credentials(
+        matches.opt_str("username"),
+        matches.opt_str("password"),
+        cache.as_ref().and_then(Cache::credentials),
+        crate::prompt_password,
+    ) {
+        Some(credentials) => credentials,
+        None => {
+            eprintln!("error: Expected --username or cached credentials");
+            return 1;
+        }
+    };
+
+    let session_config = SessionConfig {
+        user_agent: version::VERSION_STRING.to_string(),
+        device_id: cache
+            .as_ref()
+            .and_then(Cache::device_id)
+            .unwrap_or_else(|| SessionConfig::default().device_id),
+        ..SessionConfig::default()
+    };
+
+    let session = match Session::connect(session_config, credentials, cache).await {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Cannot connect: {}", e);
+            return 1;
+        }
+    };
+
+    let mut filenames = matches
+        .opt_str("name")
+        .map_or_else(Filenames::default, |template| Filenames::new(&template));
+    let mut success = true;
+    for uri in &matches.free {
+        success &= download(&session, uri, &mut filenames, &options).await;
+    }
+
+    if success {
+        0
+    } else {
+        1
+    }
+}
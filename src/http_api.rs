@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{CONNECTION, CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use librespot::connect::spirc::Spirc;
+use librespot::core::session::Session;
+use librespot::playback::config::Bitrate;
+use librespot::playback::player::PlayerEvent;
+use log::{debug, info, warn};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::now_playing::NowPlaying;
+use crate::player_event_handler::event_to_json;
+use crate::track_metadata::TrackMetadata;
+
+// Defined by RFC 6455 to compute the Sec-WebSocket-Accept header.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Events a slow WebSocket client may fall behind before it starts missing some.
+const EVENT_BUFFER: usize = 64;
+
+struct ApiState {
+    spirc: Option<Spirc>,
+    session: Option<Session>,
+    now_playing: NowPlaying,
+}
+
+/// An HTTP server to control playback, with a WebSocket pushing player events as JSON.
+///
+/// * `GET /api/status`: the current track, position, and volume
+/// * `POST /api/play`, `/api/pause`, `/api/play-pause`, `/api/next`, `/api/prev`
+/// * `POST /api/seek?position_ms=<ms>`
+/// * `POST /api/load?uri=<context uri>[&index=<track index>][&position_ms=<ms>]`
+/// * `POST /api/jump?index=<track index>`: plays the track of the queue with this index
+/// * `PUT /api/volume?volume=<0-100>`
+/// * `PUT /api/bitrate?bitrate=<96, 160 or 320>`
+/// * `POST /api/precache?count=<tracks>`: downloads the next tracks of the queue to the cache
+/// * `GET /api/queue`: the context, the tracks of the queue, and the device that started playback
+/// * `GET /api/events`: WebSocket
+#[derive(Clone)]
+pub struct HttpApi {
+    state: Arc<Mutex<ApiState>>,
+    events: broadcast::Sender<String>,
+}
+
+impl HttpApi {
+    pub fn start(address: SocketAddr, volume: u16) -> hyper::Result<HttpApi> {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let api = HttpApi {
+            state: Arc::new(Mutex::new(ApiState {
+                spirc: None,
+                session: None,
+                now_playing: NowPlaying::new(volume),
+            })),
+            events,
+        };
+
+        let service_api = api.clone();
+        let make_service = make_service_fn(move |_| {
+            let api = service_api.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |request| api.clone().call(request))) }
+        });
+
+        let server = Server::try_bind(&address)?.serve(make_service);
+        info!("HTTP API listening on {}", server.local_addr());
+
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!("HTTP API stopped: {}", e);
+            }
+        });
+
+        Ok(api)
+    }
+
+    /// Routes the controls to a new Spirc, e.g. after reconnecting.
+    pub fn set_spirc(&self, session: Session, spirc: Spirc) {
+        let mut state = self.state.lock().unwrap();
+        state.session = Some(session);
+        state.spirc = Some(spirc);
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let (update, session) = {
+            let mut state = self.state.lock().unwrap();
+            (state.now_playing.update(event), state.session.clone())
+        };
+
+        if let Some(event) = event_to_json(event) {
+            // Sending only fails if no client is connected.
+            let _ = self.events.send(event.to_string());
+        }
+
+        let (track_id, session) = match (update.and_then(|update| update.new_track), session) {
+            (Some(track_id), Some(session)) => (track_id, session),
+            _ => return,
+        };
+
+        let api = self.clone();
+        tokio::spawn(async move {
+            match TrackMetadata::get(&session, track_id).await {
+                Ok(metadata) => {
+                    let event = json!({
+                        "event": "metadata",
+                        "track": metadata.to_json(),
+                    });
+
+                    if api.state.lock().unwrap().now_playing.set_metadata(metadata) {
+                        let _ = api.events.send(event.to_string());
+                    }
+                }
+                Err(_) => debug!("Cannot fetch metadata of <{}>", track_id.to_uri()),
+            }
+        });
+    }
+
+    async fn call(self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let params: HashMap<String, String> = request
+            .uri()
+            .query()
+            .map(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .into_owned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let method = request.method().clone();
+        let path = request.uri().path().trim_end_matches('/').to_owned();
+
+        let response = match (method, path.as_str()) {
+            (Method::GET, "/api/status") => json_response(StatusCode::OK, self.status()),
+            (Method::GET, "/api/queue") => self.queue(),
+            (Method::GET, "/api/events") => return Ok(self.events(request)),
+            (Method::POST, "/api/play") => self.command(|spirc| spirc.play()),
+            (Method::POST, "/api/pause") => self.command(|spirc| spirc.pause()),
+            (Method::POST, "/api/play-pause") => self.command(|spirc| spirc.play_pause()),
+            (Method::POST, "/api/next") => self.command(|spirc| spirc.next()),
+            (Method::POST, "/api/prev") => self.command(|spirc| spirc.prev()),
+            (Method::POST, "/api/seek") => match params
+                .get("position_ms")
+                .and_then(|position| position.parse::<u32>().ok())
+            {
+                Some(position_ms) => self.command(|spirc| spirc.seek(position_ms)),
+                None => error_response(StatusCode::BAD_REQUEST, "Expected position_ms"),
+            },
+            (Method::POST, "/api/load") => match params.get("uri") {
+                Some(uri) => {
+                    let index = params.get("index").and_then(|index| index.parse().ok());
+                    let position_ms = params
+                        .get("position_ms")
+                        .and_then(|position| position.parse().ok());
+                    self.command(|spirc| {
+                        spirc.load_context(uri, index.unwrap_or(0), position_ms.unwrap_or(0))
+                    })
+                }
+                None => error_response(StatusCode::BAD_REQUEST, "Expected uri"),
+            },
+            (Method::POST, "/api/jump") => match params
+                .get("index")
+                .and_then(|index| index.parse::<u32>().ok())
+            {
+                Some(index) => self.command(|spirc| spirc.jump_to_index(index)),
+                None => error_response(StatusCode::BAD_REQUEST, "Expected index"),
+            },
+            (Method::PUT, "/api/volume") => match params
+                .get("volume")
+                .and_then(|volume| volume.parse::<u32>().ok())
+                .filter(|volume| *volume <= 100)
+            {
+                Some(volume) => {
+                    let volume = (volume * 0xFFFF / 100) as u16;
+                    self.command(|spirc| spirc.set_volume(volume))
+                }
+                None => error_response(StatusCode::BAD_REQUEST, "Expected volume from 0 to 100"),
+            },
+            (Method::PUT, "/api/bitrate") => match params
+                .get("bitrate")
+                .and_then(|bitrate| Bitrate::from_str(bitrate).ok())
+            {
+                Some(bitrate) => self.command(|spirc| spirc.set_bitrate(bitrate)),
+                None => error_response(StatusCode::BAD_REQUEST, "Expected bitrate 96, 160 or 320"),
+            },
+            (Method::POST, "/api/precache") => match params
+                .get("count")
+                .and_then(|count| count.parse::<usize>().ok())
+            {
+                Some(count) => self.command(|spirc| spirc.precache_next(count)),
+                None => error_response(StatusCode::BAD_REQUEST, "Expected count"),
+            },
+            _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+        };
+
+        Ok(response)
+    }
+
+    fn command<F: FnOnce(&Spirc)>(&self, f: F) -> Response<Body> {
+        match &self.state.lock().unwrap().spirc {
+            Some(spirc) => {
+                f(spirc);
+                json_response(StatusCode::ACCEPTED, json!({}))
+            }
+            None => error_response(StatusCode::SERVICE_UNAVAILABLE, "Not connected"),
+        }
+    }
+
+    fn status(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        let now_playing = &state.now_playing;
+
+        let track = match (&now_playing.metadata, now_playing.track_id) {
+            (Some(metadata), _) => metadata.to_json(),
+            (None, Some(id)) => json!({
+                "id": id.to_base62(),
+                "uri": id.to_uri(),
+            }),
+            (None, None) => serde_json::Value::Null,
+        };
+
+        json!({
+            "connected": state.spirc.is_some(),
+            "status": now_playing.status.as_str().to_lowercase(),
+            "track": track,
+            "position_ms": now_playing.position_ms(),
+            "duration_ms": now_playing.duration_ms,
+            "volume": now_playing.volume,
+        })
+    }
+
+    fn queue(&self) -> Response<Body> {
+        let state = self.state.lock().unwrap();
+        let spirc = match &state.spirc {
+            Some(spirc) => spirc,
+            None => return error_response(StatusCode::SERVICE_UNAVAILABLE, "Not connected"),
+        };
+
+        let tracks: Vec<_> = spirc
+            .queue()
+            .iter()
+            .map(|track| {
+                json!({
+                    "index": track.index,
+                    "uri": track.track_id.to_uri(),
+                    "queued": track.queued,
+                })
+            })
+            .collect();
+
+        json_response(
+            StatusCode::OK,
+            json!({
+                "context_uri": spirc.context_uri(),
+                "playing_track_index": spirc.playing_track_index(),
+                "remote_device_name": spirc.remote_device_name(),
+                "tracks": tracks,
+            }),
+        )
+    }
+
+    fn events(&self, request: Request<Body>) -> Response<Body> {
+        let accept = match request.headers().get(SEC_WEBSOCKET_KEY) {
+            Some(key) => {
+                let mut hasher = Sha1::new();
+                hasher.update(key.as_bytes());
+                hasher.update(WEBSOCKET_GUID.as_bytes());
+                base64::encode(hasher.finalize())
+            }
+            None => return error_response(StatusCode::BAD_REQUEST, "Expected a WebSocket"),
+        };
+
+        let mut events = self.events.subscribe();
+        tokio::spawn(async move {
+            let upgraded = match hyper::upgrade::on(request).await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    warn!("WebSocket upgrade failed: {}", e);
+                    return;
+                }
+            };
+
+            let mut websocket =
+                WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) => {
+                            if websocket.send(Message::Text(event)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            debug!("WebSocket client missed {} events", skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    message = websocket.next() => match message {
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        _ => (),
+                    },
+                }
+            }
+        });
+
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, json!({ "error": message }))
+}
@@ -0,0 +1,303 @@
+//! Controls playback with hardware inputs: the media keys of Linux input devices (evdev), and
+//! buttons and rotary encoders on the GPIO pins of a Raspberry Pi.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use evdev::{Device, EventType, Key};
+use librespot::connect::spirc::Spirc;
+use log::{info, warn};
+use rppal::gpio::{Gpio, InputPin, Level, Trigger};
+
+use crate::controls::{split_binding, Action, Controls};
+
+// Edges of a button within this time after the last one are contact bounce.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+// The highest key code of the evdev protocol.
+const KEY_MAX: u16 = 0x2ff;
+
+/// A button between a GPIO pin and ground, given as `PIN=ACTION`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Button {
+    /// The BCM number of the pin.
+    pub pin: u8,
+    pub action: Action,
+}
+
+impl FromStr for Button {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pin, action) = split_binding(s)?;
+        Ok(Button {
+            pin: parse_pin(pin)?,
+            action: action.parse()?,
+        })
+    }
+}
+
+/// What turning a rotary encoder does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncoderMode {
+    Volume,
+    /// Skips to the next or previous track.
+    Track,
+}
+
+/// A rotary encoder on two GPIO pins, given as `PIN_A,PIN_B[=volume|track]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Encoder {
+    pub pin_a: u8,
+    pub pin_b: u8,
+    pub mode: EncoderMode,
+}
+
+impl FromStr for Encoder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pins, mode) = match s.find('=') {
+            Some(index) => (&s[..index], &s[index + 1..]),
+            None => (s, "volume"),
+        };
+        let mode = match mode {
+            "volume" => EncoderMode::Volume,
+            "track" => EncoderMode::Track,
+            _ => return Err(format!("unknown encoder mode {:?}", mode)),
+        };
+        let pins: Vec<&str> = pins.split(',').collect();
+        match pins[..] {
+            [pin_a, pin_b] => Ok(Encoder {
+                pin_a: parse_pin(pin_a)?,
+                pin_b: parse_pin(pin_b)?,
+                mode,
+            }),
+            _ => Err(format!("expected two pins, got {:?}", s)),
+        }
+    }
+}
+
+/// The inputs to read.
+#[derive(Clone, Debug, Default)]
+pub struct InputConfig {
+    /// Input devices such as keyboards or remotes, e.g. `/dev/input/event0`.
+    pub devices: Vec<PathBuf>,
+    /// Key codes mapped to actions, in addition to or overriding the media keys.
+    pub keys: HashMap<u16, Action>,
+    pub buttons: Vec<Button>,
+    pub encoders: Vec<Encoder>,
+}
+
+impl InputConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.devices.is_empty() || !self.buttons.is_empty() || !self.encoders.is_empty()
+    }
+}
+
+/// Parses a key binding given as `KEY=ACTION`, where the key is its name as shown by `evtest`,
+/// e.g. `KEY_PLAYPAUSE`, or its code.
+pub fn parse_key_binding(s: &str) -> Result<(u16, Action), String> {
+    let (key, action) = split_binding(s)?;
+    let code = key
+        .parse()
+        .ok()
+        .or_else(|| (0..=KEY_MAX).find(|&code| format!("{:?}", Key::new(code)) == key))
+        .ok_or_else(|| format!("unknown key {:?}", key))?;
+    Ok((code, action.parse()?))
+}
+
+fn parse_pin(pin: &str) -> Result<u8, String> {
+    pin.trim()
+        .parse()
+        .map_err(|_| format!("invalid GPIO pin {:?}", pin))
+}
+
+fn media_keys() -> HashMap<u16, Action> {
+    [
+        (Key::KEY_PLAYPAUSE, Action::PlayPause),
+        (Key::KEY_PLAY, Action::Play),
+        (Key::KEY_PLAYCD, Action::Play),
+        (Key::KEY_PAUSECD, Action::Pause),
+        (Key::KEY_NEXTSONG, Action::Next),
+        (Key::KEY_PREVIOUSSONG, Action::Prev),
+        (Key::KEY_VOLUMEUP, Action::VolumeUp),
+        (Key::KEY_VOLUMEDOWN, Action::VolumeDown),
+    ]
+    .iter()
+    .map(|&(key, action)| (key.code(), action))
+    .collect()
+}
+
+/// Reads the inputs and applies their actions to the Spirc. GPIO inputs are watched while
+/// this exists.
+pub struct Input {
+    controls: Controls,
+    _pins: Vec<InputPin>,
+}
+
+impl Input {
+    pub fn start(config: InputConfig) -> Input {
+        let controls = Controls::default();
+
+        let mut keys = media_keys();
+        keys.extend(config.keys);
+        for path in config.devices {
+            let keys = keys.clone();
+            let controls = controls.clone();
+            thread::spawn(move || read_device(path, &keys, &controls));
+        }
+
+        let mut pins = Vec::new();
+        if !config.buttons.is_empty() || !config.encoders.is_empty() {
+            match Gpio::new() {
+                Ok(gpio) => {
+                    for button in config.buttons {
+                        match watch_button(&gpio, button, controls.clone()) {
+                            Ok(pin) => pins.push(pin),
+                            Err(e) => warn!("Cannot watch GPIO pin {}: {}", button.pin, e),
+                        }
+                    }
+                    for encoder in config.encoders {
+                        match watch_encoder(&gpio, encoder, controls.clone()) {
+                            Ok(pin) => pins.push(pin),
+                            Err(e) => warn!(
+                                "Cannot watch the encoder on GPIO pins {} and {}: {}",
+                                encoder.pin_a, encoder.pin_b, e
+                            ),
+                        }
+                    }
+                }
+                Err(e) => warn!("Cannot access the GPIO pins: {}", e),
+            }
+        }
+
+        Input {
+            controls,
+            _pins: pins,
+        }
+    }
+
+    /// Routes the inputs to a new Spirc, e.g. after reconnecting.
+    pub fn set_spirc(&self, spirc: Spirc) {
+        self.controls.set_spirc(spirc);
+    }
+}
+
+fn read_device(path: PathBuf, keys: &HashMap<u16, Action>, controls: &Controls) {
+    let mut device = match Device::open(&path) {
+        Ok(device) => device,
+        Err(e) => {
+            warn!("Cannot open input device {}: {}", path.display(), e);
+            return;
+        }
+    };
+    info!(
+        "Reading keys of {} ({})",
+        path.display(),
+        device.name().unwrap_or("unnamed device")
+    );
+
+    loop {
+        let events = match device.fetch_events() {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Cannot read input device {}: {}", path.display(), e);
+                return;
+            }
+        };
+        for event in events {
+            if event.event_type() != EventType::KEY {
+                continue;
+            }
+            // A value of 1 is a press, 2 a repeat while the key is held.
+            match (keys.get(&event.code()), event.value()) {
+                (Some(&action), 1) => controls.send(action),
+                (Some(&action), 2) if action.repeats() => controls.send(action),
+                _ => (),
+            }
+        }
+    }
+}
+
+fn watch_button(gpio: &Gpio, button: Button, controls: Controls) -> rppal::gpio::Result<InputPin> {
+    let mut pin = gpio.get(button.pin)?.into_input_pullup();
+    let mut last_edge: Option<Instant> = None;
+    pin.set_async_interrupt(Trigger::FallingEdge, move |_| {
+        let now = Instant::now();
+        if last_edge.map_or(true, |last_edge| now - last_edge > DEBOUNCE) {
+            controls.send(button.action);
+        }
+        last_edge = Some(now);
+    })?;
+    Ok(pin)
+}
+
+// Each detent of common encoders is a full cycle of both signals, so a step is taken on each
+// falling edge of A. B is still high then if the encoder is turned clockwise.
+fn watch_encoder(
+    gpio: &Gpio,
+    encoder: Encoder,
+    controls: Controls,
+) -> rppal::gpio::Result<InputPin> {
+    let mut pin_a = gpio.get(encoder.pin_a)?.into_input_pullup();
+    let pin_b = gpio.get(encoder.pin_b)?.into_input_pullup();
+    let (clockwise, counter_clockwise) = match encoder.mode {
+        EncoderMode::Volume => (Action::VolumeUp, Action::VolumeDown),
+        EncoderMode::Track => (Action::Next, Action::Prev),
+    };
+    pin_a.set_async_interrupt(Trigger::FallingEdge, move |_| {
+        if pin_b.read() == Level::High {
+            controls.send(clockwise);
+        } else {
+            controls.send(counter_clockwise);
+        }
+    })?;
+    Ok(pin_a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bindings() {
+        assert_eq!(
+            parse_key_binding("KEY_NEXTSONG=next"),
+            Ok((Key::KEY_NEXTSONG.code(), Action::Next))
+        );
+        assert_eq!(
+            parse_key_binding("28=play-pause"),
+            Ok((28, Action::PlayPause))
+        );
+        assert!(parse_key_binding("KEY_NOPE=next").is_err());
+        assert!(parse_key_binding("KEY_ENTER").is_err());
+
+        assert_eq!(
+            "17=volume-up".parse(),
+            Ok(Button {
+                pin: 17,
+                action: Action::VolumeUp
+            })
+        );
+        assert!("17=louder".parse::<Button>().is_err());
+    }
+
+    #[test]
+    fn parses_encoders() {
+        assert_eq!(
+            "5,6".parse(),
+            Ok(Encoder {
+                pin_a: 5,
+                pin_b: 6,
+                mode: EncoderMode::Volume
+            })
+        );
+        assert_eq!(
+            "5,6=track".parse::<Encoder>().map(|encoder| encoder.mode),
+            Ok(EncoderMode::Track)
+        );
+        assert!("5=volume".parse::<Encoder>().is_err());
+    }
+}
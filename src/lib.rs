@@ -6,3 +6,5 @@ pub use librespot_core as core;
 pub use librespot_metadata as metadata;
 pub use librespot_playback as playback;
 pub use librespot_protocol as protocol;
+
+pub use librespot_core::Error;
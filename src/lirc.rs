@@ -0,0 +1,147 @@
+//! Controls playback with an IR remote through lircd, which decodes the signals of the remote
+//! and names its keys.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use librespot::connect::spirc::Spirc;
+use log::{info, warn};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::controls::{split_binding, Action, Controls};
+
+pub const DEFAULT_SOCKET: &str = "/var/run/lirc/lircd";
+
+// The wait before connecting again when lircd isn't running or closed the connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The lircd socket to read and the keys to act on.
+#[derive(Clone, Debug)]
+pub struct LircConfig {
+    pub socket: PathBuf,
+    /// Key names mapped to actions, in addition to or overriding the media keys.
+    pub keys: HashMap<String, Action>,
+}
+
+/// Parses a key binding given as `NAME=ACTION`, where the name is the one of the key in the
+/// configuration of the remote, e.g. `KEY_OK`.
+pub fn parse_key_binding(s: &str) -> Result<(String, Action), String> {
+    let (name, action) = split_binding(s)?;
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("invalid key name {:?}", name));
+    }
+    Ok((name.to_string(), action.parse()?))
+}
+
+fn media_keys() -> HashMap<String, Action> {
+    [
+        ("KEY_PLAYPAUSE", Action::PlayPause),
+        ("KEY_PLAY", Action::Play),
+        ("KEY_PAUSE", Action::Pause),
+        ("KEY_STOP", Action::Pause),
+        ("KEY_NEXT", Action::Next),
+        ("KEY_NEXTSONG", Action::Next),
+        ("KEY_PREVIOUS", Action::Prev),
+        ("KEY_PREVIOUSSONG", Action::Prev),
+        ("KEY_VOLUMEUP", Action::VolumeUp),
+        ("KEY_VOLUMEDOWN", Action::VolumeDown),
+    ]
+    .iter()
+    .map(|&(name, action)| (name.to_string(), action))
+    .collect()
+}
+
+// Parses a key event of lircd, `<code> <repeat count> <key name> <remote name>`, into the
+// repeat count and the key name. The count is hexadecimal and 0 for the first event of a press.
+fn parse_event(line: &str) -> Option<(u32, &str)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields[..] {
+        [_, repeat, name, _] => Some((u32::from_str_radix(repeat, 16).ok()?, name)),
+        _ => None,
+    }
+}
+
+/// Reads the keys of IR remotes from lircd and applies their actions to the Spirc.
+pub struct Lirc {
+    controls: Controls,
+}
+
+impl Lirc {
+    pub fn start(config: LircConfig) -> Lirc {
+        let controls = Controls::default();
+
+        let mut keys = media_keys();
+        keys.extend(config.keys);
+        let socket = config.socket;
+        let lirc_controls = controls.clone();
+        tokio::spawn(async move {
+            loop {
+                match UnixStream::connect(&socket).await {
+                    Ok(stream) => {
+                        info!("Reading IR remote keys from lircd at {}", socket.display());
+                        read_events(stream, &keys, &lirc_controls).await;
+                    }
+                    Err(e) => warn!("Cannot connect to lircd at {}: {}", socket.display(), e),
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+
+        Lirc { controls }
+    }
+
+    /// Routes the remote to a new Spirc, e.g. after reconnecting.
+    pub fn set_spirc(&self, spirc: Spirc) {
+        self.controls.set_spirc(spirc);
+    }
+}
+
+async fn read_events(stream: UnixStream, keys: &HashMap<String, Action>, controls: &Controls) {
+    let mut lines = BufReader::new(stream).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                warn!("lircd closed the connection");
+                return;
+            }
+            Err(e) => {
+                warn!("Cannot read from lircd: {}", e);
+                return;
+            }
+        };
+        // Replies to commands and broadcasts such as SIGHUP aren't key events.
+        match parse_event(&line).and_then(|(repeat, name)| Some((repeat, keys.get(name)?))) {
+            Some((0, &action)) => controls.send(action),
+            Some((_, &action)) if action.repeats() => controls.send(action),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_events() {
+        assert_eq!(
+            parse_event("000000037ff07bee 00 KEY_PLAYPAUSE mceusb"),
+            Some((0, "KEY_PLAYPAUSE"))
+        );
+        assert_eq!(
+            parse_event("000000037ff07bef 1a KEY_VOLUMEUP mceusb"),
+            Some((0x1a, "KEY_VOLUMEUP"))
+        );
+        assert_eq!(parse_event("SIGHUP"), None);
+
+        assert_eq!(
+            parse_key_binding("KEY_OK=play-pause"),
+            Ok(("KEY_OK".to_string(), Action::PlayPause))
+        );
+        assert!(parse_key_binding("KEY_OK").is_err());
+        assert!(parse_key_binding("=next").is_err());
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use librespot::connect::discovery::LoginRequest;
+use librespot::core::authentication::Credentials;
+use librespot::core::cache::Cache;
+use librespot::core::config::SessionConfig;
+use librespot::core::session::Session;
+use librespot::core::version;
+use log::{error, info, warn};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::process::Command as AsyncCommand;
+use url::Url;
+
+// The client id of the desktop client, which is allowed to use the loopback redirect below.
+const CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
+const AUTHORIZE_URL: &str = "https://accounts.spotify.com/authorize";
+const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+const SCOPES: &str = "streaming";
+const DEFAULT_PORT: u16 = 8898;
+
+#[derive(Debug)]
+enum LoginError {
+    Io(io::Error),
+    Denied(String),
+    Token(String),
+    Session(String),
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::Io(e) => write!(f, "{}", e),
+            LoginError::Denied(e) => write!(f, "authorization failed: {}", e),
+            LoginError::Token(e) => write!(f, "cannot obtain an access token: {}", e),
+            LoginError::Session(e) => write!(f, "cannot log in with the access token: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for LoginError {
+    fn from(e: io::Error) -> Self {
+        LoginError::Io(e)
+    }
+}
+
+fn usage(program: &str, opts: &getopts::Options) -> String {
+    let brief = format!(
+        "Usage: {} login [options]\n\n\
+         Logs in through the browser and stores reusable credentials in the cache,\n\
+         so no password has to be passed to librespot.",
+        program
+    );
+    opts.usage(&brief)
+}
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// The PKCE code challenge of a code verifier.
+fn code_challenge(verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Extracts the authorization code from the URL the browser was redirected to.
+fn authorization_code(redirect: &str, state: &str) -> Result<String, LoginError> {
+    let base = Url::parse("http://127.0.0.1/").unwrap();
+    let url = base
+        .join(redirect.trim())
+        .map_err(|e| LoginError::Denied(e.to_string()))?;
+
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    if let Some(error) = param("error") {
+        return Err(LoginError::Denied(error));
+    }
+    if param("state").as_deref() != Some(state) {
+        return Err(LoginError::Denied("state mismatch".to_owned()));
+    }
+    param("code").ok_or_else(|| LoginError::Denied("no code in redirect".to_owned()))
+}
+
+/// Waits for the browser to be redirected to the local server, or for the user to paste
+/// the URL it was redirected to, if the browser runs on another machine.
+async fn wait_for_code(listener: TcpListener, state: &str) -> Result<String, LoginError> {
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdin_open = true;
+
+    loop {
+        tokio::select! {
+            connection = listener.accept() => {
+                let (mut socket, _) = connection?;
+                let mut buffer = vec![0; 8192];
+                let length = socket.read(&mut buffer).await?;
+                let request = String::from_utf8_lossy(&buffer[..length]);
+
+                // The request line is e.g. "GET /login?code=...&state=... HTTP/1.1".
+                let path = match request.split_whitespace().nth(1) {
+                    Some(path) if path.starts_with("/login") => path,
+                    _ => {
+                        socket.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await?;
+                        continue;
+                    }
+                };
+
+                let result = authorization_code(path, state);
+                let message = match &result {
+                    Ok(_) => "librespot is logged in, you can close this window.".to_owned(),
+                    Err(e) => format!("librespot cannot log in: {}", e),
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    message.len(),
+                    message
+                );
+                socket.write_all(response.as_bytes()).await?;
+                return result;
+            },
+            line = stdin.next_line(), if stdin_open => match line? {
+                Some(line) if !line.trim().is_empty() => return authorization_code(&line, state),
+                Some(_) => (),
+                None => stdin_open = false,
+            },
+        }
+    }
+}
+
+async fn access_token(
+    code: &str,
+    redirect_uri: &str,
+    verifier: &str,
+) -> Result<String, LoginError> {
+    let token_error = |e: &dyn fmt::Display| LoginError::Token(e.to_string());
+
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("grant_type", "authorization_code")
+        .append_pair("code", code)
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("client_id", CLIENT_ID)
+        .append_pair("code_verifier", verifier)
+        .finish();
+
+    let request = Request::post(TOKEN_URL)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(body))
+        .map_err(|e| token_error(&e))?;
+
+    let client = Client::builder().build::<_, Body>(HttpsConnector::with_webpki_roots());
+    let response = client.request(request).await.map_err(|e| token_error(&e))?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| token_error(&e))?;
+
+    if !status.is_success() {
+        return Err(LoginError::Token(format!(
+            "{}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        )));
+    }
+
+    let json: serde_json::Value = serde_json::from_slice(&body).map_err(|e| token_error(&e))?;
+    json["access_token"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| LoginError::Token("no access token in response".to_owned()))
+}
+
+async fn login(cache: Cache, port: u16) -> Result<String, LoginError> {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port))).await?;
+    let redirect_uri = format!("http://127.0.0.1:{}/login", port);
+
+    let verifier = random_string(64);
+    let state = random_string(16);
+
+    let mut url = Url::parse(AUTHORIZE_URL).unwrap();
+    url.query_pairs_mut()
+        .append_pair("client_id", CLIENT_ID)
+        .append_pair("response_type", "code")
+        .append_pair("redirect_uri", &redirect_uri)
+        .append_pair("scope", SCOPES)
+        .append_pair("state", &state)
+        .append_pair("code_challenge_method", "S256")
+        .append_pair("code_challenge", &code_challenge(&verifier));
+
+    println!("Open this URL in a browser to log in:\n\n    {}\n", url);
+    println!(
+        "If the browser runs on another machine, paste the URL it was redirected to \
+         (starting with {}) here.",
+        redirect_uri
+    );
+
+    let code = wait_for_code(listener, &state).await?;
+    let token = access_token(&code, &redirect_uri, &verifier).await?;
+
+    let session_config = SessionConfig {
+        user_agent: version::VERSION_STRING.to_string(),
+        device_id: cache
+            .device_id()
+            .unwrap_or_else(|| SessionConfig::default().device_id),
+        ..SessionConfig::default()
+    };
+
+    // Connecting stores the reusable credentials in the cache.
+    let session = Session::connect(
+        session_config,
+        Credentials::with_access_token(token),
+        Some(cache),
+    )
+    .await
+    .map_err(|e| LoginError::Session(e.to_string()))?;
+
+    Ok(session.username())
+}
+
+/// Runs `librespot login ...`, returns the exit code.
+pub async fn run(args: &[String]) -> i32 {
+    let program = &args[0];
+
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "c",
+        "cache",
+        "Path to a directory where files will be cached.",
+        "CACHE",
+    )
+    .optopt(
+        "",
+        "system-cache",
+        "Path to a directory where system files (credentials, volume) will be cached.",
+        "SYSTEMCACHE",
+    )
+    .optopt(
+        "",
+        "port",
+        "Port of the local server receiving the login redirect. Defaults to 8898",
+        "PORT",
+    )
+    .optflag("v", "verbose", "Enable verbose output")
+    .optflag("h", "help", "Print this help.");
+
+    let matches = match opts.parse(&args[2..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("error: {}\n{}", f.to_string(), usage(program, &opts));
+            return 1;
+        }
+    };
+
+    if matches.opt_present("help") {
+        println!("{}", usage(program, &opts));
+        return 0;
+    }
+
+    crate::setup_logging(matches.opt_present("verbose"));
+
+    let system_dir = match matches
+        .opt_str("system-cache")
+        .or_else(|| matches.opt_str("cache"))
+    {
+        Some(system_dir) => system_dir,
+        None => {
+            eprintln!(
+                "error: Expected --cache or --system-cache to store the credentials in\n{}",
+                usage(program, &opts)
+            );
+            return 1;
+        }
+    };
+
+    let port = matches
+        .opt_str("port")
+        .map(|port| port.parse::<u16>().expect("Invalid port"))
+        .unwrap_or(DEFAULT_PORT);
+
+    let cache = match Cache::new(Some(Path::new(&system_dir)), None) {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Cannot create cache: {}", e);
+            return 1;
+        }
+    };
+
+    match login(cache, port).await {
+        Ok(username) => {
+            println!(
+                "Logged in as {}, the credentials are stored in {}",
+                username, system_dir
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Cannot log in: {}", e);
+            1
+        }
+    }
+}
+
+/// Runs the `--zeroconf-confirm` program for a zeroconf login, which accepts it by exiting
+/// successfully.
+pub async fn confirm_login(program: &str, request: LoginRequest) -> bool {
+    let mut v: Vec<&str> = program.split_whitespace().collect();
+    let env_vars = login_env_vars(request);
+    info!("Running {:?} with environment variables {:?}", v, env_vars);
+
+    let status = AsyncCommand::new(&v.remove(0))
+        .args(&v)
+        .envs(env_vars.iter())
+        // Stops the program when the login times out.
+        .kill_on_drop(true)
+        .status()
+        .await;
+    match status {
+        Ok(status) => status.success(),
+        Err(e) => {
+            error!("Cannot run {}: {}", program, e);
+            false
+        }
+    }
+}
+
+/// Shows the PIN of a zeroconf login with `--zeroconf-pin`: logs it and passes it to the
+/// program, if there is one, in `LOGIN_PIN`.
+pub fn show_login_pin(program: Option<&str>, request: &LoginRequest, pin: &str) {
+    warn!(
+        "Enter PIN {} at the zeroconf server with ?action=confirmLogin&pin={} to log in {} from {}",
+        pin, pin, request.username, request.client_address
+    );
+
+    if let Some(program) = program {
+        let mut v: Vec<&str> = program.split_whitespace().collect();
+        let mut env_vars = login_env_vars(request.clone());
+        env_vars.insert("LOGIN_PIN", pin.to_owned());
+        info!("Running {:?} with environment variables {:?}", v, env_vars);
+        if let Err(e) = AsyncCommand::new(&v.remove(0))
+            .args(&v)
+            .envs(env_vars.iter())
+            .spawn()
+        {
+            error!("Cannot run {}: {}", program, e);
+        }
+    }
+}
+
+fn login_env_vars(request: LoginRequest) -> HashMap<&'static str, String> {
+    let mut env_vars = HashMap::new();
+    env_vars.insert("LOGIN_USERNAME", request.username);
+    env_vars.insert("LOGIN_ADDRESS", request.client_address.to_string());
+    env_vars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkce_challenge() {
+        // From RFC 7636, appendix B.
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn code_from_redirect() {
+        let code = authorization_code("http://127.0.0.1:8898/login?code=abc&state=xyz", "xyz");
+        assert_eq!(code.ok().as_deref(), Some("abc"));
+
+        assert!(authorization_code("/login?code=abc&state=other", "xyz").is_err());
+        assert!(authorization_code("/login?error=access_denied&state=xyz", "xyz").is_err());
+    }
+}
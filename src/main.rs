@@ -1,47 +1,99 @@
 use futures_util::{future, FutureExt, StreamExt};
 use librespot_playback::player::PlayerEvent;
-use log::{error, info, warn};
+use log::{debug, info, warn};
 use sha1::{Digest, Sha1};
 use tokio::sync::mpsc::UnboundedReceiver;
 use url::Url;
 
+use librespot::audio;
+use librespot::connect::discovery::{
+    DiscoveryConfig, DiscoveryEvent, IpNetwork, LoginConfirmation, ZeroconfBackend,
+};
 use librespot::connect::spirc::Spirc;
 use librespot::core::authentication::Credentials;
 use librespot::core::cache::Cache;
-use librespot::core::config::{ConnectConfig, DeviceType, SessionConfig, VolumeCtrl};
-use librespot::core::session::Session;
+#[cfg(feature = "keyring")]
+use librespot::core::credentials_store::KeyringStore;
+use librespot::core::config::{
+    ConnectCapabilities, ConnectConfig, DeviceType, PlaybackMovedPolicy, SessionConfig, VolumeCtrl,
+};
+use librespot::core::session::{Session, SessionEvent, SessionEventChannel};
+use librespot::core::util::create_private_file;
 use librespot::core::version;
 use librespot::playback::audio_backend::{self, Sink, BACKENDS};
 use librespot::playback::config::{
-    AudioFormat, Bitrate, NormalisationMethod, NormalisationType, PlayerConfig,
+    AdaptiveBitrate, AudioFormat, BackpressurePolicy, Bitrate, FetchProfile, FileFormatPolicy,
+    NormalisationMethod, NormalisationType, OutputGain, PlayerConfig, NORMALISATION_REFERENCE_LUFS,
 };
 use librespot::playback::mixer::{self, Mixer, MixerConfig};
+use librespot::playback::pacing::{self, PacedSink};
 use librespot::playback::player::{NormalisationData, Player};
-
+use librespot::playback::sync::{self, SyncSink};
+
+mod audio_tags;
+mod cache_command;
+#[cfg(any(
+    all(feature = "input", target_os = "linux"),
+    all(feature = "lirc", unix)
+))]
+mod controls;
+#[cfg(feature = "display")]
+mod display;
+mod download;
+mod login;
 mod player_event_handler;
-use player_event_handler::{emit_sink_event, run_program_on_events};
+mod service;
+use player_event_handler::{emit_sink_event, CoverArtWriter, EventProgramRunner, JsonEventWriter};
+
+#[cfg(feature = "http-api")]
+mod http_api;
+#[cfg(all(feature = "input", target_os = "linux"))]
+mod input;
+#[cfg(all(feature = "lirc", unix))]
+mod lirc;
+#[cfg(feature = "mpris")]
+mod mpris;
+#[cfg(any(feature = "mpris", feature = "http-api", feature = "display"))]
+mod now_playing;
+#[cfg(feature = "scrobble")]
+mod scrobble;
+mod track_metadata;
+#[cfg(windows)]
+mod win_service;
 
 use std::convert::TryFrom;
-use std::path::Path;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+#[cfg(feature = "http-api")]
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
-use std::{env, time::Instant};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{
     io::{stderr, Write},
     pin::Pin,
 };
 
 const MILLIS: f32 = 1000.0;
+// How long a user switch waits for the Spirc of the previous user to hand back the player.
+const SPIRC_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn device_id(name: &str) -> String {
     hex::encode(Sha1::digest(name.as_bytes()))
 }
 
 fn usage(program: &str, opts: &getopts::Options) -> String {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!(
+        "Usage: {0} [options]\n       {0} cache stats|prune|clear [options]\n       {0} download URI... [options]\n       {0} login [options]\n       {0} windows-service install [options]|uninstall|start|stop",
+        program
+    );
     opts.usage(&brief)
 }
 
+#[cfg(not(feature = "tracing"))]
 fn setup_logging(verbose: bool) {
     let mut builder = env_logger::Builder::new();
     match env::var("RUST_LOG") {
@@ -64,6 +116,30 @@ fn setup_logging(verbose: bool) {
     }
 }
 
+// Log records are forwarded to the subscriber too, so they show up within their spans.
+#[cfg(feature = "tracing")]
+fn setup_logging(verbose: bool) {
+    use tracing_subscriber::fmt::format::FmtSpan;
+    use tracing_subscriber::EnvFilter;
+
+    let config = env::var("RUST_LOG");
+    let filter = match &config {
+        Ok(config) => EnvFilter::new(config),
+        Err(_) if verbose => EnvFilter::new("libmdns=info,librespot=trace"),
+        Err(_) => EnvFilter::new("libmdns=info,librespot=info"),
+    };
+
+    // Closing spans are logged along with their duration.
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+
+    if config.is_ok() && verbose {
+        warn!("`--verbose` flag overidden by `RUST_LOG` environment variable");
+    }
+}
+
 fn list_backends() {
     println!("Available Backends : ");
     for (&(name, _), idx) in BACKENDS.iter().zip(0..) {
@@ -75,7 +151,7 @@ fn list_backends() {
     }
 }
 
-pub fn get_credentials<F: FnOnce(&String) -> Option<String>>(
+pub fn get_credentials<F: FnOnce(&str) -> Option<String>>(
     username: Option<String>,
     password: Option<String>,
     cached_credentials: Option<Credentials>,
@@ -98,6 +174,12 @@ pub fn get_credentials<F: FnOnce(&String) -> Option<String>>(
     }
 }
 
+fn prompt_password(username: &str) -> Option<String> {
+    write!(stderr(), "Password for {}: ", username).ok()?;
+    stderr().flush().ok()?;
+    rpassword::read_password().ok()
+}
+
 fn print_version() {
     println!(
         "librespot {semver} {sha} (Built on {build_date}, Build ID: {build_id})",
@@ -123,9 +205,30 @@ struct Setup {
     mixer_config: MixerConfig,
     credentials: Option<Credentials>,
     enable_discovery: bool,
-    zeroconf_port: u16,
+    discovery_config: DiscoveryConfig,
     player_event_program: Option<String>,
+    player_event_json: Option<String>,
+    metadata_pipe: Option<String>,
+    cover_art_path: Option<PathBuf>,
     emit_sink_events: bool,
+    export_credentials: Option<String>,
+    daemon: bool,
+    pidfile: Option<String>,
+    pace_output: Option<Duration>,
+    sync_master: Option<String>,
+    sync_follow: Option<String>,
+    sync_latency: Duration,
+    wait_when_kicked: bool,
+    #[cfg(feature = "http-api")]
+    http_api_address: Option<SocketAddr>,
+    #[cfg(feature = "scrobble")]
+    scrobble_config: scrobble::ScrobbleConfig,
+    #[cfg(all(feature = "input", target_os = "linux"))]
+    input_config: input::InputConfig,
+    #[cfg(all(feature = "lirc", unix))]
+    lirc_config: Option<lirc::LircConfig>,
+    #[cfg(feature = "display")]
+    display_config: display::DisplayConfig,
 }
 
 fn get_setup(args: &[String]) -> Setup {
@@ -149,19 +252,114 @@ fn get_setup(args: &[String]) -> Setup {
             "Bitrate (96, 160 or 320). Defaults to 160",
             "BITRATE",
         )
+        .optopt(
+            "",
+            "format-preference",
+            "Comma separated list of bitrates to play, in order of preference, e.g. 320,160. Overrides --bitrate.",
+            "BITRATES",
+        )
+        .optopt(
+            "",
+            "fetch-profile",
+            "How audio files are downloaded: default, low-memory, aggressive or metered.",
+            "PROFILE",
+        )
+        .optopt(
+            "",
+            "tmp-dir",
+            "Path to a directory for the files being downloaded. Defaults to the audio cache directory, or the system temporary directory without a cache.",
+            "TMPDIR",
+        )
+        .optopt(
+            "",
+            "adaptive-bitrate",
+            "Switch to another bitrate of the format policy at the next track depending on the download rate: disabled, downgrade or full. Defaults to disabled.",
+            "MODE",
+        )
+        .optopt(
+            "",
+            "decode-ahead",
+            "Milliseconds of audio to decode ahead of the audio output, to ride out CPU spikes on slow devices. Defaults to 0.",
+            "MS",
+        )
+        .optopt(
+            "",
+            "backpressure-policy",
+            "What to do when the audio output stalls with the --decode-ahead buffer full, e.g. a Bluetooth hiccup: block, drop-oldest or pause. Defaults to block.",
+            "POLICY",
+        )
+        .optflagopt(
+            "",
+            "pace-output",
+            "Write the audio output in small, evenly timed buffers, keeping MS of audio ahead, for Bluetooth (A2DP) outputs that glitch with bursty writes. Defaults to 250.",
+            "MS",
+        )
+        .optflag(
+            "",
+            "strict-format",
+            "Treat tracks as unavailable if they are in none of the formats given with --format-preference.",
+        )
         .optopt(
             "",
             "onevent",
             "Run PROGRAM when playback is about to begin.",
             "PROGRAM",
         )
+        .optopt(
+            "",
+            "onevent-json",
+            "Write player events including track metadata as newline-delimited JSON to FILE, e.g. a named pipe. Use - for stdout.",
+            "FILE",
+        )
+        .optopt(
+            "",
+            "metadata-pipe",
+            "Write a JSON line with the metadata and title of each track that starts playing to FILE, e.g. a named pipe or /dev/fd/3, for an encoder of the pipe or subprocess backend.",
+            "FILE",
+        )
+        .optopt(
+            "",
+            "cover-art",
+            "Write the cover of each track that starts playing to FILE, e.g. for a photo frame. The --onevent program gets a cover_changed event with the path in COVER_PATH.",
+            "FILE",
+        )
         .optflag("", "emit-sink-events", "Run program set by --onevent before sink is opened and after it is closed.")
         .optflag("v", "verbose", "Enable verbose output")
         .optflag("V", "version", "Display librespot version string")
         .optopt("u", "username", "Username to sign in with", "USERNAME")
         .optopt("p", "password", "Password", "PASSWORD")
+        .optopt(
+            "",
+            "import-credentials",
+            "Sign in with credentials exported by another instance using --export-credentials",
+            "FILE",
+        )
+        .optopt(
+            "",
+            "export-credentials",
+            "Write the reusable credentials to this file once signed in, e.g. to share them with other instances",
+            "FILE",
+        )
+        .optopt(
+            "",
+            "device-id",
+            "Identifier of this device in Spotify Connect. Defaults to the id kept in the cache, which is derived from the name on the first run, or a random id if there is no cache",
+            "DEVICE_ID",
+        )
         .optopt("", "proxy", "HTTP proxy to use when connecting", "PROXY")
         .optopt("", "ap-port", "Connect to AP with specified port. If no AP with that port are present fallback AP will be used. Available ports are usually 80, 443 and 4070", "AP_PORT")
+        .optopt(
+            "",
+            "keep-alive-interval",
+            "Seconds between the keep-alive pings of the AP, at least 100. Defaults to 120.",
+            "SECS",
+        )
+        .optopt(
+            "",
+            "keep-alive-timeout",
+            "Seconds a keep-alive of the AP may be late before the connection is closed and reconnected. 0 waits for the TCP connection to fail. Defaults to 20.",
+            "SECS",
+        )
         .optflag("", "disable-discovery", "Disable discovery mode")
         .optopt(
             "",
@@ -172,7 +370,7 @@ fn get_setup(args: &[String]) -> Setup {
         .optopt(
             "",
             "device",
-            "Audio device to use. Use '?' to list options if using portaudio or alsa",
+            "Audio device to use. Use '?' to list options if using portaudio or alsa. The pipe backend writes each track to a file of its own if this is a directory.",
             "DEVICE",
         )
         .optopt(
@@ -217,6 +415,47 @@ fn get_setup(args: &[String]) -> Setup {
             "The port the internal server advertised over zeroconf uses.",
             "ZEROCONF_PORT",
         )
+        .optopt(
+            "",
+            "zeroconf-interface",
+            "The IP address of the interface to advertise over zeroconf and bind the internal server to. Defaults to all interfaces.",
+            "IP",
+        )
+        .optopt(
+            "",
+            "zeroconf-backend",
+            "Zeroconf implementation to use: auto, libmdns or dns-sd. Defaults to auto, which uses a running DNS-SD daemon (e.g. avahi) if available.",
+            "BACKEND",
+        )
+        .optflag(
+            "",
+            "zeroconf-tls",
+            "Serve the internal server over HTTPS with a self-signed certificate. The official Spotify clients only speak HTTP to addUser, so they can't connect then, only other clients trying HTTPS can.",
+        )
+        .optopt(
+            "",
+            "zeroconf-allow",
+            "Comma separated addresses or networks (e.g. 192.168.1.0/24) the internal server accepts connections from. Defaults to any.",
+            "NETWORKS",
+        )
+        .optopt(
+            "",
+            "zeroconf-auth-limit",
+            "The maximum number of zeroconf logins per minute from an address. Defaults to no limit.",
+            "ATTEMPTS",
+        )
+        .optopt(
+            "",
+            "zeroconf-confirm",
+            "Run PROGRAM to confirm a zeroconf login, which is accepted if it exits successfully. It gets LOGIN_USERNAME and LOGIN_ADDRESS in its environment.",
+            "PROGRAM",
+        )
+        .optflagopt(
+            "",
+            "zeroconf-pin",
+            "Confirm a zeroconf login with a PIN that the connecting user enters at http://DEVICE:PORT/?action=confirmLogin&pin=PIN on the zeroconf server, e.g. from a browser. The PIN is logged and, if PROGRAM is given, shown by PROGRAM, which gets LOGIN_USERNAME, LOGIN_ADDRESS and LOGIN_PIN in its environment.",
+            "PROGRAM",
+        )
         .optflag(
             "",
             "enable-volume-normalisation",
@@ -234,10 +473,16 @@ fn get_setup(args: &[String]) -> Setup {
             "Specify the normalisation gain type to use - [track, album]. Default is album.",
             "GAIN_TYPE",
         )
+        .optopt(
+            "",
+            "normalisation-target",
+            "Loudness (LUFS) volume normalisation brings tracks to, e.g. -23, -18 or -14. Default is -14.",
+            "LUFS",
+        )
         .optopt(
             "",
             "normalisation-pregain",
-            "Pregain (dB) applied by volume normalisation",
+            "Deprecated, use --normalisation-target. Pregain (dB) on top of the -14 LUFS of Spotify's gain data.",
             "PREGAIN",
         )
         .optopt(
@@ -264,21 +509,105 @@ fn get_setup(args: &[String]) -> Setup {
             "Knee steepness of the dynamic limiter. Default is 1.0.",
             "KNEE",
         )
+        .optmulti(
+            "",
+            "output-gain",
+            "Gain in dB applied after normalisation, e.g. -6 if the input of the output clips. With OUTPUT= only for the outputs whose name contains OUTPUT, e.g. hdmi=-6 for a PulseAudio HDMI profile. Can be given more than once. Default is 0.",
+            "[OUTPUT=]GAIN",
+        )
         .optopt(
             "",
             "volume-ctrl",
             "Volume control type - [linear, log, fixed]. Default is logarithmic",
             "VOLUME_CTRL"
         )
+        .optopt(
+            "",
+            "volume-steps",
+            "Number of volume steps offered to Spotify Connect clients. Default is 16.",
+            "STEPS",
+        )
+        .optopt(
+            "",
+            "volume-range",
+            "Range of the mixer volume in % the Spotify Connect volume is mapped onto, e.g. 20-80. Default is 0-100.",
+            "MIN-MAX",
+        )
         .optflag(
             "",
             "autoplay",
             "autoplay similar songs when your music ends.",
         )
+        .optflag(
+            "",
+            "passive",
+            "Show up in Spotify Connect without taking over playback when a client transfers it to this device.",
+        )
+        .optopt(
+            "",
+            "playback-moved",
+            "What to do when another device of the account takes over playback - [pause, keep, reclaim:SECONDS]. Default is pause",
+            "POLICY",
+        )
         .optflag(
             "",
             "disable-gapless",
             "disable gapless playback.",
+        )
+        .optflag(
+            "",
+            "disable-volume-control",
+            "Hide the volume control from Connect clients and ignore their volume changes.",
+        )
+        .optflag(
+            "",
+            "disable-shuffle",
+            "Ignore shuffle requests of Connect clients.",
+        )
+        .optflag(
+            "",
+            "disable-repeat",
+            "Ignore repeat requests of Connect clients.",
+        )
+        .optflag(
+            "",
+            "wait-when-kicked",
+            "When another device takes over the session, wait to be selected in a Connect client again instead of reconnecting.",
+        )
+        .optflag(
+            "",
+            "enable-logout",
+            "Let Connect clients log the device out, which removes the cached credentials.",
+        )
+        .optflag(
+            "",
+            "filter-explicit-content",
+            "Skip explicit tracks. They are always skipped if the account filters explicit content.",
+        )
+        .optflag(
+            "",
+            "pause-on-output-change",
+            "Pause when the default output device changes, e.g. when headphones are unplugged. Supported by the pulseaudio, rodio and coreaudio backends.",
+        )
+        .optflag("", "daemon", "Run in the background, detached from the terminal.")
+        .optopt("", "pidfile", "Write the process ID to PIDFILE.", "PIDFILE")
+        .optopt(
+            "",
+            "sync-master",
+            "Stream the played audio to synchronized followers connecting to this address, e.g. 0.0.0.0:4700",
+            "ADDRESS",
+        )
+        .optopt(
+            "",
+            "sync-follow",
+            "Play the audio of the sync master at ADDRESS in sync, instead of connecting to Spotify.",
+            "ADDRESS",
+        )
+        .optopt(
+            "",
+            "sync-latency",
+            "Delay of the synchronized playback in milliseconds. Defaults to 500",
+            "MS",
         )
 	    .optflag(
             "",
@@ -286,6 +615,112 @@ fn get_setup(args: &[String]) -> Setup {
             "Pass raw stream to output, only works for \"pipe\"."
         );
 
+    #[cfg(feature = "http-api")]
+    opts.optopt(
+        "",
+        "http-api",
+        "Serve an HTTP control API with a WebSocket for player events on this address, e.g. 127.0.0.1:8080",
+        "ADDRESS",
+    );
+
+    #[cfg(feature = "scrobble")]
+    opts.optopt(
+        "",
+        "listenbrainz-token",
+        "Submit the tracks played to ListenBrainz with this user token.",
+        "TOKEN",
+    )
+    .optopt(
+        "",
+        "lastfm-api-key",
+        "Scrobble the tracks played to Last.fm with this API account. Requires --lastfm-api-secret-file, --lastfm-username and --lastfm-password-file.",
+        "KEY",
+    )
+    .optopt(
+        "",
+        "lastfm-api-secret-file",
+        "File containing the shared secret of the Last.fm API account. Can also be given in the LIBRESPOT_LASTFM_API_SECRET environment variable.",
+        "PATH",
+    )
+    .optopt("", "lastfm-username", "Last.fm username to scrobble for.", "USERNAME")
+    .optopt(
+        "",
+        "lastfm-password-file",
+        "File containing the password of the Last.fm user. Can also be given in the LIBRESPOT_LASTFM_PASSWORD environment variable.",
+        "PATH",
+    );
+
+    #[cfg(all(feature = "input", target_os = "linux"))]
+    opts.optmulti(
+        "",
+        "input-device",
+        "Control playback with the media keys of this input device, e.g. /dev/input/event0. Can be given several times.",
+        "PATH",
+    )
+    .optmulti(
+        "",
+        "input-key",
+        "Map a key of the input devices to an action - [play, pause, play-pause, next, prev, volume-up, volume-down]. The key is its name as shown by evtest, or its code.",
+        "KEY=ACTION",
+    )
+    .optmulti(
+        "",
+        "gpio-button",
+        "Map a button between a GPIO pin, in BCM numbering, and ground to an action.",
+        "PIN=ACTION",
+    )
+    .optmulti(
+        "",
+        "gpio-encoder",
+        "Change the volume, or skip tracks with =track, with a rotary encoder on two GPIO pins.",
+        "PIN_A,PIN_B[=volume|track]",
+    );
+
+    #[cfg(all(feature = "lirc", unix))]
+    opts.optflagopt(
+        "",
+        "lirc",
+        "Control playback with an IR remote through the socket of lircd. Defaults to /var/run/lirc/lircd.",
+        "SOCKET",
+    )
+    .optmulti(
+        "",
+        "lirc-key",
+        "Map a key of the remote to an action - [play, pause, play-pause, next, prev, volume-up, volume-down]. The key is its name in the lircd configuration of the remote.",
+        "NAME=ACTION",
+    );
+
+    #[cfg(feature = "display")]
+    opts.optopt(
+        "",
+        "display-socket",
+        "Send the track, artist and progress as lines of text to display clients connecting to this address, e.g. 127.0.0.1:5030.",
+        "ADDRESS",
+    );
+
+    #[cfg(all(feature = "display", target_os = "linux"))]
+    opts.optopt(
+        "",
+        "display-lcd",
+        "Show the track and progress on an HD44780 character LCD with a PCF8574 I2C backpack, e.g. 1:0x27:20x4. Defaults to 16x2.",
+        "BUS:ADDRESS[:COLSxROWS]",
+    );
+
+    #[cfg(feature = "keyring")]
+    opts.optflag(
+        "",
+        "keyring",
+        "Keep the credentials in the keyring of the OS instead of the system cache directory.",
+    );
+
+    #[cfg(feature = "packet-trace")]
+    opts.optopt(
+        "",
+        "packet-capture",
+        "Write the decrypted packets of the AP connection to a pcap file, for protocol debugging.",
+        "FILE",
+    );
+
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
@@ -310,6 +745,17 @@ fn get_setup(args: &[String]) -> Setup {
         build_id = version::BUILD_ID
     );
 
+    #[cfg(feature = "packet-trace")]
+    {
+        if let Some(path) = matches.opt_str("packet-capture") {
+            if let Err(e) = librespot::core::packet_trace::capture_to_file(&path) {
+                log::error!("Cannot open packet capture file {}: {}", path, e);
+                exit(1);
+            }
+            info!("Writing the packets of the AP connection to {}", path);
+        }
+    }
+
     let backend_name = matches.opt_str("backend");
     if backend_name == Some("?".into()) {
         list_backends();
@@ -347,6 +793,7 @@ fn get_setup(args: &[String]) -> Setup {
         mapped_volume: !matches.opt_present("mixer-linear-volume"),
     };
 
+    let has_system_cache = matches.opt_present("system-cache") || matches.opt_present("c");
     let cache = {
         let audio_dir;
         let system_dir;
@@ -367,7 +814,17 @@ fn get_setup(args: &[String]) -> Setup {
                 .map(|p| p.into());
         }
 
-        match Cache::new(system_dir, audio_dir) {
+        let cache = Cache::new(system_dir, audio_dir);
+        #[cfg(feature = "keyring")]
+        let cache = cache.map(|cache| {
+            if matches.opt_present("keyring") {
+                cache.with_credentials_store(KeyringStore::default())
+            } else {
+                cache
+            }
+        });
+
+        match cache {
             Ok(cache) => Some(cache),
             Err(e) => {
                 warn!("Cannot create cache: {}", e);
@@ -388,34 +845,102 @@ fn get_setup(args: &[String]) -> Setup {
         .or_else(|| cache.as_ref().and_then(Cache::volume))
         .unwrap_or(0x8000);
 
-    let zeroconf_port = matches
-        .opt_str("zeroconf-port")
-        .map(|port| port.parse::<u16>().unwrap())
-        .unwrap_or(0);
+    if matches.opt_present("zeroconf-confirm") && matches.opt_present("zeroconf-pin") {
+        eprintln!(
+            "error: --zeroconf-confirm and --zeroconf-pin can't be used together\n{}",
+            usage(&args[0], &opts)
+        );
+        exit(1);
+    }
+    let confirm_login = if matches.opt_present("zeroconf-pin") {
+        let program = matches.opt_str("zeroconf-pin");
+        Some(LoginConfirmation::pin(move |request, pin| {
+            login::show_login_pin(program.as_deref(), request, pin)
+        }))
+    } else {
+        matches.opt_str("zeroconf-confirm").map(|program| {
+            LoginConfirmation::new(move |request| {
+                let program = program.clone();
+                async move { login::confirm_login(&program, request).await }
+            })
+        })
+    };
+
+    let discovery_config = DiscoveryConfig {
+        port: matches
+            .opt_str("zeroconf-port")
+            .map(|port| port.parse::<u16>().unwrap())
+            .unwrap_or(0),
+        bind_address: matches.opt_str("zeroconf-interface").map(|ip| {
+            ip.parse::<IpAddr>()
+                .expect("Invalid zeroconf interface, expected an IP address")
+        }),
+        backend: matches
+            .opt_str("zeroconf-backend")
+            .as_ref()
+            .map(|backend| ZeroconfBackend::from_str(backend).expect("Invalid zeroconf backend"))
+            .unwrap_or_default(),
+        tls: matches.opt_present("zeroconf-tls"),
+        allowed_networks: matches
+            .opt_str("zeroconf-allow")
+            .map(|networks| {
+                networks
+                    .split(',')
+                    .map(|network| {
+                        IpNetwork::from_str(network.trim())
+                            .expect("Invalid zeroconf network, expected an address or CIDR")
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        max_auth_attempts: matches.opt_str("zeroconf-auth-limit").map(|attempts| {
+            attempts
+                .parse::<u32>()
+                .expect("Invalid zeroconf auth limit, expected a number")
+        }),
+        confirm_login,
+    };
 
     let name = matches
         .opt_str("name")
         .unwrap_or_else(|| "Librespot".to_string());
 
     let credentials = {
-        let cached_credentials = cache.as_ref().and_then(Cache::credentials);
+        let imported_credentials = matches.opt_str("import-credentials").map(|path| {
+            let exported = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Cannot read credentials from {}: {}", path, e));
+            Credentials::import(&exported)
+                .unwrap_or_else(|e| panic!("Cannot import credentials from {}: {}", path, e))
+        });
 
-        let password = |username: &String| -> Option<String> {
-            write!(stderr(), "Password for {}: ", username).ok()?;
-            stderr().flush().ok()?;
-            rpassword::read_password().ok()
-        };
+        let cached_credentials =
+            imported_credentials.or_else(|| cache.as_ref().and_then(Cache::credentials));
 
         get_credentials(
             matches.opt_str("username"),
             matches.opt_str("password"),
             cached_credentials,
-            password,
+            prompt_password,
         )
     };
 
     let session_config = {
-        let device_id = device_id(&name);
+        let device_id = match matches.opt_str("device-id") {
+            Some(device_id) if device_id.trim().is_empty() => {
+                panic!("Device id must not be empty")
+            }
+            Some(device_id) => device_id,
+            // The first run with a cache keeps the id derived from the name, which earlier
+            // versions used, so that upgrading doesn't add a second Connect device.
+            None => match cache.as_ref().filter(|_| has_system_cache) {
+                Some(cache) => cache.device_id().unwrap_or_else(|| {
+                    let device_id = device_id(&name);
+                    cache.save_device_id(&device_id);
+                    device_id
+                }),
+                None => SessionConfig::default().device_id,
+            },
+        };
 
         SessionConfig {
             user_agent: version::VERSION_STRING.to_string(),
@@ -440,10 +965,42 @@ fn get_setup(args: &[String]) -> Setup {
             ap_port: matches
                 .opt_str("ap-port")
                 .map(|port| port.parse::<u16>().expect("Invalid port")),
+            keep_alive_interval: matches
+                .opt_str("keep-alive-interval")
+                .map(|secs| match secs.parse().map(Duration::from_secs) {
+                    Ok(interval) if interval >= SessionConfig::MIN_KEEP_ALIVE_INTERVAL => interval,
+                    _ => {
+                        eprintln!(
+                            "error: Invalid keep-alive interval, expected at least {} seconds\n{}",
+                            SessionConfig::MIN_KEEP_ALIVE_INTERVAL.as_secs(),
+                            usage(&args[0], &opts)
+                        );
+                        exit(1);
+                    }
+                })
+                .unwrap_or(SessionConfig::default().keep_alive_interval),
+            keep_alive_timeout: match matches.opt_str("keep-alive-timeout") {
+                Some(secs) => match secs.parse() {
+                    Ok(0) => None,
+                    Ok(secs) => Some(Duration::from_secs(secs)),
+                    Err(_) => {
+                        eprintln!(
+                            "error: Invalid keep-alive timeout\n{}",
+                            usage(&args[0], &opts)
+                        );
+                        exit(1);
+                    }
+                },
+                None => SessionConfig::default().keep_alive_timeout,
+            },
+            ..SessionConfig::default()
         }
     };
 
     let passthrough = matches.opt_present("passthrough");
+    let pace_output = matches
+        .opt_default("pace-output", &pacing::DEFAULT_LEAD.as_millis().to_string())
+        .map(|lead| Duration::from_millis(lead.parse().expect("Invalid output pacing lead")));
 
     let player_config = {
         let bitrate = matches
@@ -451,6 +1008,33 @@ fn get_setup(args: &[String]) -> Setup {
             .as_ref()
             .map(|bitrate| Bitrate::from_str(bitrate).expect("Invalid bitrate"))
             .unwrap_or_default();
+        let file_format_policy = matches.opt_str("format-preference").map(|formats| {
+            let mut policy =
+                FileFormatPolicy::from_str(&formats).expect("Invalid format preference");
+            policy.fallback = !matches.opt_present("strict-format");
+            policy
+        });
+        let fetch_profile = match matches.opt_str("fetch-profile") {
+            Some(profile) => FetchProfile::from_str(&profile).unwrap_or_else(|_| {
+                eprintln!(
+                    "error: Invalid fetch profile: {}\n{}",
+                    profile,
+                    usage(&args[0], &opts)
+                );
+                exit(1);
+            }),
+            None => FetchProfile::default(),
+        };
+        let adaptive_bitrate = matches
+            .opt_str("adaptive-bitrate")
+            .map(|mode| AdaptiveBitrate::from_str(&mode).expect("Invalid adaptive bitrate mode"))
+            .unwrap_or_default();
+        let backpressure_policy = matches
+            .opt_str("backpressure-policy")
+            .map(|policy| {
+                BackpressurePolicy::from_str(&policy).expect("Invalid backpressure policy")
+            })
+            .unwrap_or_default();
         let gain_type = matches
             .opt_str("normalisation-gain-type")
             .as_ref()
@@ -466,16 +1050,50 @@ fn get_setup(args: &[String]) -> Setup {
             })
             .unwrap_or_default();
 
+        let normalisation_target_lufs = match (
+            matches.opt_str("normalisation-target"),
+            matches.opt_str("normalisation-pregain"),
+        ) {
+            (Some(target), _) => target
+                .parse::<f32>()
+                .expect("Invalid normalisation target float value"),
+            (None, Some(pregain)) => {
+                warn!("--normalisation-pregain is deprecated, use --normalisation-target");
+                NORMALISATION_REFERENCE_LUFS
+                    + pregain.parse::<f32>().expect("Invalid pregain float value")
+            }
+            (None, None) => PlayerConfig::default().normalisation_target_lufs,
+        };
+
+        let mut output_gain_db = PlayerConfig::default().output_gain_db;
+        let mut output_gains = Vec::new();
+        for gain in matches.opt_strs("output-gain") {
+            let (output, gain) = match gain.rfind('=') {
+                Some(i) => (Some(&gain[..i]), &gain[i + 1..]),
+                None => (None, gain.as_str()),
+            };
+            let gain_db = gain
+                .parse::<f32>()
+                .expect("Invalid output gain float value");
+            match output {
+                Some(output) => output_gains.push(OutputGain {
+                    output: output.to_owned(),
+                    gain_db,
+                }),
+                None => output_gain_db = gain_db,
+            }
+        }
+
         PlayerConfig {
             bitrate,
+            file_format_policy,
+            fetch_profile,
+            adaptive_bitrate,
             gapless: !matches.opt_present("disable-gapless"),
             normalisation: matches.opt_present("enable-volume-normalisation"),
             normalisation_method,
             normalisation_type: gain_type,
-            normalisation_pregain: matches
-                .opt_str("normalisation-pregain")
-                .map(|pregain| pregain.parse::<f32>().expect("Invalid pregain float value"))
-                .unwrap_or(PlayerConfig::default().normalisation_pregain),
+            normalisation_target_lufs,
             normalisation_threshold: NormalisationData::db_to_ratio(
                 matches
                     .opt_str("normalisation-threshold")
@@ -500,9 +1118,47 @@ fn get_setup(args: &[String]) -> Setup {
                 .opt_str("normalisation-knee")
                 .map(|knee| knee.parse::<f32>().expect("Invalid knee float value"))
                 .unwrap_or(PlayerConfig::default().normalisation_knee),
+            output_gain_db,
+            output_gains,
+            ducking: false,
             passthrough,
+            decode_ahead_ms: matches
+                .opt_str("decode-ahead")
+                .map(|ms| ms.parse::<u32>().expect("Invalid decode ahead milliseconds"))
+                .unwrap_or(PlayerConfig::default().decode_ahead_ms),
+            backpressure_policy,
+            // The paced sink keeps up to the lead written ahead, which the stall timeout
+            // tolerates on top.
+            sink_stall_timeout_ms: PlayerConfig::default().sink_stall_timeout_ms
+                + pace_output.map_or(0, |lead| lead.as_millis() as u32),
+            filter_explicit_content: matches.opt_present("filter-explicit-content"),
+            pause_on_output_change: matches.opt_present("pause-on-output-change"),
+            fetch_tmp_dir: matches.opt_str("tmp-dir").map(|dir| dir.into()),
         }
     };
+    if let Err(e) = player_config.validate() {
+        eprintln!(
+            "error: Invalid player configuration: {}\n{}",
+            e,
+            usage(&args[0], &opts)
+        );
+        exit(1);
+    }
+
+    // Remove the downloads a previous run left behind.
+    let tmp_dir = player_config.fetch_tmp_dir.clone().or_else(|| {
+        cache
+            .as_ref()
+            .and_then(Cache::audio_location)
+            .map(Path::to_owned)
+    });
+    if let Some(tmp_dir) = tmp_dir {
+        match fs::create_dir_all(&tmp_dir).and_then(|_| audio::remove_temp_files(&tmp_dir)) {
+            Ok(0) => (),
+            Ok(removed) => info!("Removed {} unfinished downloads", removed),
+            Err(e) => warn!("Cannot clean up {}: {}", tmp_dir.display(), e),
+        }
+    }
 
     let connect_config = {
         let device_type = matches
@@ -517,17 +1173,98 @@ fn get_setup(args: &[String]) -> Setup {
             .map(|volume_ctrl| VolumeCtrl::from_str(volume_ctrl).expect("Invalid volume ctrl type"))
             .unwrap_or_default();
 
+        let volume_steps = matches
+            .opt_str("volume-steps")
+            .map(|steps| match steps.parse::<u16>() {
+                Ok(steps) if steps > 0 => steps,
+                _ => panic!("Volume steps must be a positive number"),
+            })
+            .unwrap_or(ConnectConfig::DEFAULT_VOLUME_STEPS);
+
+        let mixer_volume_range = matches
+            .opt_str("volume-range")
+            .map(|range| {
+                let percent = |value: &str| match value.trim().parse::<u16>() {
+                    Ok(value) if value <= 100 => (value as u32 * 0xFFFF / 100) as u16,
+                    _ => panic!("Volume range must be given as MIN-MAX in the range 0-100"),
+                };
+                let bounds: Vec<u16> = range.splitn(2, '-').map(percent).collect();
+                match bounds[..] {
+                    [min, max] if min <= max => (min, max),
+                    _ => panic!("Volume range must be given as MIN-MAX with MIN <= MAX"),
+                }
+            })
+            .unwrap_or((0, 0xFFFF));
+
+        let playback_moved_policy = matches
+            .opt_str("playback-moved")
+            .map(|policy| {
+                PlaybackMovedPolicy::from_str(&policy).expect("Invalid playback moved policy")
+            })
+            .unwrap_or_default();
+
         ConnectConfig {
             name,
             device_type,
             volume: initial_volume,
             volume_ctrl,
+            volume_steps,
+            mixer_volume_range,
             autoplay: matches.opt_present("autoplay"),
+            passive: matches.opt_present("passive"),
+            capabilities: ConnectCapabilities {
+                volume_control: !matches.opt_present("disable-volume-control"),
+                shuffle: !matches.opt_present("disable-shuffle"),
+                repeat: !matches.opt_present("disable-repeat"),
+                logout: matches.opt_present("enable-logout"),
+                gapless: player_config.gapless,
+            },
+            playback_moved_policy,
         }
     };
 
     let enable_discovery = !matches.opt_present("disable-discovery");
 
+    // Secrets are read from a file or the environment, so they don't show up in the process
+    // list.
+    #[cfg(feature = "scrobble")]
+    let lastfm = matches.opt_str("lastfm-api-key").map(|api_key| {
+        let secret = |name: &str, var: &str| {
+            let secret = match matches.opt_str(&format!("{}-file", name)) {
+                Some(path) => match fs::read_to_string(&path) {
+                    Ok(secret) => Some(secret.trim().to_string()),
+                    Err(e) => {
+                        eprintln!("error: Unable to read {}: {}", path, e);
+                        exit(1);
+                    }
+                },
+                None => env::var(var).ok(),
+            };
+            secret.unwrap_or_else(|| {
+                eprintln!(
+                    "error: --lastfm-api-key requires --{}-file or {}\n{}",
+                    name,
+                    var,
+                    usage(&args[0], &opts)
+                );
+                exit(1);
+            })
+        };
+        let username = matches.opt_str("lastfm-username").unwrap_or_else(|| {
+            eprintln!(
+                "error: --lastfm-api-key requires --lastfm-username\n{}",
+                usage(&args[0], &opts)
+            );
+            exit(1);
+        });
+        scrobble::LastFmConfig {
+            api_key,
+            api_secret: secret("lastfm-api-secret", "LIBRESPOT_LASTFM_API_SECRET"),
+            username,
+            password: secret("lastfm-password", "LIBRESPOT_LASTFM_PASSWORD"),
+        }
+    });
+
     Setup {
         format,
         backend,
@@ -538,41 +1275,276 @@ fn get_setup(args: &[String]) -> Setup {
         credentials,
         device,
         enable_discovery,
-        zeroconf_port,
+        discovery_config,
         mixer,
         mixer_config,
         player_event_program: matches.opt_str("onevent"),
+        player_event_json: matches.opt_str("onevent-json"),
+        metadata_pipe: matches.opt_str("metadata-pipe"),
+        cover_art_path: matches.opt_str("cover-art").map(PathBuf::from),
         emit_sink_events: matches.opt_present("emit-sink-events"),
+        export_credentials: matches.opt_str("export-credentials"),
+        daemon: matches.opt_present("daemon"),
+        pidfile: matches.opt_str("pidfile"),
+        pace_output,
+        sync_master: matches.opt_str("sync-master"),
+        sync_follow: matches.opt_str("sync-follow"),
+        sync_latency: matches
+            .opt_str("sync-latency")
+            .map(|latency| Duration::from_millis(latency.parse().expect("Invalid sync latency")))
+            .unwrap_or(sync::DEFAULT_LATENCY),
+        wait_when_kicked: matches.opt_present("wait-when-kicked"),
+        #[cfg(feature = "http-api")]
+        http_api_address: matches.opt_str("http-api").map(|address| {
+            address
+                .parse()
+                .expect("Invalid HTTP API address, expected IP:PORT")
+        }),
+        #[cfg(feature = "scrobble")]
+        scrobble_config: scrobble::ScrobbleConfig {
+            listenbrainz_token: matches.opt_str("listenbrainz-token"),
+            lastfm,
+        },
+        #[cfg(all(feature = "input", target_os = "linux"))]
+        input_config: input::InputConfig {
+            devices: matches
+                .opt_strs("input-device")
+                .into_iter()
+                .map(|path| path.into())
+                .collect(),
+            keys: matches
+                .opt_strs("input-key")
+                .iter()
+                .map(|binding| {
+                    input::parse_key_binding(binding)
+                        .unwrap_or_else(|e| panic!("Invalid input key: {}", e))
+                })
+                .collect(),
+            buttons: matches
+                .opt_strs("gpio-button")
+                .iter()
+                .map(|button| {
+                    button
+                        .parse()
+                        .unwrap_or_else(|e| panic!("Invalid GPIO button: {}", e))
+                })
+                .collect(),
+            encoders: matches
+                .opt_strs("gpio-encoder")
+                .iter()
+                .map(|encoder| {
+                    encoder
+                        .parse()
+                        .unwrap_or_else(|e| panic!("Invalid GPIO encoder: {}", e))
+                })
+                .collect(),
+        },
+        #[cfg(all(feature = "lirc", unix))]
+        lirc_config: matches
+            .opt_default("lirc", lirc::DEFAULT_SOCKET)
+            .map(|socket| lirc::LircConfig {
+                socket: socket.into(),
+                keys: matches
+                    .opt_strs("lirc-key")
+                    .iter()
+                    .map(|binding| {
+                        lirc::parse_key_binding(binding)
+                            .unwrap_or_else(|e| panic!("Invalid lirc key: {}", e))
+                    })
+                    .collect(),
+            }),
+        #[cfg(feature = "display")]
+        display_config: display::DisplayConfig {
+            socket: matches.opt_str("display-socket").map(|address| {
+                address
+                    .parse()
+                    .expect("Invalid display socket address, expected IP:PORT")
+            }),
+            #[cfg(target_os = "linux")]
+            lcd: matches.opt_str("display-lcd").map(|lcd| {
+                lcd.parse()
+                    .unwrap_or_else(|e| panic!("Invalid display LCD: {}", e))
+            }),
+        },
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
+fn main() {
     if env::var("RUST_BACKTRACE").is_err() {
         env::set_var("RUST_BACKTRACE", "full")
     }
 
     let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("cache") {
+        exit(cache_command::run(&args));
+    }
+    if args.get(1).map(String::as_str) == Some("download") {
+        exit(runtime().block_on(download::run(&args)));
+    }
+    if args.get(1).map(String::as_str) == Some("login") {
+        exit(runtime().block_on(login::run(&args)));
+    }
+    if args.get(1).map(String::as_str) == Some("windows-service") {
+        #[cfg(windows)]
+        exit(win_service::run(&args));
+        #[cfg(not(windows))]
+        {
+            eprintln!("Running as a service is only supported on Windows");
+            exit(1);
+        }
+    }
+
     let setup = get_setup(&args);
 
+    // Forking must happen before the runtime starts.
+    if setup.daemon {
+        if let Err(e) = service::daemonize(setup.pidfile.as_deref()) {
+            eprintln!("Cannot run as a daemon: {}", e);
+            exit(1);
+        }
+    } else if let Some(pidfile) = &setup.pidfile {
+        if let Err(e) = service::write_pidfile(pidfile) {
+            eprintln!("Cannot write PID file {}: {}", pidfile, e);
+            exit(1);
+        }
+    }
+
+    runtime().block_on(run(setup));
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Cannot start the runtime")
+}
+
+/// Plays the audio of a sync master until it is interrupted, without connecting to Spotify.
+async fn follow(setup: Setup, master: String) {
+    let backend = setup.backend;
+    let device = setup.device.clone();
+    let format = setup.format;
+    thread::spawn(move || sync::follow(master.as_str(), (backend)(device, format)));
+
+    let mut terminate = service::TerminateSignal::new().expect("Cannot listen for SIGTERM");
+    service::systemd::ready();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+
+    service::systemd::stopping();
+    if let Some(pidfile) = &setup.pidfile {
+        service::remove_pidfile(pidfile);
+    }
+}
+
+async fn run(setup: Setup) {
+    if let Some(master) = setup.sync_follow.clone() {
+        return follow(setup, master).await;
+    }
+
     let mut last_credentials = None;
     let mut spirc: Option<Spirc> = None;
+    let mut current_session: Option<Session> = None;
     let mut spirc_task: Option<Pin<_>> = None;
+    // The Spirc of the previous user, which hands back the player and mixer once it ends.
+    let mut stopping_spirc_task: Option<Pin<_>> = None;
+    let mut playback: Option<(Player, Box<dyn Mixer>)> = None;
     let mut player_event_channel: Option<UnboundedReceiver<PlayerEvent>> = None;
+    let mut session_event_channel: Option<SessionEventChannel> = None;
     let mut auto_connect_times: Vec<Instant> = vec![];
     let mut discovery = None;
     let mut connecting: Pin<Box<dyn future::FusedFuture<Output = _>>> = Box::pin(future::pending());
 
+    let event_program = setup
+        .player_event_program
+        .clone()
+        .map(EventProgramRunner::new);
+    let json_events = setup.player_event_json.clone().map(JsonEventWriter::new);
+    let metadata_pipe = setup
+        .metadata_pipe
+        .clone()
+        .map(JsonEventWriter::track_changes);
+    let cover_art = setup
+        .cover_art_path
+        .clone()
+        .map(|path| CoverArtWriter::new(path, event_program.clone()));
+
+    let sync_master = setup.sync_master.as_ref().and_then(|address| {
+        match sync::SyncMaster::bind(address.as_str()) {
+            Ok(master) => Some(master),
+            Err(e) => {
+                warn!("Cannot start sync master on {}: {}", address, e);
+                None
+            }
+        }
+    });
+
+    #[cfg(feature = "mpris")]
+    let mpris =
+        match mpris::Mpris::new(&setup.connect_config.name, setup.connect_config.volume).await {
+            Ok(mpris) => Some(mpris),
+            Err(e) => {
+                warn!("Cannot start MPRIS interface: {}", e);
+                None
+            }
+        };
+
+    #[cfg(feature = "http-api")]
+    let http_api = setup.http_api_address.and_then(|address| {
+        match http_api::HttpApi::start(address, setup.connect_config.volume) {
+            Ok(http_api) => Some(http_api),
+            Err(e) => {
+                warn!("Cannot start HTTP API: {}", e);
+                None
+            }
+        }
+    });
+
+    #[cfg(all(feature = "input", target_os = "linux"))]
+    let input = if setup.input_config.is_enabled() {
+        Some(input::Input::start(setup.input_config.clone()))
+    } else {
+        None
+    };
+
+    #[cfg(all(feature = "lirc", unix))]
+    let lirc = setup.lirc_config.clone().map(lirc::Lirc::start);
+
+    #[cfg(feature = "display")]
+    let display = if setup.display_config.is_enabled() {
+        Some(
+            display::Display::start(setup.display_config.clone(), setup.connect_config.volume)
+                .await,
+        )
+    } else {
+        None
+    };
+
+    #[cfg(feature = "scrobble")]
+    let scrobbler = if setup.scrobble_config.is_enabled() {
+        Some(scrobble::Scrobbler::new(setup.scrobble_config.clone()))
+    } else {
+        None
+    };
+
     if setup.enable_discovery {
         let config = setup.connect_config.clone();
-        let device_id = setup.session_config.device_id.clone();
+        let session_config = setup.session_config.clone();
 
         discovery = Some(
-            librespot_connect::discovery::discovery(config, device_id, setup.zeroconf_port)
-                .unwrap(),
+            librespot_connect::discovery::discovery(
+                config,
+                session_config,
+                setup.discovery_config.clone(),
+            )
+            .unwrap(),
         );
     }
 
+    let wait_when_kicked = setup.wait_when_kicked;
     if let Some(credentials) = setup.credentials {
         last_credentials = Some(credentials.clone());
         connecting = Box::pin(
@@ -585,20 +1557,29 @@ async fn main() {
         );
     }
 
+    let mut terminate = service::TerminateSignal::new().expect("Cannot listen for SIGTERM");
+    let watchdog_interval = service::systemd::watchdog_interval();
+    let mut watchdog = tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(1)));
+
+    service::systemd::ready();
+
     loop {
         tokio::select! {
-            credentials = async { discovery.as_mut().unwrap().next().await }, if discovery.is_some() => {
-                match credentials {
-                    Some(credentials) => {
+            event = async { discovery.as_mut().unwrap().next().await }, if discovery.is_some() => {
+                match event {
+                    Some(DiscoveryEvent::CredentialsReceived { credentials, .. }) => {
                         last_credentials = Some(credentials.clone());
                         auto_connect_times.clear();
 
                         if let Some(spirc) = spirc.take() {
                             spirc.shutdown();
                         }
-                        if let Some(spirc_task) = spirc_task.take() {
-                            // Continue shutdown in its own task
-                            tokio::spawn(spirc_task);
+                        // Its player and mixer are used for the new session. A previous
+                        // switch that is still stopping its Spirc keeps going, as no new Spirc
+                        // is created until it has finished.
+                        if let Some(task) = spirc_task.take() {
+                            stopping_spirc_task =
+                                Some(Box::pin(tokio::time::timeout(SPIRC_SHUTDOWN_TIMEOUT, task)));
                         }
 
                         connecting = Box::pin(Session::connect(
@@ -607,62 +1588,179 @@ async fn main() {
                             setup.cache.clone(),
                         ).fuse());
                     },
+                    Some(DiscoveryEvent::ActiveUserChanged { username }) => {
+                        debug!("Active zeroconf user is now {:?}", username);
+                    }
+                    // Already logged by discovery.
+                    Some(DiscoveryEvent::Error(_)) => (),
                     None => {
                         warn!("Discovery stopped!");
                         discovery = None;
                     }
                 }
             },
-            session = &mut connecting, if !connecting.is_terminated() => match session {
+            // Waits for the Spirc of the previous user to hand back the player.
+            session = &mut connecting, if !connecting.is_terminated() && stopping_spirc_task.is_none() => match session {
                 Ok(session) => {
-                    let mixer_config = setup.mixer_config.clone();
-                    let mixer = (setup.mixer)(Some(mixer_config));
-                    let player_config = setup.player_config.clone();
-                    let connect_config = setup.connect_config.clone();
-
-                    let audio_filter = mixer.get_audio_filter();
-                    let format = setup.format;
-                    let backend = setup.backend;
-                    let device = setup.device.clone();
-                    let (player, event_channel) =
-                        Player::new(player_config, session.clone(), audio_filter, move || {
-                            (backend)(device, format)
+                    if let Some(path) = &setup.export_credentials {
+                        let exported = create_private_file(Path::new(path)).and_then(|mut file| {
+                            file.write_all(session.credentials().export().as_bytes())
                         });
+                        if let Err(e) = exported {
+                            warn!("Cannot export credentials to {}: {}", path, e);
+                        }
+                    }
 
-                    if setup.emit_sink_events {
-                        if let Some(player_event_program) = setup.player_event_program.clone() {
-                            player.set_sink_event_callback(Some(Box::new(move |sink_status| {
-                                match emit_sink_event(sink_status, &player_event_program) {
-                                    Ok(e) if e.success() => (),
-                                    Ok(e) => {
-                                        if let Some(code) = e.code() {
-                                            warn!("Sink event prog returned exit code {}", code);
-                                        } else {
-                                            warn!("Sink event prog returned failure");
-                                        }
+                    let connect_config = setup.connect_config.clone();
+                    let (player, mixer) = match playback.take() {
+                        // Switching users keeps the sink open.
+                        Some((player, mixer)) => {
+                            player.set_session(session.clone());
+                            #[cfg(feature = "scrobble")]
+                            {
+                                if let Some(scrobbler) = &scrobbler {
+                                    scrobbler.set_session(session.clone());
+                                }
+                            }
+                            (player, mixer)
+                        }
+                        None => {
+                            let mixer_config = setup.mixer_config.clone();
+                            let mixer = (setup.mixer)(Some(mixer_config));
+                            let player_config = setup.player_config.clone();
+                            let audio_filter = mixer.get_audio_filter();
+                            let format = setup.format;
+                            let backend = setup.backend;
+                            let device = setup.device.clone();
+                            let sync_master = sync_master.clone();
+                            let sync_latency = setup.sync_latency;
+                            let pace_output = setup.pace_output;
+                            let (player, event_channel) =
+                                Player::new(player_config, session.clone(), audio_filter, move || -> Box<dyn Sink> {
+                                    let mut sink = (backend)(device, format);
+                                    if let Some(lead) = pace_output {
+                                        sink = Box::new(PacedSink::new(sink, pacing::DEFAULT_CHUNK, lead));
                                     }
-                                    Err(e) => {
-                                        warn!("Emitting sink event failed: {}", e);
+                                    match sync_master {
+                                        Some(master) => Box::new(SyncSink::new(sink, master, sync_latency)),
+                                        None => sink,
                                     }
+                                });
+
+                            if setup.emit_sink_events {
+                                if let Some(player_event_program) = setup.player_event_program.clone() {
+                                    player.set_sink_event_callback(Some(Box::new(move |sink_status| {
+                                        match emit_sink_event(sink_status, &player_event_program) {
+                                            Ok(e) if e.success() => (),
+                                            Ok(e) => {
+                                                if let Some(code) = e.code() {
+                                                    warn!("Sink event prog returned exit code {}", code);
+                                                } else {
+                                                    warn!("Sink event prog returned failure");
+                                                }
+                                            }
+                                            Err(e) => {
+                                                warn!("Emitting sink event failed: {}", e);
+                                            }
+                                        }
+                                    })));
+                                }
+                            };
+
+                            #[cfg(feature = "scrobble")]
+                            {
+                                if let Some(scrobbler) = &scrobbler {
+                                    scrobbler.set_player(session.clone(), player.get_listen_channel());
                                 }
-                            })));
+                            }
+
+                            player_event_channel = Some(event_channel);
+                            (player, mixer)
                         }
                     };
 
-                    let (spirc_, spirc_task_) = Spirc::new(connect_config, session, player, mixer);
+                    let (spirc_, spirc_task_) =
+                        Spirc::new(connect_config, session.clone(), player, mixer);
+
+                    #[cfg(feature = "mpris")]
+                    {
+                        if let Some(mpris) = &mpris {
+                            mpris.set_spirc(session.clone(), spirc_.clone());
+                        }
+                    }
+
+                    #[cfg(feature = "http-api")]
+                    {
+                        if let Some(http_api) = &http_api {
+                            http_api.set_spirc(session.clone(), spirc_.clone());
+                        }
+                    }
+
+                    #[cfg(all(feature = "input", target_os = "linux"))]
+                    {
+                        if let Some(input) = &input {
+                            input.set_spirc(spirc_.clone());
+                        }
+                    }
+
+                    #[cfg(all(feature = "lirc", unix))]
+                    {
+                        if let Some(lirc) = &lirc {
+                            lirc.set_spirc(spirc_.clone());
+                        }
+                    }
+
+                    #[cfg(feature = "display")]
+                    {
+                        if let Some(display) = &display {
+                            display.set_session(session.clone());
+                        }
+                    }
+
+                    service::systemd::status(&format!("Connected as {}", session.username()));
+                    session_event_channel = Some(session.get_event_channel());
+                    if let Some(previous) = current_session.take() {
+                        // Reconnected after the connection was lost: subscriptions continue.
+                        if previous.is_invalid() && previous.username() == session.username() {
+                            previous.set_successor(&session);
+                        }
+                    }
+                    current_session = Some(session);
 
                     spirc = Some(spirc_);
                     spirc_task = Some(Box::pin(spirc_task_));
-                    player_event_channel = Some(event_channel);
                 },
                 Err(e) => {
                     warn!("Connection failed: {}", e);
                 }
             },
-            _ = async { spirc_task.as_mut().unwrap().await }, if spirc_task.is_some() => {
+            stopped = async { stopping_spirc_task.as_mut().unwrap().await }, if stopping_spirc_task.is_some() => {
+                stopping_spirc_task = None;
+                match stopped {
+                    Ok(resources) => playback = Some(resources),
+                    Err(_) => warn!("Previous Spirc didn't shut down, opening the sink again"),
+                }
+            },
+            resources = async { spirc_task.as_mut().unwrap().await }, if spirc_task.is_some() => {
                 spirc_task = None;
-
-                warn!("Spirc shut down unexpectedly");
+                playback = Some(resources);
+
+                let kicked = current_session
+                    .as_ref()
+                    .and_then(Session::kick_reason)
+                    .filter(|_| wait_when_kicked);
+                if spirc.as_ref().map_or(false, Spirc::logged_out) {
+                    info!("Logged out by a Connect client");
+                    last_credentials = None;
+                    if let Some(discovery) = &discovery {
+                        discovery.set_active_user(None);
+                    }
+                } else if let Some(reason) = kicked {
+                    info!("Session kicked because {}, waiting to be selected again", reason);
+                    last_credentials = None;
+                } else {
+                    warn!("Spirc shut down unexpectedly");
+                }
                 while !auto_connect_times.is_empty()
                     && ((Instant::now() - auto_connect_times[0]).as_secs() > 600)
                 {
@@ -683,33 +1781,89 @@ async fn main() {
                     }
                 }
             },
+            event = async { session_event_channel.as_mut().unwrap().recv().await }, if session_event_channel.is_some() => match event {
+                Some(SessionEvent::Kicked { .. }) => {
+                    // The Spirc task ends when the connection is closed, but keeps running when
+                    // only the playback was taken over.
+                    if wait_when_kicked {
+                        if let Some(spirc) = &spirc {
+                            spirc.shutdown();
+                        }
+                        if discovery.is_none() {
+                            warn!("Discovery is disabled, the session won't be resumed");
+                        }
+                    }
+                },
+                // The Spirc task ends with the session and a new one is connected.
+                Some(SessionEvent::ConnectionTimedOut) => (),
+                None => {
+                    session_event_channel = None;
+                }
+            },
             event = async { player_event_channel.as_mut().unwrap().recv().await }, if player_event_channel.is_some() => match event {
                 Some(event) => {
-                    if let Some(program) = &setup.player_event_program {
-                        if let Some(child) = run_program_on_events(event, program) {
-                            let mut child = child.expect("program failed to start");
-
-                            tokio::spawn(async move {
-                                match child.wait().await  {
-                                    Ok(status) if !status.success() => error!("child exited with status {:?}", status.code()),
-                                    Err(e) => error!("failed to wait on child process: {}", e),
-                                    _ => {}
-                                }
-                            });
+                    #[cfg(feature = "mpris")]
+                    {
+                        if let Some(mpris) = &mpris {
+                            mpris.handle_event(&event);
                         }
                     }
+
+                    #[cfg(feature = "http-api")]
+                    {
+                        if let Some(http_api) = &http_api {
+                            http_api.handle_event(&event);
+                        }
+                    }
+
+                    #[cfg(feature = "display")]
+                    {
+                        if let Some(display) = &display {
+                            display.handle_event(&event);
+                        }
+                    }
+
+                    #[cfg(feature = "scrobble")]
+                    {
+                        if let Some(scrobbler) = &scrobbler {
+                            scrobbler.handle_event(&event);
+                        }
+                    }
+
+                    if let Some(json_events) = &json_events {
+                        json_events.write(event.clone(), current_session.clone());
+                    }
+
+                    if let Some(metadata_pipe) = &metadata_pipe {
+                        metadata_pipe.write(event.clone(), current_session.clone());
+                    }
+
+                    if let Some(cover_art) = &cover_art {
+                        cover_art.write(event.clone(), current_session.clone());
+                    }
+
+                    if let Some(event_program) = &event_program {
+                        event_program.run(event, current_session.clone());
+                    }
                 },
                 None => {
                     player_event_channel = None;
                 }
             },
+            _ = watchdog.tick(), if watchdog_interval.is_some() => {
+                service::systemd::watchdog();
+            },
             _ = tokio::signal::ctrl_c() => {
                 break;
+            },
+            _ = terminate.recv() => {
+                break;
             }
         }
     }
 
     info!("Gracefully shutting down");
+    service::systemd::stopping();
 
     // Shutdown spirc if necessary
     if let Some(spirc) = spirc {
@@ -722,4 +1876,8 @@ async fn main() {
             }
         }
     }
+
+    if let Some(pidfile) = &setup.pidfile {
+        service::remove_pidfile(pidfile);
+    }
 }
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use librespot::connect::spirc::Spirc;
+use librespot::core::session::Session;
+use librespot::playback::player::PlayerEvent;
+use log::{debug, warn};
+use zbus::zvariant::{ObjectPath, Value};
+use zbus::{dbus_interface, fdo, Connection, ConnectionBuilder, SignalContext};
+
+use crate::now_playing::NowPlaying;
+use crate::track_metadata::TrackMetadata;
+
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+struct MprisState {
+    spirc: Option<Spirc>,
+    session: Option<Session>,
+    now_playing: NowPlaying,
+}
+
+fn track_path(now_playing: &NowPlaying) -> ObjectPath<'static> {
+    let path = match now_playing.track_id {
+        Some(id) => format!("/org/librespot/track/{}", id.to_base62()),
+        None => NO_TRACK.to_owned(),
+    };
+    ObjectPath::try_from(path).unwrap_or_else(|_| ObjectPath::from_static_str_unchecked(NO_TRACK))
+}
+
+struct MediaPlayer2 {
+    identity: String,
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2")]
+impl MediaPlayer2 {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[dbus_interface(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn identity(&self) -> String {
+        self.identity.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[dbus_interface(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+struct MprisPlayer {
+    state: Arc<Mutex<MprisState>>,
+}
+
+impl MprisPlayer {
+    fn spirc(&self) -> fdo::Result<Spirc> {
+        self.state
+            .lock()
+            .unwrap()
+            .spirc
+            .clone()
+            .ok_or_else(|| fdo::Error::Failed("Not connected".to_owned()))
+    }
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MprisPlayer {
+    fn next(&self) -> fdo::Result<()> {
+        self.spirc()?.next();
+        Ok(())
+    }
+
+    fn previous(&self) -> fdo::Result<()> {
+        self.spirc()?.prev();
+        Ok(())
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        self.spirc()?.pause();
+        Ok(())
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        self.spirc()?.play_pause();
+        Ok(())
+    }
+
+    fn stop(&self) -> fdo::Result<()> {
+        self.spirc()?.pause();
+        Ok(())
+    }
+
+    fn play(&self) -> fdo::Result<()> {
+        self.spirc()?.play();
+        Ok(())
+    }
+
+    fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let spirc = self.spirc()?;
+        let (position_ms, duration_ms) = {
+            let state = self.state.lock().unwrap();
+            let now_playing = &state.now_playing;
+            (
+                now_playing.position_ms() as i64,
+                now_playing.duration_ms as i64,
+            )
+        };
+
+        let position_ms = position_ms + offset / 1000;
+        if position_ms > duration_ms {
+            spirc.next();
+        } else {
+            spirc.seek(position_ms.max(0) as u32);
+        }
+        Ok(())
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        let spirc = self.spirc()?;
+        let state = self.state.lock().unwrap();
+        let now_playing = &state.now_playing;
+
+        // Requests for a track that isn't current anymore must be ignored.
+        if track_id != track_path(now_playing) || position < 0 {
+            return Ok(());
+        }
+
+        let position_ms = position / 1000;
+        if position_ms <= now_playing.duration_ms as i64 {
+            spirc.seek(position_ms as u32);
+        }
+        Ok(())
+    }
+
+    fn open_uri(&self, _uri: &str) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "Opening URIs is not supported".to_owned(),
+        ))
+    }
+
+    #[dbus_interface(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    fn playback_status(&self) -> String {
+        self.state
+            .lock()
+            .unwrap()
+            .now_playing
+            .status
+            .as_str()
+            .to_owned()
+    }
+
+    #[dbus_interface(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn metadata(&self) -> HashMap<String, Value<'static>> {
+        let state = self.state.lock().unwrap();
+        let now_playing = &state.now_playing;
+        let mut metadata = HashMap::new();
+
+        metadata.insert("mpris:trackid".to_owned(), track_path(now_playing).into());
+
+        if let Some(id) = now_playing.track_id {
+            metadata.insert("xesam:url".to_owned(), id.to_uri().into());
+            metadata.insert(
+                "mpris:length".to_owned(),
+                (now_playing.duration_ms as i64 * 1000).into(),
+            );
+        }
+
+        if let Some(track) = &now_playing.metadata {
+            metadata.insert("xesam:title".to_owned(), track.name.clone().into());
+            metadata.insert("xesam:album".to_owned(), track.album.clone().into());
+            metadata.insert("xesam:artist".to_owned(), track.artists.clone().into());
+            if let Some(cover_url) = &track.cover_url {
+                metadata.insert("mpris:artUrl".to_owned(), cover_url.clone().into());
+            }
+        }
+
+        metadata
+    }
+
+    #[dbus_interface(property)]
+    fn volume(&self) -> f64 {
+        self.state.lock().unwrap().now_playing.volume as f64 / u16::MAX as f64
+    }
+
+    #[dbus_interface(property)]
+    fn set_volume(&mut self, volume: f64) {
+        if let Ok(spirc) = self.spirc() {
+            let volume = volume.max(0.0).min(1.0);
+            spirc.set_volume((volume * u16::MAX as f64).round() as u16);
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn position(&self) -> i64 {
+        self.state.lock().unwrap().now_playing.position_ms() as i64 * 1000
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// Exposes playback state and controls on the session bus, following the
+/// [MPRIS](https://specifications.freedesktop.org/mpris-spec/latest/) specification.
+#[derive(Clone)]
+pub struct Mpris {
+    connection: Connection,
+    state: Arc<Mutex<MprisState>>,
+}
+
+impl Mpris {
+    pub async fn new(name: &str, volume: u16) -> zbus::Result<Mpris> {
+        let state = Arc::new(Mutex::new(MprisState {
+            spirc: None,
+            session: None,
+            now_playing: NowPlaying::new(volume),
+        }));
+
+        let connection = ConnectionBuilder::session()?
+            .name(format!(
+                "org.mpris.MediaPlayer2.librespot.instance{}",
+                std::process::id()
+            ))?
+            .serve_at(
+                MPRIS_PATH,
+                MediaPlayer2 {
+                    identity: name.to_owned(),
+                },
+            )?
+            .serve_at(
+                MPRIS_PATH,
+                MprisPlayer {
+                    state: state.clone(),
+                },
+            )?
+            .build()
+            .await?;
+
+        Ok(Mpris { connection, state })
+    }
+
+    /// Routes the controls to a new Spirc, e.g. after reconnecting.
+    pub fn set_spirc(&self, session: Session, spirc: Spirc) {
+        let mut state = self.state.lock().unwrap();
+        state.session = Some(session);
+        state.spirc = Some(spirc);
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let (update, session) = {
+            let mut state = self.state.lock().unwrap();
+            match state.now_playing.update(event) {
+                Some(update) => (update, state.session.clone()),
+                None => return,
+            }
+        };
+
+        let mpris = self.clone();
+        tokio::spawn(async move {
+            let seeked = update
+                .seeked_ms
+                .map(|position_ms| position_ms as i64 * 1000);
+            if let Err(e) = mpris.notify(seeked).await {
+                warn!("Cannot send MPRIS notification: {}", e);
+            }
+
+            if let (Some(track_id), Some(session)) = (update.new_track, session) {
+                match TrackMetadata::get(&session, track_id).await {
+                    Ok(metadata) => {
+                        let changed = mpris
+                            .state
+                            .lock()
+                            .unwrap()
+                            .now_playing
+                            .set_metadata(metadata);
+
+                        if changed {
+                            if let Err(e) = mpris.notify(None).await {
+                                warn!("Cannot send MPRIS notification: {}", e);
+                            }
+                        }
+                    }
+                    Err(_) => debug!("Cannot fetch metadata of <{}>", track_id.to_uri()),
+                }
+            }
+        });
+    }
+
+    async fn notify(&self, seeked: Option<i64>) -> zbus::Result<()> {
+        let iface_ref = self
+            .connection
+            .object_server()
+            .interface::<_, MprisPlayer>(MPRIS_PATH)
+            .await?;
+        let ctxt = iface_ref.signal_context();
+        let iface = iface_ref.get().await;
+
+        iface.playback_status_changed(ctxt).await?;
+        iface.metadata_changed(ctxt).await?;
+        iface.volume_changed(ctxt).await?;
+
+        if let Some(position) = seeked {
+            MprisPlayer::seeked(ctxt, position).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::Instant;
+
+use librespot::core::spotify_id::SpotifyId;
+use librespot::playback::player::PlayerEvent;
+
+use crate::track_metadata::TrackMetadata;
+
+// A position reported by the player is considered a seek if it deviates this much from
+// the position we expected.
+const SEEK_THRESHOLD_MS: i64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+impl PlaybackStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PlaybackStatus::Playing => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Stopped => "Stopped",
+        }
+    }
+}
+
+/// What changed when applying a player event.
+#[derive(Debug, Default)]
+pub struct Update {
+    /// The player jumped to this position.
+    pub seeked_ms: Option<u32>,
+    /// A different track is playing now, its metadata still needs to be fetched.
+    pub new_track: Option<SpotifyId>,
+}
+
+/// Keeps track of the playback state as reported by the player's events.
+pub struct NowPlaying {
+    pub status: PlaybackStatus,
+    pub track_id: Option<SpotifyId>,
+    pub metadata: Option<TrackMetadata>,
+    pub duration_ms: u32,
+    pub volume: u16,
+    position_ms: u32,
+    position_updated: Instant,
+}
+
+impl NowPlaying {
+    pub fn new(volume: u16) -> NowPlaying {
+        NowPlaying {
+            status: PlaybackStatus::Stopped,
+            track_id: None,
+            metadata: None,
+            duration_ms: 0,
+            volume,
+            position_ms: 0,
+            position_updated: Instant::now(),
+        }
+    }
+
+    pub fn position_ms(&self) -> u32 {
+        match self.status {
+            PlaybackStatus::Playing => {
+                let elapsed = self.position_updated.elapsed().as_millis() as u32;
+                self.position_ms.saturating_add(elapsed)
+            }
+            _ => self.position_ms,
+        }
+    }
+
+    fn set_position(&mut self, position_ms: u32) {
+        self.position_ms = position_ms;
+        self.position_updated = Instant::now();
+    }
+
+    /// Applies a player event, returns `None` if it doesn't affect the playback state.
+    pub fn update(&mut self, event: &PlayerEvent) -> Option<Update> {
+        let mut update = Update::default();
+
+        let track_id = match *event {
+            PlayerEvent::Stopped { track_id, .. } => {
+                self.status = PlaybackStatus::Stopped;
+                self.set_position(0);
+                Some(track_id)
+            }
+            PlayerEvent::Started { track_id, .. }
+            | PlayerEvent::Changed {
+                new_track_id: track_id,
+                ..
+            }
+            | PlayerEvent::Loading { track_id, .. } => Some(track_id),
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            }
+            | PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                let expected_ms = self.position_ms() as i64;
+                if (position_ms as i64 - expected_ms).abs() > SEEK_THRESHOLD_MS {
+                    update.seeked_ms = Some(position_ms);
+                }
+
+                self.status = match event {
+                    PlayerEvent::Playing { .. } => PlaybackStatus::Playing,
+                    _ => PlaybackStatus::Paused,
+                };
+                self.duration_ms = duration_ms;
+                self.set_position(position_ms);
+                Some(track_id)
+            }
+            PlayerEvent::VolumeSet { volume } => {
+                self.volume = volume;
+                None
+            }
+            _ => return None,
+        };
+
+        if let Some(track_id) = track_id {
+            if self.track_id != Some(track_id) {
+                self.track_id = Some(track_id);
+                self.metadata = None;
+                update.new_track = Some(track_id);
+            }
+        }
+
+        Some(update)
+    }
+
+    /// Stores the metadata fetched for a track, unless another track is playing by now.
+    pub fn set_metadata(&mut self, metadata: TrackMetadata) -> bool {
+        if self.track_id != Some(metadata.id) {
+            return false;
+        }
+
+        self.duration_ms = metadata.duration_ms;
+        self.metadata = Some(metadata);
+        true
+    }
+}
@@ -1,13 +1,29 @@
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
 use librespot::playback::player::PlayerEvent;
 use librespot::playback::player::SinkStatus;
-use log::info;
+use log::{debug, error, info, warn};
+use serde_json::json;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::process::{Child as AsyncChild, Command as AsyncCommand};
+use tokio::sync::mpsc;
+use tokio::sync::Mutex as AsyncMutex;
 
 use std::collections::HashMap;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::process::{Command, ExitStatus};
+use std::sync::{Arc, Mutex};
 
-pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Result<AsyncChild>> {
+use crate::track_metadata::{fetch_cover, TrackMetadata};
+
+pub fn run_program_on_events(
+    event: PlayerEvent,
+    metadata: Option<&TrackMetadata>,
+    onevent: &str,
+) -> Option<io::Result<AsyncChild>> {
     let mut env_vars = HashMap::new();
     match event {
         PlayerEvent::Changed {
@@ -56,17 +72,519 @@ pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Option<io::Re
             env_vars.insert("PLAYER_EVENT", "volume_set".to_string());
             env_vars.insert("VOLUME", volume.to_string());
         }
+        PlayerEvent::TrackChanged {
+            track_id,
+            format,
+            normalisation_data,
+            ..
+        } => {
+            env_vars.insert("PLAYER_EVENT", "track_changed".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+            env_vars.insert("FORMAT", format!("{:?}", format));
+            if let Some(data) = normalisation_data {
+                env_vars.insert("TRACK_GAIN_DB", data.track_gain_db.to_string());
+                env_vars.insert("TRACK_PEAK", data.track_peak.to_string());
+                env_vars.insert("ALBUM_GAIN_DB", data.album_gain_db.to_string());
+                env_vars.insert("ALBUM_PEAK", data.album_peak.to_string());
+            }
+        }
+        PlayerEvent::FormatChanged {
+            track_id,
+            old_format,
+            new_format,
+            ..
+        } => {
+            env_vars.insert("PLAYER_EVENT", "format_changed".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+            env_vars.insert("OLD_FORMAT", format!("{:?}", old_format));
+            env_vars.insert("FORMAT", format!("{:?}", new_format));
+        }
+        PlayerEvent::TrackFiltered { track_id, .. } => {
+            env_vars.insert("PLAYER_EVENT", "track_filtered".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+        }
+        PlayerEvent::TrackRelinked {
+            track_id,
+            relinked_id,
+            ..
+        } => {
+            env_vars.insert("PLAYER_EVENT", "track_relinked".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+            env_vars.insert("RELINKED_TRACK_ID", relinked_id.to_base62());
+        }
+        PlayerEvent::OutputChanged { track_id, .. } => {
+            env_vars.insert("PLAYER_EVENT", "output_changed".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+        }
+        PlayerEvent::SinkStalled {
+            track_id, paused, ..
+        } => {
+            env_vars.insert("PLAYER_EVENT", "sink_stalled".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+            env_vars.insert("PAUSED", paused.to_string());
+        }
+        PlayerEvent::SinkRecovered { track_id, .. } => {
+            env_vars.insert("PLAYER_EVENT", "sink_recovered".to_string());
+            env_vars.insert("TRACK_ID", track_id.to_base62());
+        }
+        PlayerEvent::TrackTransition { from, to, reason } => {
+            env_vars.insert("PLAYER_EVENT", "track_transition".to_string());
+            env_vars.insert("OLD_TRACK_ID", from.to_base62());
+            if let Some(to) = to {
+                env_vars.insert("TRACK_ID", to.to_base62());
+            }
+            env_vars.insert("REASON", reason.name().to_string());
+        }
         _ => return None,
     }
 
-    let mut v: Vec<&str> = onevent.split_whitespace().collect();
+    if let Some(metadata) = metadata {
+        env_vars.insert("NAME", metadata.name.clone());
+        env_vars.insert("ARTISTS", metadata.artists.join("\n"));
+        env_vars.insert("ALBUM", metadata.album.clone());
+        env_vars
+            .entry("DURATION_MS")
+            .or_insert_with(|| metadata.duration_ms.to_string());
+        if let Some(cover_url) = &metadata.cover_url {
+            env_vars.insert("COVER_URL", cover_url.clone());
+        }
+    }
+
+    Some(spawn_program(onevent, &env_vars))
+}
+
+fn spawn_program(program: &str, env_vars: &HashMap<&str, String>) -> io::Result<AsyncChild> {
+    let mut v: Vec<&str> = program.split_whitespace().collect();
     info!("Running {:?} with environment variables {:?}", v, env_vars);
-    Some(
-        AsyncCommand::new(&v.remove(0))
-            .args(&v)
-            .envs(env_vars.iter())
-            .spawn(),
-    )
+    AsyncCommand::new(&v.remove(0))
+        .args(&v)
+        .envs(env_vars.iter())
+        .spawn()
+}
+
+/// Describes an event as JSON, using the same names as the environment variables passed to
+/// the `--onevent` program.
+pub fn event_to_json(event: &PlayerEvent) -> Option<serde_json::Value> {
+    let value = match *event {
+        PlayerEvent::Changed {
+            old_track_id,
+            new_track_id,
+        } => json!({
+            "event": "changed",
+            "old_track_id": old_track_id.to_base62(),
+            "track_id": new_track_id.to_base62(),
+        }),
+        PlayerEvent::Started { track_id, .. } => json!({
+            "event": "started",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::Stopped { track_id, .. } => json!({
+            "event": "stopped",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::Playing {
+            track_id,
+            duration_ms,
+            position_ms,
+            ..
+        } => json!({
+            "event": "playing",
+            "track_id": track_id.to_base62(),
+            "duration_ms": duration_ms,
+            "position_ms": position_ms,
+        }),
+        PlayerEvent::Paused {
+            track_id,
+            duration_ms,
+            position_ms,
+            ..
+        } => json!({
+            "event": "paused",
+            "track_id": track_id.to_base62(),
+            "duration_ms": duration_ms,
+            "position_ms": position_ms,
+        }),
+        PlayerEvent::Preloading { track_id, .. } => json!({
+            "event": "preloading",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::VolumeSet { volume } => json!({
+            "event": "volume_set",
+            "volume": volume,
+        }),
+        PlayerEvent::TrackChanged {
+            track_id,
+            format,
+            normalisation_data,
+            ..
+        } => json!({
+            "event": "track_changed",
+            "track_id": track_id.to_base62(),
+            "format": format!("{:?}", format),
+            "track_gain_db": normalisation_data.map(|data| data.track_gain_db),
+            "track_peak": normalisation_data.map(|data| data.track_peak),
+            "album_gain_db": normalisation_data.map(|data| data.album_gain_db),
+            "album_peak": normalisation_data.map(|data| data.album_peak),
+        }),
+        PlayerEvent::FormatChanged {
+            track_id,
+            old_format,
+            new_format,
+            ..
+        } => json!({
+            "event": "format_changed",
+            "track_id": track_id.to_base62(),
+            "old_format": format!("{:?}", old_format),
+            "format": format!("{:?}", new_format),
+        }),
+        PlayerEvent::TrackFiltered { track_id, .. } => json!({
+            "event": "track_filtered",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::TrackRelinked {
+            track_id,
+            relinked_id,
+            ..
+        } => json!({
+            "event": "track_relinked",
+            "track_id": track_id.to_base62(),
+            "relinked_track_id": relinked_id.to_base62(),
+        }),
+        PlayerEvent::OutputChanged { track_id, .. } => json!({
+            "event": "output_changed",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::SinkStalled {
+            track_id, paused, ..
+        } => json!({
+            "event": "sink_stalled",
+            "track_id": track_id.to_base62(),
+            "paused": paused,
+        }),
+        PlayerEvent::SinkRecovered { track_id, .. } => json!({
+            "event": "sink_recovered",
+            "track_id": track_id.to_base62(),
+        }),
+        PlayerEvent::TrackTransition { from, to, reason } => json!({
+            "event": "track_transition",
+            "old_track_id": from.to_base62(),
+            "track_id": to.map(|to| to.to_base62()),
+            "reason": reason.name(),
+        }),
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+fn event_track_id(event: &PlayerEvent) -> Option<SpotifyId> {
+    match *event {
+        PlayerEvent::Changed { new_track_id, .. } => Some(new_track_id),
+        PlayerEvent::Started { track_id, .. }
+        | PlayerEvent::Stopped { track_id, .. }
+        | PlayerEvent::Playing { track_id, .. }
+        | PlayerEvent::Paused { track_id, .. }
+        | PlayerEvent::Preloading { track_id, .. }
+        | PlayerEvent::TrackChanged { track_id, .. }
+        | PlayerEvent::FormatChanged { track_id, .. }
+        | PlayerEvent::TrackFiltered { track_id, .. }
+        | PlayerEvent::TrackRelinked { track_id, .. }
+        | PlayerEvent::OutputChanged { track_id, .. }
+        | PlayerEvent::SinkStalled { track_id, .. }
+        | PlayerEvent::SinkRecovered { track_id, .. } => Some(track_id),
+        // The metadata is of the track the reason applies to.
+        PlayerEvent::TrackTransition { from, .. } => Some(from),
+        _ => None,
+    }
+}
+
+/// Remembers the metadata of the last track, as most events are about the same track.
+#[derive(Default)]
+struct MetadataCache(Option<TrackMetadata>);
+
+impl MetadataCache {
+    async fn get(
+        &mut self,
+        event: &PlayerEvent,
+        session: Option<Session>,
+    ) -> Option<&TrackMetadata> {
+        let track_id = event_track_id(event)?;
+        if self.0.as_ref().map(|metadata| metadata.id) != Some(track_id) {
+            self.0 = TrackMetadata::get(&session?, track_id).await.ok();
+        }
+        self.0.as_ref()
+    }
+}
+
+/// Shares the metadata of the last track between the events handled concurrently. The
+/// metadata is fetched once, the other events about the track wait for it.
+#[derive(Clone, Default)]
+struct SharedMetadata(Arc<Mutex<Option<(SpotifyId, MetadataEntry)>>>);
+
+type MetadataEntry = Arc<AsyncMutex<Option<TrackMetadata>>>;
+
+impl SharedMetadata {
+    async fn get(&self, event: &PlayerEvent, session: Option<Session>) -> Option<TrackMetadata> {
+        let track_id = event_track_id(event)?;
+        let entry = {
+            let mut last = self.0.lock().unwrap();
+            match &*last {
+                Some((id, entry)) if *id == track_id => entry.clone(),
+                _ => {
+                    let entry = MetadataEntry::default();
+                    *last = Some((track_id, entry.clone()));
+                    entry
+                }
+            }
+        };
+
+        // The lock is fair, so the events of a track are still handled in order.
+        let mut metadata = entry.lock().await;
+        if metadata.is_none() {
+            *metadata = TrackMetadata::get(&session?, track_id).await.ok();
+        }
+        metadata.clone()
+    }
+}
+
+/// Runs the `--onevent` program for player events, passing the metadata of the track
+/// along with the event.
+#[derive(Clone)]
+pub struct EventProgramRunner {
+    runs: mpsc::UnboundedSender<ProgramRun>,
+}
+
+enum ProgramRun {
+    Event(PlayerEvent, Option<Session>),
+    // An event that isn't a player event, with its environment variables.
+    Env(HashMap<&'static str, String>),
+}
+
+impl EventProgramRunner {
+    pub fn new(program: String) -> EventProgramRunner {
+        let (tx, mut rx) = mpsc::unbounded_channel::<ProgramRun>();
+
+        tokio::spawn(async move {
+            let shared_metadata = SharedMetadata::default();
+
+            while let Some(run) = rx.recv().await {
+                let shared_metadata = shared_metadata.clone();
+                let program = program.clone();
+
+                // Waiting for the metadata of a track only holds back the events of that
+                // track, the others start the program right away.
+                tokio::spawn(async move {
+                    let child = match run {
+                        ProgramRun::Event(event, session) => {
+                            let metadata = shared_metadata.get(&event, session).await;
+                            run_program_on_events(event, metadata.as_ref(), &program)
+                        }
+                        ProgramRun::Env(env_vars) => Some(spawn_program(&program, &env_vars)),
+                    };
+
+                    match child {
+                        Some(Ok(mut child)) => match child.wait().await {
+                            Ok(status) if !status.success() => {
+                                error!("child exited with status {:?}", status.code())
+                            }
+                            Err(e) => error!("failed to wait on child process: {}", e),
+                            _ => {}
+                        },
+                        Some(Err(e)) => error!("program failed to start: {}", e),
+                        None => {}
+                    }
+                });
+            }
+        });
+
+        EventProgramRunner { runs: tx }
+    }
+
+    pub fn run(&self, event: PlayerEvent, session: Option<Session>) {
+        let _ = self.runs.send(ProgramRun::Event(event, session));
+    }
+
+    /// Runs the program with the given environment variables, for the events that don't
+    /// come from the player.
+    pub fn run_with_env(&self, env_vars: HashMap<&'static str, String>) {
+        let _ = self.runs.send(ProgramRun::Env(env_vars));
+    }
+}
+
+/// Writes player events as newline-delimited JSON, including the metadata of the track,
+/// to a file (e.g. a named pipe) or to stdout if the path is `-`.
+pub struct JsonEventWriter {
+    events: mpsc::UnboundedSender<(PlayerEvent, Option<Session>)>,
+}
+
+impl JsonEventWriter {
+    pub fn new(path: String) -> JsonEventWriter {
+        Self::with_filter(path, false)
+    }
+
+    /// Writes only the `track_changed` events, with a `title` of the artists and the name
+    /// of the track, e.g. for an encoder of the pipe or subprocess backend to update the
+    /// title of its stream.
+    pub fn track_changes(path: String) -> JsonEventWriter {
+        Self::with_filter(path, true)
+    }
+
+    fn with_filter(path: String, track_changes_only: bool) -> JsonEventWriter {
+        let (tx, mut rx) = mpsc::unbounded_channel::<(PlayerEvent, Option<Session>)>();
+
+        tokio::spawn(async move {
+            let mut output: Option<Pin<Box<dyn AsyncWrite + Send>>> = None;
+            let mut metadata_cache = MetadataCache::default();
+
+            while let Some((event, session)) = rx.recv().await {
+                if track_changes_only && !matches!(event, PlayerEvent::TrackChanged { .. }) {
+                    continue;
+                }
+
+                let mut json = match event_to_json(&event) {
+                    Some(json) => json,
+                    None => continue,
+                };
+
+                if let Some(metadata) = metadata_cache.get(&event, session).await {
+                    json["track"] = metadata.to_json();
+                    if track_changes_only {
+                        json["title"] =
+                            format!("{} - {}", metadata.artists.join(", "), metadata.name).into();
+                    }
+                }
+
+                let mut line = json.to_string();
+                line.push('\n');
+
+                if output.is_none() {
+                    output = match open_event_output(&path).await {
+                        Ok(output) => Some(output),
+                        Err(e) => {
+                            warn!("Cannot open {} to write events: {}", path, e);
+                            continue;
+                        }
+                    };
+                }
+
+                if let Some(writer) = output.as_mut() {
+                    let result = async {
+                        writer.write_all(line.as_bytes()).await?;
+                        writer.flush().await
+                    };
+
+                    if let Err(e) = result.await {
+                        // The reader of a pipe went away, reopen it for the next event.
+                        warn!("Cannot write event to {}: {}", path, e);
+                        output = None;
+                    }
+                }
+            }
+        });
+
+        JsonEventWriter { events: tx }
+    }
+
+    pub fn write(&self, event: PlayerEvent, session: Option<Session>) {
+        let _ = self.events.send((event, session));
+    }
+}
+
+/// Writes the cover of each track that starts playing to a file, e.g. for a photo frame, and
+/// runs the `--onevent` program with a `cover_changed` event once it is written.
+pub struct CoverArtWriter {
+    events: mpsc::UnboundedSender<(PlayerEvent, Option<Session>)>,
+}
+
+impl CoverArtWriter {
+    pub fn new(path: PathBuf, event_program: Option<EventProgramRunner>) -> CoverArtWriter {
+        let (tx, mut rx) = mpsc::unbounded_channel::<(PlayerEvent, Option<Session>)>();
+
+        tokio::spawn(async move {
+            let mut metadata_cache = MetadataCache::default();
+            let mut last_track_id = None;
+
+            while let Some((event, session)) = rx.recv().await {
+                let track_id = match event {
+                    PlayerEvent::TrackChanged { track_id, .. } => track_id,
+                    _ => continue,
+                };
+                if last_track_id == Some(track_id) {
+                    continue;
+                }
+                last_track_id = Some(track_id);
+
+                let metadata = metadata_cache.get(&event, session.clone()).await;
+                let cover = metadata.and_then(|metadata| metadata.cover.as_ref());
+                let cover = match (cover, &session) {
+                    (Some(cover), Some(session)) => fetch_cover(session, cover).await,
+                    _ => None,
+                };
+
+                let written = match cover {
+                    Some(cover) => match write_cover(&path, &cover).await {
+                        Ok(()) => true,
+                        Err(e) => {
+                            warn!("Cannot write cover to {}: {}", path.display(), e);
+                            false
+                        }
+                    },
+                    None => {
+                        debug!("No cover for <{}>", track_id.to_uri());
+                        false
+                    }
+                };
+                // A stale cover would show the previous track.
+                if !written {
+                    let _ = tokio::fs::remove_file(&path).await;
+                }
+
+                if let Some(event_program) = &event_program {
+                    let mut env_vars = HashMap::new();
+                    env_vars.insert("PLAYER_EVENT", "cover_changed".to_string());
+                    env_vars.insert("TRACK_ID", track_id.to_base62());
+                    if written {
+                        env_vars.insert("COVER_PATH", path.display().to_string());
+                    }
+                    event_program.run_with_env(env_vars);
+                }
+            }
+        });
+
+        CoverArtWriter { events: tx }
+    }
+
+    pub fn write(&self, event: PlayerEvent, session: Option<Session>) {
+        let _ = self.events.send((event, session));
+    }
+}
+
+// Writes to a temporary file first, so readers never see a partially written cover.
+async fn write_cover(path: &Path, cover: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let result = async {
+        tokio::fs::write(&temp_path, cover).await?;
+        tokio::fs::rename(&temp_path, path).await
+    };
+    let result = result.await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+async fn open_event_output(path: &str) -> io::Result<Pin<Box<dyn AsyncWrite + Send>>> {
+    if path == "-" {
+        return Ok(Box::pin(tokio::io::stdout()));
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    Ok(Box::pin(file))
 }
 
 pub fn emit_sink_event(sink_status: SinkStatus, onevent: &str) -> io::Result<ExitStatus> {
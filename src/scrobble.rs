@@ -0,0 +1,366 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use hyper::client::HttpConnector;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use librespot::core::version;
+use librespot::playback::listen::Listen;
+use librespot::playback::player::{ListenChannel, PlayerEvent};
+use log::{debug, warn};
+use md5::{Digest, Md5};
+use serde_json::json;
+
+use crate::track_metadata::TrackMetadata;
+
+const LISTENBRAINZ_URL: &str = "https://api.listenbrainz.org/1/submit-listens";
+const LASTFM_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+// The rules of Last.fm, which ListenBrainz recommends as well: tracks shorter than 30 seconds
+// are not scrobbled, others once they were played for half their duration or 4 minutes.
+const MIN_TRACK_DURATION: Duration = Duration::from_secs(30);
+const MAX_LISTEN_DURATION: Duration = Duration::from_secs(4 * 60);
+
+/// The accounts listens are submitted to.
+#[derive(Clone, Debug, Default)]
+pub struct ScrobbleConfig {
+    /// The user token of a ListenBrainz account.
+    pub listenbrainz_token: Option<String>,
+    pub lastfm: Option<LastFmConfig>,
+}
+
+impl ScrobbleConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.listenbrainz_token.is_some() || self.lastfm.is_some()
+    }
+}
+
+/// A Last.fm API account and the user to scrobble for.
+#[derive(Clone, Debug)]
+pub struct LastFmConfig {
+    pub api_key: String,
+    pub api_secret: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug)]
+struct ScrobbleError(String);
+
+impl fmt::Display for ScrobbleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<hyper::Error> for ScrobbleError {
+    fn from(e: hyper::Error) -> Self {
+        ScrobbleError(e.to_string())
+    }
+}
+
+impl From<hyper::http::Error> for ScrobbleError {
+    fn from(e: hyper::http::Error) -> Self {
+        ScrobbleError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ScrobbleError {
+    fn from(e: serde_json::Error) -> Self {
+        ScrobbleError(e.to_string())
+    }
+}
+
+struct State {
+    session: Option<Session>,
+    // The track last submitted as playing now.
+    now_playing: Option<SpotifyId>,
+}
+
+struct LastFm {
+    config: LastFmConfig,
+    // Requested on the first submission.
+    session_key: tokio::sync::Mutex<Option<String>>,
+}
+
+/// Submits the tracks playing now and the listens of the player to ListenBrainz and Last.fm.
+/// Podcast episodes are not submitted.
+#[derive(Clone)]
+pub struct Scrobbler {
+    client: Client<HttpsConnector<HttpConnector>>,
+    listenbrainz_token: Option<Arc<String>>,
+    lastfm: Option<Arc<LastFm>>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Scrobbler {
+    pub fn new(config: ScrobbleConfig) -> Scrobbler {
+        Scrobbler {
+            client: Client::builder().build(HttpsConnector::with_webpki_roots()),
+            listenbrainz_token: config.listenbrainz_token.map(Arc::new),
+            lastfm: config.lastfm.map(|config| {
+                Arc::new(LastFm {
+                    config,
+                    session_key: tokio::sync::Mutex::new(None),
+                })
+            }),
+            state: Arc::new(Mutex::new(State {
+                session: None,
+                now_playing: None,
+            })),
+        }
+    }
+
+    /// Submits the listens of a new player, whose metadata is fetched with `session`.
+    pub fn set_player(&self, session: Session, mut listens: ListenChannel) {
+        self.set_session(session);
+
+        let scrobbler = self.clone();
+        tokio::spawn(async move {
+            while let Some(listen) = listens.recv().await {
+                scrobbler.submit_listen(listen).await;
+            }
+        });
+    }
+
+    /// Fetches the metadata with `session` after the player switched to it.
+    pub fn set_session(&self, session: Session) {
+        self.state.lock().unwrap().session = Some(session);
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let track_id = match *event {
+            PlayerEvent::Playing { track_id, .. } => track_id,
+            // A repeated track is playing now again.
+            PlayerEvent::EndOfTrack { .. } | PlayerEvent::Stopped { .. } => {
+                self.state.lock().unwrap().now_playing = None;
+                return;
+            }
+            _ => return,
+        };
+
+        let session = {
+            let mut state = self.state.lock().unwrap();
+            if state.now_playing == Some(track_id) {
+                return;
+            }
+            state.now_playing = Some(track_id);
+            state.session.clone()
+        };
+
+        if let Some(session) = session {
+            let scrobbler = self.clone();
+            tokio::spawn(async move { scrobbler.submit_now_playing(session, track_id).await });
+        }
+    }
+
+    async fn submit_now_playing(&self, session: Session, track_id: SpotifyId) {
+        let metadata = match metadata(&session, track_id).await {
+            Some(metadata) => metadata,
+            None => return,
+        };
+
+        if let Some(token) = &self.listenbrainz_token {
+            let payload = json!({
+                "listen_type": "playing_now",
+                "payload": [{ "track_metadata": listenbrainz_metadata(&metadata) }],
+            });
+            if let Err(e) = self.submit_listenbrainz(token, payload).await {
+                warn!("Cannot submit now playing to ListenBrainz: {}", e);
+            }
+        }
+
+        if let Some(lastfm) = &self.lastfm {
+            let mut params = lastfm_track_params(&metadata);
+            params.insert("method", "track.updateNowPlaying".to_string());
+            if let Err(e) = self.submit_lastfm(lastfm, params).await {
+                warn!("Cannot submit now playing to Last.fm: {}", e);
+            }
+        }
+    }
+
+    async fn submit_listen(&self, listen: Listen) {
+        let duration = Duration::from_millis(listen.duration_ms as u64);
+        let listened = Duration::from_millis(listen.listened_ms as u64);
+        if duration < MIN_TRACK_DURATION || listened < (duration / 2).min(MAX_LISTEN_DURATION) {
+            debug!(
+                "Not scrobbling {}, listened for {} of {} ms",
+                listen.track_id.to_base62(),
+                listen.listened_ms,
+                listen.duration_ms
+            );
+            return;
+        }
+
+        let session = self.state.lock().unwrap().session.clone();
+        let metadata = match session {
+            Some(session) => match metadata(&session, listen.track_id).await {
+                Some(metadata) => metadata,
+                None => return,
+            },
+            None => return,
+        };
+        let listened_at = listen
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        if let Some(token) = &self.listenbrainz_token {
+            let payload = json!({
+                "listen_type": "single",
+                "payload": [{
+                    "listened_at": listened_at,
+                    "track_metadata": listenbrainz_metadata(&metadata),
+                }],
+            });
+            if let Err(e) = self.submit_listenbrainz(token, payload).await {
+                warn!("Cannot submit listen to ListenBrainz: {}", e);
+            }
+        }
+
+        if let Some(lastfm) = &self.lastfm {
+            let mut params = lastfm_track_params(&metadata);
+            params.insert("method", "track.scrobble".to_string());
+            params.insert("timestamp", listened_at.to_string());
+            if let Err(e) = self.submit_lastfm(lastfm, params).await {
+                warn!("Cannot scrobble to Last.fm: {}", e);
+            }
+        }
+    }
+
+    async fn submit_listenbrainz(
+        &self,
+        token: &str,
+        payload: serde_json::Value,
+    ) -> Result<(), ScrobbleError> {
+        let request = Request::post(LISTENBRAINZ_URL)
+            .header(AUTHORIZATION, format!("Token {}", token))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()))?;
+        self.send(request).await.map(|_| ())
+    }
+
+    async fn submit_lastfm(
+        &self,
+        lastfm: &LastFm,
+        mut params: BTreeMap<&'static str, String>,
+    ) -> Result<(), ScrobbleError> {
+        let mut session_key = lastfm.session_key.lock().await;
+        if session_key.is_none() {
+            *session_key = Some(self.lastfm_session_key(&lastfm.config).await?);
+        }
+
+        params.insert("sk", session_key.clone().unwrap_or_default());
+        self.call_lastfm(&lastfm.config, params).await.map(|_| ())
+    }
+
+    async fn lastfm_session_key(&self, config: &LastFmConfig) -> Result<String, ScrobbleError> {
+        let mut params = BTreeMap::new();
+        params.insert("method", "auth.getMobileSession".to_string());
+        params.insert("username", config.username.clone());
+        params.insert("password", config.password.clone());
+
+        let response = self.call_lastfm(config, params).await?;
+        response["session"]["key"]
+            .as_str()
+            .map(str::to_owned)
+            .ok_or_else(|| ScrobbleError("no session key in Last.fm response".to_string()))
+    }
+
+    // Signs and sends a call of the Last.fm API, see https://www.last.fm/api/authspec.
+    async fn call_lastfm(
+        &self,
+        config: &LastFmConfig,
+        mut params: BTreeMap<&'static str, String>,
+    ) -> Result<serde_json::Value, ScrobbleError> {
+        params.insert("api_key", config.api_key.clone());
+
+        // The signature covers the parameters ordered by name, but not the format.
+        let mut signature = Md5::new();
+        for (name, value) in &params {
+            signature.update(name.as_bytes());
+            signature.update(value.as_bytes());
+        }
+        signature.update(config.api_secret.as_bytes());
+        let signature = hex::encode(signature.finalize());
+
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&params)
+            .append_pair("api_sig", &signature)
+            .append_pair("format", "json")
+            .finish();
+
+        let request = Request::post(LASTFM_URL)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))?;
+        let response = self.send(request).await?;
+        Ok(serde_json::from_slice(&response)?)
+    }
+
+    async fn send(&self, request: Request<Body>) -> Result<hyper::body::Bytes, ScrobbleError> {
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+
+        if !status.is_success() {
+            return Err(ScrobbleError(format!(
+                "{}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            )));
+        }
+        Ok(body)
+    }
+}
+
+async fn metadata(session: &Session, track_id: SpotifyId) -> Option<TrackMetadata> {
+    if track_id.audio_type != SpotifyAudioType::Track {
+        return None;
+    }
+
+    match TrackMetadata::get(session, track_id).await {
+        Ok(metadata) => Some(metadata),
+        Err(e) => {
+            warn!(
+                "Cannot get metadata of {} to scrobble: {}",
+                track_id.to_uri(),
+                e
+            );
+            None
+        }
+    }
+}
+
+fn listenbrainz_metadata(metadata: &TrackMetadata) -> serde_json::Value {
+    json!({
+        "artist_name": metadata.artists.join(", "),
+        "track_name": metadata.name,
+        "release_name": metadata.album,
+        "additional_info": {
+            "duration_ms": metadata.duration_ms,
+            "spotify_id": format!("https://open.spotify.com/track/{}", metadata.id.to_base62()),
+            "media_player": "librespot",
+            "submission_client": "librespot",
+            "submission_client_version": version::SEMVER,
+        },
+    })
+}
+
+fn lastfm_track_params(metadata: &TrackMetadata) -> BTreeMap<&'static str, String> {
+    let mut params = BTreeMap::new();
+    // Last.fm expects the main artist only.
+    params.insert(
+        "artist",
+        metadata.artists.first().cloned().unwrap_or_default(),
+    );
+    params.insert("track", metadata.name.clone());
+    params.insert("album", metadata.album.clone());
+    params.insert("duration", (metadata.duration_ms / 1000).to_string());
+    params
+}
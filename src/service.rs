@@ -0,0 +1,152 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+#[cfg(windows)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::warn;
+
+/// Forks into the background, detaching from the terminal. The process keeps the current
+/// working directory, so relative cache paths still work.
+///
+/// This has to happen before the runtime starts any threads.
+#[cfg(unix)]
+pub fn daemonize(pidfile: Option<&str>) -> io::Result<()> {
+    let mut daemon = daemonize::Daemonize::new().working_directory(std::env::current_dir()?);
+    if let Some(pidfile) = pidfile {
+        daemon = daemon.pid_file(pidfile);
+    }
+
+    daemon
+        .start()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_pidfile: Option<&str>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Running as a daemon is only supported on Unix",
+    ))
+}
+
+pub fn write_pidfile(path: &str) -> io::Result<()> {
+    fs::write(path, format!("{}\n", std::process::id()))
+}
+
+pub fn remove_pidfile(path: &str) {
+    if Path::new(path).exists() {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Cannot remove PID file {}: {}", path, e);
+        }
+    }
+}
+
+/// Notifications for systemd services of `Type=notify`. These do nothing unless the process
+/// was started by systemd.
+#[cfg(unix)]
+pub mod systemd {
+    use super::*;
+    use sd_notify::NotifyState;
+
+    fn notify(state: NotifyState) {
+        if let Err(e) = sd_notify::notify(false, &[state]) {
+            warn!("Cannot notify systemd: {}", e);
+        }
+    }
+
+    pub fn ready() {
+        notify(NotifyState::Ready);
+    }
+
+    pub fn stopping() {
+        notify(NotifyState::Stopping);
+    }
+
+    pub fn status(status: &str) {
+        notify(NotifyState::Status(status));
+    }
+
+    pub fn watchdog() {
+        notify(NotifyState::Watchdog);
+    }
+
+    /// How often to ping the watchdog, half the `WatchdogSec` of the service.
+    pub fn watchdog_interval() -> Option<Duration> {
+        let mut usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut usec) {
+            Some(Duration::from_micros(usec) / 2)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub mod systemd {
+    use super::*;
+
+    pub fn ready() {}
+
+    pub fn stopping() {}
+
+    pub fn status(_status: &str) {}
+
+    pub fn watchdog() {}
+
+    pub fn watchdog_interval() -> Option<Duration> {
+        None
+    }
+}
+
+/// Makes `TerminateSignal` resolve, e.g. when the Windows service is stopped.
+#[cfg(windows)]
+pub fn request_termination() {
+    TERMINATION_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(windows)]
+static TERMINATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Resolves when the process is asked to terminate, i.e. on SIGTERM or when the Windows
+/// service is stopped.
+pub struct TerminateSignal {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl TerminateSignal {
+    #[cfg(unix)]
+    pub fn new() -> io::Result<TerminateSignal> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(TerminateSignal {
+            signal: signal(SignalKind::terminate())?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new() -> io::Result<TerminateSignal> {
+        Ok(TerminateSignal {})
+    }
+
+    #[cfg(unix)]
+    pub async fn recv(&mut self) {
+        self.signal.recv().await;
+    }
+
+    #[cfg(windows)]
+    pub async fn recv(&mut self) {
+        // Requested from a thread of the service control manager.
+        let mut interval = tokio::time::interval(Duration::from_millis(200));
+        while !TERMINATION_REQUESTED.load(Ordering::SeqCst) {
+            interval.tick().await;
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    pub async fn recv(&mut self) {
+        futures_util::future::pending::<()>().await
+    }
+}
@@ -0,0 +1,85 @@
+use librespot::core::session::Session;
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use librespot::core::Error;
+use librespot::metadata::{Album, Artist, Episode, Image, Metadata, Show, Track};
+use serde_json::json;
+
+/// Human readable metadata of a track or podcast episode.
+///
+/// For episodes, the show takes the place of the album and its publisher the place of the artist.
+#[derive(Debug, Clone)]
+pub struct TrackMetadata {
+    pub id: SpotifyId,
+    pub name: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub duration_ms: u32,
+    pub cover: Option<Image>,
+    pub cover_url: Option<String>,
+}
+
+impl TrackMetadata {
+    pub async fn get(session: &Session, id: SpotifyId) -> Result<TrackMetadata, Error> {
+        match id.audio_type {
+            SpotifyAudioType::Track => {
+                let track = Track::get(session, id).await?;
+                let album = Album::get(session, track.album).await?;
+
+                let mut artists = Vec::with_capacity(track.artists.len());
+                for artist in track.artists {
+                    artists.push(Artist::get(session, artist).await?.name);
+                }
+
+                let cover = album.covers.largest();
+                Ok(TrackMetadata {
+                    id,
+                    name: track.name,
+                    artists,
+                    album: album.name,
+                    duration_ms: track.duration as u32,
+                    cover: cover.copied(),
+                    cover_url: cover.map(Image::url),
+                })
+            }
+            SpotifyAudioType::Podcast => {
+                let episode = Episode::get(session, id).await?;
+                let show = Show::get(session, episode.show).await?;
+
+                let cover = episode
+                    .covers
+                    .largest()
+                    .or_else(|| show.covers.largest())
+                    .copied();
+
+                Ok(TrackMetadata {
+                    id,
+                    name: episode.name,
+                    artists: vec![show.publisher],
+                    album: show.name,
+                    duration_ms: episode.duration as u32,
+                    cover_url: cover.as_ref().map(Image::url),
+                    cover,
+                })
+            }
+            SpotifyAudioType::NonPlayable => Err(Error::Unavailable(id.to_uri())),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id.to_base62(),
+            "uri": self.id.to_uri(),
+            "name": self.name,
+            "artists": self.artists,
+            "album": self.album,
+            "duration_ms": self.duration_ms,
+            "cover_url": self.cover_url,
+        })
+    }
+}
+
+/// Downloads the image of a cover, e.g. `TrackMetadata::cover`, through the proxy of the session.
+pub async fn fetch_cover(session: &Session, cover: &Image) -> Option<Vec<u8>> {
+    let image = session.fetch_image(&cover.url()).await.ok()?;
+    Some(image.to_vec())
+}
@@ -0,0 +1,149 @@
+//! Running as a Windows service, started by the service control manager (SCM).
+//!
+//! `librespot windows-service install [options]` registers a service that starts with
+//! Windows and runs librespot with the given options. `start`, `stop` and `uninstall`
+//! control it, `run` is what the SCM launches.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::time::Duration;
+
+use log::error;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use crate::service;
+
+const SERVICE_NAME: &str = "librespot";
+const SERVICE_DISPLAY_NAME: &str = "Librespot";
+const SERVICE_DESCRIPTION: &str = "Spotify Connect receiver";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+pub fn run(args: &[String]) -> i32 {
+    let result = match args.get(2).map(String::as_str) {
+        Some("install") => install(&args[3..]),
+        Some("uninstall") => uninstall(),
+        Some("start") => start(),
+        Some("stop") => stop(),
+        Some("run") => service_dispatcher::start(SERVICE_NAME, ffi_service_main),
+        _ => {
+            eprintln!(
+                "Usage: {} windows-service install [options]|uninstall|start|stop",
+                args[0]
+            );
+            return 1;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Windows service: {}", e);
+            1
+        }
+    }
+}
+
+fn install(options: &[String]) -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    let mut launch_arguments = vec![OsString::from("windows-service"), OsString::from("run")];
+    launch_arguments.extend(options.iter().map(OsString::from));
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_DISPLAY_NAME),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments,
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description(SERVICE_DESCRIPTION)?;
+    println!("Installed the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+fn uninstall() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    // Removed once the service has stopped and all handles to it are closed.
+    service.delete()?;
+    println!("Uninstalled the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+fn start() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::START)?;
+    service.start(&[] as &[&OsStr])
+}
+
+fn stop() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::STOP)?;
+    service.stop().map(|_| ())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+// The arguments from the SCM are those of a manual start, the options are the launch
+// arguments given at install.
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        error!("Windows service failed: {}", e);
+    }
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let status_handle = service_control_handler::register(SERVICE_NAME, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            service::request_termination();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    let set_state = |current_state, controls_accepted| {
+        status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+    };
+
+    let args: Vec<String> = env::args().collect();
+    let mut options = vec![args[0].clone()];
+    options.extend(args.iter().skip(3).cloned());
+    let setup = crate::get_setup(&options);
+
+    set_state(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    )?;
+    crate::runtime().block_on(crate::run(setup));
+    set_state(ServiceState::Stopped, ServiceControlAccept::empty())
+}